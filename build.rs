@@ -1,6 +1,10 @@
 extern crate cc;
 
 fn main() {
+    if std::env::var_os("CARGO_FEATURE_LZMA").is_none() {
+        return;
+    }
+    println!("cargo:rerun-if-changed=src/lzma.c");
     cc::Build::new()
         .file("lzma-19.00/src/Alloc.c")
         .file("lzma-19.00/src/LzFind.c")
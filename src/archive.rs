@@ -0,0 +1,180 @@
+use super::cache::CachedReader;
+use super::*;
+
+// Reader of archive entry data from its start, e.g. made by zip or 7z crate
+pub type EntryReader = Box<dyn Read + Send>;
+
+// Block cache of open_entry, 4M in 64K blocks
+pub const BLOCK_SIZE: usize = 64 * 1024;
+pub const BLOCKS: usize = 64;
+
+// Read + Seek over chd stored compressed inside zip, 7z or other archive, which entries
+// can be read only forward. Forward seeks skip data, backward ones reopen the entry
+// through caller's callback. Entries stored without compression, or seekable entry
+// readers of archive crates, don't need it: Chd accepts any Read + Seek.
+pub struct ArchiveEntry {
+    open: Box<dyn FnMut() -> io::Result<EntryReader> + Send>,
+    reader: Option<EntryReader>, // opened on first read
+    size: u64,                   // uncompressed size of the entry
+    pos: u64,                    // current read position
+    offset: u64,                 // position of reader
+    opens: u64,                  // times entry was opened
+}
+
+impl ArchiveEntry {
+    pub fn new<F>(size: u64, open: F) -> Self
+    where
+        F: FnMut() -> io::Result<EntryReader> + Send + 'static,
+    {
+        Self {
+            open: Box::new(open),
+            reader: None,
+            size,
+            pos: 0,
+            offset: 0,
+            opens: 0,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Times entry was opened, each one decompresses it from the start again
+    pub fn opens(&self) -> u64 {
+        self.opens
+    }
+
+    // move reader to current position, reopen entry if reader is past it
+    fn position(&mut self) -> io::Result<()> {
+        if self.reader.is_none() || self.offset > self.pos {
+            self.reader = Some((self.open)()?);
+            self.offset = 0;
+            self.opens += 1;
+        }
+        let reader = self.reader.as_mut().unwrap();
+        let skip = self.pos - self.offset;
+        let skipped = io::copy(&mut reader.take(skip), &mut io::sink())?;
+        self.offset += skipped;
+        match skipped == skip {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "archive: entry ended at {} bytes, expected {}",
+                    self.offset, self.size
+                ),
+            )),
+        }
+    }
+}
+
+impl Read for ArchiveEntry {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        self.position()?;
+        let len = std::cmp::min(buf.len() as u64, self.size - self.pos) as usize;
+        let n = self.reader.as_mut().unwrap().read(&mut buf[..len])?;
+        self.offset += n as u64;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ArchiveEntry {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "archive: seek before start")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+// Open chd stored in archive entry of `size` bytes. Reads go through block cache,
+// so map, metadata and nearby hunk reads don't reopen the entry.
+pub fn open_entry<F>(size: u64, open: F) -> io::Result<Chd<CachedReader<ArchiveEntry>>>
+where
+    F: FnMut() -> io::Result<EntryReader> + Send + 'static,
+{
+    let entry = ArchiveEntry::new(size, open);
+    Chd::open(CachedReader::new(entry, BLOCK_SIZE, BLOCKS)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // like decompressor of archive entry: no seeking
+    struct Forward(Cursor<&'static [u8]>);
+
+    impl Read for Forward {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    fn opener(data: &'static [u8]) -> impl FnMut() -> io::Result<EntryReader> {
+        move || Ok(Box::new(Forward(Cursor::new(data))))
+    }
+
+    #[test]
+    fn test_archive_entry() {
+        let data: &[u8] = include_bytes!("../samples/data.b64");
+        let mut entry = ArchiveEntry::new(data.len() as u64, opener(data));
+        assert_eq!(entry.opens(), 0);
+        let mut buf = [0; 100];
+        entry.seek(SeekFrom::Start(1000)).unwrap();
+        entry.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[1000..1100]);
+        entry.seek(SeekFrom::Current(50)).unwrap();
+        entry.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[1150..1250]);
+        assert_eq!(entry.opens(), 1);
+
+        entry.seek(SeekFrom::Start(10)).unwrap();
+        entry.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[10..110]);
+        assert_eq!(entry.opens(), 2);
+        assert_eq!(entry.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+        assert_eq!(entry.read(&mut buf).unwrap(), 0);
+        assert!(entry
+            .seek(SeekFrom::Current(-(data.len() as i64) - 1))
+            .is_err());
+
+        // entry shorter than told
+        let mut entry = ArchiveEntry::new(data.len() as u64 + 10, opener(data));
+        entry.seek(SeekFrom::Start(data.len() as u64 + 1)).unwrap();
+        let err = entry.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_open_entry() {
+        let raw: &'static [u8] = include_bytes!("../samples/zlib.chd");
+        let opens = Arc::new(AtomicUsize::new(0));
+        let counter = opens.clone();
+        let mut open = opener(raw);
+        let mut chd = open_entry(raw.len() as u64, move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            open()
+        })
+        .unwrap();
+        let mut data = Vec::new();
+        chd.extract(&mut data).unwrap();
+        assert_eq!(&data[..], &include_bytes!("../samples/data.b64")[..]);
+        chd.verify().unwrap();
+        // whole file fits into the cache
+        assert_eq!(opens.load(Ordering::Relaxed), 1);
+        assert_eq!(chd.into_inner().into_inner().opens(), 1);
+    }
+}
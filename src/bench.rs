@@ -0,0 +1,143 @@
+use super::*;
+use std::time::Duration;
+
+// What and how much to measure
+#[derive(Clone, Debug)]
+pub struct BenchProfile {
+    pub sequential_hunks: Option<usize>, // hunks to read sequentially, all if None
+    pub random_reads: usize,             // number of reads at random offsets
+    pub random_read_size: usize,         // bytes per random read
+    pub seed: u64,                       // random offsets generator seed
+}
+
+impl Default for BenchProfile {
+    fn default() -> Self {
+        Self {
+            sequential_hunks: None,
+            random_reads: 1000,
+            random_read_size: 2048,
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+impl BenchProfile {
+    // short run for a rough estimate
+    pub fn quick() -> Self {
+        Self {
+            sequential_hunks: Some(256),
+            random_reads: 100,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Throughput {
+    pub bytes: u64,
+    pub reads: u64,
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    pub fn bytes_per_sec(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.bytes as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    fn add(&mut self, bytes: usize, elapsed: Duration) {
+        self.bytes += bytes as u64;
+        self.reads += 1;
+        self.elapsed += elapsed;
+    }
+}
+
+// Hunk decode speed of one kind of hunks: codec slot, uncompressed, self or parent reference
+#[derive(Clone, Copy, Debug)]
+pub struct HunkBench {
+    pub compression: u8, // 0-3 for codec slots, see COMPRESSION_* for others
+    pub tag: u32,        // codec tag for codec slots, 0 otherwise
+    pub decode: Throughput,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BenchReport {
+    pub sequential: Throughput,
+    pub random: Throughput,
+    pub hunks: Vec<HunkBench>, // per hunk kind, in order of first appearance
+    pub cache: CacheStat,      // cache lookups during random reads
+}
+
+// xorshift64*, good enough to scatter read offsets
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+// Measure read throughput of chd. Current position is preserved, statistics are not
+pub fn bench<T: R>(chd: &mut Chd<T>, profile: &BenchProfile) -> io::Result<BenchReport> {
+    let mut report = BenchReport::default();
+    let pos = chd.pos;
+
+    // sequential decode of whole hunks, bypassing the cache
+    let hunks = match profile.sequential_hunks {
+        Some(n) => std::cmp::min(n, chd.hunk_count()),
+        None => chd.hunk_count(),
+    };
+    let mut buf = vec![0; chd.hunk_size()];
+    for hunknum in 0..hunks {
+        let compression = chd.map.locate(hunknum)?.0;
+        let start = Timer::start();
+        chd.read_hunk(hunknum, &mut buf)?;
+        let elapsed = start.elapsed();
+        report.sequential.add(buf.len(), elapsed);
+        let i = match report
+            .hunks
+            .iter()
+            .position(|h| h.compression == compression)
+        {
+            Some(i) => i,
+            None => {
+                let tag = match compression {
+                    COMPRESSION_TYPE_0..=COMPRESSION_TYPE_3 => {
+                        chd.header.compressors[compression as usize]
+                    }
+                    _ => 0,
+                };
+                report.hunks.push(HunkBench {
+                    compression,
+                    tag,
+                    decode: Throughput::default(),
+                });
+                report.hunks.len() - 1
+            }
+        };
+        report.hunks[i].decode.add(buf.len(), elapsed);
+    }
+
+    // random reads through the regular read path
+    let size = chd.size();
+    let mut buf = vec![0; std::cmp::min(profile.random_read_size as u64, size) as usize];
+    let span = size - buf.len() as u64 + 1;
+    let mut random = Random(profile.seed | 1);
+    let before = chd.stats().cache;
+    for _ in 0..profile.random_reads {
+        let offset = random.next() % span;
+        let start = Timer::start();
+        chd.seek(SeekFrom::Start(offset))?;
+        chd.read_exact(&mut buf)?;
+        report.random.add(buf.len(), start.elapsed());
+    }
+    report.cache = chd.stats().cache.since(&before);
+
+    chd.pos = pos;
+    Ok(report)
+}
@@ -1,18 +1,2361 @@
 extern crate chd;
 
-use std::fs::File;
-use std::io;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-use chd::Chd;
+use chd::cd::{self, CdSheet, USER_DATA_SIZE};
+use chd::cdimage::{self, CdImage};
+use chd::info::HardDiskInfo;
+use chd::parents::ParentIndex;
+use chd::redump;
+use chd::tags::{self, metadata, parse_tag, CodecTag};
+use chd::utils::{hex_string, IoLimit};
+use chd::writer::{self, ChdWriter, WriterOptions};
+use chd::{Chd, ChdProbe, HunkCompression, HunkError, MapFormat, Progress};
 
-fn main() -> io::Result<()> {
-    let path = std::env::args_os()
-        .nth(1)
-        .expect("Usage: rchdtool <chd-file>");
-    println!("Input file: {:?}", path);
+const USAGE: &str = "Usage: rchdtool [-q | -v] [-j <jobs>] [--parent <chd>]... [--io-limit <bytes>]
+                [--format json]
+                <command> [options]
+
+Options:
+  -q, --quiet            print only errors, no progress
+  -v, --verbose          print details of opened files and timings
+  -j, --jobs <jobs>      parallel workers for info, verify and checksum of several
+                         files, which are printed as a table
+  --parent <chd>         parent of input chd, repeat for each chd of parent chain
+  --io-limit <bytes>     read at most that many bytes per second of each opened
+                         chd, e.g. to verify in background
+  --format <text|json>   print failures to stderr as text, or as JSON records
+                         one per line with file, operation, kind, error, hunk,
+                         file offset of its data and parent unit
+
+Commands:
+  info <chd>... [--json] print header, metadata and track details
+  verify <chd>...        check logical data and metadata against header sha1
+  checksum <chd>... [--tracks]
+                         print CRC32, MD5 and SHA1 of logical data, or of each
+                         CD track in redump form
+  extract, extracthd <chd> (-o <raw|-> | --name <template>) [-f]
+          [-isb <start>] [-ib <bytes>]
+                         write logical image, or its byte range, to file or stdout
+  extractcd <chd> -o <sheet> [-f] [--gdi | --toc] [--splitbin]
+            [--name <template>]
+                         write CD or GD-ROM as bin/cue, gdi or cdrdao toc with
+                         track data next to it, one bin per track with --splitbin
+                         or with track files named by template
+  extractdvd <chd> -o <iso|-> [-f]
+                         write DVD as 2048 bytes sectors iso
+  createraw -i <raw> -o <chd> -us <unit> [-hs <hunk>] [-c <codecs>] [-f]
+            [--no-verify]
+                         create chd of raw image compressed by comma separated
+                         codecs, lzma,zlib,huff,flac by default, or none
+  createcd -i <cue|gdi|toc|iso> -o <chd> [-c <codecs>] [-f] [--no-verify]
+                         create CD or GD-ROM chd compressed by cdlz,cdzl,cdfl
+                         by default, or by given codecs, or none
+  createhd [-i <raw>] -o <chd> [-chs <c,h,s>] [-ss <bytes>] [-s <size>]
+           [-tp <chd>] [-id <ident>] [-hs <hunk>] [-c <codecs>] [-f] [--no-verify]
+                         create hard disk chd compressed like createraw, geometry
+                         given by -chs, copied from template chd or guessed from size
+  copy -i <chd> -o <chd> [-ip <parent>] [-op <parent> | --standalone]
+       [-hs <hunk>] [-c <codecs>] [-f] [--no-verify]
+                         copy chd changing hunk size or codecs, which are those
+                         of input by default, merging in input parent or
+                         storing only hunks that differ from output parent
+                         created and copied chds are read back and compared
+                         with source, unless --no-verify is given
+  addmeta -i <chd> -t <tag> [-ix <index>] (-vt <text> | -vf <file>) [-nocs]
+                         add or replace metadata entry in place
+  delmeta -i <chd> -t <tag> [-ix <index>]
+                         remove metadata entry in place
+  dumpmeta <chd> [-t <tag> [-ix <index>]] [-o <file|-> [-f]]
+                         print metadata entries, first bytes of long ones,
+                         or write raw bytes of one
+  listtracks <chd> [--cue | --gdi | --toc]
+                         print CD or GD-ROM track table, or sheet for extractcd
+  dumphunk <chd> <hunk> [--raw]
+                         hex dump of decoded hunk with its map entry, and of
+                         data stored in file with --raw
+  dumpsector <chd> <sector>
+                         hex dump of CD frame at disc address, or of unit
+  map <chd> [--csv | --json | --summary | --tracks]
+                         print hunk map entries and hunks per compression,
+                         and compression of each CD track with --tracks
+  diff <chd> <chd> [-pa <parent>] [-pb <parent>] [--hunks]
+                         print byte ranges, or hunks of first chd, where
+                         logical images differ, exit status is 1 if they do
+  selftest               check each codec against built-in known answer vectors
+  scan <dir>             probe chd headers in directory tree and report parents
+                         with their children, missing parents, orphans and
+                         duplicate sha1s
+
+Templates of --name have {title}, {stem} and {sha1} of chd, and {track} and
+{type} of CD track. {track:02} pads number by zeroes.
+";
+
+const QUIET: u8 = 0;
+const NORMAL: u8 = 1;
+const VERBOSE: u8 = 2;
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(NORMAL);
+// Parallel workers of commands taking several files
+static JOBS: AtomicUsize = AtomicUsize::new(1);
+// Bytes per second read from each chd, 0 is unlimited
+static IO_LIMIT: AtomicU64 = AtomicU64::new(0);
+
+// Failures are printed as JSON records with --format json
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+// Input chd of running command, file of its failure record
+static INPUT: Mutex<Option<OsString>> = Mutex::new(None);
+
+fn verbosity() -> u8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+fn jobs() -> usize {
+    JOBS.load(Ordering::Relaxed)
+}
+
+fn io_limit() -> Option<IoLimit> {
+    match IO_LIMIT.load(Ordering::Relaxed) {
+        0 => None,
+        bytes => Some(IoLimit {
+            bytes_per_sec: Some(bytes),
+            reads_per_sec: None,
+        }),
+    }
+}
+
+// Messages about done work, suppressed by --quiet
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if verbosity() > QUIET {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+// Details printed only with --verbose
+macro_rules! detail {
+    ($($arg:tt)*) => {
+        if verbosity() >= VERBOSE {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Failure as one line JSON record. Hunk, file offset of its data and parent unit
+// of parent hunk come from HunkError when they are not known by caller.
+fn error_record(
+    operation: &str,
+    file: Option<&OsStr>,
+    e: &io::Error,
+    hunk: Option<u64>,
+    offset: Option<u64>,
+) -> String {
+    // without usage text of usage errors
+    let message = e.to_string();
+    let message = message.lines().next().unwrap_or_default();
+    let error = HunkError::find(e);
+    let number = |n: Option<u64>| n.map_or("null".to_string(), |n| n.to_string());
+    format!(
+        "{{\"file\":{},\"operation\":{},\"kind\":{},\"error\":{},\"hunk\":{},\"offset\":{},\"unit\":{}}}",
+        file.map_or("null".to_string(), |file| json_string(
+            &file.to_string_lossy()
+        )),
+        json_string(operation),
+        json_string(&format!("{:?}", e.kind())),
+        json_string(message),
+        number(hunk.or_else(|| error.map(|error| error.hunk as u64))),
+        number(offset.or_else(|| error.and_then(|error| error.offset))),
+        number(error.and_then(|error| error.unit)),
+    )
+}
+
+fn json_errors() -> bool {
+    JSON_ERRORS.load(Ordering::Relaxed)
+}
+
+fn set_input(path: &OsStr) {
+    *INPUT.lock().unwrap() = Some(path.to_owned());
+}
+
+fn duration(seconds: u64) -> String {
+    match seconds >= 3600 {
+        true => format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        ),
+        false => format!("{:02}:{:02}", seconds / 60, seconds % 60),
+    }
+}
+
+// Progress bar with ETA on stderr, if it's a terminal and output isn't quiet.
+// With --verbose, time and speed are printed when operation is done.
+fn progress_bar(label: &'static str) -> Option<Progress> {
+    let bar = io::stderr().is_terminal() && verbosity() > QUIET;
+    if !bar && verbosity() < VERBOSE {
+        return None;
+    }
+    let start = Instant::now();
+    let last = Mutex::new(None::<Instant>);
+    Some(Progress::new(move |done, total| {
+        let mut last = last.lock().unwrap();
+        let now = Instant::now();
+        let finished = done >= total;
+        // redraw at most 10 times a second
+        if !finished && last.is_some_and(|last| now - last < Duration::from_millis(100)) {
+            return;
+        }
+        *last = Some(now);
+        let elapsed = (now - start).as_secs_f64();
+        let rate = done as f64 / elapsed.max(1e-3);
+        if bar {
+            const WIDTH: usize = 30;
+            let fraction = match total {
+                0 => 1.0,
+                total => done as f64 / total as f64,
+            };
+            let filled = (fraction * WIDTH as f64) as usize;
+            let eta = match done {
+                0 => "--:--".to_string(),
+                _ => duration(((total - done.min(total)) as f64 / rate) as u64),
+            };
+            eprint!(
+                "\r{:10} [{}{}] {:5.1}% {:8.1} MiB/s ETA {}",
+                label,
+                "#".repeat(filled),
+                "-".repeat(WIDTH - filled),
+                1e2 * fraction,
+                rate / (1 << 20) as f64,
+                eta
+            );
+            if finished {
+                eprintln!();
+            }
+        }
+        if finished {
+            detail!(
+                "{} {} bytes in {:.2}s, {:.1} MiB/s",
+                label,
+                done,
+                elapsed,
+                rate / (1 << 20) as f64
+            );
+        }
+    }))
+}
+
+fn usage_error(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}\n\n{}", message, USAGE),
+    )
+}
+
+// Parent chain candidates given by --parent options
+static PARENTS: OnceLock<Vec<OsString>> = OnceLock::new();
+
+fn parents() -> &'static [OsString] {
+    PARENTS.get().map_or(&[], |parents| parents)
+}
+
+// Chd with its parent chain attached from --parent files, if there are any
+fn open(path: &OsString) -> io::Result<Chd<File>> {
+    set_input(path);
     let file = File::open(path)?;
-    let mut chd = Chd::open(file)?;
-    chd.write_summary(&mut std::io::stdout())?;
-    chd.dump_metadata(&mut std::io::stdout())?;
+    let mut chd = match parents().is_empty() {
+        true => Chd::open(file)?,
+        false => {
+            let candidates = parents()
+                .iter()
+                .map(|parent| Chd::open(File::open(parent)?))
+                .collect::<io::Result<Vec<_>>>()?;
+            Chd::open_with_parents(file, candidates)
+                .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))?
+        }
+    };
+    chd.set_io_limit(io_limit());
+    detail!(
+        "Opened {:?}: v{}, {} bytes in {} hunks of {} bytes, {}",
+        path,
+        chd.version(),
+        chd.size(),
+        chd.hunk_count(),
+        chd.hunk_size(),
+        match chd.compressed() {
+            true => "compressed",
+            false => "uncompressed",
+        }
+    );
+    Ok(chd)
+}
+
+// Chd with parent given by option or --parent, error if it needs one and there's none
+fn open_child(
+    command: &str,
+    path: &OsString,
+    parent: Option<&OsString>,
+    option: &str,
+) -> io::Result<Chd<File>> {
+    let parent = match parent {
+        Some(parent) => parent,
+        None => return open_input(command, path, option),
+    };
+    let mut chd = Chd::open(File::open(path)?)?;
+    chd.set_parent(open(parent)?)?;
+    set_input(path);
+    Ok(chd)
+}
+
+// Chd which data is read by command, so it must have parent if it's a child
+fn open_input(command: &str, path: &OsString, option: &str) -> io::Result<Chd<File>> {
+    let chd = open(path)?;
+    if chd.has_parent() && parents().is_empty() {
+        return Err(usage_error(&format!(
+            "{}: {:?} needs parent {}, use {}",
+            command,
+            path,
+            chd.parent_sha1_hex(),
+            option
+        )));
+    }
+    Ok(chd)
+}
+
+// Value of option that takes one
+fn value<'a, I: Iterator<Item = &'a OsString>>(
+    args: &mut I,
+    option: &str,
+) -> io::Result<&'a OsString> {
+    args.next()
+        .ok_or_else(|| usage_error(&format!("{} needs a value", option)))
+}
+
+fn number<'a, I: Iterator<Item = &'a OsString>>(args: &mut I, option: &str) -> io::Result<u64> {
+    let text = value(args, option)?.to_string_lossy();
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| usage_error(&format!("{}: bad number {}", option, text)))
+}
+
+// Existing files are kept unless force is set
+fn create_file(path: &OsString, force: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    match force {
+        true => options.write(true).create(true).truncate(true),
+        false => options.write(true).create_new(true),
+    };
+    options
+        .open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))
+}
+
+// Output file, or stdout for "-"
+fn create(path: &OsString, force: bool) -> io::Result<Box<dyn Write>> {
+    match path == "-" {
+        true => Ok(Box::new(io::stdout().lock())),
+        false => Ok(Box::new(create_file(path, force)?)),
+    }
+}
+
+// Whether name matches pattern with * for any bytes and ? for any one byte
+fn wildcard(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (Some((b'*', rest)), _) => {
+            wildcard(rest, name) || (!name.is_empty() && wildcard(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name))) => wildcard(rest, name),
+        (Some((p, rest)), Some((n, name))) => p == n && wildcard(rest, name),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+// Input files with wildcards in file names expanded, for shells that don't do it
+fn expand(command: &str, args: &[&OsString]) -> io::Result<Vec<OsString>> {
+    let mut paths = Vec::new();
+    for arg in args {
+        let path = Path::new(arg);
+        let pattern = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.contains(['*', '?']) => name,
+            _ => {
+                paths.push(arg.to_os_string());
+                continue;
+            }
+        };
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            if wildcard(pattern.as_bytes(), name.as_encoded_bytes()) {
+                found.push(path.with_file_name(name).into_os_string());
+            }
+        }
+        if found.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{}: no files match {:?}", command, arg),
+            ));
+        }
+        found.sort();
+        paths.append(&mut found);
+    }
+    if paths.is_empty() {
+        return Err(usage_error(&format!("{}: no input file", command)));
+    }
+    Ok(paths)
+}
+
+// Run command for each file by -j workers, printing table rows as files are done
+// and summary at the end. Error if any of the files failed.
+fn batch<F>(command: &str, paths: &[OsString], header: &str, run: F) -> io::Result<()>
+where
+    F: Fn(&OsString) -> io::Result<String> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    println!("{}  File", header);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs().min(paths.len()) {
+            scope.spawn(|| {
+                while let Some(path) = paths.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let row = match run(path) {
+                        Ok(columns) => {
+                            format!("{:<7} {}  {}", "OK", columns, path.to_string_lossy())
+                        }
+                        Err(e) => {
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            if json_errors() {
+                                eprintln!("{}", error_record(command, Some(path), &e, None, None));
+                            }
+                            // without usage text of usage errors
+                            let e = e.to_string();
+                            let message = e.lines().next().unwrap_or_default();
+                            format!("{:<7} {}: {}", "FAILED", path.to_string_lossy(), message)
+                        }
+                    };
+                    println!("{}", row);
+                }
+            });
+        }
+    });
+    // failures of files are already reported, summary isn't about one of them
+    *INPUT.lock().unwrap() = None;
+    let failed = failed.into_inner();
+    status!(
+        "{} files: {} ok, {} failed",
+        paths.len(),
+        paths.len() - failed,
+        failed
+    );
+    match failed {
+        0 => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "{} of {} files failed",
+            failed,
+            paths.len()
+        ))),
+    }
+}
+
+// Expand {name} and {name:spec} of template, where spec is width with leading 0
+// for zero padding like in format!, numbers are aligned right and text left.
+// {{ and }} are literal braces. Values are made file name safe, so only the
+// template itself may have path separators.
+fn render(template: &str, vars: &[(&str, String)]) -> io::Result<String> {
+    let bad = |text: &str| usage_error(&format!("--name {:?}: {}", template, text));
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        result.push_str(&rest[..i]);
+        let brace = &rest[i..i + 1];
+        rest = &rest[i + 1..];
+        if let Some(tail) = rest.strip_prefix(brace) {
+            result.push_str(brace);
+            rest = tail;
+            continue;
+        }
+        if brace == "}" {
+            return Err(bad("unmatched }"));
+        }
+        let end = rest.find('}').ok_or_else(|| bad("unmatched {"))?;
+        let (var, spec) = match rest[..end].split_once(':') {
+            Some((var, spec)) => (var, spec),
+            None => (&rest[..end], ""),
+        };
+        rest = &rest[end + 1..];
+        let value = vars
+            .iter()
+            .find(|(name, _)| *name == var)
+            .map(|(_, value)| file_name_safe(value))
+            .ok_or_else(|| bad(&format!("unknown variable {}", var)))?;
+        let width: usize = match spec.trim_start_matches('0') {
+            "" => 0,
+            width => width.parse().map_err(|_| bad("bad width"))?,
+        };
+        let number = value.bytes().all(|b| b.is_ascii_digit());
+        match (spec.starts_with('0'), number) {
+            (true, _) => result.push_str(&format!("{:0>1$}", value, width)),
+            (false, true) => result.push_str(&format!("{:>1$}", value, width)),
+            (false, false) => result.push_str(&format!("{:1$}", value, width)),
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+// Characters that can't be in file names on some systems are replaced by _,
+// as well as names that aren't files: empty, . and ..
+fn file_name_safe(text: &str) -> String {
+    if matches!(text, "" | "." | "..") {
+        return "_".to_string();
+    }
+    text.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+// Variables of --name templates for whole chd: {title} is volume label or title
+// from system header, or chd file name without extension if there's none
+fn name_vars(chd: &mut Chd<File>, path: &OsString) -> Vec<(&'static str, String)> {
+    let stem = Path::new(path)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let label = chd::ident::identify(chd).ok().and_then(|disc| disc.label);
+    vec![
+        ("title", label.unwrap_or_else(|| stem.clone())),
+        ("stem", stem),
+        ("sha1", chd.sha1_hex()),
+    ]
+}
+
+// Track file names by --name template with {track} number and {type} of track.
+// Tracks sharing a file have to be adjacent, and gdi needs a file per track.
+fn track_names(
+    chd: &mut Chd<File>,
+    path: &OsString,
+    toc: &[cd::Track],
+    template: &str,
+    sheet: CdSheet,
+) -> io::Result<Vec<String>> {
+    let vars = name_vars(chd, path);
+    let mut files: Vec<String> = Vec::with_capacity(toc.len());
+    for track in toc {
+        let mut vars = vars.clone();
+        vars.push(("track", track.number.to_string()));
+        vars.push(("type", track.track_type.name().to_lowercase()));
+        let file = render(template, &vars)?;
+        let shared = files.iter().position(|f| *f == file);
+        match shared {
+            Some(_) if sheet == CdSheet::Gdi => {
+                return Err(usage_error(&format!(
+                    "--name {:?}: gdi needs a file per track, use {{track}}",
+                    template
+                )))
+            }
+            Some(_) if files.last() != Some(&file) => {
+                return Err(usage_error(&format!(
+                    "--name {:?}: track {} has the same file {:?} as earlier track that isn't next to it",
+                    template, track.number, file
+                )))
+            }
+            _ => files.push(file),
+        }
+    }
+    Ok(files)
+}
+
+// Short names of compressors, like huff,zlib
+fn codecs(chd: &Chd<File>) -> String {
+    let names: Vec<String> = chd
+        .codecs()
+        .iter()
+        .flatten()
+        .map(|tag| CodecTag(*tag).to_string())
+        .collect();
+    match names.is_empty() {
+        true => "none".to_string(),
+        false => names.join(","),
+    }
+}
+
+fn info(args: &[OsString]) -> io::Result<()> {
+    let (args, flags) = positional("info", args, &["--json"])?;
+    let json = flags.contains(&"--json");
+    let paths = expand("info", &args)?;
+    if let [path] = &paths[..] {
+        let mut chd = open(path)?;
+        let info = chd.info()?;
+        let mut stdout = io::stdout();
+        if json {
+            return info.write_json(&mut stdout);
+        }
+        println!("Input file: {:?}", path);
+        chd.write_summary(&mut stdout)?;
+        chd.dump_metadata(&mut stdout)?;
+        return info.write_details(&mut stdout);
+    }
+    if json {
+        return Err(usage_error("info: --json takes one input file"));
+    }
+    let header = format!(
+        "{:<7} {:>2} {:>12} {:>6} {:<23} {:<40}",
+        "Status", "V", "Size", "Hunk", "Compression", "SHA1"
+    );
+    batch("info", &paths, &header, |path| {
+        let chd = open(path)?;
+        Ok(format!(
+            "{:>2} {:>12} {:>6} {:<23} {}",
+            chd.version(),
+            chd.size(),
+            chd.hunk_size(),
+            codecs(&chd),
+            chd.sha1_hex()
+        ))
+    })
+}
+
+fn verify(args: &[OsString]) -> io::Result<()> {
+    let (args, _) = positional("verify", args, &[])?;
+    let paths = expand("verify", &args)?;
+    let path = match &paths[..] {
+        [path] => path,
+        _ => {
+            let header = format!("{:<7} {:<40}", "Status", "SHA1");
+            return batch("verify", &paths, &header, |path| {
+                let mut chd = open_input("verify", path, "--parent")?;
+                chd.verify()?;
+                Ok(chd.sha1_hex())
+            });
+        }
+    };
+    let mut chd = open_input("verify", path, "--parent")?;
+    chd.set_progress(progress_bar("Verifying"));
+    chd.verify()?;
+    status!("{:?}: SHA1 {} verified", path, chd.sha1_hex());
+    Ok(())
+}
+
+fn checksum(args: &[OsString]) -> io::Result<()> {
+    let (args, flags) = positional("checksum", args, &["--tracks"])?;
+    let paths = expand("checksum", &args)?;
+    let path = match &paths[..] {
+        [path] => path,
+        _ if flags.contains(&"--tracks") => {
+            return Err(usage_error("checksum: --tracks takes one input file"))
+        }
+        _ => {
+            let header = format!(
+                "{:<7} {:>12} {:<8} {:<32} {:<40}",
+                "Status", "Size", "CRC32", "MD5", "SHA1"
+            );
+            return batch("checksum", &paths, &header, |path| {
+                let mut chd = open_input("checksum", path, "--parent")?;
+                let hashes = redump::image_hashes(&mut chd)?;
+                Ok(format!(
+                    "{:>12} {:08x} {} {}",
+                    hashes.size,
+                    hashes.crc32,
+                    hex_string(&hashes.md5),
+                    hex_string(&hashes.sha1)
+                ))
+            });
+        }
+    };
+    let mut chd = open_input("checksum", path, "--parent")?;
+    chd.set_progress(progress_bar("Hashing"));
+    if !flags.contains(&"--tracks") {
+        let hashes = redump::image_hashes(&mut chd)?;
+        println!("Size:  {}", hashes.size);
+        println!("CRC32: {:08x}", hashes.crc32);
+        println!("MD5:   {}", hex_string(&hashes.md5));
+        println!("SHA1:  {}", hex_string(&hashes.sha1));
+        return Ok(());
+    }
+    if cd::read_toc(&mut chd)?.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "checksum: chd has no CD tracks",
+        ));
+    }
+    println!("Track       Size CRC32    MD5                              SHA1");
+    for track in redump::track_hashes(&mut chd)? {
+        println!(
+            "{:5} {:10} {:08x} {} {}",
+            track.number,
+            track.size,
+            track.crc32,
+            hex_string(&track.md5),
+            hex_string(&track.sha1)
+        );
+    }
+    Ok(())
+}
+
+fn extract(args: &[OsString]) -> io::Result<()> {
+    let mut path = None;
+    let mut output = None;
+    let mut force = false;
+    let mut start = 0;
+    let mut length = None;
+    let mut name = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-o") | Some("--output") => output = Some(value(&mut args, "--output")?),
+            Some("--name") => name = Some(value(&mut args, "--name")?),
+            Some("-f") | Some("--force") => force = true,
+            Some("-isb") | Some("--inputstartbyte") => {
+                start = number(&mut args, "--inputstartbyte")?
+            }
+            Some("-ib") | Some("--inputbytes") => length = Some(number(&mut args, "--inputbytes")?),
+            Some(option) if option.starts_with('-') => {
+                return Err(usage_error(&format!("extract: unknown option {}", option)))
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(usage_error("extract: more than one input file")),
+        }
+    }
+    let path = path.ok_or_else(|| usage_error("extract: no input file"))?;
+    let mut chd = open_input("extract", path, "--parent")?;
+    let output = match (output, name) {
+        (Some(output), None) => output.clone(),
+        (None, Some(name)) => render(&name.to_string_lossy(), &name_vars(&mut chd, path))?.into(),
+        (Some(_), Some(_)) => return Err(usage_error("extract: -o and --name exclude each other")),
+        (None, None) => return Err(usage_error("extract: no output file")),
+    };
+    let output = &output;
+    if start > chd.size() {
+        return Err(usage_error(&format!(
+            "extract: start byte {} is past end of {} bytes image",
+            start,
+            chd.size()
+        )));
+    }
+    let length = length.unwrap_or(chd.size() - start);
+    if length > chd.size() - start {
+        return Err(usage_error(&format!(
+            "extract: {} bytes at {} is past end of {} bytes image",
+            length,
+            start,
+            chd.size()
+        )));
+    }
+    let mut to = BufWriter::new(create(output, force)?);
+    let progress = progress_bar("Extracting");
+    let copied = match start == 0 && length == chd.size() {
+        true => {
+            chd.set_progress(progress);
+            chd.extract(&mut to)?
+        }
+        false => {
+            chd.seek(SeekFrom::Start(start))?;
+            let mut buf = vec![0; chd.hunk_size()];
+            let mut copied = 0;
+            while copied < length {
+                let chunk = std::cmp::min(buf.len() as u64, length - copied) as usize;
+                chd.read_exact(&mut buf[..chunk])?;
+                to.write_all(&buf[..chunk])?;
+                copied += chunk as u64;
+                if let Some(progress) = &progress {
+                    progress.report(copied, length);
+                }
+            }
+            copied
+        }
+    };
+    to.flush()?;
+    if output != "-" {
+        status!("Extracted {} bytes to {:?}", copied, output);
+    }
+    Ok(())
+}
+
+fn extractcd(args: &[OsString]) -> io::Result<()> {
+    let mut path = None;
+    let mut output = None;
+    let mut force = false;
+    let mut sheet = None;
+    let mut split = false;
+    let mut name = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-o") | Some("--output") => output = Some(value(&mut args, "--output")?),
+            Some("--name") => name = Some(value(&mut args, "--name")?.to_string_lossy()),
+            Some("-f") | Some("--force") => force = true,
+            Some("--cue") => sheet = Some(CdSheet::Cue),
+            Some("--gdi") => sheet = Some(CdSheet::Gdi),
+            Some("--toc") => sheet = Some(CdSheet::Toc),
+            Some("-sb") | Some("--splitbin") => split = true,
+            Some(option) if option.starts_with('-') => {
+                return Err(usage_error(&format!(
+                    "extractcd: unknown option {}",
+                    option
+                )))
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(usage_error("extractcd: more than one input file")),
+        }
+    }
+    let path = path.ok_or_else(|| usage_error("extractcd: no input file"))?;
+    let output = Path::new(output.ok_or_else(|| usage_error("extractcd: no output file"))?);
+    // without an option the sheet format follows output extension
+    let sheet = sheet.unwrap_or_else(|| match output.extension().and_then(|e| e.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("gdi") => CdSheet::Gdi,
+        Some(ext) if ext.eq_ignore_ascii_case("toc") => CdSheet::Toc,
+        _ => CdSheet::Cue,
+    });
+    let mut chd = open_input("extractcd", path, "--parent")?;
+    let toc = cd::read_toc(&mut chd)?;
+    let files = match name {
+        Some(name) => track_names(&mut chd, path, &toc, &name, sheet)?,
+        None => {
+            let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+            cd::track_files(&toc, &stem, sheet, split)
+        }
+    };
+    if !force {
+        let dir = output.parent().unwrap_or_else(|| Path::new(""));
+        let existing = std::iter::once(output.to_path_buf())
+            .chain(files.iter().map(|file| dir.join(file)))
+            .find(|file| file.exists());
+        if let Some(file) = existing {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} exists, use -f to overwrite", file),
+            ));
+        }
+    }
+    chd.set_progress(progress_bar("Extracting"));
+    for file in cd::extract_cd_files(&mut chd, output, sheet, &files)? {
+        status!("Wrote {:?}", file);
+    }
+    Ok(())
+}
+
+fn extractdvd(args: &[OsString]) -> io::Result<()> {
+    let mut path = None;
+    let mut output = None;
+    let mut force = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-o") | Some("--output") => output = Some(value(&mut args, "--output")?),
+            Some("-f") | Some("--force") => force = true,
+            Some(option) if option.starts_with('-') => {
+                return Err(usage_error(&format!(
+                    "extractdvd: unknown option {}",
+                    option
+                )))
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(usage_error("extractdvd: more than one input file")),
+        }
+    }
+    let path = path.ok_or_else(|| usage_error("extractdvd: no input file"))?;
+    let output = output.ok_or_else(|| usage_error("extractdvd: no output file"))?;
+    let mut chd = open_input("extractdvd", path, "--parent")?;
+    if chd.read_metadata_simple(metadata::DVD)?.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "extractdvd: chd has no DVD metadata",
+        ));
+    }
+    let sector_size = USER_DATA_SIZE as u64;
+    if chd.unit_size() != USER_DATA_SIZE || chd.size() % sector_size != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "extractdvd: {} bytes of {} bytes units is not a whole number of sectors",
+                chd.size(),
+                chd.unit_size()
+            ),
+        ));
+    }
+    let sectors = chd.size() / sector_size;
+
+    // bad hunks are written as zeroes and reported once everything is copied
+    let mut to = BufWriter::new(create(output, force)?);
+    let mut hunk = vec![0; chd.hunk_size()];
+    let mut bad: Vec<(u64, u64)> = Vec::new();
+    let progress = progress_bar("Extracting");
+    for hunknum in 0..chd.hunk_count() {
+        let offset = hunknum as u64 * hunk.len() as u64;
+        let size = std::cmp::min(hunk.len() as u64, chd.size() - offset) as usize;
+        if let Err(e) = chd.read_exact_at(offset, &mut hunk[..size]) {
+            let (first, last) = (
+                offset / sector_size,
+                (offset + size as u64) / sector_size - 1,
+            );
+            match json_errors() {
+                true => eprintln!(
+                    "{}",
+                    error_record("extractdvd", Some(path), &e, Some(hunknum as u64), None)
+                ),
+                false => eprintln!("Sectors {}-{} unreadable: {}", first, last, e),
+            }
+            bad.push((first, last));
+            hunk[..size].fill(0);
+        }
+        to.write_all(&hunk[..size])?;
+        if let Some(progress) = &progress {
+            progress.report(offset + size as u64, chd.size());
+        }
+    }
+    to.flush()?;
+    let unreadable: u64 = bad.iter().map(|(first, last)| last - first + 1).sum();
+    if unreadable > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} of {} sectors unreadable", unreadable, sectors),
+        ));
+    }
+    if output != "-" {
+        status!("Extracted {} sectors to {:?}", sectors, output);
+    }
     Ok(())
 }
+
+fn createraw(args: &[OsString]) -> io::Result<()> {
+    let mut input = None;
+    let mut output = None;
+    let mut force = false;
+    let mut verify = true;
+    let mut hunk_size = 4096;
+    let mut unit_size = None;
+    let mut codecs = default_codecs(DEFAULT_CODECS);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-i") | Some("--input") => input = Some(value(&mut args, "--input")?),
+            Some("-o") | Some("--output") => output = Some(value(&mut args, "--output")?),
+            Some("-f") | Some("--force") => force = true,
+            Some("--no-verify") => verify = false,
+            Some("-hs") | Some("--hunksize") => hunk_size = number(&mut args, "--hunksize")?,
+            Some("-us") | Some("--unitsize") => unit_size = Some(number(&mut args, "--unitsize")?),
+            Some("-c") | Some("--compression") => {
+                codecs = compression_option(&mut args, "createraw")?
+            }
+            Some(option) => {
+                return Err(usage_error(&format!(
+                    "createraw: unknown argument {}",
+                    option
+                )))
+            }
+            None => return Err(usage_error("createraw: bad argument")),
+        }
+    }
+    let input = input.ok_or_else(|| usage_error("createraw: no input file"))?;
+    let output = output.ok_or_else(|| usage_error("createraw: no output file"))?;
+    let unit_size = unit_size.ok_or_else(|| usage_error("createraw: no unit size"))?;
+    let (hunk_size, unit_size) = match (u32::try_from(hunk_size), u32::try_from(unit_size)) {
+        (Ok(hunk_size), Ok(unit_size)) => (hunk_size, unit_size),
+        _ => return Err(usage_error("createraw: hunk or unit size is too big")),
+    };
+
+    let mut from = File::open(input)?;
+    let size = from.metadata()?.len();
+    let mut options = WriterOptions::new(size, hunk_size, unit_size).with_compression(&codecs);
+    options.progress = progress_bar("Creating");
+    let mut writer = ChdWriter::create(BufWriter::new(create_file(output, force)?), &options)?;
+    let mut hunk = vec![0; writer.hunk_size()];
+    for hunknum in 0..writer.hunk_count() {
+        let offset = hunknum as u64 * hunk.len() as u64;
+        let length = std::cmp::min(hunk.len() as u64, size - offset) as usize;
+        from.read_exact(&mut hunk[..length])?;
+        writer.write_hunk(&hunk[..length])?;
+    }
+    writer.finish()?.flush()?;
+    status!("Created {:?} of {} bytes", output, size);
+    if verify {
+        verify_output(&mut open(output)?, &mut File::open(input)?)?;
+    }
+    print_stats(output)
+}
+
+// Audio and data tracks compress differently, which helps to choose codecs
+fn write_track_compression<W: Write>(to: &mut W, chd: &mut Chd<File>) -> io::Result<()> {
+    let report = cd::track_compression(chd)?;
+    if report.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "map: chd has no tracks",
+        ));
+    }
+    writeln!(to, "Track compression:")?;
+    for track in report {
+        let kinds: Vec<String> = track
+            .compression
+            .iter()
+            .map(|c| format!("{} {}", c.compression, c.hunks))
+            .collect();
+        writeln!(
+            to,
+            "  {:02} {:10} {:12} of {:12} bytes {:5.1}%  {}",
+            track.number,
+            track.track_type.name(),
+            track.compressed(),
+            track.uncompressed(),
+            1e2 * track.ratio(),
+            kinds.join(", ")
+        )?;
+    }
+    Ok(())
+}
+
+// Hunks and bytes stored in file per storage kind
+fn write_histogram<W: Write>(to: &mut W, chd: &Chd<File>) -> io::Result<()> {
+    writeln!(to, "Compression statistics:")?;
+    for stat in chd.compression_stats()? {
+        let kind = match stat.compression {
+            // uncompressed map stores zero hunks of chd without parent as parent ones
+            HunkCompression::Parent if !chd.has_parent() => "zero".to_string(),
+            compression => compression.to_string(),
+        };
+        writeln!(
+            to,
+            "  {:10} {:8} hunks {:5.1}% {:12} bytes",
+            kind,
+            stat.hunks,
+            1e2 * stat.hunks as f64 / chd.hunk_count() as f64,
+            stat.compressed
+        )?;
+    }
+    writeln!(
+        to,
+        "  {} of {} bytes, ratio {:.1}%",
+        chd.file_size(),
+        chd.size(),
+        1e2 * chd.file_size() as f64 / chd.size() as f64
+    )
+}
+
+// Read just created chd back and compare every hunk and sha1 with its source
+fn verify_output<S: Read>(chd: &mut Chd<File>, source: &mut S) -> io::Result<()> {
+    chd.set_progress(progress_bar("Verifying"));
+    let report = writer::verify_written(chd, source)?;
+    for mismatch in &report.mismatched {
+        eprintln!("Hunk {}: {}", mismatch.hunk, mismatch.reason);
+    }
+    if report.rawsha1 != report.header_rawsha1 {
+        eprintln!(
+            "Data sha1 {} differs from header rawsha1 {}",
+            hex_string(&report.rawsha1),
+            hex_string(&report.header_rawsha1)
+        );
+    }
+    if report.sha1 != report.header_sha1 {
+        eprintln!(
+            "Overall sha1 {} differs from header sha1 {}",
+            hex_string(&report.sha1),
+            hex_string(&report.header_sha1)
+        );
+    }
+    match report.passed() {
+        true => {
+            status!("Verified {} hunks against source", report.hunks);
+            Ok(())
+        }
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "verification failed: {} of {} hunks differ from source",
+                report.mismatched.len(),
+                report.hunks
+            ),
+        )),
+    }
+}
+
+// Statistics of just created chd
+fn print_stats(path: &OsString) -> io::Result<()> {
+    if verbosity() == QUIET {
+        return Ok(());
+    }
+    write_histogram(&mut io::stderr(), &open(path)?)
+}
+
+fn createcd(args: &[OsString]) -> io::Result<()> {
+    let mut input = None;
+    let mut output = None;
+    let mut force = false;
+    let mut verify = true;
+    let mut codecs = default_codecs(DEFAULT_CD_CODECS);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-i") | Some("--input") => input = Some(value(&mut args, "--input")?),
+            Some("-o") | Some("--output") => output = Some(value(&mut args, "--output")?),
+            Some("-f") | Some("--force") => force = true,
+            Some("--no-verify") => verify = false,
+            Some("-c") | Some("--compression") => {
+                codecs = compression_option(&mut args, "createcd")?
+            }
+            Some(option) => {
+                return Err(usage_error(&format!(
+                    "createcd: unknown argument {}",
+                    option
+                )))
+            }
+            None => return Err(usage_error("createcd: bad argument")),
+        }
+    }
+    let input = input.ok_or_else(|| usage_error("createcd: no input file"))?;
+    let output = output.ok_or_else(|| usage_error("createcd: no output file"))?;
+    let image = CdImage::open(Path::new(input))?;
+    for t in &image.tracks {
+        status!(
+            "Track {:02}: {} {} frames from {:?}",
+            t.track.number,
+            t.track.track_type.name(),
+            t.track.frames,
+            t.file
+        );
+        if let Some((file, _, frames)) = &t.pregap_file {
+            status!("          {} pregap frames from {:?}", frames, file);
+        }
+    }
+    let to = BufWriter::new(create_file(output, force)?);
+    cdimage::create_cd(&image, to, &codecs, progress_bar("Creating"))?.flush()?;
+    status!("Created {:?}", output);
+    if verify {
+        verify_output(&mut open(output)?, &mut image.reader())?;
+    }
+    print_stats(output)?;
+    if verbosity() != QUIET {
+        write_track_compression(&mut io::stderr(), &mut open(output)?)?;
+    }
+    Ok(())
+}
+
+// Cylinders, heads and sectors of --chs
+fn parse_chs(text: &str) -> io::Result<(u32, u32, u32)> {
+    let fields: Vec<&str> = text.split(',').collect();
+    let names = ["cylinders", "heads", "sectors"];
+    if fields.len() != names.len() {
+        return Err(usage_error(&format!(
+            "--chs: bad geometry {}, need cylinders,heads,sectors",
+            text
+        )));
+    }
+    let mut numbers = [0; 3];
+    for ((number, field), name) in numbers.iter_mut().zip(&fields).zip(&names) {
+        *number = field
+            .parse()
+            .map_err(|_| usage_error(&format!("--chs: bad {} {:?} in {}", name, field, text)))?;
+    }
+    Ok((numbers[0], numbers[1], numbers[2]))
+}
+
+fn createhd(args: &[OsString]) -> io::Result<()> {
+    let mut input = None;
+    let mut output = None;
+    let mut force = false;
+    let mut verify = true;
+    let mut chs = None;
+    let mut sector_size = None;
+    let mut size = None;
+    let mut template = None;
+    let mut ident = None;
+    let mut hunk_size = None;
+    let mut codecs = default_codecs(DEFAULT_CODECS);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-i") | Some("--input") => input = Some(value(&mut args, "--input")?),
+            Some("-o") | Some("--output") => output = Some(value(&mut args, "--output")?),
+            Some("-f") | Some("--force") => force = true,
+            Some("--no-verify") => verify = false,
+            Some("-chs") | Some("--chs") => {
+                chs = Some(parse_chs(&value(&mut args, "--chs")?.to_string_lossy())?)
+            }
+            Some("-ss") | Some("--sectorsize") => {
+                sector_size = Some(number(&mut args, "--sectorsize")?)
+            }
+            Some("-s") | Some("--size") => size = Some(number(&mut args, "--size")?),
+            Some("-tp") | Some("--template") => template = Some(value(&mut args, "--template")?),
+            Some("-id") | Some("--ident") => ident = Some(value(&mut args, "--ident")?),
+            Some("-hs") | Some("--hunksize") => hunk_size = Some(number(&mut args, "--hunksize")?),
+            Some("-c") | Some("--compression") => {
+                codecs = compression_option(&mut args, "createhd")?
+            }
+            Some(option) => {
+                return Err(usage_error(&format!(
+                    "createhd: unknown argument {}",
+                    option
+                )))
+            }
+            None => return Err(usage_error("createhd: bad argument")),
+        }
+    }
+    let output = output.ok_or_else(|| usage_error("createhd: no output file"))?;
+    let mut from = match input {
+        Some(input) => Some(File::open(input)?),
+        None => None,
+    };
+    let input_size = match &from {
+        Some(file) => file.metadata()?.len(),
+        None => 0,
+    };
+
+    // template gives geometry and ident of another hard disk chd
+    let mut ident = match ident {
+        Some(path) => Some(std::fs::read(path)?),
+        None => None,
+    };
+    let mut geometry = None;
+    if let Some(template) = template {
+        let mut chd = open(template)?;
+        let meta = chd.read_metadata_simple(metadata::HARD_DISK)?;
+        let meta = meta.map(|m| String::from_utf8_lossy(&m).into_owned());
+        geometry = meta.as_deref().and_then(HardDiskInfo::parse);
+        if geometry.is_none() {
+            return Err(usage_error("createhd: template has no hard disk geometry"));
+        }
+        if ident.is_none() {
+            ident = chd.read_metadata_simple(metadata::HARD_DISK_IDENT)?;
+        }
+    }
+    let sector_size = match (sector_size, &geometry) {
+        (Some(sector_size), _) => u32::try_from(sector_size)
+            .map_err(|_| usage_error("createhd: sector size is too big"))?,
+        (None, Some(geometry)) => geometry.bytes_per_sector,
+        (None, None) => 512,
+    };
+    if sector_size == 0 {
+        return Err(usage_error("createhd: sector size is zero"));
+    }
+    if let Some((cylinders, heads, sectors)) = chs {
+        geometry = Some(HardDiskInfo {
+            cylinders,
+            heads,
+            sectors,
+            bytes_per_sector: sector_size,
+        });
+    }
+    let geometry = match geometry {
+        Some(geometry) => geometry,
+        None => {
+            let size = size.unwrap_or(input_size);
+            HardDiskInfo::guess(size, sector_size).ok_or_else(|| {
+                usage_error(&format!(
+                    "createhd: can't guess geometry of {} bytes with {} bytes sectors, use -chs",
+                    size, sector_size
+                ))
+            })?
+        }
+    };
+    let size = geometry.size();
+    if input_size > size {
+        return Err(usage_error(&format!(
+            "createhd: {} bytes input doesn't fit {} bytes disk",
+            input_size, size
+        )));
+    }
+    let hunk_size = match hunk_size {
+        Some(hunk_size) => {
+            let hunk_size = u32::try_from(hunk_size)
+                .map_err(|_| usage_error("createhd: hunk size is too big"))?;
+            if hunk_size == 0 || hunk_size % sector_size != 0 {
+                return Err(usage_error(&format!(
+                    "createhd: hunk size {} isn't a multiple of sector size {}",
+                    hunk_size, sector_size
+                )));
+            }
+            hunk_size
+        }
+        None => std::cmp::max(4096 / sector_size, 1) * sector_size,
+    };
+
+    let mut options = WriterOptions::new(size, hunk_size, sector_size).with_compression(&codecs);
+    options.progress = progress_bar("Creating");
+    let mut writer = ChdWriter::create(BufWriter::new(create_file(output, force)?), &options)?;
+    writer.add_metadata(metadata::HARD_DISK, &geometry.to_metadata(), true)?;
+    if let Some(ident) = ident {
+        writer.add_metadata(metadata::HARD_DISK_IDENT, &ident, true)?;
+    }
+    // input shorter than disk is padded by zeroes
+    let mut hunk = vec![0; writer.hunk_size()];
+    for hunknum in 0..writer.hunk_count() {
+        let offset = hunknum as u64 * hunk.len() as u64;
+        let length = std::cmp::min(hunk.len() as u64, size - offset) as usize;
+        let have = std::cmp::min(length as u64, input_size.saturating_sub(offset)) as usize;
+        hunk.fill(0);
+        if let Some(from) = &mut from {
+            from.read_exact(&mut hunk[..have])?;
+        }
+        writer.write_hunk(&hunk[..length])?;
+    }
+    writer.finish()?.flush()?;
+    status!(
+        "Created {:?}: {} cylinders, {} heads, {} sectors, {} bytes per sector",
+        output,
+        geometry.cylinders,
+        geometry.heads,
+        geometry.sectors,
+        geometry.bytes_per_sector
+    );
+    if verify {
+        // input shorter than disk was padded by zeroes
+        let source: Box<dyn Read> = match input {
+            Some(input) => Box::new(File::open(input)?.chain(io::repeat(0))),
+            None => Box::new(io::repeat(0)),
+        };
+        verify_output(&mut open(output)?, &mut source.take(size))?;
+    }
+    print_stats(output)
+}
+
+fn copy(args: &[OsString]) -> io::Result<()> {
+    let mut input = None;
+    let mut output = None;
+    let mut force = false;
+    let mut verify = true;
+    let mut input_parent = None;
+    let mut output_parent = None;
+    let mut standalone = false;
+    let mut hunk_size = None;
+    let mut codecs = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-i") | Some("--input") => input = Some(value(&mut args, "--input")?),
+            Some("-o") | Some("--output") => output = Some(value(&mut args, "--output")?),
+            Some("-f") | Some("--force") => force = true,
+            Some("--no-verify") => verify = false,
+            Some("-ip") | Some("--inputparent") => {
+                input_parent = Some(value(&mut args, "--inputparent")?)
+            }
+            Some("-op") | Some("--outputparent") => {
+                output_parent = Some(value(&mut args, "--outputparent")?)
+            }
+            Some("--standalone") => standalone = true,
+            Some("-hs") | Some("--hunksize") => hunk_size = Some(number(&mut args, "--hunksize")?),
+            Some("-c") | Some("--compression") => {
+                codecs = Some(compression_option(&mut args, "copy")?)
+            }
+            Some(option) => return Err(usage_error(&format!("copy: unknown argument {}", option))),
+            None => return Err(usage_error("copy: bad argument")),
+        }
+    }
+    let input = input.ok_or_else(|| usage_error("copy: no input file"))?;
+    let output = output.ok_or_else(|| usage_error("copy: no output file"))?;
+    if standalone && output_parent.is_some() {
+        return Err(usage_error("copy: --standalone and -op exclude each other"));
+    }
+    let mut chd = open_child("copy", input, input_parent, "-ip")?;
+    let hunk_size = match hunk_size {
+        Some(hunk_size) => {
+            u32::try_from(hunk_size).map_err(|_| usage_error("copy: hunk size is too big"))?
+        }
+        None => chd.hunk_size_u32(),
+    };
+    // codecs of input by default
+    let codecs = codecs.unwrap_or_else(|| writer::codecs_of(&chd));
+    let mut parent = match output_parent {
+        Some(parent) => Some(open(parent)?),
+        None => None,
+    };
+    let to = BufWriter::new(create_file(output, force)?);
+    chd.set_progress(progress_bar("Copying"));
+    writer::transcode(&mut chd, parent.as_mut(), hunk_size, &codecs, to)?.flush()?;
+    status!("Copied {:?} to {:?}", input, output);
+    if verify {
+        let mut copy = Chd::open(File::open(output)?)?;
+        if let Some(parent) = parent {
+            copy.set_parent(parent)?;
+        }
+        chd.set_progress(None);
+        chd.seek(SeekFrom::Start(0))?;
+        verify_output(&mut copy, &mut chd)?;
+    }
+    print_stats(output)
+}
+
+// Default codecs of chdman for raw and hard disk images
+const DEFAULT_CODECS: &[u32] = &[
+    tags::CHD_CODEC_LZMA,
+    tags::CHD_CODEC_ZLIB,
+    tags::CHD_CODEC_HUFF,
+    tags::CHD_CODEC_FLAC,
+];
+// Default codecs of chdman for CD images
+const DEFAULT_CD_CODECS: &[u32] = &[
+    tags::CHD_CODEC_CD_LZMA,
+    tags::CHD_CODEC_CD_ZLIB,
+    tags::CHD_CODEC_CD_FLAC,
+];
+
+// Those of codecs which this build has
+fn default_codecs(codecs: &[u32]) -> Vec<u32> {
+    codecs
+        .iter()
+        .copied()
+        .filter(|tag| writer::COMPRESSORS.contains(tag))
+        .collect()
+}
+
+// Comma separated codecs of --compression, at most 4 of them, or none for uncompressed chd
+fn compression_option<'a, I: Iterator<Item = &'a OsString>>(
+    args: &mut I,
+    command: &str,
+) -> io::Result<Vec<u32>> {
+    let text = value(args, "--compression")?.to_string_lossy();
+    if text == "none" {
+        return Ok(Vec::new());
+    }
+    let codecs = text
+        .split(',')
+        .map(|name| match parse_tag(name) {
+            Some(tag) if writer::COMPRESSORS.contains(&tag) => Ok(tag),
+            _ => Err(usage_error(&format!(
+                "{}: codec {:?} is not supported",
+                command, name
+            ))),
+        })
+        .collect::<io::Result<Vec<u32>>>()?;
+    if codecs.len() > 4 {
+        return Err(usage_error(&format!(
+            "{}: {} codecs given, at most 4 are allowed",
+            command,
+            codecs.len()
+        )));
+    }
+    Ok(codecs)
+}
+
+fn tag_option<'a, I: Iterator<Item = &'a OsString>>(args: &mut I) -> io::Result<u32> {
+    let text = value(args, "--tag")?.to_string_lossy();
+    parse_tag(&text).ok_or_else(|| usage_error(&format!("--tag: bad tag {}", text)))
+}
+
+// Chd opened for in place editing
+fn open_rw(path: &OsString) -> io::Result<Chd<File>> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    Chd::open(file)
+}
+
+fn addmeta(args: &[OsString]) -> io::Result<()> {
+    let mut input = None;
+    let mut tag = None;
+    let mut index = 0;
+    let mut data = None;
+    let mut checksum = true;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-i") | Some("--input") => input = Some(value(&mut args, "--input")?),
+            Some("-t") | Some("--tag") => tag = Some(tag_option(&mut args)?),
+            Some("-ix") | Some("--index") => index = number(&mut args, "--index")?,
+            // text is stored nul terminated like chdman does
+            Some("-vt") | Some("--valuetext") => {
+                let mut text = value(&mut args, "--valuetext")?
+                    .to_string_lossy()
+                    .into_owned()
+                    .into_bytes();
+                text.push(0);
+                data = Some(text);
+            }
+            Some("-vf") | Some("--valuefile") => {
+                data = Some(std::fs::read(value(&mut args, "--valuefile")?)?)
+            }
+            Some("-nocs") | Some("--nochecksum") => checksum = false,
+            Some(option) => {
+                return Err(usage_error(&format!(
+                    "addmeta: unknown argument {}",
+                    option
+                )))
+            }
+            None => return Err(usage_error("addmeta: bad argument")),
+        }
+    }
+    let input = input.ok_or_else(|| usage_error("addmeta: no input file"))?;
+    let tag = tag.ok_or_else(|| usage_error("addmeta: no tag"))?;
+    let data = data.ok_or_else(|| usage_error("addmeta: no value, use -vt or -vf"))?;
+    let index = u32::try_from(index).map_err(|_| usage_error("addmeta: index is too big"))?;
+    let mut chd = open_rw(input)?;
+    chd.set_metadata(tag, index, &data, checksum)?;
+    status!(
+        "Set {}#{} to {} bytes, SHA1 {}",
+        chd::tags::tag_string(tag),
+        index,
+        data.len(),
+        chd.sha1_hex()
+    );
+    Ok(())
+}
+
+fn delmeta(args: &[OsString]) -> io::Result<()> {
+    let mut input = None;
+    let mut tag = None;
+    let mut index = 0;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-i") | Some("--input") => input = Some(value(&mut args, "--input")?),
+            Some("-t") | Some("--tag") => tag = Some(tag_option(&mut args)?),
+            Some("-ix") | Some("--index") => index = number(&mut args, "--index")?,
+            Some(option) => {
+                return Err(usage_error(&format!(
+                    "delmeta: unknown argument {}",
+                    option
+                )))
+            }
+            None => return Err(usage_error("delmeta: bad argument")),
+        }
+    }
+    let input = input.ok_or_else(|| usage_error("delmeta: no input file"))?;
+    let tag = tag.ok_or_else(|| usage_error("delmeta: no tag"))?;
+    let index = u32::try_from(index).map_err(|_| usage_error("delmeta: index is too big"))?;
+    let mut chd = open_rw(input)?;
+    if !chd.delete_metadata(tag, index)? {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "delmeta: no {}#{} metadata",
+                chd::tags::tag_string(tag),
+                index
+            ),
+        ));
+    }
+    status!(
+        "Removed {}#{}, SHA1 {}",
+        chd::tags::tag_string(tag),
+        index,
+        chd.sha1_hex()
+    );
+    Ok(())
+}
+
+// Entries longer than this are printed by their first bytes only
+const METADATA_PRINT_LIMIT: u64 = 4096;
+
+fn dumpmeta(args: &[OsString]) -> io::Result<()> {
+    let mut path = None;
+    let mut tag = None;
+    let mut index = None;
+    let mut output = None;
+    let mut force = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-t") | Some("--tag") => tag = Some(tag_option(&mut args)?),
+            Some("-ix") | Some("--index") => index = Some(number(&mut args, "--index")?),
+            Some("-o") | Some("--output") => output = Some(value(&mut args, "--output")?),
+            Some("-f") | Some("--force") => force = true,
+            Some(option) if option.starts_with('-') => {
+                return Err(usage_error(&format!("dumpmeta: unknown option {}", option)))
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(usage_error("dumpmeta: more than one input file")),
+        }
+    }
+    let path = path.ok_or_else(|| usage_error("dumpmeta: no input file"))?;
+    let index = match index {
+        Some(index) => {
+            Some(u32::try_from(index).map_err(|_| usage_error("dumpmeta: index is too big"))?)
+        }
+        None => None,
+    };
+    let mut chd = open(path)?;
+    let tag = match tag {
+        Some(tag) => tag,
+        None if output.is_some() || index.is_some() => {
+            return Err(usage_error("dumpmeta: -o and -ix need a tag"))
+        }
+        None => return chd.dump_metadata(&mut io::stdout()),
+    };
+    let tag_name = chd::tags::tag_string(tag);
+
+    if let Some(output) = output {
+        let index = index.unwrap_or(0);
+        let mut reader = chd.metadata_reader(tag, index)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("dumpmeta: no {}#{} metadata", tag_name, index),
+            )
+        })?;
+        let mut to = create(output, force)?;
+        let length = io::copy(&mut reader, &mut to)?;
+        to.flush()?;
+        if output != "-" {
+            status!(
+                "Wrote {} bytes of {}#{} to {:?}",
+                length,
+                tag_name,
+                index,
+                output
+            );
+        }
+        return Ok(());
+    }
+
+    // all entries with the tag unless index is given
+    let indexes = match index {
+        Some(index) => index..index + 1,
+        None => 0..u32::MAX,
+    };
+    for index in indexes {
+        let mut reader = match chd.metadata_reader(tag, index)? {
+            Some(reader) => reader,
+            None => break,
+        };
+        let length = reader.len();
+        if length > METADATA_PRINT_LIMIT {
+            let mut head = [0; 32];
+            reader.read_exact(&mut head)?;
+            println!(
+                "{}#{}: ({}) {}... (-o writes whole entry)",
+                tag_name,
+                index,
+                length,
+                chd::utils::hex_string(&head)
+            );
+            continue;
+        }
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let text = data.strip_suffix(&[0]).unwrap_or(&data);
+        match text.iter().all(|b| (0x20..0x7f).contains(b)) {
+            true => println!(
+                "{}#{}: ({}) {}",
+                tag_name,
+                index,
+                data.len(),
+                String::from_utf8_lossy(text)
+            ),
+            false => println!(
+                "{}#{}: ({}) {}",
+                tag_name,
+                index,
+                data.len(),
+                chd::utils::hex_string(&data)
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn listtracks(args: &[OsString]) -> io::Result<()> {
+    let mut path = None;
+    let mut sheet = None;
+    for arg in args {
+        match arg.to_str() {
+            Some("--cue") => sheet = Some(CdSheet::Cue),
+            Some("--gdi") => sheet = Some(CdSheet::Gdi),
+            Some("--toc") => sheet = Some(CdSheet::Toc),
+            Some(option) if option.starts_with('-') => {
+                return Err(usage_error(&format!(
+                    "listtracks: unknown option {}",
+                    option
+                )))
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(usage_error("listtracks: more than one input file")),
+        }
+    }
+    let path = path.ok_or_else(|| usage_error("listtracks: no input file"))?;
+    let mut chd = open(path)?;
+    let toc = cd::read_toc(&mut chd)?;
+    if toc.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "listtracks: chd has no track metadata",
+        ));
+    }
+    let gdrom = cd::is_gdrom(&mut chd)?;
+    let mut stdout = io::stdout();
+    if let Some(sheet) = sheet {
+        // file names as extractcd would write them next to the chd
+        let stem = Path::new(path)
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let files = cd::track_files(&toc, &stem, sheet, false);
+        return cd::write_sheet(&toc, &files, sheet, gdrom, &mut stdout);
+    }
+    println!("Track Type           Subtype  Frames  Pregap Postgap      LBA      MSF");
+    for (track, start) in toc.iter().zip(cd::track_starts(&toc, gdrom)) {
+        println!(
+            "{:5} {:14} {:7} {:7} {:7} {:7} {:8} {:>8}",
+            track.number,
+            track.track_type.name(),
+            track.subcode.name(),
+            track.frames,
+            track.pregap,
+            track.postgap,
+            start,
+            cd::msf(start)
+        );
+    }
+    Ok(())
+}
+
+// 16 bytes per line with offset and ascii
+fn hex_dump<W: Write>(to: &mut W, base: u64, data: &[u8]) -> io::Result<()> {
+    for (i, line) in data.chunks(16).enumerate() {
+        write!(to, "{:08x}:", base + i as u64 * 16)?;
+        for (j, byte) in line.iter().enumerate() {
+            let space = match j == 8 {
+                true => "  ",
+                false => " ",
+            };
+            write!(to, "{}{:02x}", space, byte)?;
+        }
+        let pad = (16 - line.len()) * 3 + usize::from(line.len() <= 8);
+        write!(to, "{:pad$}  ", "", pad = pad)?;
+        for byte in line {
+            let c = match (0x20..0x7f).contains(byte) {
+                true => *byte as char,
+                false => '.',
+            };
+            write!(to, "{}", c)?;
+        }
+        writeln!(to)?;
+    }
+    Ok(())
+}
+
+fn print_hunk_info(chd: &Chd<File>, hunknum: usize) -> io::Result<()> {
+    let info = chd.hunk_info(hunknum)?;
+    print!(
+        "Hunk {}: {} offset {} length {}",
+        hunknum, info.compression, info.offset, info.length
+    );
+    match info.crc {
+        Some(crc) => println!(" crc {:04x}", crc),
+        None => println!(),
+    }
+    Ok(())
+}
+
+// Positional arguments of command without options except the listed flags
+fn positional<'a>(
+    command: &str,
+    args: &'a [OsString],
+    flags: &[&str],
+) -> io::Result<(Vec<&'a OsString>, Vec<&'a str>)> {
+    let mut positional = Vec::new();
+    let mut set = Vec::new();
+    for arg in args {
+        match arg.to_str() {
+            Some(flag) if flags.contains(&flag) => set.push(flag),
+            Some(option) if option.starts_with('-') => {
+                return Err(usage_error(&format!(
+                    "{}: unknown option {}",
+                    command, option
+                )))
+            }
+            _ => positional.push(arg),
+        }
+    }
+    Ok((positional, set))
+}
+
+fn parse_number(command: &str, arg: &OsString) -> io::Result<u64> {
+    let text = arg.to_string_lossy();
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| usage_error(&format!("{}: bad number {}", command, text)))
+}
+
+fn dumphunk(args: &[OsString]) -> io::Result<()> {
+    let (args, flags) = positional("dumphunk", args, &["--raw"])?;
+    let (path, hunknum) = match args[..] {
+        [path, hunknum] => (path, parse_number("dumphunk", hunknum)? as usize),
+        _ => return Err(usage_error("dumphunk: need chd and hunk number")),
+    };
+    let mut chd = open_input("dumphunk", path, "--parent")?;
+    print_hunk_info(&chd, hunknum)?;
+    let mut stdout = io::stdout();
+    if flags.contains(&"--raw") {
+        let raw = chd.read_hunk_raw(hunknum)?;
+        println!("Stored data, {} bytes:", raw.len());
+        hex_dump(&mut stdout, 0, &raw)?;
+    }
+    let mut hunk = vec![0; chd.hunk_size()];
+    println!("Decoded data:");
+    match chd.read_hunk(hunknum, &mut hunk) {
+        Ok(()) => hex_dump(&mut stdout, hunknum as u64 * hunk.len() as u64, &hunk),
+        Err(e) => {
+            println!("  error: {}", e);
+            Err(e)
+        }
+    }
+}
+
+fn dumpsector(args: &[OsString]) -> io::Result<()> {
+    let (args, _) = positional("dumpsector", args, &[])?;
+    let (path, sector) = match args[..] {
+        [path, sector] => (path, parse_number("dumpsector", sector)?),
+        _ => return Err(usage_error("dumpsector: need chd and sector number")),
+    };
+    let mut chd = open_input("dumpsector", path, "--parent")?;
+    let toc = cd::read_toc(&mut chd)?;
+    // CD frame by disc address, unit by index for other chds
+    let (offset, size) = match toc.is_empty() {
+        true => (sector * chd.unit_size_u64(), chd.unit_size()),
+        false => {
+            let gdrom = cd::is_gdrom(&mut chd)?;
+            let starts = cd::track_starts(&toc, gdrom);
+            let found = toc.iter().zip(starts).find(|(track, start)| {
+                let first = *start as u64 - track.stored_pregap() as u64;
+                sector >= first && sector < first + track.frames as u64
+            });
+            let (track, start) = found.ok_or_else(|| {
+                usage_error(&format!("dumpsector: no track has sector {}", sector))
+            })?;
+            let frame = sector - (start as u64 - track.stored_pregap() as u64);
+            println!(
+                "Track {} {} frame {}",
+                track.number,
+                track.track_type.name(),
+                frame
+            );
+            (
+                track.chd_offset() + frame * cd::FRAME_SIZE as u64,
+                cd::FRAME_SIZE,
+            )
+        }
+    };
+    if offset + size as u64 > chd.size() {
+        return Err(usage_error(&format!(
+            "dumpsector: sector {} is past end of image",
+            sector
+        )));
+    }
+    let hunknum = (offset / chd.hunk_size() as u64) as usize;
+    print_hunk_info(&chd, hunknum)?;
+    let mut data = vec![0; size];
+    chd.read_exact_at(offset, &mut data)?;
+    hex_dump(&mut io::stdout(), offset, &data)
+}
+
+fn map(args: &[OsString]) -> io::Result<()> {
+    let (args, flags) = positional("map", args, &["--csv", "--json", "--summary", "--tracks"])?;
+    let path = match args[..] {
+        [path] => path,
+        _ => return Err(usage_error("map: need one chd")),
+    };
+    let mut chd = open(path)?;
+    let mut stdout = io::stdout();
+    match flags.last().copied() {
+        Some("--csv") => return chd.export_map(&mut stdout, MapFormat::Csv),
+        Some("--json") => return chd.export_map(&mut stdout, MapFormat::Json),
+        Some("--tracks") => {
+            write_histogram(&mut stdout, &chd)?;
+            return write_track_compression(&mut stdout, &mut chd);
+        }
+        Some(_) => (),
+        None => {
+            writeln!(stdout, "    Hunk Compression     Offset   Length  CRC")?;
+            for hunknum in 0..chd.hunk_count() {
+                let info = chd.hunk_info(hunknum)?;
+                write!(
+                    stdout,
+                    "{:8} {:11} {:10} {:8}",
+                    hunknum,
+                    info.compression.to_string(),
+                    info.offset,
+                    info.length
+                )?;
+                match info.crc {
+                    Some(crc) => writeln!(stdout, "  {:04x}", crc)?,
+                    None => writeln!(stdout)?,
+                }
+            }
+        }
+    }
+    write_histogram(&mut stdout, &chd)
+}
+
+// Logical images are compared by hunks of first chd, differing bytes are
+// merged into ranges. Returns true if images are the same.
+fn diff(args: &[OsString]) -> io::Result<bool> {
+    let mut paths = Vec::new();
+    let mut by_hunk = false;
+    let mut parent_a = None;
+    let mut parent_b = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-pa") | Some("--parenta") => parent_a = Some(value(&mut args, "--parenta")?),
+            Some("-pb") | Some("--parentb") => parent_b = Some(value(&mut args, "--parentb")?),
+            Some("--hunks") => by_hunk = true,
+            Some(option) if option.starts_with('-') => {
+                return Err(usage_error(&format!("diff: unknown option {}", option)))
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let (path_a, path_b) = match paths[..] {
+        [a, b] => (a, b),
+        _ => return Err(usage_error("diff: need two chds")),
+    };
+    let mut a = open_child("diff", path_a, parent_a, "-pa")?;
+    let mut b = open_child("diff", path_b, parent_b, "-pb")?;
+    let hunk_size = a.hunk_size() as u64;
+    let size = a.size().min(b.size());
+    let mut buf_a = vec![0u8; a.hunk_size()];
+    let mut buf_b = vec![0u8; a.hunk_size()];
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    let mut hunks = 0;
+    let mut offset = 0;
+    let mut stdout = io::stdout().lock();
+    while offset < size {
+        let length = hunk_size.min(size - offset) as usize;
+        a.read_exact(&mut buf_a[..length])?;
+        b.read_exact(&mut buf_b[..length])?;
+        if buf_a[..length] == buf_b[..length] {
+            offset += length as u64;
+            continue;
+        }
+        hunks += 1;
+        for (i, (x, y)) in buf_a[..length].iter().zip(&buf_b[..length]).enumerate() {
+            if x == y {
+                continue;
+            }
+            let at = offset + i as u64;
+            match ranges.last_mut() {
+                Some((_, end)) if *end == at => *end += 1,
+                _ => ranges.push((at, at + 1)),
+            }
+        }
+        if by_hunk {
+            let differ = buf_a[..length]
+                .iter()
+                .zip(&buf_b[..length])
+                .filter(|(x, y)| x != y)
+                .count();
+            writeln!(
+                stdout,
+                "hunk {:8} {:10} bytes differ",
+                offset / hunk_size,
+                differ
+            )?;
+        }
+        offset += length as u64;
+    }
+    for (start, end) in ranges.iter().filter(|_| !by_hunk) {
+        writeln!(
+            stdout,
+            "{:#012x}-{:#012x} {:10} bytes  hunk {}-{}",
+            start,
+            end - 1,
+            end - start,
+            start / hunk_size,
+            (end - 1) / hunk_size
+        )?;
+    }
+    let bytes: u64 = ranges.iter().map(|(start, end)| end - start).sum();
+    writeln!(
+        stdout,
+        "{} bytes in {} ranges of {} hunks differ",
+        bytes,
+        ranges.len(),
+        hunks
+    )?;
+    if a.size() != b.size() {
+        writeln!(
+            stdout,
+            "Logical sizes differ: {} and {}",
+            a.size(),
+            b.size()
+        )?;
+    }
+    Ok(ranges.is_empty() && a.size() == b.size())
+}
+
+// Children whose parent is missing, and their descendants, are orphans
+fn scan(args: &[OsString]) -> io::Result<()> {
+    let (args, _) = positional("scan", args, &[])?;
+    let dir = match args[..] {
+        [dir] => Path::new(dir),
+        _ => return Err(usage_error("scan: need one directory")),
+    };
+    let mut index = ParentIndex::new();
+    let mut chds = Vec::new();
+    let mut failed = Vec::new();
+    index.scan_dir_with(dir, |path, probe| match probe {
+        Ok(probe) => chds.push((path.to_path_buf(), probe)),
+        Err(e) => failed.push((path.to_path_buf(), e)),
+    })?;
+    chds.sort_by(|a, b| a.0.cmp(&b.0));
+    failed.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut by_sha1: HashMap<[u8; 20], Vec<&Path>> = HashMap::new();
+    let mut by_parent: HashMap<[u8; 20], Vec<&Path>> = HashMap::new();
+    let mut by_path: HashMap<&Path, &ChdProbe> = HashMap::new();
+    for (path, probe) in &chds {
+        by_sha1.entry(probe.sha1).or_default().push(path);
+        if probe.has_parent() {
+            by_parent.entry(probe.parentsha1).or_default().push(path);
+        }
+        by_path.insert(path, probe);
+    }
+    // walk up parent chain until a chd without parent or a missing one
+    let complete = |probe: &ChdProbe| {
+        let mut probe = probe;
+        for _ in 0..chds.len() {
+            if !probe.has_parent() {
+                return true;
+            }
+            match index.get(&probe.parentsha1) {
+                Some(parent) => probe = by_path[parent],
+                None => return false,
+            }
+        }
+        // longer chain has a loop
+        false
+    };
+    let mut stdout = io::stdout().lock();
+    let children = |sha1: [u8; 20]| by_parent.get(&sha1).into_iter().flatten();
+
+    writeln!(stdout, "Parents:")?;
+    let mut parents = 0;
+    for (path, probe) in &chds {
+        if children(probe.sha1).next().is_none() {
+            continue;
+        }
+        parents += 1;
+        writeln!(stdout, "  {} {}", hex_string(&probe.sha1), path.display())?;
+        for child in children(probe.sha1) {
+            writeln!(stdout, "    {}", child.display())?;
+        }
+    }
+
+    writeln!(stdout, "Missing parents:")?;
+    let mut missing: Vec<[u8; 20]> = chds
+        .iter()
+        .filter(|(_, probe)| probe.has_parent() && index.get(&probe.parentsha1).is_none())
+        .map(|(_, probe)| probe.parentsha1)
+        .collect();
+    missing.sort();
+    missing.dedup();
+    for sha1 in &missing {
+        writeln!(stdout, "  {}", hex_string(sha1))?;
+        for child in children(*sha1) {
+            writeln!(stdout, "    {}", child.display())?;
+        }
+    }
+
+    writeln!(stdout, "Orphans:")?;
+    let mut orphans = 0;
+    for (path, probe) in chds.iter().filter(|(_, probe)| !complete(probe)) {
+        orphans += 1;
+        writeln!(stdout, "  {} {}", hex_string(&probe.sha1), path.display())?;
+    }
+
+    writeln!(stdout, "Duplicate sha1s:")?;
+    let mut duplicates: Vec<_> = by_sha1
+        .iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .collect();
+    duplicates.sort();
+    for (sha1, paths) in &duplicates {
+        writeln!(stdout, "  {}", hex_string(*sha1))?;
+        for path in paths.iter() {
+            writeln!(stdout, "    {}", path.display())?;
+        }
+    }
+
+    if !failed.is_empty() {
+        writeln!(stdout, "Unreadable:")?;
+        for (path, e) in &failed {
+            writeln!(stdout, "  {}: {}", path.display(), e)?;
+        }
+    }
+    writeln!(
+        stdout,
+        "{} chds, {} parents, {} missing parents, {} orphans, {} duplicate sha1s, {} unreadable",
+        chds.len(),
+        parents,
+        missing.len(),
+        orphans,
+        duplicates.len(),
+        failed.len()
+    )
+}
+
+// Take out options of any command, returns the rest of arguments
+fn global_options(all: Vec<OsString>) -> io::Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    let mut parents = Vec::new();
+    let mut all = all.into_iter();
+    while let Some(arg) = all.next() {
+        match arg.to_str() {
+            Some("-q") | Some("--quiet") => VERBOSITY.store(QUIET, Ordering::Relaxed),
+            Some("-v") | Some("--verbose") => VERBOSITY.store(VERBOSE, Ordering::Relaxed),
+            Some("-j") | Some("--jobs") => {
+                let jobs = all
+                    .next()
+                    .and_then(|jobs| jobs.to_str()?.parse().ok())
+                    .filter(|jobs| *jobs > 0)
+                    .ok_or_else(|| usage_error("--jobs needs a number of workers"))?;
+                JOBS.store(jobs, Ordering::Relaxed);
+            }
+            Some("--io-limit") => {
+                let limit = all
+                    .next()
+                    .and_then(|limit| limit.to_str()?.parse().ok())
+                    .filter(|limit| *limit > 0)
+                    .ok_or_else(|| usage_error("--io-limit needs bytes per second"))?;
+                IO_LIMIT.store(limit, Ordering::Relaxed);
+            }
+            Some("--format") => match all.next().as_ref().and_then(|f| f.to_str()) {
+                Some("text") => JSON_ERRORS.store(false, Ordering::Relaxed),
+                Some("json") => JSON_ERRORS.store(true, Ordering::Relaxed),
+                _ => return Err(usage_error("--format needs text or json")),
+            },
+            Some("--parent") => parents.push(
+                all.next()
+                    .ok_or_else(|| usage_error("--parent needs a value"))?,
+            ),
+            _ => args.push(arg),
+        }
+    }
+    PARENTS.get_or_init(|| parents);
+    Ok(args)
+}
+
+fn selftest(args: &[OsString]) -> io::Result<()> {
+    if !args.is_empty() {
+        return Err(usage_error("selftest: takes no arguments"));
+    }
+    let mut failed = 0;
+    for test in chd::codec_self_test() {
+        match &test.result {
+            Ok(()) => println!("{:<6} ok", CodecTag(test.tag).to_string()),
+            Err(e) => {
+                println!("{:<6} FAILED: {}", CodecTag(test.tag).to_string(), e);
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(io::Error::other(format!("selftest: {} codecs failed", n))),
+    }
+}
+
+fn run(args: &[OsString]) -> io::Result<()> {
+    match args.first().and_then(|command| command.to_str()) {
+        Some("info") => info(&args[1..]),
+        Some("verify") => verify(&args[1..]),
+        Some("checksum") => checksum(&args[1..]),
+        Some("extract") | Some("extracthd") => extract(&args[1..]),
+        Some("extractcd") => extractcd(&args[1..]),
+        Some("extractdvd") => extractdvd(&args[1..]),
+        Some("createraw") => createraw(&args[1..]),
+        Some("createcd") => createcd(&args[1..]),
+        Some("createhd") => createhd(&args[1..]),
+        Some("copy") => copy(&args[1..]),
+        Some("addmeta") => addmeta(&args[1..]),
+        Some("delmeta") => delmeta(&args[1..]),
+        Some("dumpmeta") => dumpmeta(&args[1..]),
+        Some("listtracks") => listtracks(&args[1..]),
+        Some("dumphunk") => dumphunk(&args[1..]),
+        Some("dumpsector") => dumpsector(&args[1..]),
+        Some("map") => map(&args[1..]),
+        Some("scan") => scan(&args[1..]),
+        Some("selftest") => selftest(&args[1..]),
+        Some("diff") => match diff(&args[1..]) {
+            Ok(false) => std::process::exit(1),
+            result => result.map(|_| ()),
+        },
+        Some("help") | Some("-h") | Some("--help") => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        // plain rchdtool <chd-file> still prints info
+        Some(_) if args.len() == 1 => info(args),
+        Some(command) => Err(usage_error(&format!("unknown command {}", command))),
+        None => Err(usage_error("no command")),
+    }
+}
+
+fn main() {
+    let args = std::env::args_os().skip(1).collect();
+    let mut command = "rchdtool".to_string();
+    let result = global_options(args).and_then(|args| {
+        if let Some(arg) = args.first() {
+            command = arg.to_string_lossy().into_owned();
+        }
+        run(&args)
+    });
+    if let Err(e) = result {
+        match json_errors() {
+            true => {
+                let input = INPUT.lock().unwrap().take();
+                eprintln!(
+                    "{}",
+                    error_record(&command, input.as_deref(), &e, None, None)
+                );
+            }
+            false => eprintln!("rchdtool: {}", e),
+        }
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_record() {
+        let e = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        assert_eq!(
+            error_record("verify", Some(OsStr::new("a\"b.chd")), &e, None, None),
+            "{\"file\":\"a\\\"b.chd\",\"operation\":\"verify\",\"kind\":\"NotFound\",\
+             \"error\":\"no such file\",\"hunk\":null,\"offset\":null,\"unit\":null}"
+        );
+        // hunk, offset and unit of hunk error, but not numbers of its message
+        let hunk = |offset, unit| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                HunkError {
+                    hunk: 7,
+                    offset,
+                    unit,
+                    error: io::Error::other("hunk@5 at 6"),
+                },
+            )
+        };
+        let record = error_record("extract", None, &hunk(Some(1024), None), None, None);
+        assert!(
+            record.contains("\"error\":\"hunk#7: hunk@5 at 6\""),
+            "{}",
+            record
+        );
+        assert!(
+            record.ends_with("\"hunk\":7,\"offset\":1024,\"unit\":null}"),
+            "{}",
+            record
+        );
+        let record = error_record("extract", None, &hunk(None, Some(48)), None, None);
+        assert!(
+            record.ends_with("\"hunk\":7,\"offset\":null,\"unit\":48}"),
+            "{}",
+            record
+        );
+        // caller knows better
+        let record = error_record("extract", None, &hunk(Some(1024), None), Some(3), Some(9));
+        assert!(
+            record.ends_with("\"hunk\":3,\"offset\":9,\"unit\":null}"),
+            "{}",
+            record
+        );
+        // the first line of usage error
+        let record = error_record(
+            "copy",
+            None,
+            &usage_error("copy: no input file"),
+            None,
+            None,
+        );
+        assert!(
+            record.contains("\"error\":\"copy: no input file\",\"hunk\""),
+            "{}",
+            record
+        );
+    }
+
+    #[test]
+    fn test_parse_chs() {
+        assert_eq!(parse_chs("10,4,2").unwrap(), (10, 4, 2));
+        let error = |text: &str| parse_chs(text).unwrap_err().to_string();
+        assert!(error("10,x,2").starts_with("--chs: bad heads \"x\" in 10,x,2"));
+        assert!(error("10,4,").starts_with("--chs: bad sectors \"\""));
+        assert!(error("-1,4,2").starts_with("--chs: bad cylinders"));
+        assert!(error("10,x,4,2").starts_with("--chs: bad geometry 10,x,4,2"));
+        assert!(error("10,4").starts_with("--chs: bad geometry"));
+    }
+
+    #[test]
+    fn test_wildcard() {
+        let matches = |pattern: &str, name: &str| wildcard(pattern.as_bytes(), name.as_bytes());
+        assert!(matches("*.chd", "game.chd"));
+        assert!(matches("*.chd", ".chd"));
+        assert!(matches("g*e*.chd", "game.chd"));
+        assert!(matches("*", ""));
+        assert!(matches("**", "a"));
+        assert!(!matches("*.chd", "game.chd.bak"));
+        assert!(matches("disc?.chd", "disc1.chd"));
+        assert!(!matches("disc?.chd", "disc.chd"));
+        assert!(!matches("disc?.chd", "disc12.chd"));
+        assert!(matches("?*", "a"));
+        assert!(!matches("?*", ""));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+        assert!(matches("game.chd", "game.chd"));
+        assert!(!matches("game.chd", "Game.chd"));
+        assert!(!matches("game.chd", "game.ch"));
+    }
+
+    #[test]
+    fn test_render() {
+        let vars = [
+            ("title", "AC/DC: Live?".to_string()),
+            ("track", "3".to_string()),
+        ];
+        let render = |template: &str| render(template, &vars);
+        assert_eq!(render("{title}").unwrap(), "AC_DC_ Live_");
+        for (title, safe) in [("", "_"), (".", "_"), ("..", "_"), ("...", "...")] {
+            let vars = [("title", title.to_string())];
+            assert_eq!(
+                super::render("{title}/x.bin", &vars).unwrap(),
+                format!("{}/x.bin", safe)
+            );
+        }
+        assert_eq!(render("out/{title}\\").unwrap(), "out/AC_DC_ Live_\\");
+        assert_eq!(render("{track:02}-{track:3}").unwrap(), "03-  3");
+        assert_eq!(render("{title:14}|").unwrap(), "AC_DC_ Live_  |");
+        assert_eq!(render("{{track}} }}{{").unwrap(), "{track} }{");
+        assert_eq!(render("{{{track}}}").unwrap(), "{3}");
+        assert_eq!(render("plain.bin").unwrap(), "plain.bin");
+        let error = |template: &str| render(template).unwrap_err().to_string();
+        assert!(error("{album}").contains("unknown variable album"));
+        assert!(error("{}").contains("unknown variable"));
+        assert!(error("{track").contains("unmatched {"));
+        assert!(error("track}").contains("unmatched }"));
+        assert!(error("{track:x}").contains("bad width"));
+    }
+}
@@ -1,3 +1,8 @@
+// MSB first bit reader used by huffman coded map and codecs. Reads past the end
+// of data return zero bits, overflow() tells if that happened, so decoders can
+// check it once at the end. try_read() reports it as error right away instead.
+use std::io;
+
 pub struct BitReader<'a> {
     data: &'a [u8], // read pointer
     buffer: u32,    // current bit accumulator
@@ -5,14 +10,17 @@ pub struct BitReader<'a> {
     offset: usize,  // byte offset within the data
 }
 
+// Reads are limited by accumulator size
+pub const MAX_BITS: usize = 31;
+
 fn check(numbits: usize) {
-    if numbits >= 32 {
+    if numbits > MAX_BITS {
         panic!("number of bits to read must be < 32")
     }
 }
 
 impl BitReader<'_> {
-    pub fn new<'a>(data: &'a [u8]) -> BitReader {
+    pub fn new(data: &[u8]) -> BitReader<'_> {
         BitReader {
             data,
             buffer: 0,
@@ -21,10 +29,22 @@ impl BitReader<'_> {
         }
     }
 
+    // More bits were read than data has
     pub fn overflow(&self) -> bool {
         self.offset - self.bits / 8 > self.data.len()
     }
 
+    // Number of bits read so far
+    pub fn position(&self) -> usize {
+        self.offset * 8 - self.bits
+    }
+
+    // Number of bits left in data, 0 after overflow
+    pub fn remaining(&self) -> usize {
+        (self.data.len() * 8).saturating_sub(self.position())
+    }
+
+    // Next numbits (up to MAX_BITS) without consuming them
     pub fn peek(&mut self, numbits: usize) -> u32 {
         check(numbits);
 
@@ -46,7 +66,8 @@ impl BitReader<'_> {
         self.buffer >> (32 - numbits)
     }
 
-    pub fn seek(&mut self, numbits: usize) {
+    // Consume numbits, which must be peeked before
+    pub fn skip(&mut self, numbits: usize) {
         check(numbits);
         self.buffer <<= numbits;
         self.bits -= numbits;
@@ -54,9 +75,71 @@ impl BitReader<'_> {
 
     pub fn read(&mut self, numbits: usize) -> u32 {
         let val = self.peek(numbits);
-        self.seek(numbits);
+        self.skip(numbits);
         val
     }
+
+    // Read, which fails instead of returning zero bits past the end of data
+    pub fn try_read(&mut self, numbits: usize) -> io::Result<u32> {
+        if numbits > MAX_BITS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bitstream: can't read {} bits at once", numbits),
+            ));
+        }
+        if numbits > self.remaining() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "bitstream: read of {} bits at bit {} is past the end of {} bits",
+                    numbits,
+                    self.position(),
+                    self.data.len() * 8
+                ),
+            ));
+        }
+        Ok(self.read(numbits))
+    }
+}
+
+// MSB first bit writer, counterpart of BitReader
+#[derive(Default)]
+pub struct BitWriter {
+    data: Vec<u8>, // complete bytes
+    buffer: u64,   // bits not yet stored in data
+    bits: usize,   // number of bits in the buffer
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Number of bits written so far
+    pub fn position(&self) -> usize {
+        self.data.len() * 8 + self.bits
+    }
+
+    // Append numbits (up to MAX_BITS) lowest bits of value
+    pub fn write(&mut self, value: u32, numbits: usize) {
+        check(numbits);
+        let value = value & ((1u32 << numbits) - 1);
+        self.buffer = (self.buffer << numbits) | value as u64;
+        self.bits += numbits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.data.push((self.buffer >> self.bits) as u8);
+        }
+        self.buffer &= (1 << self.bits) - 1;
+    }
+
+    // Written data, last byte is padded with zero bits
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.data.push((self.buffer << (8 - self.bits)) as u8);
+        }
+        self.data
+    }
 }
 
 #[cfg(test)]
@@ -68,11 +151,39 @@ mod tests {
         let mut bit_reader = BitReader::new(&[0b11110011, 0b01100010]);
 
         assert_eq!(bit_reader.read(4), 0b1111);
-        assert_eq!(bit_reader.read(2), 0b__00);
-        assert_eq!(bit_reader.read(4), 0b1101);
-        assert_eq!(bit_reader.read(6), 0b100010);
-        assert_eq!(bit_reader.overflow(), false);
+        assert_eq!(bit_reader.read(2), 0b00);
+        assert_eq!(bit_reader.position(), 6);
+        assert_eq!(bit_reader.peek(4), 0b1101);
+        assert_eq!(bit_reader.position(), 6);
+        bit_reader.skip(4);
+        assert_eq!(bit_reader.remaining(), 6);
+        assert!(bit_reader.try_read(7).is_err());
+        assert_eq!(bit_reader.try_read(6).unwrap(), 0b100010);
+        assert!(!bit_reader.overflow());
         assert_eq!(bit_reader.read(31), 0);
-        assert_eq!(bit_reader.overflow(), true);
+        assert!(bit_reader.overflow());
+        assert_eq!(bit_reader.remaining(), 0);
+        assert!(bit_reader.try_read(32).is_err());
+    }
+
+    #[test]
+    fn test_writer() {
+        let mut bit_writer = BitWriter::new();
+        bit_writer.write(0b1111, 4);
+        bit_writer.write(0b00, 2);
+        bit_writer.write(0xffff_ff00 | 0b1101, 4);
+        bit_writer.write(0, 0);
+        bit_writer.write(0b100010, 6);
+        bit_writer.write(0b101, 3);
+        assert_eq!(bit_writer.position(), 19);
+        assert_eq!(
+            bit_writer.finish(),
+            vec![0b11110011, 0b01100010, 0b10100000]
+        );
+
+        let mut bit_writer = BitWriter::new();
+        bit_writer.write(0x7fff_ffff, 31);
+        bit_writer.write(1, 1);
+        assert_eq!(bit_writer.finish(), vec![0xff; 4]);
     }
 }
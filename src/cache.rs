@@ -0,0 +1,294 @@
+use super::utils::{IoStat, ReadAt};
+use std::io::{self, Read, Seek, SeekFrom};
+
+// Storage for decompressed hunks used by Chd read path
+pub trait HunkCache: Send {
+    // decompressed hunk data if it's present in the cache
+    fn get(&mut self, hunknum: usize) -> Option<&[u8]>;
+    // store decompressed hunk data, possibly replacing other hunks
+    fn put(&mut self, hunknum: usize, data: &[u8]);
+    // forget hunk data if it's present in the cache
+    fn evict(&mut self, hunknum: usize);
+    // forget all cached hunks
+    fn clear(&mut self);
+    // bytes held by cached data
+    fn memory_usage(&self) -> usize;
+}
+
+// Keeps only the last stored hunk. Default cache for reads not aligned to hunk boundaries
+pub struct SingleCache {
+    hunknum: Option<usize>,
+    data: Vec<u8>,
+}
+
+impl SingleCache {
+    pub fn new() -> Self {
+        Self {
+            hunknum: None,
+            data: Vec::new(),
+        }
+    }
+}
+
+impl Default for SingleCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HunkCache for SingleCache {
+    fn get(&mut self, hunknum: usize) -> Option<&[u8]> {
+        match self.hunknum {
+            Some(n) if n == hunknum => Some(&self.data),
+            _ => None,
+        }
+    }
+
+    fn put(&mut self, hunknum: usize, data: &[u8]) {
+        self.data.clear();
+        self.data.extend_from_slice(data);
+        self.hunknum = Some(hunknum);
+    }
+
+    fn evict(&mut self, hunknum: usize) {
+        if self.hunknum == Some(hunknum) {
+            self.hunknum = None;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.hunknum = None;
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.data.capacity()
+    }
+}
+
+// Keeps up to `capacity` most recently used hunks
+pub struct LruCache {
+    capacity: usize,
+    entries: Vec<(usize, Vec<u8>)>, // least recently used first
+}
+
+impl LruCache {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "lru cache capacity must be > 0");
+        Self {
+            capacity,
+            entries: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn position(&self, hunknum: usize) -> Option<usize> {
+        self.entries.iter().position(|(n, _)| *n == hunknum)
+    }
+}
+
+impl HunkCache for LruCache {
+    fn get(&mut self, hunknum: usize) -> Option<&[u8]> {
+        let i = self.position(hunknum)?;
+        // move entry to the most recently used end
+        self.entries[i..].rotate_left(1);
+        self.entries.last().map(|(_, data)| data.as_slice())
+    }
+
+    fn put(&mut self, hunknum: usize, data: &[u8]) {
+        let mut entry = match self.position(hunknum) {
+            Some(i) => self.entries.remove(i),
+            // reuse buffer of the least recently used entry
+            None if self.entries.len() == self.capacity => self.entries.remove(0),
+            None => (hunknum, Vec::with_capacity(data.len())),
+        };
+        entry.0 = hunknum;
+        entry.1.clear();
+        entry.1.extend_from_slice(data);
+        self.entries.push(entry);
+    }
+
+    fn evict(&mut self, hunknum: usize) {
+        if let Some(i) = self.position(hunknum) {
+            self.entries.remove(i);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.entries.iter().map(|(_, data)| data.capacity()).sum()
+    }
+}
+
+// Read + Seek wrapper for slow backends (network, disks over SMB) that reads whole
+// aligned blocks and keeps up to `capacity` most recently used ones, so small
+// seek+read pairs issued by map and hunk reads don't reach the backend each time.
+pub struct CachedReader<T: Read + Seek> {
+    inner: T,
+    block_size: usize,
+    blocks: LruCache, // cached blocks by index, the last one may be short
+    size: u64,        // backend size at creation
+    pos: u64,         // current read position
+    stat: IoStat,     // reads passed to the backend
+}
+
+impl<T: Read + Seek> CachedReader<T> {
+    pub fn new(mut inner: T, block_size: usize, capacity: usize) -> io::Result<Self> {
+        assert!(block_size > 0, "block size must be > 0");
+        let size = inner.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            inner,
+            block_size,
+            blocks: LruCache::new(capacity),
+            size,
+            pos: 0,
+            stat: IoStat::default(),
+        })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn stat(&self) -> &IoStat {
+        &self.stat
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn block(&mut self, index: u64) -> io::Result<&[u8]> {
+        if self.blocks.get(index as usize).is_none() {
+            let offset = index * self.block_size as u64;
+            let length = std::cmp::min(self.block_size as u64, self.size - offset) as usize;
+            let mut data = vec![0; length];
+            self.inner.read_at(offset, &mut data)?;
+            self.stat.add(length);
+            self.blocks.put(index as usize, &data);
+        }
+        Ok(self.blocks.get(index as usize).unwrap())
+    }
+}
+
+impl<T: Read + Seek> Read for CachedReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() && self.pos < self.size {
+            let block_size = self.block_size as u64;
+            let (index, within) = (self.pos / block_size, (self.pos % block_size) as usize);
+            let block = self.block(index)?;
+            let n = std::cmp::min(block.len() - within, buf.len() - done);
+            buf[done..done + n].copy_from_slice(&block[within..within + n]);
+            done += n;
+            self.pos += n as u64;
+        }
+        Ok(done)
+    }
+}
+
+impl<T: Read + Seek> Seek for CachedReader<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "cache: seek before start")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single() {
+        let mut cache = SingleCache::new();
+        assert!(cache.get(0).is_none());
+        cache.put(0, &[1, 2]);
+        assert_eq!(cache.get(0).unwrap(), &[1, 2]);
+        cache.put(1, &[3, 4]);
+        assert!(cache.get(0).is_none());
+        assert_eq!(cache.get(1).unwrap(), &[3, 4]);
+        cache.evict(0);
+        assert!(cache.get(1).is_some());
+        cache.evict(1);
+        assert!(cache.get(1).is_none());
+    }
+
+    #[test]
+    fn test_lru() {
+        let mut cache = LruCache::new(2);
+        cache.put(0, &[0]);
+        cache.put(1, &[1]);
+        // touch 0, so 1 becomes least recently used
+        assert_eq!(cache.get(0).unwrap(), &[0]);
+        cache.put(2, &[2]);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(1).is_none());
+        assert_eq!(cache.get(0).unwrap(), &[0]);
+        assert_eq!(cache.get(2).unwrap(), &[2]);
+        // overwrite existing entry
+        cache.put(2, &[3]);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(2).unwrap(), &[3]);
+        cache.evict(0);
+        assert!(cache.get(0).is_none());
+        assert_eq!(cache.memory_usage(), 1);
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.memory_usage(), 0);
+    }
+
+    #[test]
+    fn test_cached_reader() {
+        let data: Vec<u8> = (0..10000u32).map(|i| (i * 7) as u8).collect();
+        let mut reader = CachedReader::new(io::Cursor::new(&data[..]), 1024, 4).unwrap();
+
+        // small sequential reads hit the backend once per block
+        let mut buf = [0; 100];
+        for i in 0..20 {
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &data[i * 100..(i + 1) * 100]);
+        }
+        assert_eq!(reader.stat().reads, 2);
+
+        // reads across blocks and the short last block
+        let mut buf = vec![0; 3000];
+        reader.seek(SeekFrom::Start(500)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[500..3500]);
+        reader.seek(SeekFrom::End(-100)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 100);
+        assert_eq!(&buf[..100], &data[9900..]);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(reader.seek(SeekFrom::Current(-20000)).is_err());
+
+        // chd opened through the cache
+        let raw = include_bytes!("../samples/zlib.chd");
+        let reader = CachedReader::new(io::Cursor::new(&raw[..]), 4096, 8).unwrap();
+        let mut chd = crate::Chd::open(reader).unwrap();
+        chd.verify().unwrap();
+    }
+}
@@ -5,3 +5,951 @@ pub const SYNC_NUM_BYTES: usize = 12;
 pub const SYNC_HEADER: [u8; SYNC_NUM_BYTES] = [
     0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00,
 ];
+
+use super::tags::metadata;
+use super::utils::{crc32_tables, crc32_update, invalid_data};
+use super::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+// CHT2 tracks are padded to a multiple of this many frames inside chd
+pub const TRACK_PADDING: u64 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrackType {
+    Mode1,        // 2048 bytes of user data
+    Mode1Raw,     // whole 2352 bytes sector with sync and ecc
+    Mode2,        // 2336 bytes of mode 2 payload
+    Mode2Form1,   // 2048 bytes of form 1 user data
+    Mode2Form2,   // 2324 bytes of form 2 user data
+    Mode2FormMix, // 2336 bytes with subheaders, forms mixed
+    Mode2Raw,     // whole 2352 bytes mode 2 sector
+    Audio,        // 2352 bytes of 16-bit stereo samples
+}
+
+impl TrackType {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "MODE1" => Some(Self::Mode1),
+            "MODE1_RAW" => Some(Self::Mode1Raw),
+            "MODE2" => Some(Self::Mode2),
+            "MODE2_FORM1" => Some(Self::Mode2Form1),
+            "MODE2_FORM2" => Some(Self::Mode2Form2),
+            "MODE2_FORM_MIX" => Some(Self::Mode2FormMix),
+            "MODE2_RAW" => Some(Self::Mode2Raw),
+            "AUDIO" => Some(Self::Audio),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Mode1 => "MODE1",
+            Self::Mode1Raw => "MODE1_RAW",
+            Self::Mode2 => "MODE2",
+            Self::Mode2Form1 => "MODE2_FORM1",
+            Self::Mode2Form2 => "MODE2_FORM2",
+            Self::Mode2FormMix => "MODE2_FORM_MIX",
+            Self::Mode2Raw => "MODE2_RAW",
+            Self::Audio => "AUDIO",
+        }
+    }
+
+    // bytes of sector data stored at the start of each frame
+    pub fn sector_size(&self) -> usize {
+        match self {
+            Self::Mode1 | Self::Mode2Form1 => 2048,
+            Self::Mode2Form2 => 2324,
+            Self::Mode2 | Self::Mode2FormMix => 2336,
+            Self::Mode1Raw | Self::Mode2Raw | Self::Audio => MAX_SECTOR_DATA,
+        }
+    }
+
+    // where 2048 bytes of form 1 user data start within stored sector data,
+    // None for tracks without such data
+    pub fn user_data_offset(&self) -> Option<usize> {
+        match self {
+            Self::Mode1 | Self::Mode2Form1 => Some(0),
+            Self::Mode2 | Self::Mode2FormMix => Some(8), // after subheader
+            Self::Mode1Raw => Some(16),                  // after sync and header
+            Self::Mode2Raw => Some(24),                  // after sync, header and subheader
+            Self::Mode2Form2 | Self::Audio => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubcodeType {
+    Normal, // "RW", cooked 96 bytes
+    Raw,    // "RW_RAW", interleaved 96 bytes
+    None,   // no subcode data
+}
+
+impl SubcodeType {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "RW" => Some(Self::Normal),
+            "RW_RAW" => Some(Self::Raw),
+            "NONE" => Some(Self::None),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Normal => "RW",
+            Self::Raw => "RW_RAW",
+            Self::None => "NONE",
+        }
+    }
+}
+
+// One track of CD-ROM or GD-ROM chd as described by its track metadata
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Track {
+    pub number: u32,            // 1-based track number
+    pub track_type: TrackType,  // sector format
+    pub subcode: SubcodeType,   // subcode format
+    pub frames: u32,            // frames stored in chd, including pregap stored in data
+    pub pregap: u32,            // pregap frames
+    pub pgtype: Option<String>, // pregap type, starts with 'V' if pregap is stored in data
+    pub pgsub: Option<String>,  // pregap subcode type
+    pub postgap: u32,           // postgap frames
+    pub pad: u32,               // padding frames stored after the track
+    pub chd_frame: u64,         // first frame of the track in chd logical data
+}
+
+impl Track {
+    pub fn sector_size(&self) -> usize {
+        self.track_type.sector_size()
+    }
+
+    // size of track data without subcode and padding
+    pub fn data_size(&self) -> u64 {
+        self.frames as u64 * self.sector_size() as u64
+    }
+
+    // offset of track's first frame in chd logical data
+    pub fn chd_offset(&self) -> u64 {
+        self.chd_frame * FRAME_SIZE as u64
+    }
+
+    // pregap frames stored in chd before the track's index 1
+    pub fn stored_pregap(&self) -> u32 {
+        match &self.pgtype {
+            Some(pgtype) if pgtype.starts_with('V') => self.pregap,
+            _ => 0,
+        }
+    }
+}
+
+// Size of user data in a data sector
+pub const USER_DATA_SIZE: usize = 2048;
+
+// EDC of mode 1 sector: CRC-32 with reflected 0xd8018001 polynomial, no inversion
+const EDC_TABLES: [[u32; 256]; 8] = crc32_tables(0xd801_8001);
+
+pub fn edc(data: &[u8]) -> u32 {
+    crc32_update(&EDC_TABLES, 0, data)
+}
+
+// Minute, second and frame of sector address, 2 seconds lead-in included, in BCD
+pub fn msf_bcd(lba: u32) -> [u8; 3] {
+    let frame = lba + 150;
+    let bcd = |v: u32| (((v / 10) << 4) | (v % 10)) as u8;
+    [
+        bcd(frame / (75 * 60)),
+        bcd(frame / 75 % 60),
+        bcd(frame % 75),
+    ]
+}
+
+// Sector data and subcode of one frame
+pub fn split_frame(frame: &[u8]) -> io::Result<(&[u8], &[u8])> {
+    match frame.len() {
+        FRAME_SIZE => Ok(frame.split_at(MAX_SECTOR_DATA)),
+        len => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cd: frame of {} bytes, expected {}", len, FRAME_SIZE),
+        )),
+    }
+}
+
+fn check_frames(frames: usize, sectors: usize, subcode: usize) -> io::Result<()> {
+    match (
+        frames.is_multiple_of(FRAME_SIZE),
+        frames / FRAME_SIZE * MAX_SECTOR_DATA == sectors,
+        frames / FRAME_SIZE * MAX_SUBCODE_DATA == subcode,
+    ) {
+        (true, true, true) => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "cd: {} bytes of frames don't match {} bytes of sectors and {} bytes of subcode",
+                frames, sectors, subcode
+            ),
+        )),
+    }
+}
+
+// Interleave frames from all sectors data followed by all subcode, the way CD
+// codecs store them
+pub fn assemble_frames(sectors: &[u8], subcode: &[u8], frames: &mut [u8]) -> io::Result<()> {
+    check_frames(frames.len(), sectors.len(), subcode.len())?;
+    for ((frame, sector), sub) in frames
+        .chunks_exact_mut(FRAME_SIZE)
+        .zip(sectors.chunks_exact(MAX_SECTOR_DATA))
+        .zip(subcode.chunks_exact(MAX_SUBCODE_DATA))
+    {
+        frame[..MAX_SECTOR_DATA].copy_from_slice(sector);
+        frame[MAX_SECTOR_DATA..].copy_from_slice(sub);
+    }
+    Ok(())
+}
+
+// Reverse of assemble_frames
+pub fn split_frames(frames: &[u8], sectors: &mut [u8], subcode: &mut [u8]) -> io::Result<()> {
+    check_frames(frames.len(), sectors.len(), subcode.len())?;
+    for ((frame, sector), sub) in frames
+        .chunks_exact(FRAME_SIZE)
+        .zip(sectors.chunks_exact_mut(MAX_SECTOR_DATA))
+        .zip(subcode.chunks_exact_mut(MAX_SUBCODE_DATA))
+    {
+        sector.copy_from_slice(&frame[..MAX_SECTOR_DATA]);
+        sub.copy_from_slice(&frame[MAX_SECTOR_DATA..]);
+    }
+    Ok(())
+}
+
+// Format of raw sector as told by its sync, mode byte and mode 2 subheader
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SectorMode {
+    Audio,       // no sync, audio samples or other raw data
+    Mode0,       // zero filled user data
+    Mode1,       // 2048 bytes of user data with EDC and ECC
+    Mode2,       // 2336 bytes of payload without valid subheader
+    Mode2Form1,  // 2048 bytes of user data with EDC and ECC
+    Mode2Form2,  // 2324 bytes of user data with optional EDC
+    Unknown(u8), // sync with unknown mode byte
+}
+
+// Mode 2 subheader is stored twice, submode has form 2 bit
+const SUBHEADER_OFFSET: usize = 16;
+const SUBMODE_FORM2: u8 = 0x20;
+
+pub fn sector_mode(sector: &[u8]) -> SectorMode {
+    if sector.len() < MAX_SECTOR_DATA || sector[..SYNC_NUM_BYTES] != SYNC_HEADER {
+        return SectorMode::Audio;
+    }
+    let subheader = &sector[SUBHEADER_OFFSET..SUBHEADER_OFFSET + 8];
+    match sector[SYNC_NUM_BYTES + 3] {
+        0 => SectorMode::Mode0,
+        1 => SectorMode::Mode1,
+        2 if subheader[..4] != subheader[4..] => SectorMode::Mode2,
+        2 if subheader[2] & SUBMODE_FORM2 != 0 => SectorMode::Mode2Form2,
+        2 => SectorMode::Mode2Form1,
+        mode => SectorMode::Unknown(mode),
+    }
+}
+
+// Read user data of sector counted from the track's index 1 (stored pregap is skipped)
+pub fn read_user_data<T: R>(
+    chd: &mut Chd<T>,
+    track: &Track,
+    sector: u64,
+    buf: &mut [u8; USER_DATA_SIZE],
+) -> io::Result<()> {
+    let offset = track.track_type.user_data_offset().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "cd: track {} of type {} has no user data sectors",
+                track.number,
+                track.track_type.name()
+            ),
+        )
+    })?;
+    let frame = track.stored_pregap() as u64 + sector;
+    if frame >= track.frames as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "cd: sector {} is past end of track {}",
+                sector, track.number
+            ),
+        ));
+    }
+    let pos = track.chd_offset() + frame * FRAME_SIZE as u64 + offset as u64;
+    chd.read_exact_at(pos, buf)
+}
+
+// Values of "KEY:VALUE" fields in track metadata text
+fn parse_fields(text: &str) -> HashMap<&str, &str> {
+    text.split(|c: char| c.is_ascii_whitespace() || c == '\0')
+        .filter_map(|field| field.split_once(':'))
+        .collect()
+}
+
+fn parse_track(text: &str, gdrom: bool, chd_frame: u64) -> io::Result<Track> {
+    let fields = parse_fields(text);
+    let field = |key: &str| -> io::Result<&str> {
+        fields
+            .get(key)
+            .copied()
+            .ok_or_else(|| invalid_data(format!("cd: track metadata has no {}: {:?}", key, text)))
+    };
+    let number = |key: &str| -> io::Result<u32> {
+        match fields.get(key) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| invalid_data(format!("cd: bad {} value in {:?}", key, text))),
+            None => Ok(0),
+        }
+    };
+    let optional = |key: &str| match fields.get(key) {
+        Some(&"") | None => None,
+        Some(value) => Some(value.to_string()),
+    };
+
+    let track_type = field("TYPE")?;
+    let track_type = TrackType::parse(track_type)
+        .ok_or_else(|| invalid_data(format!("cd: unknown track type {}", track_type)))?;
+    let subcode = field("SUBTYPE")?;
+    let subcode = SubcodeType::parse(subcode)
+        .ok_or_else(|| invalid_data(format!("cd: unknown subcode type {}", subcode)))?;
+    field("TRACK")?;
+    field("FRAMES")?;
+    let frames = number("FRAMES")?;
+    let pad = match gdrom {
+        true => number("PAD")?,
+        false => {
+            let padded = (frames as u64).div_ceil(TRACK_PADDING) * TRACK_PADDING;
+            (padded - frames as u64) as u32
+        }
+    };
+    Ok(Track {
+        number: number("TRACK")?,
+        track_type,
+        subcode,
+        frames,
+        pregap: number("PREGAP")?,
+        pgtype: optional("PGTYPE"),
+        pgsub: optional("PGSUB"),
+        postgap: number("POSTGAP")?,
+        pad,
+        chd_frame,
+    })
+}
+
+// Track list of CD-ROM or GD-ROM chd, empty for other kinds of media
+pub fn read_toc<T: R>(chd: &mut Chd<T>) -> io::Result<Vec<Track>> {
+    let mut tracks: Vec<Track> = Vec::new();
+    let mut chd_frame = 0;
+    for (tag, gdrom) in [
+        (metadata::CDROM_TRACK2, false),
+        (metadata::CDROM_TRACK, false),
+        (metadata::GDROM_TRACK, true),
+    ] {
+        let mut index = 0;
+        while let Some(entry) = chd.find_metadata(tag, index)? {
+            let mut text = vec![0; entry.length as usize];
+            chd.io
+                .read_at_stat(entry.offset, &mut text, &mut chd.stat.raw)?;
+            let text = String::from_utf8_lossy(&text);
+            let track = parse_track(&text, gdrom, chd_frame)?;
+            chd_frame += track.frames as u64 + track.pad as u64;
+            tracks.push(track);
+            index += 1;
+        }
+        if !tracks.is_empty() {
+            break;
+        }
+    }
+    Ok(tracks)
+}
+
+// Read track data at offset within the track, as it would be stored in a .bin file:
+// sector data of each frame without subcode, audio samples in little endian.
+// Returns number of bytes read, which is less than buf length at the end of track.
+pub fn read_track_at<T: R>(
+    chd: &mut Chd<T>,
+    track: &Track,
+    offset: u64,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    if track.track_type != TrackType::Audio {
+        return read_stored(chd, track, offset, buf);
+    }
+    // chd keeps samples big endian, so swap whole pairs counted from the track start
+    let start = offset & !1;
+    let skip = (offset - start) as usize;
+    let mut pairs = vec![0; (skip + buf.len() + 1) & !1];
+    let read = read_stored(chd, track, start, &mut pairs)?;
+    for pair in pairs[..read].chunks_exact_mut(2) {
+        pair.swap(0, 1);
+    }
+    let done = std::cmp::min(read.saturating_sub(skip), buf.len());
+    buf[..done].copy_from_slice(&pairs[skip..skip + done]);
+    Ok(done)
+}
+
+// Sector data of track frames as stored in chd
+fn read_stored<T: R>(
+    chd: &mut Chd<T>,
+    track: &Track,
+    offset: u64,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let sector_size = track.sector_size() as u64;
+    let end = std::cmp::min(offset.saturating_add(buf.len() as u64), track.data_size());
+    let mut pos = offset;
+    let mut done = 0;
+    while pos < end {
+        let (frame, within) = (pos / sector_size, pos % sector_size);
+        let chunk = std::cmp::min(sector_size - within, end - pos) as usize;
+        let from = track.chd_offset() + frame * FRAME_SIZE as u64 + within;
+        chd.read_exact_at(from, &mut buf[done..done + chunk])?;
+        pos += chunk as u64;
+        done += chunk;
+    }
+    Ok(done)
+}
+
+// Cue sheet formats extract_cd can write next to track data
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CdSheet {
+    Cue, // cue sheet with one bin, or bin per track when split
+    Gdi, // GD-ROM track list, always a file per track
+    Toc, // cdrdao toc
+}
+
+// first frame of GD-ROM high density area, where track 3 starts
+pub const GDROM_HIGH_DENSITY: u32 = 45000;
+
+fn cue_type(track_type: TrackType) -> &'static str {
+    match track_type {
+        TrackType::Mode1 => "MODE1/2048",
+        TrackType::Mode1Raw => "MODE1/2352",
+        TrackType::Mode2 => "MODE2/2336",
+        TrackType::Mode2Form1 => "MODE2/2048",
+        TrackType::Mode2Form2 => "MODE2/2324",
+        TrackType::Mode2FormMix => "MODE2/2336",
+        TrackType::Mode2Raw => "MODE2/2352",
+        TrackType::Audio => "AUDIO",
+    }
+}
+
+// mm:ss:ff of frame count as used in cue and toc files
+pub fn msf(frames: u32) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        frames / (60 * 75),
+        frames / 75 % 60,
+        frames % 75
+    )
+}
+
+// Names of files with data of each track: stem.bin, "stem (Track NN).bin" when
+// split, and stemNN.bin or stemNN.raw for audio in gdi
+pub fn track_files(toc: &[Track], stem: &str, sheet: CdSheet, split: bool) -> Vec<String> {
+    toc.iter()
+        .map(|track| match (sheet, split) {
+            (CdSheet::Gdi, _) => match track.track_type {
+                TrackType::Audio => format!("{}{:02}.raw", stem, track.number),
+                _ => format!("{}{:02}.bin", stem, track.number),
+            },
+            (_, true) => format!("{} (Track {:02}).bin", stem, track.number),
+            (_, false) => format!("{}.bin", stem),
+        })
+        .collect()
+}
+
+// Write sheet describing tracks stored in files, one file name per track.
+// Consecutive tracks with the same file name share it. Gdi files don't contain
+// stored pregaps, and gdrom places track 3 at the start of high density area.
+pub fn write_sheet<W: Write>(
+    toc: &[Track],
+    files: &[String],
+    sheet: CdSheet,
+    gdrom: bool,
+    to: &mut W,
+) -> io::Result<()> {
+    match sheet {
+        CdSheet::Cue => write_cue(toc, files, to),
+        CdSheet::Gdi => write_gdi(toc, files, gdrom, to),
+        CdSheet::Toc => write_cdrdao(toc, files, to),
+    }
+}
+
+fn write_cue<W: Write>(toc: &[Track], files: &[String], to: &mut W) -> io::Result<()> {
+    let mut file_frame = 0;
+    for (i, track) in toc.iter().enumerate() {
+        if i == 0 || files[i] != files[i - 1] {
+            writeln!(to, "FILE \"{}\" BINARY", files[i])?;
+            file_frame = 0;
+        }
+        writeln!(
+            to,
+            "  TRACK {:02} {}",
+            track.number,
+            cue_type(track.track_type)
+        )?;
+        let stored = track.stored_pregap();
+        if stored > 0 {
+            writeln!(to, "    INDEX 00 {}", msf(file_frame))?;
+        } else if track.pregap > 0 {
+            writeln!(to, "    PREGAP {}", msf(track.pregap))?;
+        }
+        writeln!(to, "    INDEX 01 {}", msf(file_frame + stored))?;
+        if track.postgap > 0 {
+            writeln!(to, "    POSTGAP {}", msf(track.postgap))?;
+        }
+        file_frame += track.frames;
+    }
+    Ok(())
+}
+
+// Disc address of index 1 of each track. Pregaps that aren't stored still take
+// place on disc, and gdrom places track 3 at the start of high density area.
+pub fn track_starts(toc: &[Track], gdrom: bool) -> Vec<u32> {
+    let mut lba = 0;
+    toc.iter()
+        .map(|track| {
+            if gdrom && track.number == 3 {
+                lba = std::cmp::max(lba, GDROM_HIGH_DENSITY);
+            }
+            let stored = track.stored_pregap();
+            lba += track.pregap - stored;
+            let start = lba + stored;
+            lba += track.frames;
+            start
+        })
+        .collect()
+}
+
+// Whether chd has GD-ROM track metadata
+pub fn is_gdrom<T: R>(chd: &mut Chd<T>) -> io::Result<bool> {
+    Ok(chd.find_metadata(metadata::GDROM_TRACK, 0)?.is_some())
+}
+
+fn write_gdi<W: Write>(toc: &[Track], files: &[String], gdrom: bool, to: &mut W) -> io::Result<()> {
+    writeln!(to, "{}", toc.len())?;
+    let starts = track_starts(toc, gdrom);
+    for ((track, file), start) in toc.iter().zip(files).zip(starts) {
+        let kind = match track.track_type {
+            TrackType::Audio => 0,
+            _ => 4,
+        };
+        writeln!(
+            to,
+            "{} {} {} {} {} 0",
+            track.number,
+            start,
+            kind,
+            track.sector_size(),
+            file
+        )?;
+    }
+    Ok(())
+}
+
+fn write_cdrdao<W: Write>(toc: &[Track], files: &[String], to: &mut W) -> io::Result<()> {
+    let xa = toc.iter().any(|t| t.track_type.name().starts_with("MODE2"));
+    writeln!(to, "{}", if xa { "CD_ROM_XA" } else { "CD_ROM" })?;
+    // audio samples are little endian, burn with cdrdao --swap
+    let mut offset = 0;
+    for (i, track) in toc.iter().enumerate() {
+        if i > 0 && files[i] != files[i - 1] {
+            offset = 0;
+        }
+        writeln!(to)?;
+        writeln!(to, "// Track {}", track.number)?;
+        writeln!(to, "TRACK {}", track.track_type.name())?;
+        writeln!(to, "NO COPY")?;
+        match track.track_type {
+            TrackType::Audio => {
+                writeln!(to, "NO PRE_EMPHASIS")?;
+                writeln!(to, "TWO_CHANNEL_AUDIO")?;
+                writeln!(
+                    to,
+                    "FILE \"{}\" #{} 0 {}",
+                    files[i],
+                    offset,
+                    msf(track.frames)
+                )?;
+            }
+            _ => writeln!(
+                to,
+                "DATAFILE \"{}\" #{} {} // length in bytes: {}",
+                files[i],
+                offset,
+                msf(track.frames),
+                track.data_size()
+            )?,
+        }
+        let stored = track.stored_pregap();
+        if stored > 0 {
+            writeln!(to, "START {}", msf(stored))?;
+        } else if track.pregap > 0 {
+            writeln!(to, "PREGAP {}", msf(track.pregap))?;
+        }
+        offset += track.data_size();
+    }
+    Ok(())
+}
+
+// Compression of hunks holding frames of a track
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackCompression {
+    pub number: u32,
+    pub track_type: TrackType,
+    // per kind of hunks with track frames and padding; hunk shared with the next
+    // track is counted for both, its compressed bytes are split by frames
+    pub compression: Vec<CompressionStat>,
+}
+
+impl TrackCompression {
+    pub fn compressed(&self) -> u64 {
+        self.compression.iter().map(|c| c.compressed).sum()
+    }
+
+    pub fn uncompressed(&self) -> u64 {
+        self.compression.iter().map(|c| c.uncompressed).sum()
+    }
+
+    // compressed bytes per logical byte, 0 for empty track
+    pub fn ratio(&self) -> f64 {
+        match self.uncompressed() {
+            0 => 0.0,
+            total => self.compressed() as f64 / total as f64,
+        }
+    }
+}
+
+// Compression efficiency per track, computed from track metadata and hunk map
+// without reading hunk data
+pub fn track_compression<T: R>(chd: &mut Chd<T>) -> io::Result<Vec<TrackCompression>> {
+    let toc = read_toc(chd)?;
+    let hunkbytes = chd.hunk_size() as u64;
+    // hunk which first used data stored at file offset
+    let mut owners = HashMap::new();
+    let mut result = Vec::with_capacity(toc.len());
+    for track in toc {
+        let start = track.chd_offset();
+        let frames = track.frames as u64 + track.pad as u64;
+        let end = std::cmp::min(start + frames * FRAME_SIZE as u64, chd.size());
+        let mut compression = Vec::new();
+        for hunknum in start / hunkbytes..end.div_ceil(hunkbytes) {
+            let info = chd.hunk_info(hunknum as usize)?;
+            let hunk_start = hunknum * hunkbytes;
+            let hunk_end = std::cmp::min(hunk_start + hunkbytes, chd.size());
+            let share = std::cmp::min(end, hunk_end) - std::cmp::max(start, hunk_start);
+            let compressed =
+                match info.length > 0 && *owners.entry(info.offset).or_insert(hunknum) == hunknum {
+                    true => info.length as u64 * share / (hunk_end - hunk_start),
+                    false => 0,
+                };
+            CompressionStat::count(&mut compression, info.compression, compressed, share);
+        }
+        result.push(TrackCompression {
+            number: track.number,
+            track_type: track.track_type,
+            compression,
+        });
+    }
+    Ok(result)
+}
+
+// Copy track data in .bin form to writer, starting from given frame.
+// Returns number of bytes written.
+pub fn write_track<T: R, W: Write>(
+    chd: &mut Chd<T>,
+    track: &Track,
+    first_frame: u32,
+    to: &mut W,
+) -> io::Result<u64> {
+    let mut buf = vec![0; 64 * track.sector_size()];
+    let mut offset = first_frame as u64 * track.sector_size() as u64;
+    let start = offset;
+    loop {
+        let n = read_track_at(chd, track, offset, &mut buf)?;
+        if n == 0 {
+            return Ok(offset - start);
+        }
+        to.write_all(&buf[..n])?;
+        offset += n as u64;
+        let frames = offset / track.sector_size() as u64;
+        chd.report_progress(track.chd_offset() + frames * FRAME_SIZE as u64);
+    }
+}
+
+// Extract CD or GD-ROM chd as sheet file and track data files next to it, named
+// after the sheet as in track_files. Returns paths of all written files, sheet first.
+pub fn extract_cd<T: R>(
+    chd: &mut Chd<T>,
+    sheet_path: &Path,
+    sheet: CdSheet,
+    split: bool,
+) -> io::Result<Vec<PathBuf>> {
+    let toc = read_toc(chd)?;
+    let stem = sheet_path
+        .file_stem()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "cd: no sheet file name"))?
+        .to_string_lossy();
+    let files = track_files(&toc, &stem, sheet, split);
+    extract_cd_files(chd, sheet_path, sheet, &files)
+}
+
+// Same as extract_cd, but with caller's names of track files, relative to the sheet
+pub fn extract_cd_files<T: R>(
+    chd: &mut Chd<T>,
+    sheet_path: &Path,
+    sheet: CdSheet,
+    files: &[String],
+) -> io::Result<Vec<PathBuf>> {
+    let toc = read_toc(chd)?;
+    if toc.is_empty() {
+        return Err(invalid_data_str("cd: chd has no track metadata"));
+    }
+    if files.len() != toc.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cd: {} file names for {} tracks", files.len(), toc.len()),
+        ));
+    }
+    let gdrom = is_gdrom(chd)?;
+    let dir = sheet_path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut written = vec![sheet_path.to_path_buf()];
+    let mut to = BufWriter::new(File::create(sheet_path)?);
+    write_sheet(&toc, files, sheet, gdrom, &mut to)?;
+    to.flush()?;
+
+    let mut out: Option<BufWriter<File>> = None;
+    for (i, track) in toc.iter().enumerate() {
+        if i == 0 || files[i] != files[i - 1] {
+            if let Some(mut prev) = out.take() {
+                prev.flush()?;
+            }
+            let path = dir.join(&files[i]);
+            out = Some(BufWriter::new(File::create(&path)?));
+            written.push(path);
+        }
+        let first_frame = match sheet {
+            CdSheet::Gdi => track.stored_pregap(),
+            _ => 0,
+        };
+        write_track(chd, track, first_frame, out.as_mut().unwrap())?;
+    }
+    if let Some(mut last) = out {
+        last.flush()?;
+    }
+    // padding of tracks isn't extracted
+    chd.report_progress(chd.size());
+    chd.report_finished(chd.size());
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::{ChdWriter, WriterOptions};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_frames() {
+        let sectors: Vec<u8> = (0..3 * MAX_SECTOR_DATA).map(|i| i as u8).collect();
+        let subcode: Vec<u8> = (0..3 * MAX_SUBCODE_DATA).map(|i| !i as u8).collect();
+        let mut frames = vec![0; 3 * FRAME_SIZE];
+        assemble_frames(&sectors, &subcode, &mut frames).unwrap();
+        let (sector, sub) = split_frame(&frames[FRAME_SIZE..2 * FRAME_SIZE]).unwrap();
+        assert_eq!(sector, &sectors[MAX_SECTOR_DATA..2 * MAX_SECTOR_DATA]);
+        assert_eq!(sub, &subcode[MAX_SUBCODE_DATA..2 * MAX_SUBCODE_DATA]);
+        assert!(split_frame(&frames[1..FRAME_SIZE]).is_err());
+
+        let mut sectors2 = vec![0; sectors.len()];
+        let mut subcode2 = vec![0; subcode.len()];
+        split_frames(&frames, &mut sectors2, &mut subcode2).unwrap();
+        assert_eq!((sectors2, subcode2), (sectors.clone(), subcode.clone()));
+        assert!(assemble_frames(&sectors, &subcode, &mut frames[1..]).is_err());
+        assert!(assemble_frames(&sectors[1..], &subcode, &mut frames).is_err());
+        assert!(split_frames(&frames, &mut [0; 10], &mut [0; 10]).is_err());
+    }
+
+    #[test]
+    fn test_sector_mode() {
+        let mut sector = vec![0; MAX_SECTOR_DATA];
+        assert_eq!(sector_mode(&sector), SectorMode::Audio);
+        crate::ecc::set_header(&mut sector, 0, 0);
+        assert_eq!(sector_mode(&sector), SectorMode::Mode0);
+        assert_eq!(sector_mode(&sector[..16]), SectorMode::Audio);
+        sector[15] = 1;
+        assert_eq!(sector_mode(&sector), SectorMode::Mode1);
+        sector[15] = 2;
+        assert_eq!(sector_mode(&sector), SectorMode::Mode2Form1);
+        sector[18] = SUBMODE_FORM2;
+        assert_eq!(sector_mode(&sector), SectorMode::Mode2);
+        sector[22] = SUBMODE_FORM2;
+        assert_eq!(sector_mode(&sector), SectorMode::Mode2Form2);
+        sector[15] = 0xe1;
+        assert_eq!(sector_mode(&sector), SectorMode::Unknown(0xe1));
+    }
+
+    #[test]
+    fn test_toc() {
+        let raw = include_bytes!("../samples/cdzl.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let toc = read_toc(&mut chd).unwrap();
+        assert_eq!(toc.len(), 1);
+        let track = &toc[0];
+        assert_eq!(track.number, 1);
+        assert_eq!(track.track_type, TrackType::Audio);
+        assert_eq!(track.subcode, SubcodeType::None);
+        assert_eq!(track.frames, 10);
+        assert_eq!(track.pad, 2);
+        assert_eq!(track.pgtype.as_deref(), Some("MODE1"));
+        assert_eq!(track.chd_frame, 0);
+        assert_eq!(track.data_size(), 10 * 2352);
+
+        // hard disk has no tracks
+        let raw = include_bytes!("../samples/zlib.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        assert!(read_toc(&mut chd).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_track() {
+        let raw = include_bytes!("../samples/cdzl.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let track = read_toc(&mut chd).unwrap().remove(0);
+        let mut data = vec![0; track.data_size() as usize + 100];
+        let size = read_track_at(&mut chd, &track, 0, &mut data).unwrap();
+        assert_eq!(size as u64, track.data_size());
+
+        // samples are little endian like in the wav the image was made of
+        let wav = include_bytes!("../samples/bell.wav");
+        let data_chunk = wav.windows(4).position(|w| w == b"data").unwrap();
+        let pcm = &wav[data_chunk + 8..];
+        let n = std::cmp::min(pcm.len(), size);
+        assert_eq!(&data[..n], &pcm[..n]);
+
+        // unaligned reads give the same bytes
+        let mut part = [0; 4001];
+        let n = read_track_at(&mut chd, &track, 2351, &mut part).unwrap();
+        assert_eq!(n, part.len());
+        assert_eq!(&part[..], &data[2351..2351 + n]);
+        assert_eq!(
+            read_track_at(&mut chd, &track, size as u64, &mut part).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_track_compression() {
+        // 24 frames in 2 hunks, the second one is shared by both tracks
+        let hunk_size = 12 * FRAME_SIZE as u32;
+        let image: Vec<u8> = (0..24 * FRAME_SIZE).map(|i| (i % 251) as u8).collect();
+        let options = WriterOptions::new(image.len() as u64, hunk_size, FRAME_SIZE as u32);
+        let mut writer = ChdWriter::create(Cursor::new(Vec::new()), &options).unwrap();
+        let tracks = [
+            "TRACK:1 TYPE:MODE1_RAW SUBTYPE:NONE FRAMES:14 PREGAP:0 PGTYPE:MODE1 PGSUB:NONE POSTGAP:0\0",
+            "TRACK:2 TYPE:AUDIO SUBTYPE:NONE FRAMES:8 PREGAP:0 PGTYPE:MODE1 PGSUB:NONE POSTGAP:0\0",
+        ];
+        for meta in tracks {
+            writer
+                .add_metadata(metadata::CDROM_TRACK2, meta.as_bytes(), true)
+                .unwrap();
+        }
+        for hunk in image.chunks(hunk_size as usize) {
+            writer.write_hunk(hunk).unwrap();
+        }
+        let raw = writer.finish().unwrap().into_inner();
+        let mut chd = Chd::open(Cursor::new(raw)).unwrap();
+
+        let report = track_compression(&mut chd).unwrap();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].track_type, TrackType::Mode1Raw);
+        assert_eq!(report[1].track_type, TrackType::Audio);
+        // padded to 16 frames, 8 frames left for the second track
+        assert_eq!(report[0].uncompressed(), 16 * FRAME_SIZE as u64);
+        assert_eq!(report[1].uncompressed(), 8 * FRAME_SIZE as u64);
+        assert_eq!(report[0].compression[0].hunks, 2);
+        assert_eq!(report[1].compression[0].hunks, 1);
+        assert_eq!(report[0].compression[0].compression, HunkCompression::None);
+        // stored whole, so compressed bytes are split by frames
+        assert_eq!(report[0].ratio(), 1.0);
+        assert_eq!(report[1].ratio(), 1.0);
+
+        // hard disk has no tracks
+        let raw = include_bytes!("../samples/zlib.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        assert!(track_compression(&mut chd).unwrap().is_empty());
+    }
+
+    fn track(number: u32, track_type: TrackType, frames: u32, pregap: u32, pgtype: &str) -> Track {
+        Track {
+            number,
+            track_type,
+            subcode: SubcodeType::None,
+            frames,
+            pregap,
+            pgtype: Some(pgtype.to_string()),
+            pgsub: None,
+            postgap: 0,
+            pad: 0,
+            chd_frame: 0,
+        }
+    }
+
+    fn sheet(toc: &[Track], sheet: CdSheet, split: bool, gdrom: bool) -> String {
+        let files = track_files(toc, "disc", sheet, split);
+        let mut text = Vec::new();
+        write_sheet(toc, &files, sheet, gdrom, &mut text).unwrap();
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn test_sheets() {
+        let toc = vec![
+            track(1, TrackType::Mode1Raw, 1000, 0, "MODE1"),
+            track(2, TrackType::Audio, 500, 150, "VAUDIO"),
+            track(3, TrackType::Audio, 400, 150, "AUDIO"),
+        ];
+        assert_eq!(
+            sheet(&toc, CdSheet::Cue, false, false),
+            "FILE \"disc.bin\" BINARY\n  TRACK 01 MODE1/2352\n    INDEX 01 00:00:00\n\
+             \x20 TRACK 02 AUDIO\n    INDEX 00 00:13:25\n    INDEX 01 00:15:25\n\
+             \x20 TRACK 03 AUDIO\n    PREGAP 00:02:00\n    INDEX 01 00:20:00\n"
+        );
+        let split = sheet(&toc, CdSheet::Cue, true, false);
+        assert!(split.contains("FILE \"disc (Track 02).bin\" BINARY\n  TRACK 02 AUDIO\n    INDEX 00 00:00:00\n    INDEX 01 00:02:00\n"));
+        assert_eq!(
+            sheet(&toc, CdSheet::Gdi, false, true),
+            "3\n1 0 4 2352 disc01.bin 0\n2 1150 0 2352 disc02.raw 0\n3 45150 0 2352 disc03.raw 0\n"
+        );
+        let toc_text = sheet(&toc, CdSheet::Toc, false, false);
+        assert!(toc_text.starts_with("CD_ROM\n\n// Track 1\nTRACK MODE1_RAW\n"));
+        assert!(toc_text.contains("FILE \"disc.bin\" #2352000 0 00:06:50\nSTART 00:02:00\n"));
+        assert!(toc_text.contains("#3528000 0 00:05:25\nPREGAP 00:02:00\n"));
+    }
+
+    #[test]
+    fn test_extract_cd() {
+        let raw = include_bytes!("../samples/cdzl.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let track = read_toc(&mut chd).unwrap().remove(0);
+        let mut data = vec![0; track.data_size() as usize];
+        read_track_at(&mut chd, &track, 0, &mut data).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("chd-extract-cd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = extract_cd(&mut chd, &dir.join("bell.cue"), CdSheet::Cue, false).unwrap();
+        assert_eq!(files, vec![dir.join("bell.cue"), dir.join("bell.bin")]);
+        let cue = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(cue.starts_with("FILE \"bell.bin\" BINARY\n  TRACK 01 AUDIO\n"));
+        assert_eq!(std::fs::read(&files[1]).unwrap(), data);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,797 @@
+use super::cd::GDROM_HIGH_DENSITY;
+use super::cd::{SubcodeType, Track, TrackType, FRAME_SIZE, MAX_SECTOR_DATA, TRACK_PADDING};
+use super::tags::metadata;
+use super::utils::invalid_data;
+use super::writer::{ChdWriter, WriterOptions};
+use super::*;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+// Frames per hunk of CD chds made by chdman
+pub const CD_FRAMES_PER_HUNK: u32 = 8;
+
+// Track of CD image to be stored in chd: its metadata and where its data is
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageTrack {
+    pub track: Track,  // as described in chd metadata
+    pub file: PathBuf, // file with track data
+    pub offset: u64,   // byte offset of the first stored frame in file
+    pub swap: bool,    // audio samples are little endian in file
+    // file, offset and frames of pregap stored at the end of previous track file,
+    // which come before the frames in `file`
+    pub pregap_file: Option<(PathBuf, u64, u32)>,
+}
+
+// Track being parsed from cue sheet
+struct CueTrack {
+    image: ImageTrack,
+    index0: Option<u32>, // frame of INDEX 00 in pregap file, or in track file
+    index1: Option<u32>, // frame of INDEX 01 in track file
+    end: u64,            // end of track file data
+    // previous file with INDEX 00, its data offset and end
+    pregap_file: Option<(PathBuf, u64, u64)>,
+}
+
+// CD or GD-ROM image described by cue, gdi or cdrdao toc sheet, or plain iso
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CdImage {
+    pub tracks: Vec<ImageTrack>,
+    pub gdrom: bool, // written with GD-ROM track metadata
+}
+
+fn bad_sheet(path: &Path, line: usize, text: &str) -> io::Error {
+    invalid_data(format!("cdimage: {:?}:{}: {}", path, line + 1, text))
+}
+
+// Frame count of "mm:ss:ff"
+fn parse_msf(text: &str) -> Option<u32> {
+    let mut parts = text.split(':').map(|p| p.parse::<u32>().ok());
+    let (m, s, f) = (parts.next()??, parts.next()??, parts.next()??);
+    match parts.next().is_none() && s < 60 && f < 75 {
+        true => Some((m * 60 + s) * 75 + f),
+        false => None,
+    }
+}
+
+// Frames of cdrdao time, either "mm:ss:ff" or number of samples
+fn toc_time(text: &str) -> Option<u32> {
+    match text.contains(':') {
+        true => parse_msf(text),
+        false => text.parse::<u32>().ok().map(|samples| samples / 588),
+    }
+}
+
+// Words of sheet line, quoted strings are one word without quotes
+fn words(line: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut rest = line.trim();
+    while !rest.is_empty() {
+        let (word, tail) = match rest.strip_prefix('"') {
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => match rest.find(char::is_whitespace) {
+                Some(end) => (&rest[..end], &rest[end..]),
+                None => (rest, ""),
+            },
+        };
+        words.push(word);
+        rest = tail.trim_start();
+    }
+    words
+}
+
+fn cue_track_type(text: &str) -> Option<TrackType> {
+    match text {
+        "MODE1/2048" => Some(TrackType::Mode1),
+        "MODE1/2352" => Some(TrackType::Mode1Raw),
+        "MODE2/2048" => Some(TrackType::Mode2Form1),
+        "MODE2/2324" => Some(TrackType::Mode2Form2),
+        "MODE2/2336" => Some(TrackType::Mode2),
+        "MODE2/2352" => Some(TrackType::Mode2Raw),
+        "AUDIO" => Some(TrackType::Audio),
+        _ => None,
+    }
+}
+
+// Offset and size of samples in wav file
+fn wav_data(path: &Path) -> io::Result<(u64, u64)> {
+    let mut file = File::open(path)?;
+    let mut header = [0; 12];
+    file.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err(invalid_data(format!(
+            "cdimage: {:?} is not a wav file",
+            path
+        )));
+    }
+    let mut offset = 12;
+    loop {
+        let mut chunk = [0; 8];
+        file.read_exact(&mut chunk)?;
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+        offset += 8;
+        if &chunk[0..4] == b"data" {
+            return Ok((offset, size));
+        }
+        // chunks are word aligned
+        offset += size + (size & 1);
+        file.seek(SeekFrom::Start(offset))?;
+    }
+}
+
+fn file_size(path: &Path) -> io::Result<u64> {
+    std::fs::metadata(path)
+        .map(|m| m.len())
+        .map_err(|e| io::Error::new(e.kind(), format!("cdimage: {:?}: {}", path, e)))
+}
+
+fn new_track(number: u32, track_type: TrackType) -> Track {
+    Track {
+        number,
+        track_type,
+        subcode: SubcodeType::None,
+        frames: 0,
+        pregap: 0,
+        pgtype: None,
+        pgsub: None,
+        postgap: 0,
+        pad: 0,
+        chd_frame: 0,
+    }
+}
+
+// Whole frames of track starting at offset, up to the end of file. Like chdman,
+// incomplete last sector is dropped.
+fn frames_to_end(size: u64, offset: u64, track_type: TrackType) -> u32 {
+    let sector_size = track_type.sector_size() as u64;
+    (size.saturating_sub(offset) / sector_size) as u32
+}
+
+impl CdImage {
+    // Open image by extension: .cue, .gdi, .toc, anything else is iso
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let mut image = match ext.as_str() {
+            "cue" | "gdi" | "toc" => {
+                let text = std::fs::read_to_string(path)?;
+                match ext.as_str() {
+                    "cue" => Self::parse_cue(path, &text)?,
+                    "gdi" => Self::parse_gdi(path, &text)?,
+                    _ => Self::parse_toc(path, &text)?,
+                }
+            }
+            _ => Self::iso(path)?,
+        };
+        if image.tracks.is_empty() {
+            return Err(invalid_data(format!("cdimage: {:?} has no tracks", path)));
+        }
+        image.layout();
+        Ok(image)
+    }
+
+    // Single mode 1 track of 2048 bytes sectors
+    pub fn iso(path: &Path) -> io::Result<Self> {
+        let mut track = new_track(1, TrackType::Mode1);
+        track.frames = frames_to_end(file_size(path)?, 0, track.track_type);
+        Ok(Self {
+            tracks: vec![ImageTrack {
+                track,
+                file: path.to_path_buf(),
+                offset: 0,
+                swap: false,
+                pregap_file: None,
+            }],
+            gdrom: false,
+        })
+    }
+
+    pub fn parse_cue(path: &Path, text: &str) -> io::Result<Self> {
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        // file, its data offset and size, whether audio is little endian
+        let mut file: Option<(PathBuf, u64, u64, bool)> = None;
+        // tracks with INDEX 00 and INDEX 01 frames within file and end of their file.
+        // INDEX 00 of split bin dumps may be at the end of previous file, then the
+        // file, its data offset and end are kept for the pregap.
+        let mut tracks: Vec<CueTrack> = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let words = words(line);
+            let bad = |text: &str| bad_sheet(path, n, text);
+            match words.first().map(|w| w.to_ascii_uppercase()).as_deref() {
+                Some("FILE") if words.len() >= 2 => {
+                    let name = dir.join(words[1]);
+                    let kind = words.get(2).map(|w| w.to_ascii_uppercase());
+                    file = Some(match kind.as_deref() {
+                        Some("WAVE") => {
+                            let (offset, size) = wav_data(&name)?;
+                            (name, offset, size, true)
+                        }
+                        Some("MOTOROLA") => {
+                            let size = file_size(&name)?;
+                            (name, 0, size, false)
+                        }
+                        _ => {
+                            let size = file_size(&name)?;
+                            (name, 0, size, true)
+                        }
+                    });
+                }
+                Some("TRACK") if words.len() >= 3 => {
+                    let (name, offset, size, swap) =
+                        file.clone().ok_or_else(|| bad("TRACK before FILE"))?;
+                    let number = words[1].parse().map_err(|_| bad("bad track number"))?;
+                    let track_type = cue_track_type(&words[2].to_ascii_uppercase())
+                        .ok_or_else(|| bad("unsupported track type"))?;
+                    let track = ImageTrack {
+                        track: new_track(number, track_type),
+                        file: name,
+                        offset,
+                        swap: swap && track_type == TrackType::Audio,
+                        pregap_file: None,
+                    };
+                    tracks.push(CueTrack {
+                        image: track,
+                        index0: None,
+                        index1: None,
+                        end: offset + size,
+                        pregap_file: None,
+                    });
+                }
+                Some(command @ ("INDEX" | "PREGAP" | "POSTGAP")) => {
+                    let track = tracks.last_mut().ok_or_else(|| bad("no TRACK"))?;
+                    let msf = words.last().and_then(|w| parse_msf(w));
+                    let msf = msf.ok_or_else(|| bad("bad time"))?;
+                    match (command, words.get(1).copied()) {
+                        ("INDEX", Some("00")) | ("INDEX", Some("0")) => track.index0 = Some(msf),
+                        ("INDEX", Some("01")) | ("INDEX", Some("1")) => {
+                            let (name, offset, size, swap) = file.clone().unwrap_or_default();
+                            if name != track.image.file {
+                                if track.index0.is_none() {
+                                    return Err(bad("TRACK and INDEX 01 in different files"));
+                                }
+                                let image = &mut track.image;
+                                track.pregap_file =
+                                    Some((image.file.clone(), image.offset, track.end));
+                                image.file = name;
+                                image.offset = offset;
+                                image.swap = swap && image.track.track_type == TrackType::Audio;
+                                track.end = offset + size;
+                            }
+                            track.index1 = Some(msf);
+                        }
+                        ("INDEX", _) => (),
+                        ("PREGAP", _) => track.image.track.pregap = msf,
+                        _ => track.image.track.postgap = msf,
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // frames of a track last until the next track of the same file starts
+        let mut result: Vec<ImageTrack> = Vec::with_capacity(tracks.len());
+        for i in 0..tracks.len() {
+            let CueTrack {
+                image,
+                index0,
+                index1,
+                end,
+                pregap_file,
+            } = &tracks[i];
+            let mut track = image.clone();
+            let index1 = index1.ok_or_else(|| {
+                invalid_data(format!(
+                    "cdimage: {:?}: track {} has no INDEX 01",
+                    path, track.track.number
+                ))
+            })?;
+            let mut start = index0.unwrap_or(index1);
+            // frames before INDEX 01 stored in previous file
+            let mut split_pregap = 0;
+            if let (Some((name, offset, end)), Some(index0)) = (pregap_file, index0) {
+                let sector_size = track.track.sector_size() as u64;
+                split_pregap = frames_to_end(*end, *offset, track.track.track_type)
+                    .checked_sub(*index0)
+                    .ok_or_else(|| {
+                        invalid_data(format!(
+                            "cdimage: {:?}: INDEX 00 of track {} is past end of {:?}",
+                            path, track.track.number, name
+                        ))
+                    })?;
+                track.pregap_file = Some((
+                    name.clone(),
+                    offset + *index0 as u64 * sector_size,
+                    split_pregap,
+                ));
+                track.track.pregap = split_pregap + index1;
+                track.track.pgtype = Some(format!("V{}", track.track.track_type.name()));
+                start = 0;
+            } else if let Some(index0) = index0 {
+                track.track.pregap = index1.saturating_sub(*index0);
+                track.track.pgtype = Some(format!("V{}", track.track.track_type.name()));
+            } else if track.track.pregap > 0 {
+                track.track.pgtype = Some(track.track.track_type.name().to_string());
+            }
+            if let Some(prev) = result.last() {
+                if prev.file == track.file {
+                    let split = prev
+                        .pregap_file
+                        .as_ref()
+                        .map_or(0, |(_, _, frames)| *frames);
+                    let sector_size = prev.track.sector_size() as u64;
+                    track.offset =
+                        prev.offset + prev.track.data_size() - split as u64 * sector_size;
+                }
+            }
+            // next track starts in this file with its INDEX 01 or only its pregap
+            let next_start = tracks.get(i + 1).and_then(|next| {
+                match (&next.pregap_file, next.image.file == track.file) {
+                    (Some((name, _, _)), _) if *name == track.file => next.index0,
+                    (None, true) => next.index0.or(next.index1),
+                    _ => None,
+                }
+            });
+            track.track.frames = split_pregap
+                + match next_start {
+                    Some(next_start) => next_start.checked_sub(start).ok_or_else(|| {
+                        invalid_data(format!(
+                            "cdimage: {:?}: track {} starts before track {}",
+                            path,
+                            track.track.number + 1,
+                            track.track.number
+                        ))
+                    })?,
+                    None => frames_to_end(*end, track.offset, track.track.track_type),
+                };
+            result.push(track);
+        }
+        Ok(Self {
+            tracks: result,
+            gdrom: false,
+        })
+    }
+
+    // Lines of "number lba type sector_size file offset" after track count.
+    // Gaps between tracks become pregaps that aren't stored, except the gap
+    // before track 3 in high density area.
+    pub fn parse_gdi(path: &Path, text: &str) -> io::Result<Self> {
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut tracks: Vec<ImageTrack> = Vec::new();
+        let mut lba = 0;
+        for (n, line) in text.lines().enumerate().skip(1) {
+            let words = words(line);
+            if words.is_empty() {
+                continue;
+            }
+            let bad = |text: &str| bad_sheet(path, n, text);
+            if words.len() < 6 {
+                return Err(bad("expected 6 fields"));
+            }
+            let field =
+                |i: usize| -> io::Result<u64> { words[i].parse().map_err(|_| bad("bad number")) };
+            let (number, start, kind, sector_size, offset) = (
+                field(0)? as u32,
+                field(1)? as u32,
+                field(2)?,
+                field(3)?,
+                field(5)?,
+            );
+            let track_type = match (kind, sector_size) {
+                (0, 2352) => TrackType::Audio,
+                (4, 2048) => TrackType::Mode1,
+                (4, 2352) => TrackType::Mode1Raw,
+                _ => return Err(bad("unsupported track type or sector size")),
+            };
+            let name = dir.join(words[4]);
+            let mut track = new_track(number, track_type);
+            track.frames = frames_to_end(file_size(&name)?, offset, track_type);
+            if number == 3 {
+                lba = std::cmp::max(lba, GDROM_HIGH_DENSITY);
+            }
+            track.pregap = start.saturating_sub(lba);
+            if track.pregap > 0 {
+                track.pgtype = Some(track_type.name().to_string());
+            }
+            lba = start + track.frames;
+            tracks.push(ImageTrack {
+                track,
+                file: name,
+                offset,
+                swap: track_type == TrackType::Audio,
+                pregap_file: None,
+            });
+        }
+        Ok(Self {
+            tracks,
+            gdrom: true,
+        })
+    }
+
+    // cdrdao toc with TRACK, DATAFILE, FILE/AUDIOFILE, START and PREGAP statements.
+    // Audio files are taken as little endian, as cd::extract_cd writes them.
+    pub fn parse_toc(path: &Path, text: &str) -> io::Result<Self> {
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut tracks: Vec<ImageTrack> = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split("//").next().unwrap_or_default();
+            let words = words(line);
+            let bad = |text: &str| bad_sheet(path, n, text);
+            match words.first().copied() {
+                Some("TRACK") if words.len() >= 2 => {
+                    let track_type =
+                        TrackType::parse(words[1]).ok_or_else(|| bad("unsupported track type"))?;
+                    let number = tracks.len() as u32 + 1;
+                    tracks.push(ImageTrack {
+                        track: new_track(number, track_type),
+                        file: PathBuf::new(),
+                        offset: 0,
+                        swap: track_type == TrackType::Audio,
+                        pregap_file: None,
+                    });
+                }
+                Some(command @ ("DATAFILE" | "FILE" | "AUDIOFILE")) if words.len() >= 2 => {
+                    let track = tracks.last_mut().ok_or_else(|| bad("no TRACK"))?;
+                    track.file = dir.join(words[1]);
+                    let mut rest = &words[2..];
+                    if let Some(offset) = rest.first().and_then(|w| w.strip_prefix('#')) {
+                        track.offset = offset.parse().map_err(|_| bad("bad offset"))?;
+                        rest = &rest[1..];
+                    }
+                    // audio files have start time before length
+                    if command != "DATAFILE" && !rest.is_empty() {
+                        let start = toc_time(rest[0]).ok_or_else(|| bad("bad start"))?;
+                        track.offset += start as u64 * track.track.sector_size() as u64;
+                        rest = &rest[1..];
+                    }
+                    let size = file_size(&track.file)?;
+                    track.track.frames = match rest.first() {
+                        Some(length) => toc_time(length).ok_or_else(|| bad("bad length"))?,
+                        None => frames_to_end(size, track.offset, track.track.track_type),
+                    };
+                }
+                Some("START") if words.len() >= 2 => {
+                    let track = tracks.last_mut().ok_or_else(|| bad("no TRACK"))?;
+                    track.track.pregap = parse_msf(words[1]).ok_or_else(|| bad("bad time"))?;
+                    track.track.pgtype = Some(format!("V{}", track.track.track_type.name()));
+                }
+                Some("PREGAP") if words.len() >= 2 => {
+                    let track = tracks.last_mut().ok_or_else(|| bad("no TRACK"))?;
+                    track.track.pregap = parse_msf(words[1]).ok_or_else(|| bad("bad time"))?;
+                    track.track.pgtype = Some(track.track.track_type.name().to_string());
+                }
+                _ => (),
+            }
+        }
+        if let Some(track) = tracks.iter().find(|t| t.file.as_os_str().is_empty()) {
+            return Err(invalid_data(format!(
+                "cdimage: {:?}: track {} has no data file",
+                path, track.track.number
+            )));
+        }
+        Ok(Self {
+            tracks,
+            gdrom: false,
+        })
+    }
+
+    // Pad tracks to multiple of TRACK_PADDING frames and place them in chd
+    fn layout(&mut self) {
+        let mut chd_frame = 0;
+        for image_track in &mut self.tracks {
+            let track = &mut image_track.track;
+            let frames = track.frames as u64;
+            track.pad = (frames.div_ceil(TRACK_PADDING) * TRACK_PADDING - frames) as u32;
+            track.chd_frame = chd_frame;
+            chd_frame += frames + track.pad as u64;
+        }
+    }
+
+    // Frames in chd including padding
+    pub fn frame_count(&self) -> u64 {
+        self.tracks.last().map_or(0, |t| {
+            t.track.chd_frame + t.track.frames as u64 + t.track.pad as u64
+        })
+    }
+
+    // Track metadata as chdman writes it, nul terminated
+    pub fn metadata(&self) -> Vec<(u32, Vec<u8>)> {
+        self.tracks
+            .iter()
+            .map(|t| {
+                let t = &t.track;
+                let pgtype = t.pgtype.as_deref().unwrap_or("MODE1");
+                let pgsub = t.pgsub.as_deref().unwrap_or("NONE");
+                let text = match self.gdrom {
+                    true => format!(
+                        "TRACK:{} TYPE:{} SUBTYPE:{} FRAMES:{} PAD:{} PREGAP:{} PGTYPE:{} PGSUB:{} POSTGAP:{}\0",
+                        t.number, t.track_type.name(), t.subcode.name(), t.frames, t.pad, t.pregap, pgtype, pgsub, t.postgap
+                    ),
+                    false => format!(
+                        "TRACK:{} TYPE:{} SUBTYPE:{} FRAMES:{} PREGAP:{} PGTYPE:{} PGSUB:{} POSTGAP:{}\0",
+                        t.number, t.track_type.name(), t.subcode.name(), t.frames, t.pregap, pgtype, pgsub, t.postgap
+                    ),
+                };
+                let tag = match self.gdrom {
+                    true => metadata::GDROM_TRACK,
+                    false => metadata::CDROM_TRACK2,
+                };
+                (tag, text.into_bytes())
+            })
+            .collect()
+    }
+}
+
+// Open file with track data positioned at its first stored frame,
+// preceded by pregap data from the previous track file if there is one
+fn open_track(image_track: &ImageTrack) -> io::Result<Box<dyn Read>> {
+    let track = &image_track.track;
+    let mut file = File::open(&image_track.file)
+        .map_err(|e| io::Error::new(e.kind(), format!("cdimage: {:?}: {}", image_track.file, e)))?;
+    file.seek(SeekFrom::Start(image_track.offset))?;
+    Ok(match &image_track.pregap_file {
+        None => Box::new(file.take(track.data_size())),
+        Some((name, offset, frames)) => {
+            let pregap_size = *frames as u64 * track.sector_size() as u64;
+            let mut pregap = File::open(name)
+                .map_err(|e| io::Error::new(e.kind(), format!("cdimage: {:?}: {}", name, e)))?;
+            pregap.seek(SeekFrom::Start(*offset))?;
+            Box::new(
+                pregap
+                    .take(pregap_size)
+                    .chain(file.take(track.data_size() - pregap_size)),
+            )
+        }
+    })
+}
+
+// Logical data of chd made from image: frames of all tracks with padding frames,
+// sectors read from track files. Short files are padded by zeroes.
+pub struct ImageReader<'a> {
+    image: &'a CdImage,
+    track: usize,                // index of current track
+    file: Option<Box<dyn Read>>, // data of current track, opened on its first frame
+    frame: u32,                  // next frame of current track
+    buf: Vec<u8>,                // current frame
+    pos: usize,                  // read position in buf
+}
+
+impl<'a> ImageReader<'a> {
+    // Fill buf with the next frame, false at the end of image
+    fn next_frame(&mut self) -> io::Result<bool> {
+        let image = self.image;
+        while let Some(image_track) = image.tracks.get(self.track) {
+            let track = &image_track.track;
+            if self.frame == track.frames + track.pad {
+                self.track += 1;
+                self.frame = 0;
+                self.file = None;
+                continue;
+            }
+            if self.file.is_none() {
+                self.file = Some(open_track(image_track)?);
+            }
+            self.buf.fill(0);
+            if self.frame < track.frames {
+                let file = self.file.as_mut().unwrap();
+                let sector = &mut self.buf[..track.sector_size()];
+                let mut done = 0;
+                while done < sector.len() {
+                    match file.read(&mut sector[done..])? {
+                        0 => break,
+                        n => done += n,
+                    }
+                }
+                if image_track.swap {
+                    for pair in self.buf[..MAX_SECTOR_DATA].chunks_exact_mut(2) {
+                        pair.swap(0, 1);
+                    }
+                }
+            }
+            self.frame += 1;
+            self.pos = 0;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+impl Read for ImageReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || (self.pos == self.buf.len() && !self.next_frame()?) {
+            return Ok(0);
+        }
+        let n = std::cmp::min(buf.len(), self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl CdImage {
+    // Reader of chd logical data, frame_count() frames
+    pub fn reader(&self) -> ImageReader<'_> {
+        ImageReader {
+            image: self,
+            track: 0,
+            file: None,
+            frame: 0,
+            buf: vec![0; FRAME_SIZE],
+            pos: FRAME_SIZE,
+        }
+    }
+}
+
+// Write CD chd of image with chdman hunk and unit sizes, compressed by codecs
+// or uncompressed if there are none. Returns writer positioned at the end of chd.
+pub fn create_cd<W: Write + Seek>(
+    image: &CdImage,
+    to: W,
+    codecs: &[u32],
+    progress: Option<Progress>,
+) -> io::Result<W> {
+    let size = image.frame_count() * FRAME_SIZE as u64;
+    let mut options = WriterOptions::new(
+        size,
+        CD_FRAMES_PER_HUNK * FRAME_SIZE as u32,
+        FRAME_SIZE as u32,
+    )
+    .with_compression(codecs);
+    options.progress = progress;
+    let mut writer = ChdWriter::create(to, &options)?;
+    for (tag, data) in image.metadata() {
+        writer.add_metadata(tag, &data, true)?;
+    }
+
+    let mut from = image.reader();
+    let mut hunk = vec![0; writer.hunk_size()];
+    for hunknum in 0..writer.hunk_count() {
+        let offset = hunknum as u64 * hunk.len() as u64;
+        let length = std::cmp::min(hunk.len() as u64, size - offset) as usize;
+        from.read_exact(&mut hunk[..length])?;
+        writer.write_hunk(&hunk[..length])?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::verify_written;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_create_cd() {
+        assert_eq!(parse_msf("01:02:03"), Some(4653));
+        assert_eq!(parse_msf("0:00:75"), None);
+        assert_eq!(
+            words(" FILE \"a b.bin\" BINARY"),
+            ["FILE", "a b.bin", "BINARY"]
+        );
+
+        // the same data and metadata as made by chdman of the same cue
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("samples/bell.cue");
+        let image = CdImage::open(&path).unwrap();
+        let chd = create_cd(&image, Cursor::new(Vec::new()), &[], None)
+            .unwrap()
+            .into_inner();
+        let mut chd = Chd::open(Cursor::new(chd)).unwrap();
+        let report = verify_written(&mut chd, &mut image.reader()).unwrap();
+        assert!(report.passed());
+        assert_eq!(report.bytes, image.frame_count() * FRAME_SIZE as u64);
+        let raw = include_bytes!("../samples/cdzl.chd");
+        let expected = Chd::open(Cursor::new(&raw[..])).unwrap();
+        assert_eq!(chd.size(), expected.size());
+        assert_eq!(chd.hunk_size(), expected.hunk_size());
+        assert_eq!(chd.header.rawsha1, expected.header.rawsha1);
+        assert_eq!(chd.header.sha1, expected.header.sha1);
+
+        // compressed by chdman default codecs, audio goes to cdfl
+        let codecs = [CHD_CODEC_CD_ZLIB, CHD_CODEC_CD_FLAC];
+        let compressed = create_cd(&image, Cursor::new(Vec::new()), &codecs, None)
+            .unwrap()
+            .into_inner();
+        assert!(compressed.len() < raw.len() + raw.len() / 4);
+        let mut compressed = Chd::open(Cursor::new(compressed)).unwrap();
+        assert_eq!(compressed.header.compressors[..2], codecs);
+        let report = verify_written(&mut compressed, &mut image.reader()).unwrap();
+        assert!(report.passed());
+        assert_eq!(compressed.header.sha1, expected.header.sha1);
+        let stats = compressed.compression_stats().unwrap();
+        assert!(stats
+            .iter()
+            .any(|s| s.compression == HunkCompression::Codec(CHD_CODEC_CD_FLAC)));
+    }
+
+    #[test]
+    fn test_sheets() {
+        let dir = std::env::temp_dir().join(format!("chd-cdimage-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.bin"), vec![1; 2352 * 10 + 2352 * 5]).unwrap();
+        std::fs::write(dir.join("b.raw"), vec![2; 2352 * 7]).unwrap();
+
+        let cue = "FILE \"a.bin\" BINARY\n  TRACK 01 MODE1/2352\n    INDEX 01 00:00:00\n\
+                   \x20 TRACK 02 AUDIO\n    INDEX 00 00:00:10\n    INDEX 01 00:00:12\n";
+        let image = CdImage::parse_cue(&dir.join("a.cue"), cue).unwrap();
+        assert_eq!(image.tracks.len(), 2);
+        assert_eq!(image.tracks[0].track.frames, 10);
+        assert_eq!(image.tracks[1].track.frames, 5);
+        assert_eq!(image.tracks[1].track.stored_pregap(), 2);
+        assert_eq!(image.tracks[1].offset, 2352 * 10);
+        assert!(image.tracks[1].swap);
+
+        let gdi = "2\n1 0 4 2352 a.bin 0\n3 45150 0 2352 b.raw 0\n";
+        let image = CdImage::parse_gdi(&dir.join("a.gdi"), gdi).unwrap();
+        assert_eq!(image.tracks[1].track.number, 3);
+        assert_eq!(image.tracks[1].track.pregap, 150);
+        assert_eq!(image.tracks[1].track.frames, 7);
+
+        let toc = "CD_ROM\n// Track 1\nTRACK MODE1_RAW\nDATAFILE \"a.bin\" #0 00:00:10\n\
+                   TRACK AUDIO\nFILE \"a.bin\" #23520 0 00:00:05\nSTART 00:00:02\n";
+        let image = CdImage::parse_toc(&dir.join("a.toc"), toc).unwrap();
+        assert_eq!(image.tracks[0].track.frames, 10);
+        assert_eq!(image.tracks[1].offset, 23520);
+        assert_eq!(image.tracks[1].track.stored_pregap(), 2);
+
+        // chds of images made by cd::extract_cd keep the same data and metadata
+        let mut image = CdImage::parse_cue(&dir.join("a.cue"), cue).unwrap();
+        image.layout();
+        let chd = create_cd(&image, Cursor::new(Vec::new()), &[], None)
+            .unwrap()
+            .into_inner();
+        let mut chd = Chd::open(Cursor::new(chd)).unwrap();
+        for sheet in [cd::CdSheet::Cue, cd::CdSheet::Toc] {
+            let out = dir.join(format!("out{:?}.{:?}", sheet, sheet).to_lowercase());
+            cd::extract_cd(&mut chd, &out, sheet, false).unwrap();
+            let copy = CdImage::open(&out).unwrap();
+            let copy = create_cd(&copy, Cursor::new(Vec::new()), &[], None)
+                .unwrap()
+                .into_inner();
+            let copy = Chd::open(Cursor::new(copy)).unwrap();
+            assert_eq!(copy.header.sha1, chd.header.sha1, "{:?}", sheet);
+        }
+
+        // track per file, with pregap in its own file or at the end of previous one
+        let frames = |fill: &[(u8, usize)]| -> Vec<u8> {
+            fill.iter()
+                .flat_map(|(byte, count)| vec![*byte; 2352 * count])
+                .collect()
+        };
+        std::fs::write(dir.join("s.bin"), frames(&[(1, 10), (2, 2), (3, 3)])).unwrap();
+        std::fs::write(dir.join("t1.bin"), frames(&[(1, 10)])).unwrap();
+        std::fs::write(dir.join("t2.bin"), frames(&[(2, 2), (3, 3)])).unwrap();
+        std::fs::write(dir.join("p1.bin"), frames(&[(1, 10), (2, 2)])).unwrap();
+        std::fs::write(dir.join("p2.bin"), frames(&[(3, 3)])).unwrap();
+        let cues = [
+            "FILE s.bin BINARY\nTRACK 01 MODE1/2352\nINDEX 01 00:00:00\n\
+             TRACK 02 AUDIO\nINDEX 00 00:00:10\nINDEX 01 00:00:12\n",
+            "FILE t1.bin BINARY\nTRACK 01 MODE1/2352\nINDEX 01 00:00:00\n\
+             FILE t2.bin BINARY\nTRACK 02 AUDIO\nINDEX 00 00:00:00\nINDEX 01 00:00:02\n",
+            "FILE p1.bin BINARY\nTRACK 01 MODE1/2352\nINDEX 01 00:00:00\n\
+             TRACK 02 AUDIO\nINDEX 00 00:00:10\nFILE p2.bin BINARY\nINDEX 01 00:00:00\n",
+        ];
+        let mut sha1 = None;
+        for cue in cues {
+            let mut image = CdImage::parse_cue(&dir.join("split.cue"), cue).unwrap();
+            assert_eq!(image.tracks[0].track.frames, 10, "{}", cue);
+            assert_eq!(image.tracks[1].track.frames, 5, "{}", cue);
+            assert_eq!(image.tracks[1].track.stored_pregap(), 2, "{}", cue);
+            image.layout();
+            let chd = create_cd(&image, Cursor::new(Vec::new()), &[], None)
+                .unwrap()
+                .into_inner();
+            let chd = Chd::open(Cursor::new(chd)).unwrap();
+            assert_eq!(
+                *sha1.get_or_insert(chd.header.sha1),
+                chd.header.sha1,
+                "{}",
+                cue
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
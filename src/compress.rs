@@ -0,0 +1,384 @@
+extern crate deflate;
+
+use crate::bitstream::BitWriter;
+use crate::cd;
+use crate::decompress::Flac as FlacDecoder;
+use crate::ecc;
+use crate::flac::encode_frame;
+use crate::huffman::HuffmanEncoder;
+#[cfg(feature = "lzma")]
+use crate::lzma::LzmaEncoder;
+use crate::tags::*;
+use std::io;
+
+// Counterpart of Decompress: compressed data of hunk is read back by decompressor
+// of the same codec into the same bytes.
+pub trait Compress: Send {
+    // Append compressed src to dest
+    fn compress(&mut self, src: &[u8], dest: &mut Vec<u8>) -> io::Result<()>;
+}
+
+// Codecs handled by create(), keep in sync with it
+#[cfg(feature = "lzma")]
+pub const SUPPORTED: &[u32] = &[
+    CHD_CODEC_HUFF,
+    CHD_CODEC_FLAC,
+    CHD_CODEC_LZMA,
+    CHD_CODEC_ZLIB,
+    CHD_CODEC_CD_FLAC,
+    CHD_CODEC_CD_LZMA,
+    CHD_CODEC_CD_ZLIB,
+];
+#[cfg(not(feature = "lzma"))]
+pub const SUPPORTED: &[u32] = &[
+    CHD_CODEC_HUFF,
+    CHD_CODEC_FLAC,
+    CHD_CODEC_ZLIB,
+    CHD_CODEC_CD_FLAC,
+    CHD_CODEC_CD_ZLIB,
+];
+
+// CD codecs of SUPPORTED, which are the last ones there
+#[cfg(feature = "lzma")]
+const CD_CODECS: &[u32] = &[CHD_CODEC_CD_FLAC, CHD_CODEC_CD_LZMA, CHD_CODEC_CD_ZLIB];
+#[cfg(not(feature = "lzma"))]
+const CD_CODECS: &[u32] = &[CHD_CODEC_CD_FLAC, CHD_CODEC_CD_ZLIB];
+
+pub fn create(hunkbytes: u32, tag: u32) -> io::Result<Box<dyn Compress>> {
+    if CD_CODECS.contains(&tag) && !(hunkbytes as usize).is_multiple_of(cd::FRAME_SIZE) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "codec {}: hunk of {} bytes isn't whole CD frames",
+                tag_string(tag),
+                hunkbytes
+            ),
+        ));
+    }
+    Ok(match tag {
+        CHD_CODEC_HUFF => Box::new(Huffman::new()),
+        CHD_CODEC_FLAC => Box::new(Flac),
+        #[cfg(feature = "lzma")]
+        CHD_CODEC_LZMA => Box::new(Lzma::new(hunkbytes)),
+        CHD_CODEC_ZLIB => Box::new(Deflate),
+        CHD_CODEC_CD_FLAC => Box::new(CdFlac),
+        #[cfg(feature = "lzma")]
+        CHD_CODEC_CD_LZMA => {
+            // base codec compresses sector data of hunk
+            let sectors = hunkbytes / cd::FRAME_SIZE as u32 * cd::MAX_SECTOR_DATA as u32;
+            Box::new(CdCompress::new(Lzma::new(sectors), Deflate))
+        }
+        CHD_CODEC_CD_ZLIB => Box::new(CdCompress::new(Deflate, Deflate)),
+        x => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "codec {} can't compress hunks of {} bytes",
+                    tag_string(x),
+                    hunkbytes
+                ),
+            ))
+        }
+    })
+}
+
+pub struct Huffman {
+    encoder: HuffmanEncoder,
+}
+
+impl Huffman {
+    pub fn new() -> Self {
+        Self {
+            encoder: HuffmanEncoder::new(256, 16),
+        }
+    }
+}
+
+impl Compress for Huffman {
+    fn compress(&mut self, src: &[u8], dest: &mut Vec<u8>) -> io::Result<()> {
+        self.encoder.histo_reset();
+        for &byte in src {
+            self.encoder.histo_one(byte as u32);
+        }
+        self.encoder.compute_tree_from_histo()?;
+        let mut stream = BitWriter::new();
+        self.encoder.export_tree_huffman(&mut stream)?;
+        for &byte in src {
+            self.encoder.encode_one(&mut stream, byte as u32)?;
+        }
+        dest.extend(stream.finish());
+        Ok(())
+    }
+}
+
+// Raw deflate stream, as MAME writes it
+pub struct Deflate;
+
+impl Compress for Deflate {
+    fn compress(&mut self, src: &[u8], dest: &mut Vec<u8>) -> io::Result<()> {
+        dest.extend(deflate::deflate_bytes_conf(src, deflate::Compression::Best));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "lzma")]
+pub struct Lzma {
+    encoder: LzmaEncoder,
+}
+
+#[cfg(feature = "lzma")]
+impl Lzma {
+    pub fn new(hunkbytes: u32) -> Self {
+        Self {
+            encoder: LzmaEncoder::new(hunkbytes),
+        }
+    }
+}
+
+#[cfg(feature = "lzma")]
+impl Compress for Lzma {
+    fn compress(&mut self, src: &[u8], dest: &mut Vec<u8>) -> io::Result<()> {
+        // incompressible data grows a little
+        let limit = src.len() + src.len() / 2 + 1024;
+        Ok(self.encoder.compress(src, dest, limit)?)
+    }
+}
+
+// Block size of frames of hunk of 16 bit stereo samples, as MAME uses
+fn flac_block_size(bytes: usize, limit: usize) -> usize {
+    let mut blocksize = bytes / FlacDecoder::SAMPLE_SIZE;
+    while blocksize > limit {
+        blocksize /= 2;
+    }
+    blocksize.max(1)
+}
+
+// Encode interleaved stereo samples as frames of blocksize samples
+fn flac_compress(samples: &[(i32, i32)], blocksize: usize, dest: &mut Vec<u8>) {
+    for (number, block) in samples.chunks(blocksize).enumerate() {
+        let left: Vec<i32> = block.iter().map(|s| s.0).collect();
+        let right: Vec<i32> = block.iter().map(|s| s.1).collect();
+        encode_frame(&left, &right, number as u32, dest);
+    }
+}
+
+type SampleReader = fn([u8; 2]) -> i16;
+
+// Hunk of 16 bit stereo samples: endianness byte and frames, of the endianness
+// which compresses better
+pub struct Flac;
+
+impl Compress for Flac {
+    fn compress(&mut self, src: &[u8], dest: &mut Vec<u8>) -> io::Result<()> {
+        if !src.len().is_multiple_of(FlacDecoder::SAMPLE_SIZE) || src.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("flac: {} bytes aren't whole stereo samples", src.len()),
+            ));
+        }
+        let blocksize = flac_block_size(src.len(), 2048);
+        let mut best: Option<Vec<u8>> = None;
+        let endians: [(u8, SampleReader); 2] =
+            [(b'L', i16::from_le_bytes), (b'B', i16::from_be_bytes)];
+        for (endian, read) in endians {
+            let samples: Vec<(i32, i32)> = src
+                .chunks_exact(FlacDecoder::SAMPLE_SIZE)
+                .map(|s| (read([s[0], s[1]]) as i32, read([s[2], s[3]]) as i32))
+                .collect();
+            let mut data = vec![endian];
+            flac_compress(&samples, blocksize, &mut data);
+            if best.as_ref().is_none_or(|b| data.len() < b.len()) {
+                best = Some(data);
+            }
+        }
+        dest.extend(best.unwrap_or_default());
+        Ok(())
+    }
+}
+
+// Check that hunk is whole CD frames, returns their number
+fn cd_frames(src: &[u8]) -> io::Result<usize> {
+    match src.len().is_multiple_of(cd::FRAME_SIZE) && !src.is_empty() {
+        true => Ok(src.len() / cd::FRAME_SIZE),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cd: {} bytes aren't whole frames", src.len()),
+        )),
+    }
+}
+
+// CD hunk as CdDecompress reads it: bitmap of frames with ECC to regenerate,
+// length of sector data compressed by base codec, the data, then subcode
+// compressed by subcode codec. Sync and ECC of such frames are zeroed before
+// compression, so that they cost nothing.
+pub struct CdCompress<B: Compress, S: Compress> {
+    base: B,
+    subcode: S,
+    sectors: Vec<u8>,
+    subcodes: Vec<u8>,
+    compressed: Vec<u8>,
+}
+
+impl<B: Compress, S: Compress> CdCompress<B, S> {
+    pub fn new(base: B, subcode: S) -> Self {
+        Self {
+            base,
+            subcode,
+            sectors: Vec::new(),
+            subcodes: Vec::new(),
+            compressed: Vec::new(),
+        }
+    }
+}
+
+impl<B: Compress, S: Compress> Compress for CdCompress<B, S> {
+    fn compress(&mut self, src: &[u8], dest: &mut Vec<u8>) -> io::Result<()> {
+        let frames = cd_frames(src)?;
+        self.sectors.resize(frames * cd::MAX_SECTOR_DATA, 0);
+        self.subcodes.resize(frames * cd::MAX_SUBCODE_DATA, 0);
+        cd::split_frames(src, &mut self.sectors, &mut self.subcodes)?;
+
+        let mut bitmap = vec![0u8; frames.div_ceil(8)];
+        for (i, sector) in self
+            .sectors
+            .chunks_exact_mut(cd::MAX_SECTOR_DATA)
+            .enumerate()
+        {
+            if sector[..cd::SYNC_NUM_BYTES] == cd::SYNC_HEADER && ecc::verify(sector).is_empty() {
+                bitmap[i / 8] |= 1 << (i % 8);
+                sector[..cd::SYNC_NUM_BYTES].fill(0);
+                // Q parity follows P one up to the end of sector
+                sector[ecc::P_OFFSET..].fill(0);
+            }
+        }
+
+        self.compressed.clear();
+        self.base.compress(&self.sectors, &mut self.compressed)?;
+        let length = self.compressed.len();
+        // length is as wide as CdDecompress expects it for hunk size
+        let width = match src.len() <= u16::MAX as usize {
+            true => 2,
+            false => 3,
+        };
+        if length >> (8 * width) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cd: compressed sectors of {} bytes are too long", length),
+            ));
+        }
+        dest.extend(bitmap);
+        dest.extend_from_slice(&(length as u32).to_be_bytes()[4 - width..]);
+        dest.extend_from_slice(&self.compressed);
+        self.subcode.compress(&self.subcodes, dest)
+    }
+}
+
+// CD audio hunk: frames of big endian samples of all sectors, then deflated subcode
+pub struct CdFlac;
+
+impl Compress for CdFlac {
+    fn compress(&mut self, src: &[u8], dest: &mut Vec<u8>) -> io::Result<()> {
+        let frames = cd_frames(src)?;
+        let mut samples = Vec::with_capacity(frames * cd::MAX_SECTOR_DATA / 4);
+        let mut subcodes = Vec::with_capacity(frames * cd::MAX_SUBCODE_DATA);
+        for frame in src.chunks_exact(cd::FRAME_SIZE) {
+            let (sector, subcode) = cd::split_frame(frame)?;
+            samples.extend(sector.chunks_exact(FlacDecoder::SAMPLE_SIZE).map(|s| {
+                (
+                    i16::from_be_bytes([s[0], s[1]]) as i32,
+                    i16::from_be_bytes([s[2], s[3]]) as i32,
+                )
+            }));
+            subcodes.extend_from_slice(subcode);
+        }
+        let blocksize = flac_block_size(frames * cd::MAX_SECTOR_DATA, cd::MAX_SECTOR_DATA);
+        flac_compress(&samples, blocksize, dest);
+        Deflate.compress(&subcodes, dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompress;
+
+    // compress by codec and decompress back
+    fn round_trip(tag: u32, data: &[u8]) -> usize {
+        let mut compressed = Vec::new();
+        create(data.len() as u32, tag)
+            .unwrap()
+            .compress(data, &mut compressed)
+            .unwrap();
+        let mut codec = decompress::create(data.len() as u32, tag).unwrap().unwrap();
+        let mut hunk = vec![0; data.len()];
+        codec.decompress(&compressed, &mut hunk).unwrap();
+        assert!(hunk == data, "codec {}", tag_string(tag));
+        compressed.len()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = include_bytes!("../samples/data.b64");
+        let audio: Vec<u8> = (0..4096)
+            .flat_map(|i| {
+                let s = ((i as f64 * 0.02).sin() * 10000.0) as i16;
+                let mut sample = s.to_le_bytes().to_vec();
+                sample.extend((s / 3).to_le_bytes());
+                sample
+            })
+            .collect();
+        for &tag in &SUPPORTED[..SUPPORTED.len() - CD_CODECS.len()] {
+            assert!(round_trip(tag, &text[..8192]) < 8192);
+            round_trip(tag, &vec![0; 4096]);
+        }
+        assert!(round_trip(CHD_CODEC_FLAC, &audio) < audio.len() / 2);
+        assert!(create(4096, CHD_CODEC_CD_ZLIB.wrapping_add(1)).is_err());
+        assert!(create(4096, CHD_CODEC_CD_ZLIB).is_err());
+        assert!(Flac.compress(&[1, 2, 3], &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_cd_round_trip() {
+        // mode 1 sectors with valid ECC, one with broken ECC, and audio
+        let frames = 8;
+        let mut hunk = vec![0u8; frames * cd::FRAME_SIZE];
+        for (i, frame) in hunk.chunks_exact_mut(cd::FRAME_SIZE).enumerate() {
+            let (sector, subcode) = frame.split_at_mut(cd::MAX_SECTOR_DATA);
+            match i {
+                0..=4 => {
+                    ecc::set_header(sector, i as u32, 1);
+                    let user = &mut sector[16..16 + cd::USER_DATA_SIZE];
+                    user.copy_from_slice(&include_bytes!("../samples/data.b64")[..2048]);
+                    ecc::generate(sector);
+                    if i == 4 {
+                        sector[ecc::P_OFFSET] ^= 1;
+                    }
+                }
+                _ => {
+                    for (n, s) in sector.chunks_exact_mut(2).enumerate() {
+                        let v = ((n as f64 * 0.05).sin() * 8000.0) as i16;
+                        s.copy_from_slice(&v.to_be_bytes());
+                    }
+                }
+            }
+            subcode[i % cd::MAX_SUBCODE_DATA] = 0x80;
+        }
+        for &tag in &SUPPORTED[SUPPORTED.len() - CD_CODECS.len()..] {
+            let length = round_trip(tag, &hunk);
+            if tag != CHD_CODEC_CD_FLAC {
+                assert!(length < hunk.len() / 2, "codec {}", tag_string(tag));
+            }
+        }
+        // audio only
+        let audio = &hunk[5 * cd::FRAME_SIZE..];
+        assert!(round_trip(CHD_CODEC_CD_FLAC, audio) < audio.len() / 2);
+        let mut compressed = Vec::new();
+        create(hunk.len() as u32, CHD_CODEC_CD_ZLIB)
+            .unwrap()
+            .compress(&hunk, &mut compressed)
+            .unwrap();
+        // ECC of the first 4 frames is regenerated
+        assert_eq!(compressed[0], 0x0f);
+        assert!(CdFlac.compress(&hunk[1..], &mut Vec::new()).is_err());
+    }
+}
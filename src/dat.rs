@@ -0,0 +1,367 @@
+use super::parents::ParentIndex;
+use super::*;
+use std::path::{Path, PathBuf};
+
+// Disk entry of Logiqx XML DAT, e.g. <disk name="gdl-0001" sha1="..."/> inside <game>
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DatDisk {
+    pub game: String,           // name of game or machine the disk belongs to
+    pub name: String,           // disk name, file name without .chd
+    pub sha1: Option<[u8; 20]>, // chd sha1, none for undumped disks
+    pub status: Option<String>, // "baddump", "nodump", "good" or "verified" if present
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiskStatus {
+    Match(PathBuf),       // chd with the same sha1 found (and verified if requested)
+    Miss,                 // no chd with this sha1 or name
+    Bad(PathBuf, String), // chd found by name with another sha1, or it failed verification
+    NoDump,               // disk is not dumped, nothing to look for
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiskAudit {
+    pub disk: DatDisk,
+    pub status: DiskStatus,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuditReport {
+    pub disks: Vec<DiskAudit>,              // every dat disk in dat order
+    pub unknown: Vec<PathBuf>,              // chds not matching any dat disk
+    pub unreadable: Vec<(PathBuf, String)>, // files which are not valid chds
+}
+
+impl AuditReport {
+    pub fn matched(&self) -> usize {
+        self.count(|s| matches!(s, DiskStatus::Match(_)))
+    }
+
+    pub fn missing(&self) -> usize {
+        self.count(|s| matches!(s, DiskStatus::Miss))
+    }
+
+    pub fn bad(&self) -> usize {
+        self.count(|s| matches!(s, DiskStatus::Bad(..)))
+    }
+
+    fn count<F: Fn(&DiskStatus) -> bool>(&self, f: F) -> usize {
+        self.disks.iter().filter(|d| f(&d.status)).count()
+    }
+}
+
+// Replace predefined xml entities and numeric character references
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity.strip_prefix("#x") {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => entity.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+            }
+            .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// Attributes of element tag text without angle brackets, e.g. `disk name="a" sha1="b"/`
+fn attributes(tag: &str) -> Vec<(&str, String)> {
+    let mut result = Vec::new();
+    let mut rest = tag.trim_end_matches('/');
+    // skip element name
+    rest = rest.trim_start();
+    rest = &rest[rest.find(|c: char| c.is_whitespace()).unwrap_or(rest.len())..];
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim();
+        let value = rest[eq + 1..].trim_start();
+        let quote = match value.chars().next() {
+            Some(q @ '"') | Some(q @ '\'') => q,
+            _ => break,
+        };
+        let end = match value[1..].find(quote) {
+            Some(end) => end + 1,
+            None => break,
+        };
+        result.push((key, unescape(&value[1..end])));
+        rest = &value[end + 1..];
+    }
+    result
+}
+
+fn parse_sha1(s: &str) -> Option<[u8; 20]> {
+    if s.len() != 40 || !s.is_ascii() {
+        return None;
+    }
+    let mut sha1 = [0; 20];
+    for (i, byte) in sha1.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(sha1)
+}
+
+// Disk entries of Logiqx XML DAT text. Only elements needed for disk audit are looked at,
+// the rest of the document is skipped without validation.
+pub fn parse_dat(text: &str) -> io::Result<Vec<DatDisk>> {
+    let mut disks = Vec::new();
+    let mut game = None;
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        // comments, declarations and processing instructions
+        let skip_to = if rest.starts_with("!--") {
+            Some("-->")
+        } else if rest.starts_with("![CDATA[") {
+            Some("]]>")
+        } else if rest.starts_with('!') || rest.starts_with('?') {
+            Some(">")
+        } else {
+            None
+        };
+        if let Some(end) = skip_to {
+            let i = rest
+                .find(end)
+                .ok_or_else(|| invalid_data_str("dat: unterminated xml markup"))?;
+            rest = &rest[i + end.len()..];
+            continue;
+        }
+        // tag ends at '>' outside of quoted attribute values
+        let mut quote = None;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| match quote {
+                Some(q) if c == q => {
+                    quote = None;
+                    false
+                }
+                Some(_) => false,
+                None if c == '"' || c == '\'' => {
+                    quote = Some(c);
+                    false
+                }
+                None => c == '>',
+            })
+            .map(|(i, _)| i)
+            .ok_or_else(|| invalid_data_str("dat: unterminated xml tag"))?;
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .find(|s| !s.is_empty())
+            .unwrap_or("");
+        match name {
+            "game" | "machine" if !tag.starts_with('/') => {
+                let attrs = attributes(tag);
+                let name = attrs.into_iter().find(|(k, _)| *k == "name");
+                game = Some(name.map(|(_, v)| v).unwrap_or_default());
+            }
+            "disk" if !tag.starts_with('/') => {
+                let game = game
+                    .clone()
+                    .ok_or_else(|| invalid_data_str("dat: disk outside of game"))?;
+                let mut disk = DatDisk {
+                    game,
+                    name: String::new(),
+                    sha1: None,
+                    status: None,
+                };
+                for (key, value) in attributes(tag) {
+                    match key {
+                        "name" => disk.name = value,
+                        "sha1" => {
+                            disk.sha1 = Some(parse_sha1(&value).ok_or_else(|| {
+                                invalid_data(format!("dat: bad disk sha1 {}", value))
+                            })?)
+                        }
+                        "status" => disk.status = Some(value),
+                        _ => {}
+                    }
+                }
+                disks.push(disk);
+            }
+            _ => {}
+        }
+        if tag.starts_with("/game") || tag.starts_with("/machine") {
+            game = None;
+        }
+    }
+    Ok(disks)
+}
+
+pub fn read_dat<P: AsRef<Path>>(path: P) -> io::Result<Vec<DatDisk>> {
+    parse_dat(&std::fs::read_to_string(path)?)
+}
+
+// Match chd files against dat disks by header sha1. With `verify` set, matched chds
+// are also fully verified, parents are looked up among the given files.
+pub fn audit<P: AsRef<Path>>(disks: &[DatDisk], files: &[P], verify: bool) -> AuditReport {
+    let mut report = AuditReport::default();
+    let mut index = ParentIndex::new();
+    let mut headers = Vec::new();
+    for path in files {
+        let path = path.as_ref();
+        match probe_file(path) {
+            Ok(probe) => {
+                index.insert(probe.sha1, path);
+                headers.push((path.to_path_buf(), probe.sha1));
+            }
+            Err(e) => report.unreadable.push((path.to_path_buf(), e.to_string())),
+        }
+    }
+
+    let mut used = vec![false; headers.len()];
+    for disk in disks {
+        let by_sha1 = disk
+            .sha1
+            .and_then(|sha1| headers.iter().position(|(_, s)| *s == sha1));
+        let status = match (disk.sha1, by_sha1) {
+            (None, _) => DiskStatus::NoDump,
+            (Some(sha1), Some(i)) => {
+                used[i] = true;
+                let path = headers[i].0.clone();
+                match verify {
+                    true => match index.open(&sha1).and_then(|mut chd| chd.verify()) {
+                        Ok(()) => DiskStatus::Match(path),
+                        Err(e) => DiskStatus::Bad(path, e.to_string()),
+                    },
+                    false => DiskStatus::Match(path),
+                }
+            }
+            (Some(_), None) => {
+                let by_name = headers.iter().position(|(path, _)| {
+                    path.file_stem()
+                        .is_some_and(|stem| stem == disk.name.as_str())
+                });
+                match by_name {
+                    Some(i) => {
+                        used[i] = true;
+                        let (path, sha1) = &headers[i];
+                        DiskStatus::Bad(
+                            path.clone(),
+                            format!("sha1 mismatch, found {}", hex_string(sha1)),
+                        )
+                    }
+                    None => DiskStatus::Miss,
+                }
+            }
+        };
+        report.disks.push(DiskAudit {
+            disk: disk.clone(),
+            status,
+        });
+    }
+    for (i, (path, _)) in headers.into_iter().enumerate() {
+        if !used[i] {
+            report.unknown.push(path);
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dat() {
+        let dat = r#"<?xml version="1.0"?>
+<!DOCTYPE datafile PUBLIC "-//Logiqx//DTD ROM Management Datafile//EN" "x.dtd">
+<datafile>
+    <header><name>test</name></header>
+    <!-- <game name="commented"><disk name="x" sha1="0"/></game> -->
+    <game name="one &amp; two">
+        <description>a &gt; b</description>
+        <rom name="a.bin" size="1" crc="00000000"/>
+        <disk name='disk1' sha1="0123456789abcdef0123456789ABCDEF01234567" region="ide:0:hdd"/>
+        <disk name="disk2" status="nodump"/>
+    </game>
+    <machine name="m">
+        <disk name="d" sha1="ffffffffffffffffffffffffffffffffffffffff" status="baddump" />
+    </machine>
+</datafile>
+"#;
+        let disks = parse_dat(dat).unwrap();
+        assert_eq!(disks.len(), 3);
+        assert_eq!(disks[0].game, "one & two");
+        assert_eq!(disks[0].name, "disk1");
+        assert_eq!(
+            disks[0].sha1.map(|s| hex_string(&s)).unwrap(),
+            "0123456789abcdef0123456789abcdef01234567"
+        );
+        assert_eq!(disks[1].sha1, None);
+        assert_eq!(disks[1].status.as_deref(), Some("nodump"));
+        assert_eq!(disks[2].game, "m");
+        assert_eq!(disks[2].sha1, Some([0xff; 20]));
+        assert_eq!(disks[2].status.as_deref(), Some("baddump"));
+
+        assert!(parse_dat("<game name=\"a\"><disk name=\"b\" sha1=\"12\"/></game>").is_err());
+        assert!(parse_dat("<disk name=\"b\"/>").is_err());
+        assert_eq!(unescape("&#65;&#x42;&bogus;&"), "AB&bogus;&");
+    }
+
+    #[test]
+    fn test_audit() {
+        let zlib = Path::new("samples/zlib.chd");
+        let child = Path::new("samples/child.chd");
+        let cdzl = Path::new("samples/cdzl.chd");
+        let sha1 = |path: &Path| probe_file(path).unwrap().sha1;
+        let disk = |name: &str, sha1: Option<[u8; 20]>| DatDisk {
+            game: "game".to_string(),
+            name: name.to_string(),
+            sha1,
+            status: None,
+        };
+        let disks = [
+            disk("zlib", Some(sha1(zlib))),
+            disk("child", Some(sha1(child))),
+            disk("cdzl", Some([1; 20])),
+            disk("missing", Some([2; 20])),
+            disk("undumped", None),
+        ];
+        let files = [zlib, child, cdzl, Path::new("Cargo.toml")];
+
+        let report = audit(&disks, &files, false);
+        assert_eq!(report.disks[0].status, DiskStatus::Match(zlib.into()));
+        assert_eq!(report.disks[1].status, DiskStatus::Match(child.into()));
+        assert!(matches!(&report.disks[2].status, DiskStatus::Bad(p, _) if p == cdzl));
+        assert_eq!(report.disks[3].status, DiskStatus::Miss);
+        assert_eq!(report.disks[4].status, DiskStatus::NoDump);
+        assert_eq!(
+            (report.matched(), report.missing(), report.bad()),
+            (2, 1, 1)
+        );
+        assert!(report.unknown.is_empty());
+        assert_eq!(report.unreadable.len(), 1);
+
+        // without its parent among files the child can't be verified
+        let report = audit(&disks[..2], &files[1..2], true);
+        assert!(matches!(report.disks[1].status, DiskStatus::Bad(..)));
+        let report = audit(&disks[..2], &files, true);
+        assert_eq!(report.matched(), 2);
+    }
+}
@@ -6,48 +6,211 @@ use crate::bitstream::BitReader;
 use crate::cd;
 use crate::ecc;
 use crate::huffman::Huffman as HuffmanDecoder;
-use crate::lzma::*;
+#[cfg(feature = "lzma")]
+use crate::lzma::LzmaDecoder;
 use crate::tags::*;
 use crate::utils::*;
+use crate::Event;
 use claxon::frame::{Block, FrameReader};
 use std::io;
-use std::io::{Cursor, Write};
+use std::io::Cursor;
+use std::sync::mpsc::Sender;
 
-pub trait Decompress {
+pub trait Decompress: Send {
     fn decompress(&mut self, src: &[u8], dest: &mut [u8]) -> io::Result<()>;
+    // Same, but temporary buffers are borrowed from pool shared by codecs of Chd
+    fn decompress_pooled(
+        &mut self,
+        src: &[u8],
+        dest: &mut [u8],
+        _pool: &mut BufferPool,
+    ) -> io::Result<()> {
+        self.decompress(src, dest)
+    }
+    // bytes held by internal buffers
+    fn memory_usage(&self) -> usize {
+        0
+    }
+    // bytes borrowed from pool by decompress_pooled at most
+    fn pool_usage(&self) -> usize {
+        0
+    }
+    // Why the last decompress had to decode data in alternative way; cleared when taken
+    fn take_fallback(&mut self) -> Option<String> {
+        None
+    }
 }
 
 pub type DecompressType = Option<Box<dyn Decompress>>;
 
-fn create(header: &Header, tag: u32) -> DecompressType {
-    match tag {
+// Decompressors for each of 4 compressor slots in chd header
+pub struct Codecs {
+    pub tags: [u32; 4],
+    pub slots: [DecompressType; 4],
+    pub pool: BufferPool, // temporary buffers of hunk reads and codecs
+    pub events: Option<Sender<Event>>, // of long operations, see Chd::set_events
+    notes: Vec<Note>,     // of the hunk being read, until its number is known
+}
+
+// Event noticed deep in hunk read, which doesn't know hunk number
+pub enum Note {
+    Warning(String),
+    Fallback(u32, String), // codec tag, reason
+}
+
+impl Codecs {
+    pub fn get(&mut self, index: usize) -> Option<(&mut dyn Decompress, &mut BufferPool)> {
+        match self.slots.get_mut(index) {
+            Some(Some(d)) => Some((d.as_mut(), &mut self.pool)),
+            _ => None,
+        }
+    }
+
+    // Put codec into used slot, returns previous one
+    pub fn replace(
+        &mut self,
+        index: usize,
+        codec: Box<dyn Decompress>,
+    ) -> io::Result<Box<dyn Decompress>> {
+        match self.slots.get_mut(index) {
+            Some(Some(d)) => Ok(std::mem::replace(d, codec)),
+            Some(None) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("codec slot {} is not used", index),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("codec slot {} is out of range", index),
+            )),
+        }
+    }
+
+    // Send event if someone listens, closed receiver is ignored
+    pub fn send(&self, event: Event) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+
+    pub fn listening(&self) -> bool {
+        self.events.is_some()
+    }
+
+    pub fn note(&mut self, note: Note) {
+        if self.listening() {
+            self.notes.push(note);
+        }
+    }
+
+    // Send notes of finished read of hunk `hunk`
+    pub fn report(&mut self, hunk: usize) {
+        for note in std::mem::take(&mut self.notes) {
+            self.send(match note {
+                Note::Warning(reason) => Event::Warning { hunk, reason },
+                Note::Fallback(tag, reason) => Event::CodecFallback { hunk, tag, reason },
+            });
+        }
+    }
+
+    // Bytes held by codec in slot, 0 for unused slot
+    pub fn memory_usage(&self, index: usize) -> usize {
+        match self.slots.get(index) {
+            Some(Some(d)) => d.memory_usage(),
+            _ => 0,
+        }
+    }
+}
+
+// Codecs handled by create(), keep in sync with it
+#[cfg(feature = "lzma")]
+pub const SUPPORTED: &[u32] = &[
+    CHD_CODEC_HUFF,
+    CHD_CODEC_FLAC,
+    CHD_CODEC_LZMA,
+    CHD_CODEC_ZLIB,
+    CHD_CODEC_CD_FLAC,
+    CHD_CODEC_CD_LZMA,
+    CHD_CODEC_CD_ZLIB,
+];
+#[cfg(not(feature = "lzma"))]
+pub const SUPPORTED: &[u32] = &[
+    CHD_CODEC_HUFF,
+    CHD_CODEC_FLAC,
+    CHD_CODEC_ZLIB,
+    CHD_CODEC_CD_FLAC,
+    CHD_CODEC_CD_ZLIB,
+];
+
+pub(crate) fn create(hunkbytes: u32, tag: u32) -> io::Result<DecompressType> {
+    Ok(match tag {
         0 => None,
         CHD_CODEC_HUFF => Some(Box::new(Huffman::new())),
-        CHD_CODEC_FLAC => Some(Box::new(Flac::new())),
-        CHD_CODEC_LZMA => Some(Box::new(Lzma::new(header.hunkbytes).unwrap())),
+        CHD_CODEC_FLAC => Some(Box::new(Flac::new(hunkbytes))),
+        #[cfg(feature = "lzma")]
+        CHD_CODEC_LZMA => Some(Box::new(Lzma::new(hunkbytes)?)),
         CHD_CODEC_ZLIB => Some(Box::new(Inflate::new())),
-        CHD_CODEC_CD_FLAC => Some(Box::new(CdFlac::new(header.hunkbytes))),
+        CHD_CODEC_CD_FLAC => Some(Box::new(CdFlac::new(hunkbytes))),
+        #[cfg(feature = "lzma")]
         CHD_CODEC_CD_LZMA => Some(Box::new(CdDecompress::construct(
-            Lzma::new(header.hunkbytes).unwrap(),
+            Lzma::new(hunkbytes)?,
             Inflate::new(),
-            header.hunkbytes,
+            hunkbytes,
         ))),
         CHD_CODEC_CD_ZLIB => Some(Box::new(CdDecompress::construct(
             Inflate::new(),
             Inflate::new(),
-            header.hunkbytes,
+            hunkbytes,
         ))),
         x => Some(Box::new(Unknown::new(x))),
+    })
+}
+
+// Fail if any codec in the header can't be decompressed by this build
+fn check_supported(tags: &[u32]) -> io::Result<()> {
+    let unsupported: Vec<String> = tags
+        .iter()
+        .filter(|&&tag| tag != 0 && !SUPPORTED.contains(&tag))
+        .map(|&tag| tag_string(tag))
+        .collect();
+    match unsupported.is_empty() {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unsupported codecs: {}", unsupported.join(", ")),
+        )),
     }
 }
 
-pub(super) fn init(header: &Header) -> [DecompressType; 4] {
-    [
-        create(header, header.compressors[0]),
-        create(header, header.compressors[1]),
-        create(header, header.compressors[2]),
-        create(header, header.compressors[3]),
-    ]
+// strict: fail right away on unsupported codecs instead of on reads of hunks using them
+pub(super) fn init(header: &Header, budget: &mut MemoryBudget, strict: bool) -> io::Result<Codecs> {
+    if strict {
+        check_supported(&header.compressors)?;
+    }
+    let slots = [
+        create(header.hunkbytes, header.compressors[0])?,
+        create(header.hunkbytes, header.compressors[1])?,
+        create(header.hunkbytes, header.compressors[2])?,
+        create(header.hunkbytes, header.compressors[3])?,
+    ];
+    let mut pool_usage = 0;
+    for (i, d) in slots.iter().enumerate() {
+        if let Some(d) = d {
+            let what = format!("codec#{} {}", i, tag_string(header.compressors[i]));
+            budget.reserve(&what, d.memory_usage())?;
+            pool_usage = pool_usage.max(d.pool_usage());
+        }
+    }
+    if slots.iter().any(|d| d.is_some()) {
+        // compressed data of a hunk is read into pool buffer too
+        budget.reserve("codec buffer pool", header.hunkbytes as usize + pool_usage)?;
+    }
+    Ok(Codecs {
+        tags: header.compressors,
+        slots,
+        pool: BufferPool::new(),
+        events: None,
+        notes: Vec::new(),
+    })
 }
 
 struct Unknown {
@@ -82,6 +245,10 @@ impl Huffman {
 }
 
 impl Decompress for Huffman {
+    fn memory_usage(&self) -> usize {
+        self.inner.memory_usage()
+    }
+
     fn decompress(&mut self, src: &[u8], dest: &mut [u8]) -> io::Result<()> {
         let mut stream = BitReader::new(src);
         self.inner.import_tree_huffman(&mut stream)?;
@@ -97,90 +264,183 @@ impl Decompress for Huffman {
     }
 }
 
-pub struct Inflate {}
+// Deflate codec. MAME writes raw deflate streams, but zlib wrapped streams of
+// other writers are accepted too. Stream must fill the whole hunk.
+pub struct Inflate {
+    stream: inflate::InflateStream,
+    fallback: Option<String>, // zlib error of stream decoded as raw deflate
+}
 
 impl Inflate {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            stream: inflate::InflateStream::new(),
+            fallback: None,
+        }
+    }
+
+    // zlib header: deflate method, window up to 32K, no preset dictionary
+    fn is_zlib(src: &[u8]) -> bool {
+        match src {
+            [cmf, flg, ..] => {
+                cmf & 0x0f == 8
+                    && cmf >> 4 <= 7
+                    && flg & 0x20 == 0
+                    && (*cmf as u16 * 256 + *flg as u16).is_multiple_of(31)
+            }
+            _ => false,
+        }
+    }
+
+    fn inflate(stream: &mut inflate::InflateStream, src: &[u8], dest: &mut [u8]) -> io::Result<()> {
+        let mut input = src;
+        let mut done = 0;
+        loop {
+            let (used, output) = stream
+                .update(input)
+                .map_err(|e| invalid_data(format!("inflate: {}", e)))?;
+            if output.len() > dest.len() - done {
+                return Err(invalid_data(format!(
+                    "inflate: data is longer than {} bytes",
+                    dest.len()
+                )));
+            }
+            dest[done..done + output.len()].copy_from_slice(output);
+            done += output.len();
+            input = &input[used..];
+            if used == 0 && output.is_empty() {
+                break;
+            }
+        }
+        match done == dest.len() {
+            true => Ok(()),
+            false => Err(invalid_data(format!(
+                "inflate: decoded {} bytes, expected {}",
+                done,
+                dest.len()
+            ))),
+        }
     }
 }
 
 impl Decompress for Inflate {
     fn decompress(&mut self, src: &[u8], dest: &mut [u8]) -> io::Result<()> {
-        let mut inflate = inflate::InflateWriter::new(dest);
-        inflate.write(&src)?;
+        self.fallback = None;
+        // zlib stream keeps adler32 state after reset, so it's a new one for each hunk
+        let zlib_error = match Self::is_zlib(src) {
+            true => match Self::inflate(&mut inflate::InflateStream::from_zlib(), src, dest) {
+                Ok(()) => return Ok(()),
+                Err(e) => Some(e),
+            },
+            false => None,
+        };
+        self.stream.reset();
+        Self::inflate(&mut self.stream, src, dest)?;
+        self.fallback =
+            zlib_error.map(|e| format!("zlib header, but {}; decoded as raw deflate", e));
         Ok(())
     }
+
+    fn take_fallback(&mut self) -> Option<String> {
+        self.fallback.take()
+    }
 }
 
+#[cfg(feature = "lzma")]
 pub struct Lzma {
-    handle: usize,
+    decoder: LzmaDecoder,
 }
 
+#[cfg(feature = "lzma")]
 impl Lzma {
     pub fn new(hunkbytes: u32) -> io::Result<Self> {
-        let handle = unsafe { lzma_create(hunkbytes) };
-        match handle {
-            0 => Err(invalid_data_str("failed to create lzma decoder")),
-            _ => Ok(Self { handle }),
-        }
+        Ok(Self {
+            decoder: LzmaDecoder::new(hunkbytes)?,
+        })
     }
 }
 
-impl Drop for Lzma {
-    fn drop(&mut self) {
-        unsafe { lzma_destroy(self.handle) };
+#[cfg(feature = "lzma")]
+impl Decompress for Lzma {
+    fn memory_usage(&self) -> usize {
+        self.decoder.memory_usage()
     }
-}
 
-impl Decompress for Lzma {
     fn decompress(&mut self, src: &[u8], dest: &mut [u8]) -> io::Result<()> {
-        let error = unsafe {
-            let srclen = src.len() as u32;
-            let psrc = src.as_ptr();
-            let dstlen = dest.len() as u32;
-            let pdst = dest.as_mut_ptr();
-            lzma_decompress(self.handle, psrc, srclen, pdst, dstlen)
-        };
-        match error {
-            0 => Ok(()),
-            _ => Err(invalid_data_str("lzma decompression failed")),
-        }
+        Ok(self.decoder.decompress(src, dest)?)
     }
 }
 
-pub struct Flac {}
+pub struct Flac {
+    hunkbytes: u32,
+}
 
 impl Flac {
     pub const SAMPLE_SIZE: usize = 4; // 16bit stereo
 
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(hunkbytes: u32) -> Self {
+        Self { hunkbytes }
+    }
+}
+
+// Sample size in bits from frame header: CHD hunks store 16 bit samples only,
+// so reject other depths before decoding, with the exact value in the error.
+fn flac_bits_per_sample(src: &[u8]) -> io::Result<u32> {
+    let byte = *src
+        .get(3)
+        .ok_or(invalid_data_str("flac: frame header is too short"))?;
+    match (byte >> 1) & 0b111 {
+        0b000 => Err(invalid_data_str(
+            "flac: frame header doesn't specify bits per sample",
+        )),
+        0b001 => Ok(8),
+        0b010 => Ok(12),
+        0b100 => Ok(16),
+        0b101 => Ok(20),
+        0b110 => Ok(24),
+        0b111 => Ok(32),
+        x => Err(invalid_data(format!(
+            "flac: reserved bits per sample code {:03b}",
+            x
+        ))),
     }
 }
 
 // buffer is moved into resulting block
 fn flac_decompress(src: &[u8], buffer: Vec<i32>) -> io::Result<(Block, usize)> {
+    let bits = flac_bits_per_sample(src)?;
+    if bits != 16 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("flac: unsupported {} bits per sample, expected 16", bits),
+        ));
+    }
     let input = Cursor::new(src);
     let mut frame_reader = FrameReader::new(input);
     let result = frame_reader
         .read_next_or_eof(buffer)
-        .map_err(|_| invalid_data_str("flac: failed to decode frame"))?;
+        .map_err(|e| invalid_data(format!("flac: failed to decode frame: {}", e)))?;
     let block = result.ok_or(invalid_data_str("flac: data is too short"))?;
-    if block.channels() != 2 {
-        return Err(invalid_data(format!(
-            "flac: expected stereo, but got {} channel samples",
-            block.channels()
-        )));
+    if block.duration() == 0 {
+        return Err(invalid_data_str("flac: decoded empty block"));
     }
     Ok((block, frame_reader.into_inner().position() as usize))
 }
 
 impl Decompress for Flac {
     fn decompress(&mut self, src: &[u8], dest: &mut [u8]) -> io::Result<()> {
-        let write_endian = match src[0] {
-            b'L' => write_le16,
-            b'B' => write_be16,
+        self.decompress_pooled(src, dest, &mut BufferPool::new())
+    }
+
+    fn decompress_pooled(
+        &mut self,
+        src: &[u8],
+        dest: &mut [u8],
+        pool: &mut BufferPool,
+    ) -> io::Result<()> {
+        let write_endian = match src.first().copied().unwrap_or(0) {
+            b'L' => <[u8]>::put_le16,
+            b'B' => <[u8]>::put_be16,
             x => {
                 return Err(invalid_data(format!(
                     "flac: invalid hunk endianness {:x}",
@@ -188,29 +448,61 @@ impl Decompress for Flac {
                 )))
             }
         };
-        let frame_size = Flac::SAMPLE_SIZE;
-        let num_frames = dest.len() / frame_size;
-        let buffer = vec![0; 2 * num_frames]; // 2 channe;s
-        let block = flac_decompress(&src[1..], buffer)?.0;
-        if block.duration() != num_frames as u32 {
-            return Err(invalid_data(format!(
-                "flac: decoded duration {} doesn't match number of frames in hunk {}",
-                block.duration(),
-                num_frames
-            )));
-        }
-        for (i, (sl, sr)) in block.stereo_samples().enumerate() {
-            write_endian(&mut dest[i * frame_size + 0..i * frame_size + 2], sl as u16);
-            write_endian(&mut dest[i * frame_size + 2..i * frame_size + 4], sr as u16);
+        // hunk is 16 bit samples of all channels interleaved, that may take several blocks
+        let total = dest.len() / 2;
+        let mut src = &src[1..];
+        let mut buffer = pool.take_samples();
+        let mut channels = 0;
+        let mut done = 0;
+        while done < total {
+            let (block, pos) = flac_decompress(src, buffer)?;
+            match (channels, block.channels()) {
+                (_, n) if n > 2 => {
+                    return Err(invalid_data(format!(
+                        "flac: expected mono or stereo, but got {} channel samples",
+                        n
+                    )))
+                }
+                (0, n) => channels = n,
+                (c, n) if c != n => {
+                    return Err(invalid_data(format!(
+                        "flac: number of channels changed from {} to {} within hunk",
+                        c, n
+                    )))
+                }
+                _ => (),
+            }
+            let count = block.duration() as usize * channels as usize;
+            if done + count > total {
+                return Err(invalid_data(format!(
+                    "flac: decoded {} samples, but hunk has room for {}",
+                    done + count,
+                    total
+                )));
+            }
+            for i in 0..block.duration() {
+                for ch in 0..channels {
+                    write_endian(dest, done * 2, block.sample(ch, i) as u16)?;
+                    done += 1;
+                }
+            }
+            src = &src[pos..];
+            buffer = block.into_buffer();
         }
+        pool.give_samples(buffer);
         Ok(())
     }
+
+    // i32 per 16 bit sample
+    fn pool_usage(&self) -> usize {
+        self.hunkbytes as usize * 2
+    }
 }
 
 struct CdDecompress<B: Decompress, S: Decompress> {
     base: B,
     subcode: S,
-    buffer: Vec<u8>,
+    hunkbytes: usize,
 }
 
 impl<B: Decompress, S: Decompress> CdDecompress<B, S> {
@@ -218,58 +510,51 @@ impl<B: Decompress, S: Decompress> CdDecompress<B, S> {
         Self {
             base,
             subcode,
-            buffer: vec![0; hunkbytes as usize],
+            hunkbytes: hunkbytes as usize,
         }
     }
-}
 
-impl<B: Decompress, S: Decompress> Decompress for CdDecompress<B, S> {
-    fn decompress(&mut self, src: &[u8], dest: &mut [u8]) -> io::Result<()> {
+    // buffer receives all frames data, then all frames subcode
+    fn decompress_into(
+        &mut self,
+        src: &[u8],
+        dest: &mut [u8],
+        buffer: &mut [u8],
+    ) -> io::Result<()> {
         let frames = dest.len() / cd::FRAME_SIZE;
-        let ecc_bytes = (frames + 7) / 8;
+        let ecc_bytes = frames.div_ceil(8);
         let (compr_start, compr_len) = if dest.len() <= u16::MAX as usize {
-            (
-                ecc_bytes + 2,
-                read_be16(&src[ecc_bytes..ecc_bytes + 2]) as usize,
-            )
+            (ecc_bytes + 2, src.get_be16(ecc_bytes)? as usize)
         } else {
-            (
-                ecc_bytes + 3,
-                read_be24(&src[ecc_bytes..ecc_bytes + 3]) as usize,
-            )
+            (ecc_bytes + 3, src.get_be24(ecc_bytes)? as usize)
         };
 
         let compr_end = compr_start + compr_len;
-        let compressed = &src[compr_start..compr_end];
+        let compressed = src.get(compr_start..compr_end).ok_or_else(|| {
+            invalid_data(format!(
+                "cd: compressed length {} past the end of {} bytes",
+                compr_len,
+                src.len()
+            ))
+        })?;
         let subcode = &src[compr_end..];
         let subcode_start = frames * cd::MAX_SECTOR_DATA;
         let subcode_end = subcode_start + frames * cd::MAX_SUBCODE_DATA;
 
         self.base
-            .decompress(&compressed, &mut self.buffer[..subcode_start])?;
+            .decompress(compressed, &mut buffer[..subcode_start])?;
         self.subcode
-            .decompress(&subcode, &mut self.buffer[subcode_start..subcode_end])?;
+            .decompress(subcode, &mut buffer[subcode_start..subcode_end])?;
 
         // buffer contains first all frames data, then all frames subcode. reassemble frames
+        cd::assemble_frames(
+            &buffer[..subcode_start],
+            &buffer[subcode_start..subcode_end],
+            &mut dest[..subcode_end],
+        )?;
         for i in 0..frames {
-            let frame_offs = i * cd::FRAME_SIZE;
-
-            let data_offs = i * cd::MAX_SECTOR_DATA;
-            let framedata = &mut dest[frame_offs..frame_offs + cd::MAX_SECTOR_DATA];
-            copy_from(
-                framedata,
-                &self.buffer[data_offs..data_offs + framedata.len()],
-            );
-
-            let subcode_offs = subcode_start + i * cd::MAX_SUBCODE_DATA;
-            let framesubcode =
-                &mut dest[frame_offs + cd::MAX_SECTOR_DATA..frame_offs + cd::FRAME_SIZE];
-            copy_from(
-                framesubcode,
-                &self.buffer[subcode_offs..subcode_offs + framesubcode.len()],
-            );
-
             if src[i / 8] & (1 << (i % 8)) != 0 {
+                let frame_offs = i * cd::FRAME_SIZE;
                 let sector = &mut dest[frame_offs..frame_offs + cd::MAX_SECTOR_DATA];
                 copy_from(sector, &cd::SYNC_HEADER);
                 ecc::generate(sector);
@@ -279,8 +564,40 @@ impl<B: Decompress, S: Decompress> Decompress for CdDecompress<B, S> {
     }
 }
 
+impl<B: Decompress, S: Decompress> Decompress for CdDecompress<B, S> {
+    fn memory_usage(&self) -> usize {
+        self.base.memory_usage() + self.subcode.memory_usage()
+    }
+
+    fn pool_usage(&self) -> usize {
+        self.hunkbytes
+    }
+
+    fn decompress(&mut self, src: &[u8], dest: &mut [u8]) -> io::Result<()> {
+        self.decompress_pooled(src, dest, &mut BufferPool::new())
+    }
+
+    fn take_fallback(&mut self) -> Option<String> {
+        let base = self.base.take_fallback();
+        let subcode = self.subcode.take_fallback();
+        base.or(subcode.map(|reason| format!("subcode: {}", reason)))
+    }
+
+    fn decompress_pooled(
+        &mut self,
+        src: &[u8],
+        dest: &mut [u8],
+        pool: &mut BufferPool,
+    ) -> io::Result<()> {
+        let mut buffer = pool.take(dest.len());
+        let result = self.decompress_into(src, dest, &mut buffer);
+        pool.give(buffer);
+        result
+    }
+}
+
 struct CdFlac {
-    buffer: Vec<u8>,
+    frames: usize, // per hunk
     inflate: Inflate,
 }
 
@@ -289,17 +606,21 @@ impl CdFlac {
 
     pub fn new(hunkbytes32: u32) -> Self {
         let hunkbytes = hunkbytes32 as usize;
-        assert!(hunkbytes % cd::FRAME_SIZE == 0);
-        let num_frames = hunkbytes / cd::FRAME_SIZE;
+        assert!(hunkbytes.is_multiple_of(cd::FRAME_SIZE));
         Self {
-            buffer: vec![0; num_frames * cd::MAX_SUBCODE_DATA],
+            frames: hunkbytes / cd::FRAME_SIZE,
             inflate: Inflate::new(),
         }
     }
-}
 
-impl Decompress for CdFlac {
-    fn decompress(&mut self, src: &[u8], dest: &mut [u8]) -> io::Result<()> {
+    // buffer receives subcode of all frames, samples are reused between flac blocks
+    fn decompress_into(
+        &mut self,
+        src: &[u8],
+        dest: &mut [u8],
+        buffer: &mut [u8],
+        samples_buffer: &mut Vec<i32>,
+    ) -> io::Result<()> {
         let mut src = src;
         let frames = dest.len() / cd::FRAME_SIZE;
 
@@ -307,33 +628,410 @@ impl Decompress for CdFlac {
         let mut samples = frames * Self::SAMPLE_PER_FRAME;
         let mut sample_start = 0;
         while samples > 0 {
-            let buffer = vec![0; 2 * samples]; // 2 channels
-            let (block, pos) = flac_decompress(src, buffer)?;
+            let (block, pos) = flac_decompress(src, std::mem::take(samples_buffer))?;
+            if block.channels() != 2 {
+                return Err(invalid_data(format!(
+                    "flac: expected stereo, but got {} channel samples",
+                    block.channels()
+                )));
+            }
             // in decoded block all samples are packed together. reassemble frames
             let decoded_samples = block.duration() as usize;
+            if decoded_samples > samples {
+                return Err(invalid_data(format!(
+                    "flac: decoded {} samples, but only {} left in hunk",
+                    decoded_samples, samples
+                )));
+            }
             for (i, (sl, sr)) in block.stereo_samples().enumerate() {
                 let i = sample_start + i;
                 let frame = i / Self::SAMPLE_PER_FRAME;
                 let frame_offs = frame * cd::FRAME_SIZE;
                 let sample_offs = frame_offs + (i % Self::SAMPLE_PER_FRAME) * Flac::SAMPLE_SIZE;
-                write_be16(&mut dest[sample_offs + 0..sample_offs + 2], sl as u16);
-                write_be16(&mut dest[sample_offs + 2..sample_offs + 4], sr as u16);
+                dest.put_be16(sample_offs, sl as u16)?;
+                dest.put_be16(sample_offs + 2, sr as u16)?;
             }
             samples -= decoded_samples;
             src = &src[pos..];
             sample_start += decoded_samples;
+            *samples_buffer = block.into_buffer();
         }
         // then decompress subcode data
-        self.inflate.decompress(src, &mut self.buffer)?;
+        self.inflate.decompress(src, buffer)?;
         for frame in 0..frames {
             let frame_offs = frame * cd::FRAME_SIZE;
             let subcode_offs = frame_offs + cd::MAX_SECTOR_DATA;
             let subcode = &mut dest[subcode_offs..subcode_offs + cd::MAX_SUBCODE_DATA];
             copy_from(
                 subcode,
-                &self.buffer[frame * cd::MAX_SUBCODE_DATA..(frame + 1) * cd::MAX_SUBCODE_DATA],
+                &buffer[frame * cd::MAX_SUBCODE_DATA..(frame + 1) * cd::MAX_SUBCODE_DATA],
             );
         }
         Ok(())
     }
 }
+
+impl Decompress for CdFlac {
+    fn memory_usage(&self) -> usize {
+        self.inflate.memory_usage()
+    }
+
+    // subcode bytes and i32 per 16 bit sample
+    fn pool_usage(&self) -> usize {
+        self.frames * (cd::MAX_SUBCODE_DATA + cd::MAX_SECTOR_DATA * 2)
+    }
+
+    fn decompress(&mut self, src: &[u8], dest: &mut [u8]) -> io::Result<()> {
+        self.decompress_pooled(src, dest, &mut BufferPool::new())
+    }
+
+    fn take_fallback(&mut self) -> Option<String> {
+        self.inflate
+            .take_fallback()
+            .map(|reason| format!("subcode: {}", reason))
+    }
+
+    fn decompress_pooled(
+        &mut self,
+        src: &[u8],
+        dest: &mut [u8],
+        pool: &mut BufferPool,
+    ) -> io::Result<()> {
+        let frames = dest.len() / cd::FRAME_SIZE;
+        let mut buffer = pool.take(frames * cd::MAX_SUBCODE_DATA);
+        let mut samples = pool.take_samples();
+        let result = self.decompress_into(src, dest, &mut buffer, &mut samples);
+        pool.give(buffer);
+        pool.give_samples(samples);
+        result
+    }
+}
+
+// Known answer vector: compressed hunk which decompresses to seed repeated
+// over hunkbytes. Vectors are made by MAME compatible encoders, so the check
+// also covers FFI backends and feature sets of the build.
+struct KnownAnswer {
+    tag: u32,
+    hunkbytes: u32,
+    seed: &'static [u8],
+    data: &'static [u8],
+}
+
+const SEED: &[u8] = b"chd-rs known answer test\n";
+// constant 16 bit stereo samples, so flac frames stay short
+const FLAC_SEED: &[u8] = b"chd!";
+
+const KNOWN_ANSWERS: &[KnownAnswer] = &[
+    KnownAnswer {
+        tag: CHD_CODEC_HUFF,
+        hunkbytes: 512,
+        seed: SEED,
+        data: &[
+            0x60, 0x81, 0x14, 0xf3, 0xc0, 0x06, 0x78, 0x58, 0x4f, 0x02, 0x19, 0xe5, 0x23, 0x15,
+            0xdd, 0xc1, 0xd0, 0x76, 0x7b, 0xf0, 0xd0, 0x82, 0xfe, 0xae, 0x69, 0xd4, 0x5b, 0xc9,
+            0xbd, 0x83, 0xf0, 0x01, 0xa1, 0x05, 0xfd, 0x5c, 0xd3, 0xa8, 0xb7, 0x93, 0x7b, 0x07,
+            0xe0, 0x03, 0x42, 0x0b, 0xfa, 0xb9, 0xa7, 0x51, 0x6f, 0x26, 0xf6, 0x0f, 0xc0, 0x06,
+            0x84, 0x17, 0xf5, 0x73, 0x4e, 0xa2, 0xde, 0x4d, 0xec, 0x1f, 0x80, 0x0d, 0x08, 0x2f,
+            0xea, 0xe6, 0x9d, 0x45, 0xbc, 0x9b, 0xd8, 0x3f, 0x00, 0x1a, 0x10, 0x5f, 0xd5, 0xcd,
+            0x3a, 0x8b, 0x79, 0x37, 0xb0, 0x7e, 0x00, 0x34, 0x20, 0xbf, 0xab, 0x9a, 0x75, 0x16,
+            0xf2, 0x6f, 0x60, 0xfc, 0x00, 0x68, 0x41, 0x7f, 0x57, 0x34, 0xea, 0x2d, 0xe4, 0xde,
+            0xc1, 0xf8, 0x00, 0xd0, 0x82, 0xfe, 0xae, 0x69, 0xd4, 0x5b, 0xc9, 0xbd, 0x83, 0xf0,
+            0x01, 0xa1, 0x05, 0xfd, 0x5c, 0xd3, 0xa8, 0xb7, 0x93, 0x7b, 0x07, 0xe0, 0x03, 0x42,
+            0x0b, 0xfa, 0xb9, 0xa7, 0x51, 0x6f, 0x26, 0xf6, 0x0f, 0xc0, 0x06, 0x84, 0x17, 0xf5,
+            0x73, 0x4e, 0xa2, 0xde, 0x4d, 0xec, 0x1f, 0x80, 0x0d, 0x08, 0x2f, 0xea, 0xe6, 0x9d,
+            0x45, 0xbc, 0x9b, 0xd8, 0x3f, 0x00, 0x1a, 0x10, 0x5f, 0xd5, 0xcd, 0x3a, 0x8b, 0x79,
+            0x37, 0xb0, 0x7e, 0x00, 0x34, 0x20, 0xbf, 0xab, 0x9a, 0x75, 0x16, 0xf2, 0x6f, 0x60,
+            0xfc, 0x00, 0x68, 0x41, 0x7f, 0x57, 0x34, 0xea, 0x2d, 0xe4, 0xde, 0xc1, 0xf8, 0x00,
+            0xd0, 0x82, 0xfe, 0xae, 0x69, 0xd4, 0x5b, 0xc9, 0xbd, 0x83, 0xf0, 0x01, 0xa1, 0x05,
+            0xfd, 0x5c, 0xd3, 0xa8, 0xb7, 0x93, 0x7b, 0x07, 0xe0, 0x03, 0x42, 0x0b, 0xfa, 0xb9,
+            0xa7, 0x51, 0x6f, 0x26, 0xf6, 0x0f, 0xc0, 0x06, 0x84, 0x17, 0xf5, 0x73, 0x4e, 0xa2,
+            0xde, 0x4d, 0xec, 0x1f, 0x80, 0x0d, 0x08, 0x2f, 0xea, 0xe6, 0x9c,
+        ],
+    },
+    KnownAnswer {
+        tag: CHD_CODEC_FLAC,
+        hunkbytes: 512,
+        seed: FLAC_SEED,
+        data: &[
+            0x42, 0xff, 0xf8, 0x70, 0x18, 0x00, 0x00, 0x7f, 0x07, 0x00, 0x63, 0x68, 0x00, 0x64,
+            0x21, 0xdd, 0xd2,
+        ],
+    },
+    KnownAnswer {
+        tag: CHD_CODEC_LZMA,
+        hunkbytes: 512,
+        seed: SEED,
+        data: &[
+            0x00, 0x31, 0x9a, 0x08, 0xac, 0x49, 0xeb, 0xdc, 0x2c, 0x46, 0x5e, 0x14, 0x88, 0x2c,
+            0x79, 0xcc, 0x8a, 0xd9, 0xe0, 0x10, 0xc8, 0x97, 0xa4, 0xbd, 0x7b, 0x06, 0x36, 0xa1,
+            0x73, 0x2e, 0xf7, 0x23, 0x0d, 0x00, 0x00,
+        ],
+    },
+    KnownAnswer {
+        tag: CHD_CODEC_ZLIB,
+        hunkbytes: 512,
+        seed: SEED,
+        data: &[
+            0x4b, 0xce, 0x48, 0xd1, 0x2d, 0x2a, 0x56, 0xc8, 0xce, 0xcb, 0x2f, 0xcf, 0x53, 0x48,
+            0xcc, 0x2b, 0x2e, 0x4f, 0x2d, 0x52, 0x28, 0x49, 0x2d, 0x2e, 0xe1, 0x4a, 0x1e, 0x95,
+            0x18, 0x09, 0x12, 0x00,
+        ],
+    },
+    KnownAnswer {
+        tag: CHD_CODEC_CD_FLAC,
+        hunkbytes: 2448,
+        seed: FLAC_SEED,
+        data: &[
+            0xff, 0xf8, 0x70, 0x18, 0x00, 0x02, 0x4b, 0xa1, 0x00, 0x63, 0x68, 0x00, 0x64, 0x21,
+            0xbd, 0xc3, 0x4b, 0xce, 0x48, 0x51, 0x4c, 0xa6, 0x21, 0x06, 0x00,
+        ],
+    },
+    KnownAnswer {
+        tag: CHD_CODEC_CD_LZMA,
+        hunkbytes: 2448,
+        seed: SEED,
+        data: &[
+            0x00, 0x00, 0x2e, 0x00, 0x31, 0x9a, 0x08, 0xac, 0x49, 0xeb, 0xdc, 0x2c, 0x46, 0x5e,
+            0x14, 0x88, 0x2c, 0x79, 0xcc, 0x8a, 0xd9, 0xe0, 0x10, 0xc8, 0x97, 0xa4, 0xbd, 0x7b,
+            0x06, 0x36, 0xa1, 0x73, 0x2e, 0xfa, 0x94, 0x29, 0x0d, 0xff, 0x80, 0x07, 0x97, 0xc7,
+            0xab, 0x4f, 0xb0, 0x8a, 0x35, 0x14, 0x00, 0x4b, 0xd1, 0x2d, 0x2a, 0x56, 0xc8, 0xce,
+            0xcb, 0x2f, 0xcf, 0x53, 0x48, 0xcc, 0x2b, 0x2e, 0x4f, 0x2d, 0x52, 0x28, 0x49, 0x2d,
+            0x2e, 0xe1, 0x4a, 0xce, 0x48, 0xa1, 0x8e, 0x04, 0x00,
+        ],
+    },
+    KnownAnswer {
+        tag: CHD_CODEC_CD_ZLIB,
+        hunkbytes: 2448,
+        seed: SEED,
+        data: &[
+            0x00, 0x00, 0x30, 0x4b, 0xce, 0x48, 0xd1, 0x2d, 0x2a, 0x56, 0xc8, 0xce, 0xcb, 0x2f,
+            0xcf, 0x53, 0x48, 0xcc, 0x2b, 0x2e, 0x4f, 0x2d, 0x52, 0x28, 0x49, 0x2d, 0x2e, 0xe1,
+            0x4a, 0x1e, 0x95, 0x18, 0x95, 0x18, 0x95, 0x18, 0x95, 0x18, 0x95, 0x18, 0x95, 0x18,
+            0x95, 0x18, 0x95, 0x18, 0x95, 0x00, 0x49, 0x00, 0x00, 0x4b, 0xd1, 0x2d, 0x2a, 0x56,
+            0xc8, 0xce, 0xcb, 0x2f, 0xcf, 0x53, 0x48, 0xcc, 0x2b, 0x2e, 0x4f, 0x2d, 0x52, 0x28,
+            0x49, 0x2d, 0x2e, 0xe1, 0x4a, 0xce, 0x48, 0xa1, 0x8e, 0x04, 0x00,
+        ],
+    },
+];
+
+// Decompress known answer vector by codec of this build
+pub fn known_answer_test(tag: u32) -> io::Result<()> {
+    let vector = KNOWN_ANSWERS
+        .iter()
+        .find(|v| v.tag == tag)
+        .ok_or(invalid_data(format!(
+            "codec {} has no known answer vector",
+            tag_string(tag)
+        )))?;
+    if !SUPPORTED.contains(&tag) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("codec {} is not supported by this build", tag_string(tag)),
+        ));
+    }
+    let mut codec = create(vector.hunkbytes, tag)?
+        .ok_or(invalid_data_str("no codec for known answer vector"))?;
+    let mut hunk = vec![0; vector.hunkbytes as usize];
+    codec.decompress(vector.data, &mut hunk)?;
+    let expected = vector.seed.iter().cycle();
+    match hunk.iter().zip(expected).position(|(a, b)| a != b) {
+        None => Ok(()),
+        Some(offset) => Err(invalid_data(format!(
+            "codec {} known answer mismatch at offset {}",
+            tag_string(tag),
+            offset
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // single stored deflate block
+    fn deflate_stored(data: &[u8]) -> Vec<u8> {
+        let len = data.len() as u16;
+        let mut raw = vec![0x01];
+        raw.extend_from_slice(&len.to_le_bytes());
+        raw.extend_from_slice(&(!len).to_le_bytes());
+        raw.extend_from_slice(data);
+        raw
+    }
+
+    fn zlib_stored(data: &[u8]) -> Vec<u8> {
+        let (mut a, mut b) = (1u32, 0u32);
+        for byte in data {
+            a = (a + *byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        let mut zlib = vec![0x78, 0x01];
+        zlib.extend_from_slice(&deflate_stored(data));
+        zlib.extend_from_slice(&((b << 16) | a).to_be_bytes());
+        zlib
+    }
+
+    #[test]
+    fn test_check_supported() {
+        assert!(check_supported(&[CHD_CODEC_ZLIB, CHD_CODEC_HUFF, 0, 0]).is_ok());
+        let err = check_supported(&[CHD_CODEC_ZLIB, 0x78787878, 0, 0x79797979]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(
+            err.to_string(),
+            "unsupported codecs: xxxx (78787878), yyyy (79797979)"
+        );
+    }
+
+    #[test]
+    fn test_known_answers() {
+        for &tag in SUPPORTED {
+            known_answer_test(tag).unwrap();
+        }
+        assert!(known_answer_test(0x78787878).is_err());
+    }
+
+    #[test]
+    fn test_inflate() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let mut inflate = Inflate::new();
+        let mut dest = vec![0; data.len()];
+        inflate
+            .decompress(&deflate_stored(&data), &mut dest)
+            .unwrap();
+        assert_eq!(dest, data);
+        dest.fill(0);
+        inflate.decompress(&zlib_stored(&data), &mut dest).unwrap();
+        assert_eq!(dest, data);
+
+        // short and long output
+        assert!(inflate
+            .decompress(&deflate_stored(&data[1..]), &mut dest)
+            .is_err());
+        assert!(inflate
+            .decompress(&zlib_stored(&data), &mut dest[1..])
+            .is_err());
+        // bad zlib checksum
+        let mut zlib = zlib_stored(&data);
+        *zlib.last_mut().unwrap() ^= 1;
+        assert!(inflate.decompress(&zlib, &mut dest).is_err());
+        assert!(!Inflate::is_zlib(&deflate_stored(&data)));
+        assert!(inflate.take_fallback().is_none());
+
+        // raw stream starting with non-final stored block looks like zlib header
+        let mut raw = vec![0x78, 0x01, 0x00, 0xfe, 0xff, data[0]];
+        raw.extend_from_slice(&deflate_stored(&data[1..]));
+        assert!(Inflate::is_zlib(&raw));
+        dest.fill(0);
+        inflate.decompress(&raw, &mut dest).unwrap();
+        assert_eq!(dest, data);
+        assert!(inflate.take_fallback().unwrap().contains("raw deflate"));
+        assert!(inflate.take_fallback().is_none());
+    }
+
+    // single flac frame of 16 bit verbatim subframes, one per channel
+    fn flac_frame(bps_code: u8, channels: &[&[i16]]) -> Vec<u8> {
+        let n = channels[0].len() as u16;
+        let chan = (channels.len() as u8 - 1) << 4;
+        let mut frame = vec![0xff, 0xf8, 0x70, chan | (bps_code << 1), 0];
+        frame.extend_from_slice(&(n - 1).to_be_bytes());
+        let crc8 = frame.iter().fold(0u8, |crc, byte| {
+            (0..8).fold(crc ^ byte, |c, _| match c & 0x80 {
+                0 => c << 1,
+                _ => (c << 1) ^ 0x07,
+            })
+        });
+        frame.push(crc8);
+        for samples in channels {
+            frame.push(0x02); // verbatim
+            for sample in samples.iter() {
+                frame.extend_from_slice(&sample.to_be_bytes());
+            }
+        }
+        let crc16 = frame.iter().fold(0u16, |crc, byte| {
+            (0..8).fold(crc ^ ((*byte as u16) << 8), |c, _| match c & 0x8000 {
+                0 => c << 1,
+                _ => (c << 1) ^ 0x8005,
+            })
+        });
+        frame.extend_from_slice(&crc16.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_flac() {
+        let mut flac = Flac::new(16);
+        let left: &[i16] = &[1, -2, 3, -4];
+        let right: &[i16] = &[0x1234, 5, 6, 7];
+
+        // stereo, big endian
+        let mut hunk = vec![b'B'];
+        hunk.extend(flac_frame(0b100, &[left, right]));
+        let mut dest = vec![0; 16];
+        flac.decompress(&hunk, &mut dest).unwrap();
+        assert_eq!(dest[..4], [0, 1, 0x12, 0x34]);
+        assert_eq!(dest[4..8], [0xff, 0xfe, 0, 5]);
+
+        // mono, little endian, split into two blocks
+        let mut hunk = vec![b'L'];
+        hunk.extend(flac_frame(0b100, &[left]));
+        hunk.extend(flac_frame(0b100, &[right]));
+        flac.decompress(&hunk, &mut dest).unwrap();
+        assert_eq!(dest[..4], [1, 0, 0xfe, 0xff]);
+        assert_eq!(dest[8..10], [0x34, 0x12]);
+
+        // too many samples for the hunk
+        assert!(flac.decompress(&hunk, &mut dest[..14]).is_err());
+
+        // 8 and 24 bit samples
+        for code in [0b001, 0b110] {
+            let mut hunk = vec![b'B'];
+            hunk.extend(flac_frame(code, &[left]));
+            let err = flac.decompress(&hunk, &mut dest).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        }
+    }
+
+    #[test]
+    fn test_cd_flac() {
+        let hunkbytes = 2 * cd::FRAME_SIZE;
+        let samples = 2 * CdFlac::SAMPLE_PER_FRAME;
+        let left: Vec<i16> = (0..samples as i16).collect();
+        let right: Vec<i16> = left.iter().map(|x| !x).collect();
+        let subcode: Vec<u8> = (0..2 * cd::MAX_SUBCODE_DATA as u8).collect();
+
+        // samples are split into blocks not aligned to frames
+        let split = 1000;
+        let mut hunk = flac_frame(0b100, &[&left[..split], &right[..split]]);
+        hunk.extend(flac_frame(0b100, &[&left[split..], &right[split..]]));
+        hunk.extend(deflate_stored(&subcode));
+
+        let mut cd_flac = CdFlac::new(hunkbytes as u32);
+        let mut pool = BufferPool::new();
+        let mut dest = vec![0; hunkbytes];
+        cd_flac
+            .decompress_pooled(&hunk, &mut dest, &mut pool)
+            .unwrap();
+        let usage = pool.memory_usage();
+        assert!(usage <= cd_flac.pool_usage());
+        cd_flac
+            .decompress_pooled(&hunk, &mut dest, &mut pool)
+            .unwrap();
+        assert_eq!(pool.memory_usage(), usage);
+        let frame = &dest[cd::FRAME_SIZE..];
+        let i = CdFlac::SAMPLE_PER_FRAME as i16;
+        assert_eq!(
+            frame[..4],
+            [(i >> 8) as u8, i as u8, !(i >> 8) as u8, !i as u8]
+        );
+        assert_eq!(
+            frame[cd::MAX_SECTOR_DATA..],
+            subcode[cd::MAX_SUBCODE_DATA..]
+        );
+
+        // more samples than hunk has
+        let mut hunk = flac_frame(0b100, &[&left, &right]);
+        hunk.extend(flac_frame(0b100, &[&left[..1], &right[..1]]));
+        assert!(cd_flac
+            .decompress(&hunk, &mut dest[..cd::FRAME_SIZE])
+            .is_err());
+    }
+}
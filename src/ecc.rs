@@ -1,16 +1,21 @@
-use crate::cd::SYNC_NUM_BYTES;
+// CD-ROM ECC of mode 1 and mode 2 form 1 raw 2352 byte sectors. Layout of such
+// sector: sync, header of BCD address and mode, user data, EDC, and ECC made of
+// 86 P columns and 52 Q diagonals, two parity bytes each.
+use crate::cd::{msf_bcd, MAX_SECTOR_DATA, SYNC_HEADER, SYNC_NUM_BYTES};
 
+// offset of BCD minute, second and frame within sector
+pub const HEADER_OFFSET: usize = SYNC_NUM_BYTES;
 // offset within sector
-const MODE_OFFSET: usize = 0x00f;
+pub const MODE_OFFSET: usize = 0x00f;
 
-/// The ECC p offset within sector
-const P_OFFSET: usize = 0x81c;
-const P_NUM_BYTES: usize = 86;
+// The ECC p offset within sector
+pub const P_OFFSET: usize = 0x81c;
+pub const P_NUM_BYTES: usize = 86;
 const P_COMP: usize = 24;
 
 // The ECC q offset within sector
-const Q_OFFSET: usize = P_OFFSET + 2 * P_NUM_BYTES;
-const Q_NUM_BYTES: usize = 52;
+pub const Q_OFFSET: usize = P_OFFSET + 2 * P_NUM_BYTES;
+pub const Q_NUM_BYTES: usize = 52;
 const Q_COMP: usize = 43;
 
 // ECC lookup tables pre-calculated tables for ECC data calcs
@@ -732,9 +737,9 @@ fn source_byte(sector: &[u8], offset: u16) -> u8 {
 fn compute(sector: &[u8], row: &[u16]) -> (u8, u8) {
     let mut val1 = 0u8;
     let mut val2 = 0u8;
-    for component in 0..row.len() {
-        val1 ^= source_byte(sector, row[component]);
-        val2 ^= source_byte(sector, row[component]);
+    for component in row {
+        val1 ^= source_byte(sector, *component);
+        val2 ^= source_byte(sector, *component);
         val1 = LOW[val1 as usize];
     }
     val1 = HIGH[(LOW[val1 as usize] ^ val2) as usize];
@@ -742,6 +747,7 @@ fn compute(sector: &[u8], row: &[u16]) -> (u8, u8) {
     (val1, val2)
 }
 
+// Fill ECC of sector of at least MAX_SECTOR_DATA bytes
 pub fn generate(sector: &mut [u8]) {
     // first verify P bytes
     for byte in 0..P_NUM_BYTES {
@@ -756,3 +762,100 @@ pub fn generate(sector: &mut [u8]) {
         sector[Q_OFFSET + byte + Q_NUM_BYTES] = val2;
     }
 }
+
+// P columns and Q diagonals with parity not matching sector data
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Failures {
+    pub p: Vec<usize>, // column numbers, 0..P_NUM_BYTES
+    pub q: Vec<usize>, // diagonal numbers, 0..Q_NUM_BYTES
+}
+
+impl Failures {
+    pub fn is_empty(&self) -> bool {
+        self.p.is_empty() && self.q.is_empty()
+    }
+}
+
+// Check ECC of sector of at least MAX_SECTOR_DATA bytes
+pub fn verify(sector: &[u8]) -> Failures {
+    let check = |offsets: &[u16], offset: usize, num_bytes: usize| {
+        compute(sector, offsets) != (sector[offset], sector[offset + num_bytes])
+    };
+    Failures {
+        p: (0..P_NUM_BYTES)
+            .filter(|&byte| check(&P_OFFSETS[byte], P_OFFSET + byte, P_NUM_BYTES))
+            .collect(),
+        q: (0..Q_NUM_BYTES)
+            .filter(|&byte| check(&Q_OFFSETS[byte], Q_OFFSET + byte, Q_NUM_BYTES))
+            .collect(),
+    }
+}
+
+// Write sync, address and mode of sector
+pub fn set_header(sector: &mut [u8], lba: u32, mode: u8) {
+    sector[..SYNC_NUM_BYTES].copy_from_slice(&SYNC_HEADER);
+    sector[HEADER_OFFSET..MODE_OFFSET].copy_from_slice(&msf_bcd(lba));
+    sector[MODE_OFFSET] = mode;
+}
+
+// Address and mode of sector, None if sector has no sync or address isn't BCD
+pub fn header(sector: &[u8]) -> Option<(u32, u8)> {
+    if sector.len() < MAX_SECTOR_DATA || sector[..SYNC_NUM_BYTES] != SYNC_HEADER {
+        return None;
+    }
+    let bcd = |v: u8| match (v >> 4, v & 0xf) {
+        (hi, lo) if hi < 10 && lo < 10 => Some((hi * 10 + lo) as u32),
+        _ => None,
+    };
+    let msf = &sector[HEADER_OFFSET..MODE_OFFSET];
+    let (minute, second, frame) = (bcd(msf[0])?, bcd(msf[1])?, bcd(msf[2])?);
+    if second >= 60 || frame >= 75 {
+        return None;
+    }
+    let lba = (minute * 60 + second) * 75 + frame;
+    Some((lba.checked_sub(150)?, sector[MODE_OFFSET]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ecc() {
+        let mut sector: Vec<u8> = (0..MAX_SECTOR_DATA).map(|i| (i * 7) as u8).collect();
+        set_header(&mut sector, 4350 - 150 + 74, 1);
+        assert_eq!(&sector[HEADER_OFFSET..=MODE_OFFSET], &[0x00, 0x58, 0x74, 1]);
+        assert_eq!(header(&sector), Some((4350 - 150 + 74, 1)));
+        assert!(!verify(&sector).is_empty());
+        generate(&mut sector);
+        assert!(verify(&sector).is_empty());
+
+        // single user data byte is covered by one column and one diagonal
+        sector[HEADER_OFFSET + 4 + 100] ^= 0x40;
+        let failures = verify(&sector);
+        assert_eq!(failures.p, vec![(4 + 100) % P_NUM_BYTES]);
+        assert_eq!(failures.q.len(), 1);
+        // damaged parity byte
+        sector[HEADER_OFFSET + 4 + 100] ^= 0x40;
+        sector[Q_OFFSET + Q_NUM_BYTES + 3] ^= 1;
+        assert_eq!(
+            verify(&sector),
+            Failures {
+                p: vec![],
+                q: vec![3]
+            }
+        );
+
+        // mode 2 ECC doesn't cover the header
+        set_header(&mut sector, 0, 2);
+        generate(&mut sector);
+        set_header(&mut sector, 1000, 2);
+        assert!(verify(&sector).is_empty());
+
+        sector[MODE_OFFSET - 1] = 0x7a;
+        assert_eq!(header(&sector), None);
+        sector[0] = 1;
+        assert_eq!(header(&sector), None);
+        assert_eq!(header(&sector[..16]), None);
+    }
+}
@@ -0,0 +1,305 @@
+// FLAC frame encoder of flac and cdfl codecs. Chd stores bare frames of 16 bit
+// stereo samples without stream header, so each frame has explicit block size and
+// no sample rate. Subframes are constant, verbatim or fixed predictor ones with
+// rice coded residual, channels are stored independently or as one of stereo
+// decorrelation pairs, whichever is shorter.
+use crate::bitstream::BitWriter;
+
+const BITS_PER_SAMPLE: usize = 16;
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_PARTITION_ORDER: usize = 8;
+const MAX_RICE_PARAM: usize = 14; // 15 is escape code
+
+// Channel assignment of frame header
+const INDEPENDENT: u32 = 0b0001; // 2 channels
+const LEFT_SIDE: u32 = 0b1000;
+const RIGHT_SIDE: u32 = 0b1001;
+const MID_SIDE: u32 = 0b1010;
+
+#[derive(Clone, Copy)]
+enum Subframe {
+    Constant,
+    Verbatim,
+    Fixed(usize, usize), // predictor order, partition order
+}
+
+// Subframe of one channel signal with its size in bits
+struct Plan {
+    subframe: Subframe,
+    bits: usize,
+}
+
+// Residual of fixed predictor of order
+fn residual(samples: &[i32], order: usize) -> impl Iterator<Item = i64> + '_ {
+    (order..samples.len()).map(move |i| {
+        let s = |k: usize| samples[i - k] as i64;
+        match order {
+            0 => s(0),
+            1 => s(0) - s(1),
+            2 => s(0) - 2 * s(1) + s(2),
+            3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+            _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+        }
+    })
+}
+
+// residual folded to unsigned for rice coding
+fn fold(r: i64) -> u64 {
+    ((r << 1) ^ (r >> 63)) as u64
+}
+
+// Rice parameter and bits of partition of count values summing to sum
+fn rice_param(count: usize, sum: u64) -> (usize, usize) {
+    (0..=MAX_RICE_PARAM)
+        .map(|k| (k, count * (k + 1) + (sum >> k) as usize))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap_or((0, 0))
+}
+
+// Highest usable partition order: each partition needs the same number of
+// samples, the first one at least order of them
+fn max_partition_order(blocksize: usize, order: usize) -> usize {
+    let mut p = 0;
+    while p < MAX_PARTITION_ORDER
+        && blocksize.is_multiple_of(1 << (p + 1))
+        && blocksize >> (p + 1) > order
+    {
+        p += 1;
+    }
+    p
+}
+
+// Bits of the best partitioning of residual, and its partition order
+fn plan_residual(samples: &[i32], order: usize) -> (usize, usize) {
+    let blocksize = samples.len();
+    let max_order = max_partition_order(blocksize, order);
+    // sums of the finest partitions, merged for lower orders
+    let parts = 1 << max_order;
+    let mut sums = vec![0u64; parts];
+    for (i, r) in residual(samples, order).enumerate() {
+        sums[(i + order) / (blocksize >> max_order)] += fold(r);
+    }
+    let mut best = (usize::MAX, 0);
+    for p in (0..=max_order).rev() {
+        let mut bits = 6; // coding method and partition order
+        for (i, sum) in sums.iter().enumerate() {
+            let count = (blocksize >> p) - if i == 0 { order } else { 0 };
+            bits += 4 + rice_param(count, *sum).1;
+        }
+        if bits < best.0 {
+            best = (bits, p);
+        }
+        sums = sums.chunks(2).map(|pair| pair.iter().sum()).collect();
+    }
+    best
+}
+
+fn plan(samples: &[i32], bps: usize) -> Plan {
+    if samples.iter().all(|&s| s == samples[0]) {
+        return Plan {
+            subframe: Subframe::Constant,
+            bits: 8 + bps,
+        };
+    }
+    let mut best = Plan {
+        subframe: Subframe::Verbatim,
+        bits: 8 + bps * samples.len(),
+    };
+    for order in 0..=MAX_FIXED_ORDER.min(samples.len() - 1) {
+        let (bits, partition_order) = plan_residual(samples, order);
+        let bits = 8 + bps * order + bits;
+        if bits < best.bits {
+            best = Plan {
+                subframe: Subframe::Fixed(order, partition_order),
+                bits,
+            };
+        }
+    }
+    best
+}
+
+fn write_unary(stream: &mut BitWriter, mut zeroes: u64) {
+    while zeroes >= 31 {
+        stream.write(0, 31);
+        zeroes -= 31;
+    }
+    stream.write(1, zeroes as usize + 1);
+}
+
+fn write_subframe(stream: &mut BitWriter, samples: &[i32], bps: usize, subframe: Subframe) {
+    match subframe {
+        Subframe::Constant => {
+            stream.write(0b0000_0000, 8);
+            stream.write(samples[0] as u32, bps);
+        }
+        Subframe::Verbatim => {
+            stream.write(0b0000_0010, 8);
+            for &s in samples {
+                stream.write(s as u32, bps);
+            }
+        }
+        Subframe::Fixed(order, p) => {
+            stream.write(0b0001_0000 | (order as u32) << 1, 8);
+            for &s in &samples[..order] {
+                stream.write(s as u32, bps);
+            }
+            // 4 bit rice parameters
+            stream.write(0b00, 2);
+            stream.write(p as u32, 4);
+            let residual: Vec<u64> = residual(samples, order).map(fold).collect();
+            let mut start = 0;
+            for i in 0..1 << p {
+                let count = (samples.len() >> p) - if i == 0 { order } else { 0 };
+                let part = &residual[start..start + count];
+                let (k, _) = rice_param(count, part.iter().sum());
+                stream.write(k as u32, 4);
+                for &u in part {
+                    write_unary(stream, u >> k);
+                    stream.write(u as u32, k);
+                }
+                start += count;
+            }
+        }
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |c, _| match c & 0x80 {
+            0 => c << 1,
+            _ => (c << 1) ^ 0x07,
+        })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |c, _| match c & 0x8000 {
+            0 => c << 1,
+            _ => (c << 1) ^ 0x8005,
+        })
+    })
+}
+
+// Frame number in UTF-8 like coding
+fn write_frame_number(stream: &mut BitWriter, number: u32) {
+    if number < 0x80 {
+        stream.write(number, 8);
+        return;
+    }
+    let mut tail = 1;
+    while number >> (6 * tail) >= 1 << (6 - tail) {
+        tail += 1;
+    }
+    let lead = (0xff00u32 >> (tail + 1)) as u8 as u32;
+    stream.write(lead | number >> (6 * tail), 8);
+    for i in (0..tail).rev() {
+        stream.write(0x80 | ((number >> (6 * i)) & 0x3f), 8);
+    }
+}
+
+// Append frame `number` of stereo 16 bit samples, at most 65536 of them
+pub fn encode_frame(left: &[i32], right: &[i32], number: u32, dest: &mut Vec<u8>) {
+    let blocksize = left.len();
+    assert!(blocksize > 0 && blocksize <= 1 << 16 && right.len() == blocksize);
+    let side: Vec<i32> = left.iter().zip(right).map(|(l, r)| l - r).collect();
+    let mid: Vec<i32> = left.iter().zip(right).map(|(l, r)| (l + r) >> 1).collect();
+    let bps = BITS_PER_SAMPLE;
+    let plans = [
+        plan(left, bps),
+        plan(right, bps),
+        plan(&side, bps + 1),
+        plan(&mid, bps),
+    ];
+    // channel signals as stored: (assignment, first, second)
+    let choices = [
+        (INDEPENDENT, 0, 1),
+        (LEFT_SIDE, 0, 2),
+        (RIGHT_SIDE, 2, 1),
+        (MID_SIDE, 3, 2),
+    ];
+    let &(assignment, first, second) = choices
+        .iter()
+        .min_by_key(|(_, a, b)| plans[*a].bits + plans[*b].bits)
+        .unwrap();
+    let signal = |i: usize| match i {
+        0 => (left, bps),
+        1 => (right, bps),
+        2 => (&side[..], bps + 1),
+        _ => (&mid[..], bps),
+    };
+
+    let mut header = BitWriter::new();
+    header.write(0xfff8, 16); // sync, fixed block size
+    header.write(0b0111, 4); // block size - 1 follows as 16 bits
+    header.write(0b0000, 4); // sample rate isn't stored
+    header.write(assignment, 4);
+    header.write(0b1000, 4); // 16 bits per sample
+    write_frame_number(&mut header, number);
+    header.write(blocksize as u32 - 1, 16);
+    let start = dest.len();
+    dest.extend(header.finish());
+    dest.push(crc8(&dest[start..]));
+
+    let mut stream = BitWriter::new();
+    for i in [first, second] {
+        let (samples, bps) = signal(i);
+        write_subframe(&mut stream, samples, bps, plans[i].subframe);
+    }
+    dest.extend(stream.finish());
+    let crc = crc16(&dest[start..]);
+    dest.extend_from_slice(&crc.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claxon::frame::FrameReader;
+    use std::io::Cursor;
+
+    fn decode(data: &[u8]) -> Vec<(i32, i32)> {
+        let mut reader = FrameReader::new(Cursor::new(data));
+        let mut samples = Vec::new();
+        while let Some(block) = reader.read_next_or_eof(Vec::new()).unwrap() {
+            samples.extend(block.stereo_samples());
+        }
+        samples
+    }
+
+    #[test]
+    fn test_encode_frame() {
+        let n = 1176;
+        let sine = |i: usize, f: f64| ((i as f64 * f).sin() * 20000.0) as i32;
+        let signals: Vec<(Vec<i32>, Vec<i32>)> = vec![
+            // silence, correlated channels, noise, extremes
+            (vec![0; n], vec![0; n]),
+            (
+                (0..n).map(|i| sine(i, 0.01)).collect(),
+                (0..n).map(|i| sine(i, 0.01) / 2 + 7).collect(),
+            ),
+            (
+                (0..n)
+                    .map(|i| ((i * 2654435761) >> 7) as i16 as i32)
+                    .collect(),
+                (0..n).map(|i| ((i * 40503) >> 3) as i16 as i32).collect(),
+            ),
+            (
+                (0..n).map(|i| [-32768, 32767][i % 2]).collect(),
+                (0..n).map(|i| [32767, -32768][i % 3 % 2]).collect(),
+            ),
+            (vec![5], vec![-5]),
+        ];
+        for (number, (left, right)) in signals.iter().enumerate() {
+            let mut data = Vec::new();
+            encode_frame(left, right, number as u32 * 100, &mut data);
+            let expected: Vec<(i32, i32)> = left.iter().copied().zip(right.clone()).collect();
+            assert_eq!(decode(&data), expected);
+            if number < 2 {
+                assert!(data.len() < n);
+            }
+        }
+        let mut number = BitWriter::new();
+        write_frame_number(&mut number, 0x1234);
+        assert_eq!(number.finish(), [0xe1, 0x88, 0xb4]);
+    }
+}
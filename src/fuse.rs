@@ -0,0 +1,241 @@
+use super::cd::{read_toc, read_track_at, Track};
+use super::shared::ChdShared;
+use super::*;
+use fuser::{
+    Config, Errno, FileAttr, FileHandle, FileType, Filesystem, Generation, INodeNo, LockOwner,
+    MountOption, OpenFlags, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+// attributes never change, so kernel may cache them for long
+const TTL: Duration = Duration::from_secs(3600);
+// first inode after root, given to the logical image
+const FIRST_FILE: u64 = 2;
+
+// What a file of the mounted filesystem is made of
+#[derive(Clone, Debug)]
+pub enum FileContent {
+    Image,        // whole logical image
+    Track(Track), // sector data of one CD track
+}
+
+#[derive(Clone, Debug)]
+pub struct FileEntry {
+    pub name: String,
+    pub size: u64,
+    pub content: FileContent,
+}
+
+// Read-only filesystem with the logical image as "image.bin" and, for CD chds,
+// every track as "trackNN.bin". Data is decoded on demand through the hunk reader.
+pub struct ChdFs<T: R> {
+    chd: ChdShared<T>,
+    files: Vec<FileEntry>, // inode is FIRST_FILE + index
+    time: SystemTime,      // timestamps of all files
+}
+
+impl<T: R> ChdFs<T> {
+    pub fn new(mut chd: Chd<T>) -> io::Result<Self> {
+        let mut files = vec![FileEntry {
+            name: "image.bin".to_string(),
+            size: chd.size(),
+            content: FileContent::Image,
+        }];
+        for track in read_toc(&mut chd)? {
+            files.push(FileEntry {
+                name: format!("track{:02}.bin", track.number),
+                size: track.data_size(),
+                content: FileContent::Track(track),
+            });
+        }
+        Ok(Self {
+            chd: ChdShared::new(chd),
+            files,
+            time: SystemTime::now(),
+        })
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub fn into_inner(self) -> io::Result<Chd<T>> {
+        self.chd.into_inner()
+    }
+
+    // Up to size bytes of file at offset, shorter at the end of file
+    pub fn read_file(&self, index: usize, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let file = self
+            .files
+            .get(index)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let size = std::cmp::min(size as u64, file.size.saturating_sub(offset)) as usize;
+        let mut buf = vec![0; size];
+        match &file.content {
+            FileContent::Image => self.chd.read_exact_at(offset, &mut buf)?,
+            FileContent::Track(track) => {
+                let read = read_track_at(&mut *self.chd.lock()?, track, offset, &mut buf)?;
+                buf.truncate(read);
+            }
+        }
+        Ok(buf)
+    }
+
+    fn file(&self, ino: INodeNo) -> Option<(usize, &FileEntry)> {
+        let index = ino.0.checked_sub(FIRST_FILE)? as usize;
+        self.files.get(index).map(|file| (index, file))
+    }
+
+    fn attr(&self, ino: INodeNo, kind: FileType, size: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.time,
+            mtime: self.time,
+            ctime: self.time,
+            crtime: self.time,
+            kind,
+            perm: match kind {
+                FileType::Directory => 0o555,
+                _ => 0o444,
+            },
+            nlink: match kind {
+                FileType::Directory => 2,
+                _ => 1,
+            },
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: self.chd.hunk_size() as u32,
+            flags: 0,
+        }
+    }
+}
+
+impl<T: R + Send + 'static> Filesystem for ChdFs<T> {
+    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        if parent != INodeNo::ROOT {
+            return reply.error(Errno::ENOENT);
+        }
+        match self
+            .files
+            .iter()
+            .position(|file| name == file.name.as_str())
+        {
+            Some(index) => {
+                let ino = INodeNo(FIRST_FILE + index as u64);
+                let attr = self.attr(ino, FileType::RegularFile, self.files[index].size);
+                reply.entry(&TTL, &attr, Generation(0))
+            }
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn getattr(&self, _req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        if ino == INodeNo::ROOT {
+            return reply.attr(&TTL, &self.attr(ino, FileType::Directory, 0));
+        }
+        match self.file(ino) {
+            Some((_, file)) => reply.attr(&TTL, &self.attr(ino, FileType::RegularFile, file.size)),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        let index = match self.file(ino) {
+            Some((index, _)) => index,
+            None => return reply.error(Errno::ENOENT),
+        };
+        match self.read_file(index, offset, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(_e) => {
+                #[cfg(feature = "tracing")]
+                tracing::error!(ino = ino.0, offset, size, error = %_e, "fuse read failed");
+                reply.error(Errno::EIO)
+            }
+        }
+    }
+
+    fn readdir(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        if ino != INodeNo::ROOT {
+            return reply.error(Errno::ENOTDIR);
+        }
+        // offset of an entry is the index of the next one
+        let mut entries = vec![
+            (INodeNo::ROOT, FileType::Directory, "."),
+            (INodeNo::ROOT, FileType::Directory, ".."),
+        ];
+        for (index, file) in self.files.iter().enumerate() {
+            let ino = INodeNo(FIRST_FILE + index as u64);
+            entries.push((ino, FileType::RegularFile, file.name.as_str()));
+        }
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, i as u64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+}
+
+// Mount chd read-only at path and serve requests until it is unmounted
+pub fn mount<T: R + Send + 'static, P: AsRef<Path>>(chd: Chd<T>, path: P) -> io::Result<()> {
+    let mut config = Config::default();
+    config.mount_options = vec![
+        MountOption::RO,
+        MountOption::FSName("chd".to_string()),
+        MountOption::Subtype("chd".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+    fuser::mount(ChdFs::new(chd)?, path, &config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_files() {
+        let raw = include_bytes!("../samples/cdzl.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let mut image = Vec::new();
+        chd.read_to_end(&mut image).unwrap();
+        let toc = read_toc(&mut chd).unwrap();
+
+        let fs = ChdFs::new(chd).unwrap();
+        let names: Vec<&str> = fs.files().iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["image.bin", "track01.bin"]);
+        assert_eq!(fs.files()[0].size, image.len() as u64);
+        assert_eq!(fs.files()[1].size, toc[0].data_size());
+
+        assert_eq!(fs.read_file(0, 100, 1000).unwrap(), &image[100..1100]);
+        let tail = fs.read_file(0, image.len() as u64 - 10, 1000).unwrap();
+        assert_eq!(tail, &image[image.len() - 10..]);
+        assert!(fs.read_file(0, image.len() as u64, 10).unwrap().is_empty());
+
+        let size = toc[0].data_size();
+        assert_eq!(fs.read_file(1, 0, 1 << 20).unwrap().len() as u64, size);
+        assert!(fs.read_file(2, 0, 10).is_err());
+    }
+}
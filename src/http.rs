@@ -0,0 +1,210 @@
+use super::cache::{HunkCache, LruCache};
+use super::utils::IoStat;
+use super::*;
+
+// Default amount of data fetched by one range request
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+// Default number of chunks kept in memory
+pub const DEFAULT_CHUNKS: usize = 64;
+
+// Read + Seek over HTTP(S) url using Range requests, so chd on a web server or object
+// storage can be opened and sparsely read. Fetched chunks are kept in LRU cache.
+pub struct HttpReader {
+    agent: ureq::Agent,
+    url: String,
+    size: u64,         // total file size reported by server
+    pos: u64,          // current read position
+    chunk_size: usize, // bytes per range request
+    chunks: LruCache,  // fetched chunks by index
+    stat: IoStat,      // range requests sent and bytes received
+}
+
+impl HttpReader {
+    pub fn open(url: &str) -> io::Result<Self> {
+        Self::with_options(url, DEFAULT_CHUNK_SIZE, DEFAULT_CHUNKS)
+    }
+
+    pub fn with_options(url: &str, chunk_size: usize, chunks: usize) -> io::Result<Self> {
+        if chunk_size == 0 || chunks == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "http: chunk size and count must be > 0",
+            ));
+        }
+        let mut reader = Self {
+            agent: ureq::Agent::new(),
+            url: url.to_string(),
+            size: 0,
+            pos: 0,
+            chunk_size,
+            chunks: LruCache::new(chunks),
+            stat: IoStat::default(),
+        };
+        // first byte tells whether ranges are supported and the total size
+        let (_, size) = reader.fetch(0, 1)?;
+        reader.size = size;
+        Ok(reader)
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn stat(&self) -> &IoStat {
+        &self.stat
+    }
+
+    // Bytes [offset, offset + length) of the file and its total size
+    fn fetch(&mut self, offset: u64, length: u64) -> io::Result<(Vec<u8>, u64)> {
+        let range = format!("bytes={}-{}", offset, offset + length - 1);
+        let response = self
+            .agent
+            .get(&self.url)
+            .set("Range", &range)
+            .call()
+            .map_err(|e| io::Error::other(format!("http: {}: {}", self.url, e)))?;
+        if response.status() != 206 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "http: {}: server replied {} to range request",
+                    self.url,
+                    response.status()
+                ),
+            ));
+        }
+        // Content-Range: bytes first-last/size
+        let size = response
+            .header("Content-Range")
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, size)| size.trim().parse().ok())
+            .ok_or_else(|| invalid_data(format!("http: {}: bad Content-Range", self.url)))?;
+        let mut data = Vec::with_capacity(length as usize);
+        response.into_reader().take(length).read_to_end(&mut data)?;
+        self.stat.add(data.len());
+        Ok((data, size))
+    }
+
+    fn chunk(&mut self, index: usize) -> io::Result<&[u8]> {
+        if self.chunks.get(index).is_none() {
+            let offset = index as u64 * self.chunk_size as u64;
+            let length = std::cmp::min(self.chunk_size as u64, self.size - offset);
+            let (data, _) = self.fetch(offset, length)?;
+            if data.len() as u64 != length {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "http: {}: got {} of {} bytes at {}",
+                        self.url,
+                        data.len(),
+                        length,
+                        offset
+                    ),
+                ));
+            }
+            self.chunks.put(index, &data);
+        }
+        Ok(self.chunks.get(index).unwrap())
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let chunk_size = self.chunk_size as u64;
+        let (index, within) = (self.pos / chunk_size, (self.pos % chunk_size) as usize);
+        let chunk = self.chunk(index as usize)?;
+        let n = copy_from(buf, &chunk[within..]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for HttpReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "http: seek before start")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    // Serve data with range requests on a local port, returns the url
+    fn serve(data: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/test.chd", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 {
+                        break;
+                    }
+                    let line = line.trim_end();
+                    if let Some(value) = line.strip_prefix("Range: bytes=") {
+                        let (first, last) = value.split_once('-').unwrap();
+                        range = Some((first.parse().unwrap(), last.parse::<usize>().unwrap()));
+                    }
+                    if !line.is_empty() {
+                        continue;
+                    }
+                    let (first, last) = range.take().unwrap();
+                    let last = std::cmp::min(last, data.len() - 1);
+                    write!(
+                        stream,
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\
+                         Content-Range: bytes {}-{}/{}\r\n\r\n",
+                        last + 1 - first,
+                        first,
+                        last,
+                        data.len()
+                    )
+                    .unwrap();
+                    stream.write_all(&data[first..=last]).unwrap();
+                }
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_http() {
+        let raw = include_bytes!("../samples/zlib.chd");
+        let url = serve(raw);
+        let reader = HttpReader::with_options(&url, 4096, 4).unwrap();
+        assert_eq!(reader.size(), raw.len() as u64);
+
+        let mut chd = Chd::open(reader).unwrap();
+        let mut expected = Chd::open(io::Cursor::new(&raw[..])).unwrap();
+        let mut buf = vec![0; 5000];
+        let mut want = vec![0; 5000];
+        chd.read_exact_at(10000, &mut buf).unwrap();
+        expected.read_exact_at(10000, &mut want).unwrap();
+        assert_eq!(buf, want);
+
+        let reader = chd.into_inner();
+        // header, map and a few hunks, not the whole file
+        assert!(reader.stat().bytes < raw.len() as u64);
+        assert!(reader.stat().reads > 1);
+    }
+}
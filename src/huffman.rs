@@ -1,4 +1,10 @@
-use crate::bitstream::BitReader;
+// Canonical huffman codes as used by MAME: the decoder for the huff codec and
+// compressed map, and the encoder producing trees and data it can read.
+// Trees are stored either as RLE coded bit lengths (import_tree_rle) or as bit
+// lengths coded with a small huffman tree (import_tree_huffman). These are the
+// only tree formats of MAME, there is no delta coded tree: AVHuff audio codes
+// sample deltas as values with huffman coded trees, and the map uses RLE tree.
+use crate::bitstream::{BitReader, BitWriter};
 use crate::utils::*;
 use std::io;
 
@@ -12,7 +18,7 @@ const fn make_lookup(code: usize, bits: ValueSize) -> LookupValue {
 
 #[derive(Clone, Copy, Default)]
 struct Node {
-    bits: ValueSize,    // bits used to encode the node
+    bits: u32,          // bits used to encode the node
     numbits: ValueSize, // number of bits needed for this node
 }
 
@@ -23,6 +29,7 @@ pub struct Huffman {
 }
 
 impl Huffman {
+    // Decoder of numcodes values with codes up to maxbits (at most 16) long
     pub fn new(numcodes: NodeIndex, maxbits: ValueSize) -> Self {
         Huffman {
             numcodes,
@@ -31,52 +38,60 @@ impl Huffman {
         }
     }
 
+    pub fn memory_usage(&self) -> usize {
+        self.lookup.len() * std::mem::size_of::<LookupValue>()
+    }
+
+    // Decode next value, tree must be imported before
     pub fn decode_one(&self, stream: &mut BitReader) -> LookupValue {
         // peek ahead to get maxbits worth of data */
         let bits = stream.peek(self.maxbits as usize);
         // look it up, then remove the actual number of bits for this code
         let lookup = self.lookup[bits as usize];
-        stream.seek((lookup as usize) & 0x1f);
+        stream.skip((lookup as usize) & 0x1f);
         // return the value
-        return lookup >> 5;
+        lookup >> 5
     }
 
+    // Import tree written by HuffmanEncoder::export_tree_rle
     pub fn import_tree_rle(&mut self, stream: &mut BitReader) -> io::Result<()> {
-        let mut nodes = self.make_nodes();
+        let mut nodes = make_nodes(self.numcodes);
         self.read_numbits_rle(stream, &mut nodes)?;
-        self.assign_canonical_codes(&mut nodes)?;
+        assign_canonical_codes(&mut nodes, self.maxbits)?;
         self.build_lookup_table(&nodes);
         Ok(())
     }
 
+    // Import tree written by HuffmanEncoder::export_tree_huffman
     pub fn import_tree_huffman(&mut self, stream: &mut BitReader) -> io::Result<()> {
         let mut smallhuff = Huffman::new(24, 6);
-        let mut smallnodes = smallhuff.make_nodes();
+        let mut smallnodes = make_nodes(smallhuff.numcodes);
         smallhuff.read_numbits_small(stream, &mut smallnodes);
-        smallhuff.assign_canonical_codes(&mut smallnodes)?;
+        assign_canonical_codes(&mut smallnodes, smallhuff.maxbits)?;
         smallhuff.build_lookup_table(&smallnodes);
 
-        let mut nodes = self.make_nodes();
+        let mut nodes = make_nodes(self.numcodes);
         self.read_numbits_huffman(&smallhuff, stream, &mut nodes)?;
-        self.assign_canonical_codes(&mut nodes)?;
+        assign_canonical_codes(&mut nodes, self.maxbits)?;
         self.build_lookup_table(&nodes);
         Ok(())
     }
 
-    fn make_nodes(&self) -> Vec<Node> {
-        vec![Node::default(); self.numcodes as usize]
-    }
-
     fn read_numbits_small(&mut self, stream: &mut BitReader, smallnodes: &mut [Node]) {
         smallnodes[0].numbits = stream.read(3) as u8;
         let mut count = 0;
         let start = stream.read(3) as usize + 1;
-        for index in 1..self.numcodes as usize {
+        for (index, node) in smallnodes
+            .iter_mut()
+            .enumerate()
+            .take(self.numcodes as usize)
+            .skip(1)
+        {
             if index < start || count == 7 {
-                smallnodes[index].numbits = 0;
+                node.numbits = 0;
             } else {
                 count = stream.read(3) as usize;
-                smallnodes[index].numbits = match count {
+                node.numbits = match count {
                     7 => 0,
                     v => v as u8,
                 };
@@ -90,15 +105,7 @@ impl Huffman {
         stream: &mut BitReader,
         nodes: &mut [Node],
     ) -> io::Result<()> {
-        let rlefullbits = {
-            let mut bits = 0;
-            let mut temp = self.numcodes - 9;
-            while temp > 0 {
-                temp >>= 1;
-                bits += 1;
-            }
-            bits
-        };
+        let rlefullbits = rle_full_bits(self.numcodes);
 
         let numcodes = self.numcodes as usize;
         let mut code = 0;
@@ -134,11 +141,7 @@ impl Huffman {
 
     fn read_numbits_rle(&mut self, stream: &mut BitReader, nodes: &mut [Node]) -> io::Result<()> {
         // bits per entry depends on the maxbits
-        let numbits = match self.maxbits {
-            0..=7 => 3,
-            8..=15 => 4,
-            _ => 5,
-        };
+        let numbits = rle_numbits(self.maxbits);
 
         // loop until we read numbits for all nodes
         let numcodes = self.numcodes as usize;
@@ -160,11 +163,17 @@ impl Huffman {
                 code += 1;
             } else {
                 // otherwise, we need one for value for the repeat count
-                let repcount = stream.read(numbits) + 3;
-                for _ in 0..repcount {
-                    nodes[code].numbits = nodebits;
-                    code += 1;
+                let repcount = stream.read(numbits) as usize + 3;
+                if code + repcount > numcodes {
+                    return Err(invalid_data(format!(
+                        "huffman:read_numbits_rle: repeat of {} codes at {} exceeds {}",
+                        repcount, code, numcodes
+                    )));
+                }
+                for node in nodes[code..code + repcount].iter_mut() {
+                    node.numbits = nodebits;
                 }
+                code += repcount;
             }
         }
 
@@ -183,49 +192,9 @@ impl Huffman {
         Ok(())
     }
 
-    fn assign_canonical_codes(&mut self, nodes: &mut [Node]) -> io::Result<()> {
-        let mut bithisto = [0; 33];
-
-        // build up a histogram of bit lengths
-        for (code, node) in nodes.iter().enumerate() {
-            let numbits = node.numbits;
-            if numbits > self.maxbits {
-                return Err(invalid_data(format!(
-                    "huffman:assign_canonical_codes: code {} bit number {} exceed maximal {}",
-                    code, numbits, self.maxbits
-                )));
-            }
-            if numbits <= 32 {
-                bithisto[numbits as usize] += 1;
-            }
-        }
-
-        // for each code length, determine the starting code number
-        let mut curstart = 0;
-        for codelen in (1..32).rev() {
-            let nextstart = (curstart + bithisto[codelen]) >> 1;
-            if codelen != 1 && nextstart * 2 != (curstart + bithisto[codelen]) {
-                return Err(invalid_data(format!(
-                    "huffman:assign_canonical_codes: inconsistent starting codes {:x?}",
-                    bithisto
-                )));
-            }
-            bithisto[codelen] = curstart;
-            curstart = nextstart;
-        }
-
-        // now assign canonical codes
-        for node in nodes.iter_mut() {
-            let numbits = node.numbits as usize;
-            if numbits > 0 {
-                node.bits = bithisto[numbits];
-                bithisto[numbits] += 1;
-            }
-        }
-        Ok(())
-    }
-
     fn build_lookup_table(&mut self, nodes: &[Node]) {
+        // codes of previously imported tree may be shorter
+        self.lookup.fill(0);
         // iterate over all codes
         for (code, node) in nodes.iter().enumerate() {
             let numbits = node.numbits;
@@ -245,3 +214,386 @@ impl Huffman {
         }
     }
 }
+
+// Builds a tree for values counted by histo_one() and writes it and the values
+pub struct HuffmanEncoder {
+    numcodes: NodeIndex,
+    maxbits: ValueSize,
+    histo: Vec<u32>,
+    nodes: Vec<Node>,
+}
+
+impl HuffmanEncoder {
+    // Encoder of numcodes values with codes up to maxbits (at most 16) long
+    pub fn new(numcodes: NodeIndex, maxbits: ValueSize) -> Self {
+        HuffmanEncoder {
+            numcodes,
+            maxbits,
+            histo: vec![0; numcodes as usize],
+            nodes: make_nodes(numcodes),
+        }
+    }
+
+    pub fn histo_reset(&mut self) {
+        self.histo.fill(0);
+    }
+
+    // Count one more occurrence of value
+    pub fn histo_one(&mut self, value: NodeIndex) {
+        self.histo[value as usize] += 1;
+    }
+
+    // Assign codes to values counted so far, shortest to the most frequent
+    pub fn compute_tree_from_histo(&mut self) -> io::Result<()> {
+        let totaldata = self.histo.iter().map(|&c| c as u64).sum::<u64>();
+        // scale weights down until tree fits into maxbits, making it flatter
+        let mut lowerweight = 0;
+        let mut upperweight = totaldata * 2;
+        loop {
+            let curweight = (upperweight + lowerweight) / 2;
+            let curmaxbits = self.build_tree(totaldata, curweight);
+            if curmaxbits <= self.maxbits {
+                lowerweight = curweight;
+                if curweight == totaldata || upperweight - lowerweight <= 1 {
+                    break;
+                }
+            } else if curweight == lowerweight {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "huffman:compute_tree_from_histo: codes don't fit into {} bits",
+                        self.maxbits
+                    ),
+                ));
+            } else {
+                upperweight = curweight;
+            }
+        }
+        assign_canonical_codes(&mut self.nodes, self.maxbits)
+    }
+
+    // Write value, tree must be computed before
+    pub fn encode_one(&self, stream: &mut BitWriter, value: NodeIndex) -> io::Result<()> {
+        match self.nodes.get(value as usize) {
+            Some(node) if node.numbits > 0 => {
+                stream.write(node.bits, node.numbits as usize);
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("huffman:encode_one: no code for value {}", value),
+            )),
+        }
+    }
+
+    // Write tree for Huffman::import_tree_rle
+    pub fn export_tree_rle(&self, stream: &mut BitWriter) {
+        // bits per entry depends on the maxbits
+        let numbits = rle_numbits(self.maxbits);
+        let write = |stream: &mut BitWriter, value: ValueSize, mut repcount: usize| {
+            while repcount > 0 {
+                if value == 1 {
+                    // 1 is an escape code, so it is doubled
+                    stream.write(1, numbits);
+                    stream.write(1, numbits);
+                    repcount -= 1;
+                } else if repcount <= 2 {
+                    // short repeats are cheaper as raw values
+                    stream.write(value as u32, numbits);
+                    repcount -= 1;
+                } else {
+                    let reps = (repcount - 3).min((1 << numbits) - 1);
+                    stream.write(1, numbits);
+                    stream.write(value as u32, numbits);
+                    stream.write(reps as u32, numbits);
+                    repcount -= reps + 3;
+                }
+            }
+        };
+
+        for (value, repcount) in runs(&self.nodes) {
+            write(stream, value, repcount);
+        }
+    }
+
+    // Write tree for Huffman::import_tree_huffman
+    pub fn export_tree_huffman(&self, stream: &mut BitWriter) -> io::Result<()> {
+        // bit lengths + 1, or 0 followed by repeat count of the last length
+        let mut rle = Vec::new();
+        let mut smallhuff = HuffmanEncoder::new(24, 6);
+        for (value, repcount) in runs(&self.nodes) {
+            rle.push((value as u32 + 1, 0));
+            match repcount - 1 {
+                0 => (),
+                1 => rle.push((value as u32 + 1, 0)),
+                count => rle.push((0, count - 2)),
+            }
+        }
+        for &(value, _) in rle.iter() {
+            smallhuff.histo_one(value);
+        }
+        smallhuff.compute_tree_from_histo()?;
+
+        // bit lengths of the small tree: RLE token, then the ones from
+        // first_non_zero up to a terminating 7
+        let used = || (1..smallhuff.nodes.len()).filter(|&i| smallhuff.nodes[i].numbits != 0);
+        let first_non_zero = used().next().unwrap_or(8).min(8);
+        let last_non_zero = used().next_back().unwrap_or(0);
+        stream.write(smallhuff.nodes[0].numbits as u32, 3);
+        stream.write(first_non_zero as u32 - 1, 3);
+        for node in smallhuff.nodes[first_non_zero..=last_non_zero.max(first_non_zero - 1)].iter() {
+            stream.write(node.numbits as u32, 3);
+        }
+        stream.write(7, 3);
+
+        let rlefullbits = rle_full_bits(self.numcodes);
+        for (value, count) in rle {
+            smallhuff.encode_one(stream, value)?;
+            if value == 0 {
+                match count {
+                    0..=6 => stream.write(count as u32, 3),
+                    _ => {
+                        stream.write(7, 3);
+                        stream.write(count as u32 - 7, rlefullbits);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Build huffman tree from histogram with weights scaled to totalweight,
+    // returns length of the longest code
+    fn build_tree(&mut self, totaldata: u64, totalweight: u64) -> ValueSize {
+        let numcodes = self.numcodes as usize;
+        let mut weights = Vec::with_capacity(numcodes * 2);
+        let mut parents = vec![usize::MAX; numcodes * 2];
+        let mut list = Vec::new();
+        for (code, &count) in self.histo.iter().enumerate() {
+            weights.push(match count {
+                0 => 0,
+                _ => (count as u64 * totalweight / totaldata).max(1),
+            });
+            if count != 0 {
+                list.push(code);
+            }
+        }
+        // heaviest first, so two lightest nodes are popped from the end
+        list.sort_by(|&a, &b| weights[b].cmp(&weights[a]));
+        while list.len() > 1 {
+            let node1 = list.pop().unwrap();
+            let node0 = list.pop().unwrap();
+            let node = weights.len();
+            weights.push(weights[node0] + weights[node1]);
+            parents[node0] = node;
+            parents[node1] = node;
+            let pos = list
+                .iter()
+                .position(|&n| weights[n] < weights[node])
+                .unwrap_or(list.len());
+            list.insert(pos, node);
+        }
+
+        // code length is a depth of the leaf, at least 1 for the single code
+        let mut maxbits = 0;
+        for (code, node) in self.nodes.iter_mut().enumerate() {
+            node.bits = 0;
+            node.numbits = 0;
+            if self.histo[code] == 0 {
+                continue;
+            }
+            let mut cur = code;
+            while parents[cur] != usize::MAX {
+                node.numbits = node.numbits.saturating_add(1);
+                cur = parents[cur];
+            }
+            node.numbits = node.numbits.max(1);
+            maxbits = maxbits.max(node.numbits);
+        }
+        maxbits
+    }
+}
+
+fn make_nodes(numcodes: NodeIndex) -> Vec<Node> {
+    vec![Node::default(); numcodes as usize]
+}
+
+// Bits per entry of RLE coded tree
+fn rle_numbits(maxbits: ValueSize) -> usize {
+    match maxbits {
+        0..=7 => 3,
+        8..=15 => 4,
+        _ => 5,
+    }
+}
+
+// Bits of long repeat count of huffman coded tree
+fn rle_full_bits(numcodes: NodeIndex) -> usize {
+    let mut bits = 0;
+    let mut temp = numcodes.saturating_sub(9);
+    while temp > 0 {
+        temp >>= 1;
+        bits += 1;
+    }
+    bits
+}
+
+// Runs of equal bit lengths as (length, repeat count)
+fn runs(nodes: &[Node]) -> Vec<(ValueSize, usize)> {
+    let mut runs: Vec<(ValueSize, usize)> = Vec::new();
+    for node in nodes {
+        match runs.last_mut() {
+            Some((value, count)) if *value == node.numbits => *count += 1,
+            _ => runs.push((node.numbits, 1)),
+        }
+    }
+    runs
+}
+
+fn assign_canonical_codes(nodes: &mut [Node], maxbits: ValueSize) -> io::Result<()> {
+    let mut bithisto = [0u32; 33];
+
+    // build up a histogram of bit lengths
+    for (code, node) in nodes.iter().enumerate() {
+        let numbits = node.numbits;
+        if numbits > maxbits {
+            return Err(invalid_data(format!(
+                "huffman:assign_canonical_codes: code {} bit number {} exceed maximal {}",
+                code, numbits, maxbits
+            )));
+        }
+        if numbits <= 32 {
+            bithisto[numbits as usize] += 1;
+        }
+    }
+
+    // for each code length, determine the starting code number
+    let mut curstart = 0;
+    for codelen in (1..32).rev() {
+        let nextstart = (curstart + bithisto[codelen]) >> 1;
+        // MAME allows odd number of 1 bit codes, but they still have to fit
+        if codelen == 1 && curstart + bithisto[codelen] > 2 {
+            return Err(invalid_data(format!(
+                "huffman:assign_canonical_codes: codes overflow {:x?}",
+                bithisto
+            )));
+        }
+        if codelen != 1 && nextstart * 2 != (curstart + bithisto[codelen]) {
+            return Err(invalid_data(format!(
+                "huffman:assign_canonical_codes: inconsistent starting codes {:x?}",
+                bithisto
+            )));
+        }
+        bithisto[codelen] = curstart;
+        curstart = nextstart;
+    }
+
+    // now assign canonical codes
+    for node in nodes.iter_mut() {
+        let numbits = node.numbits as usize;
+        if numbits > 0 {
+            node.bits = bithisto[numbits];
+            bithisto[numbits] += 1;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoder(numcodes: NodeIndex, maxbits: ValueSize, data: &[u32]) -> HuffmanEncoder {
+        let mut encoder = HuffmanEncoder::new(numcodes, maxbits);
+        for &value in data {
+            encoder.histo_one(value);
+        }
+        encoder.compute_tree_from_histo().unwrap();
+        encoder
+    }
+
+    fn round_trip(numcodes: NodeIndex, maxbits: ValueSize, data: &[u32], rle: bool) -> usize {
+        let encoder = encoder(numcodes, maxbits, data);
+        let mut writer = BitWriter::new();
+        match rle {
+            true => encoder.export_tree_rle(&mut writer),
+            false => encoder.export_tree_huffman(&mut writer).unwrap(),
+        }
+        for &value in data {
+            encoder.encode_one(&mut writer, value).unwrap();
+        }
+        let bits = writer.position();
+        let encoded = writer.finish();
+
+        let mut decoder = Huffman::new(numcodes, maxbits);
+        let mut reader = BitReader::new(&encoded);
+        match rle {
+            true => decoder.import_tree_rle(&mut reader).unwrap(),
+            false => decoder.import_tree_huffman(&mut reader).unwrap(),
+        }
+        for &value in data {
+            assert_eq!(decoder.decode_one(&mut reader) as u32, value);
+        }
+        assert_eq!(reader.position(), bits);
+        bits
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // skewed distribution over all 256 byte values
+        let data: Vec<u32> = (0..20000u32)
+            .map(|i| match i % 7 {
+                0..=3 => 0,
+                4 => (i / 7) % 16,
+                _ => i.wrapping_mul(2654435761) >> 24,
+            })
+            .collect();
+        let rle = round_trip(256, 16, &data, true);
+        let huff = round_trip(256, 16, &data, false);
+        assert!(huff < data.len() * 8);
+        assert!(rle < data.len() * 8);
+
+        // map compression types
+        round_trip(16, 8, &[0, 0, 0, 1, 2, 2, 5, 0, 15], true);
+        // single value
+        round_trip(256, 16, &[42, 42, 42], false);
+        round_trip(16, 8, &[3], true);
+        // code length limit forces flatter tree
+        let data: Vec<u32> = (0..24).flat_map(|i| vec![i; 1 << (i / 2)]).collect();
+        round_trip(24, 6, &data, false);
+    }
+
+    #[test]
+    fn test_errors() {
+        let encoder = encoder(16, 8, &[1, 2]);
+        assert!(encoder.encode_one(&mut BitWriter::new(), 3).is_err());
+        assert!(encoder.encode_one(&mut BitWriter::new(), 16).is_err());
+
+        // 5 codes can't fit into 2 bits
+        let mut encoder = HuffmanEncoder::new(5, 2);
+        (0..5).for_each(|v| encoder.histo_one(v));
+        assert!(encoder.compute_tree_from_histo().is_err());
+
+        // repeat of 0b1111 + 3 codes of 8 bits past 16 codes
+        let mut decoder = Huffman::new(16, 8);
+        let data = [0b0001_1000, 0b1111_0000];
+        assert!(decoder.import_tree_rle(&mut BitReader::new(&data)).is_err());
+
+        // over-subscribed tree of 3 codes of 1 bit, the rest unused
+        let mut tree = BitWriter::new();
+        for _ in 0..3 {
+            tree.write(0b0001_0001, 8); // escaped 1 is a single 1
+        }
+        (3..16).for_each(|_| tree.write(0, 4));
+        assert!(decoder
+            .import_tree_rle(&mut BitReader::new(&tree.finish()))
+            .is_err());
+        // a single 1 bit code is still accepted
+        let mut tree = BitWriter::new();
+        tree.write(0b0001_0001, 8);
+        (1..16).for_each(|_| tree.write(0, 4));
+        decoder
+            .import_tree_rle(&mut BitReader::new(&tree.finish()))
+            .unwrap();
+    }
+}
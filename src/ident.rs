@@ -0,0 +1,188 @@
+use super::cd::{read_toc, USER_DATA_SIZE};
+use super::iso::{IsoFs, IsoSource};
+use super::*;
+
+// Guess of system a disc is made for, from boot sectors and identifying strings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiscSystem {
+    PlayStation,
+    PlayStation2,
+    Saturn,
+    Dreamcast,
+    SegaCd,
+    PcEngineCd,
+    ThreeDo,
+    Iso9660, // ISO9660 filesystem of no known system, e.g. PC
+    Unknown,
+}
+
+impl DiscSystem {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PlayStation => "Sony PlayStation",
+            Self::PlayStation2 => "Sony PlayStation 2",
+            Self::Saturn => "Sega Saturn",
+            Self::Dreamcast => "Sega Dreamcast",
+            Self::SegaCd => "Sega CD",
+            Self::PcEngineCd => "PC Engine CD",
+            Self::ThreeDo => "3DO",
+            Self::Iso9660 => "ISO9660",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscInfo {
+    pub system: DiscSystem,
+    pub label: Option<String>, // volume label or title from system header
+}
+
+// Boot sector signatures at the start of sector 0: magic, title offset and length
+const BOOT_SIGNATURES: [(&[u8], DiscSystem, usize, usize); 4] = [
+    (b"SEGA SEGAKATANA ", DiscSystem::Dreamcast, 0x80, 0x80),
+    (b"SEGA SEGASATURN ", DiscSystem::Saturn, 0x60, 0x70),
+    (b"SEGADISCSYSTEM  ", DiscSystem::SegaCd, 0x120, 0x30),
+    (
+        &[0x01, 0x5a, 0x5a, 0x5a, 0x5a, 0x5a, 0x01],
+        DiscSystem::ThreeDo,
+        0x28,
+        0x20,
+    ),
+];
+// found in sector 1 of PC Engine CD data track
+const PC_ENGINE: &[u8] = b"PC Engine CD-ROM SYSTEM";
+
+fn label(data: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(data);
+    let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    match text.is_empty() {
+        true => None,
+        false => Some(text.to_string()),
+    }
+}
+
+// Guess system of CD, GD-ROM or DVD chd by its data tracks. Chds without track
+// metadata are looked at as plain 2048 bytes sectors images.
+pub fn identify<T: R>(chd: &mut Chd<T>) -> io::Result<DiscInfo> {
+    let toc = read_toc(chd)?;
+    let sources: Vec<IsoSource> = match toc.is_empty() {
+        true => vec![IsoSource::Image],
+        false => toc
+            .into_iter()
+            .filter(|t| t.track_type.user_data_offset().is_some())
+            .map(IsoSource::Track)
+            .collect(),
+    };
+
+    // headers of consoles that don't rely on ISO9660 for booting
+    let mut sector = [0; USER_DATA_SIZE];
+    for source in &sources {
+        if source.read_sector(chd, 0, &mut sector).is_err() {
+            continue;
+        }
+        for (magic, system, offset, length) in BOOT_SIGNATURES {
+            if sector.starts_with(magic) {
+                return Ok(DiscInfo {
+                    system,
+                    label: label(&sector[offset..offset + length]),
+                });
+            }
+        }
+        if source.read_sector(chd, 1, &mut sector).is_ok()
+            && sector.windows(PC_ENGINE.len()).any(|w| w == PC_ENGINE)
+        {
+            return Ok(DiscInfo {
+                system: DiscSystem::PcEngineCd,
+                label: None,
+            });
+        }
+    }
+
+    for source in sources {
+        let mut fs = match IsoFs::open_source(chd, source) {
+            Ok(fs) => fs,
+            Err(_) => continue,
+        };
+        let label = label(fs.volume_id().as_bytes());
+        // SYSTEM.CNF names boot executable, BOOT2 for PlayStation 2 ones
+        let system = match fs.read_file("/SYSTEM.CNF") {
+            Ok(cnf) if cnf.windows(5).any(|w| w == b"BOOT2") => DiscSystem::PlayStation2,
+            Ok(cnf) if cnf.windows(4).any(|w| w == b"BOOT") => DiscSystem::PlayStation,
+            _ if fs.system_id().starts_with("PLAYSTATION") => DiscSystem::PlayStation,
+            _ => DiscSystem::Iso9660,
+        };
+        return Ok(DiscInfo { system, label });
+    }
+
+    Ok(DiscInfo {
+        system: DiscSystem::Unknown,
+        label: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iso::tests::{make_chd, record};
+    use crate::tags::metadata;
+    use std::io::Cursor;
+
+    fn identify_image(image: &[u8]) -> DiscInfo {
+        let chd = make_chd(image, 4096, 2048, (metadata::DVD, b""));
+        let mut chd = Chd::open(Cursor::new(chd)).unwrap();
+        identify(&mut chd).unwrap()
+    }
+
+    // 20 sectors with pvd at 16, root directory at 17 and SYSTEM.CNF at 18
+    fn make_iso(system_id: &[u8], cnf: &[u8]) -> Vec<u8> {
+        let mut iso = vec![0; 20 * USER_DATA_SIZE];
+        let pvd = &mut iso[16 * USER_DATA_SIZE..17 * USER_DATA_SIZE];
+        pvd[0] = 1;
+        pvd[1..6].copy_from_slice(b"CD001");
+        pvd[8..8 + system_id.len()].copy_from_slice(system_id);
+        pvd[40..50].copy_from_slice(b"MY_VOLUME ");
+        let mut root = Vec::new();
+        record(&mut root, &[0], 17, 2048, true);
+        pvd[156..190].copy_from_slice(&root);
+        record(&mut root, &[1], 17, 2048, true);
+        if !cnf.is_empty() {
+            record(&mut root, b"SYSTEM.CNF;1", 18, cnf.len() as u32, false);
+        }
+        iso[17 * USER_DATA_SIZE..][..root.len()].copy_from_slice(&root);
+        iso[18 * USER_DATA_SIZE..][..cnf.len()].copy_from_slice(cnf);
+        iso
+    }
+
+    #[test]
+    fn test_identify() {
+        let mut image = vec![0; 4 * USER_DATA_SIZE];
+        image[..16].copy_from_slice(b"SEGA SEGASATURN ");
+        image[0x60..0x6a].copy_from_slice(b"TEST GAME ");
+        let info = identify_image(&image);
+        assert_eq!(info.system, DiscSystem::Saturn);
+        assert_eq!(info.label.as_deref(), Some("TEST GAME"));
+
+        image[..16].copy_from_slice(&[0; 16]);
+        image[USER_DATA_SIZE + 0x20..][..PC_ENGINE.len()].copy_from_slice(PC_ENGINE);
+        assert_eq!(identify_image(&image).system, DiscSystem::PcEngineCd);
+
+        let cnf = b"BOOT2 = cdrom0:\\SLUS_123.45;1\r\nVER = 1.00\r\n";
+        let info = identify_image(&make_iso(b"PLAYSTATION", cnf));
+        assert_eq!(info.system, DiscSystem::PlayStation2);
+        assert_eq!(info.label.as_deref(), Some("MY_VOLUME"));
+        let cnf = b"BOOT = cdrom:\\SLUS_000.01;1\r\n";
+        let info = identify_image(&make_iso(b"PLAYSTATION", cnf));
+        assert_eq!(info.system, DiscSystem::PlayStation);
+        let info = identify_image(&make_iso(b"LINUX", b""));
+        assert_eq!(info.system, DiscSystem::Iso9660);
+
+        // audio cd has no data tracks, hard disk has no filesystem
+        let raw = include_bytes!("../samples/cdzl.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        assert_eq!(identify(&mut chd).unwrap().system, DiscSystem::Unknown);
+        let raw = include_bytes!("../samples/zlib.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        assert_eq!(identify(&mut chd).unwrap().system, DiscSystem::Unknown);
+    }
+}
@@ -0,0 +1,478 @@
+use super::cd::read_toc;
+use super::ident::identify;
+use super::*;
+
+// Everything rchdtool info reports about chd, for printing or scripting
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChdInfo {
+    pub version: u32,
+    pub file_size: u64,
+    pub logical_size: u64,
+    pub hunk_size: u32,
+    pub hunk_count: u32,
+    pub unit_size: u32,
+    pub compression: Vec<String>, // codec names, empty for uncompressed chd
+    pub ratio: f32,               // file size to logical size, in percent
+    pub sha1: String,
+    pub raw_sha1: String,
+    pub parent_sha1: Option<String>,
+    pub metadata: Vec<MetadataInfo>,
+    pub hard_disk: Option<HardDiskInfo>, // geometry from GDDD metadata
+    pub tracks: Vec<TrackInfo>,          // CD or GD-ROM table of contents
+    pub disc: Option<DiscSummary>,       // system of CD, GD-ROM or DVD chd
+}
+
+// Header fields printed by write_summary, which can be picked or formatted separately
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SummaryReport {
+    pub file_size: u64,
+    pub version: u32,
+    pub logical_size: u64,
+    pub hunk_size: u32,
+    pub hunk_count: u32,
+    pub unit_size: u32,
+    pub compression: Vec<String>, // codec names, empty for uncompressed chd
+    pub ratio: f32,               // file size to logical size, in percent
+    pub sha1: String,
+    pub raw_sha1: String,
+    pub parent_sha1: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MetadataInfo {
+    pub tag: String,
+    pub flags: u8,
+    pub length: u32,
+    pub text: Option<String>, // value if it's printable text up to MAX_TEXT_LENGTH bytes
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HardDiskInfo {
+    pub cylinders: u32,
+    pub heads: u32,
+    pub sectors: u32,
+    pub bytes_per_sector: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TrackInfo {
+    pub number: u32,
+    pub track_type: String,
+    pub subcode: String,
+    pub frames: u32,
+    pub pregap: u32,
+    pub postgap: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DiscSummary {
+    pub system: String,
+    pub label: Option<String>,
+}
+
+impl HardDiskInfo {
+    // Parse "CYLS:%d,HEADS:%d,SECS:%d,BPS:%d" hard disk metadata
+    pub fn parse(text: &str) -> Option<Self> {
+        let field = |name: &str| -> Option<u32> {
+            text.split(',')
+                .filter_map(|kv| kv.split_once(':'))
+                .find(|(key, _)| key.trim() == name)
+                .and_then(|(_, value)| value.trim_end_matches('\0').trim().parse().ok())
+        };
+        Some(Self {
+            cylinders: field("CYLS")?,
+            heads: field("HEADS")?,
+            sectors: field("SECS")?,
+            bytes_per_sector: field("BPS")?,
+        })
+    }
+
+    // Geometry of disk of given size like chdman picks it: the most sectors per
+    // track and heads that divide sector count evenly
+    pub fn guess(size: u64, bytes_per_sector: u32) -> Option<Self> {
+        let bps = bytes_per_sector as u64;
+        if bps == 0 || size == 0 || !size.is_multiple_of(bps) {
+            return None;
+        }
+        let total = size / bps;
+        for sectors in (1..=63u64).rev() {
+            for heads in (1..=16u64).rev() {
+                let cylinders = total / (sectors * heads);
+                if total.is_multiple_of(sectors * heads) && cylinders <= 65536 {
+                    return Some(Self {
+                        cylinders: cylinders as u32,
+                        heads: heads as u32,
+                        sectors: sectors as u32,
+                        bytes_per_sector,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    pub fn size(&self) -> u64 {
+        self.cylinders as u64
+            * self.heads as u64
+            * self.sectors as u64
+            * self.bytes_per_sector as u64
+    }
+
+    // GDDD metadata value, nul terminated as chdman writes it
+    pub fn to_metadata(&self) -> Vec<u8> {
+        format!(
+            "CYLS:{},HEADS:{},SECS:{},BPS:{}\0",
+            self.cylinders, self.heads, self.sectors, self.bytes_per_sector
+        )
+        .into_bytes()
+    }
+}
+
+// Metadata value as text if it's printable ascii, optionally nul terminated
+// Longer metadata entries aren't checked for text by Chd::info()
+pub const MAX_TEXT_LENGTH: u32 = 1 << 16;
+
+fn metadata_text(data: &[u8]) -> Option<String> {
+    let data = data.strip_suffix(&[0]).unwrap_or(data);
+    match data.iter().all(|b| (0x20..0x7f).contains(b)) {
+        true => Some(String::from_utf8_lossy(data).into_owned()),
+        false => None,
+    }
+}
+
+impl std::fmt::Display for SummaryReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "File size: {}", self.file_size)?;
+        writeln!(f, "CHD version: {}", self.version)?;
+        writeln!(f, "Logical size: {}", self.logical_size)?;
+        writeln!(f, "Hunk Size: {}", self.hunk_size)?;
+        writeln!(f, "Total Hunks: {}", self.hunk_count)?;
+        writeln!(f, "Unit Size: {}", self.unit_size)?;
+        match self.compression.is_empty() {
+            true => writeln!(f, "Compression: none")?,
+            false => writeln!(f, "Compression: {}", self.compression.join(" "))?,
+        }
+        writeln!(f, "Ratio: {:.1}%", self.ratio)?;
+        writeln!(f, "SHA1: {}", self.sha1)?;
+        writeln!(f, "Data SHA1: {}", self.raw_sha1)?;
+        if let Some(parent_sha1) = &self.parent_sha1 {
+            writeln!(f, "Parent SHA1: {}", parent_sha1)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: R> Chd<T> {
+    // Header fields only, without reading metadata
+    pub fn summary(&self) -> SummaryReport {
+        SummaryReport {
+            file_size: self.file_size(),
+            version: self.version(),
+            logical_size: self.size(),
+            hunk_size: self.hunk_size_u32(),
+            hunk_count: self.hunk_count_u32(),
+            unit_size: self.unit_size_u32(),
+            compression: match self.compressed() {
+                true => self.compression_names(),
+                false => Vec::new(),
+            },
+            ratio: 1e2 * (self.file_size() as f32) / (self.size() as f32),
+            sha1: hex_string(&self.header.sha1),
+            raw_sha1: hex_string(&self.header.rawsha1),
+            parent_sha1: match self.has_parent() {
+                true => Some(hex_string(&self.header.parentsha1)),
+                false => None,
+            },
+        }
+    }
+
+    // Collect header fields, metadata list and details derived from known metadata
+    pub fn info(&mut self) -> io::Result<ChdInfo> {
+        let mut metadata = Vec::new();
+        let mut hard_disk = None;
+        let mut is_dvd = false;
+        Self::visit_metadata(
+            &mut self.io,
+            self.filesize,
+            self.header.metaoffset,
+            |io, entry| {
+                // large entries are binary tables, which aren't read
+                let text = match entry.length <= MAX_TEXT_LENGTH {
+                    true => {
+                        let mut data = vec![0; entry.length as usize];
+                        io.read_at(entry.offset, &mut data)?;
+                        metadata_text(&data)
+                    }
+                    false => None,
+                };
+                match entry.metatag {
+                    metadata::HARD_DISK => {
+                        hard_disk = text.as_deref().and_then(HardDiskInfo::parse)
+                    }
+                    metadata::DVD => is_dvd = true,
+                    _ => (),
+                }
+                metadata.push(MetadataInfo {
+                    tag: tag_string(entry.metatag),
+                    flags: entry.flags,
+                    length: entry.length,
+                    text,
+                });
+                Ok(())
+            },
+        )?;
+
+        let tracks: Vec<TrackInfo> = read_toc(self)?
+            .into_iter()
+            .map(|track| TrackInfo {
+                number: track.number,
+                track_type: track.track_type.name().to_string(),
+                subcode: track.subcode.name().to_string(),
+                frames: track.frames,
+                pregap: track.pregap,
+                postgap: track.postgap,
+            })
+            .collect();
+        let disc = match !tracks.is_empty() || is_dvd {
+            true => {
+                let disc = identify(self)?;
+                Some(DiscSummary {
+                    system: disc.system.name().to_string(),
+                    label: disc.label,
+                })
+            }
+            false => None,
+        };
+
+        let summary = self.summary();
+        Ok(ChdInfo {
+            version: summary.version,
+            file_size: summary.file_size,
+            logical_size: summary.logical_size,
+            hunk_size: summary.hunk_size,
+            hunk_count: summary.hunk_count,
+            unit_size: summary.unit_size,
+            compression: summary.compression,
+            ratio: summary.ratio,
+            sha1: summary.sha1,
+            raw_sha1: summary.raw_sha1,
+            parent_sha1: summary.parent_sha1,
+            metadata,
+            hard_disk,
+            tracks,
+            disc,
+        })
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_option(s: &Option<String>) -> String {
+    match s {
+        Some(s) => json_string(s),
+        None => "null".to_string(),
+    }
+}
+
+impl ChdInfo {
+    // Details that write_summary doesn't print: hard disk geometry, tracks and disc system
+    pub fn write_details<W: Write>(&self, to: &mut W) -> io::Result<()> {
+        if let Some(hd) = &self.hard_disk {
+            writeln!(
+                to,
+                "Geometry: {} cylinders, {} heads, {} sectors, {} bytes per sector",
+                hd.cylinders, hd.heads, hd.sectors, hd.bytes_per_sector
+            )?;
+        }
+        if let Some(disc) = &self.disc {
+            write!(to, "System: {}", disc.system)?;
+            match &disc.label {
+                Some(label) => writeln!(to, " ({})", label)?,
+                None => writeln!(to)?,
+            }
+        }
+        if !self.tracks.is_empty() {
+            writeln!(to, "Tracks:")?;
+        }
+        for track in &self.tracks {
+            writeln!(
+                to,
+                "  {:02}: {} {} frames:{} pregap:{} postgap:{}",
+                track.number,
+                track.track_type,
+                track.subcode,
+                track.frames,
+                track.pregap,
+                track.postgap
+            )?;
+        }
+        Ok(())
+    }
+
+    // Same fields as serde would produce, without depending on serde_json
+    pub fn write_json<W: Write>(&self, to: &mut W) -> io::Result<()> {
+        writeln!(to, "{{")?;
+        writeln!(to, "  \"version\": {},", self.version)?;
+        writeln!(to, "  \"file_size\": {},", self.file_size)?;
+        writeln!(to, "  \"logical_size\": {},", self.logical_size)?;
+        writeln!(to, "  \"hunk_size\": {},", self.hunk_size)?;
+        writeln!(to, "  \"hunk_count\": {},", self.hunk_count)?;
+        writeln!(to, "  \"unit_size\": {},", self.unit_size)?;
+        let compression: Vec<String> = self.compression.iter().map(|c| json_string(c)).collect();
+        writeln!(to, "  \"compression\": [{}],", compression.join(", "))?;
+        writeln!(to, "  \"ratio\": {},", self.ratio)?;
+        writeln!(to, "  \"sha1\": \"{}\",", self.sha1)?;
+        writeln!(to, "  \"raw_sha1\": \"{}\",", self.raw_sha1)?;
+        writeln!(to, "  \"parent_sha1\": {},", json_option(&self.parent_sha1))?;
+        let metadata: Vec<String> = self
+            .metadata
+            .iter()
+            .map(|m| {
+                format!(
+                    "    {{\"tag\": {}, \"flags\": {}, \"length\": {}, \"text\": {}}}",
+                    json_string(&m.tag),
+                    m.flags,
+                    m.length,
+                    json_option(&m.text)
+                )
+            })
+            .collect();
+        match metadata.is_empty() {
+            true => writeln!(to, "  \"metadata\": [],")?,
+            false => writeln!(to, "  \"metadata\": [\n{}\n  ],", metadata.join(",\n"))?,
+        }
+        match &self.hard_disk {
+            Some(hd) => writeln!(
+                to,
+                "  \"hard_disk\": {{\"cylinders\": {}, \"heads\": {}, \"sectors\": {}, \"bytes_per_sector\": {}}},",
+                hd.cylinders, hd.heads, hd.sectors, hd.bytes_per_sector
+            )?,
+            None => writeln!(to, "  \"hard_disk\": null,")?,
+        }
+        let tracks: Vec<String> = self
+            .tracks
+            .iter()
+            .map(|t| {
+                format!(
+                    "    {{\"number\": {}, \"track_type\": {}, \"subcode\": {}, \"frames\": {}, \"pregap\": {}, \"postgap\": {}}}",
+                    t.number,
+                    json_string(&t.track_type),
+                    json_string(&t.subcode),
+                    t.frames,
+                    t.pregap,
+                    t.postgap
+                )
+            })
+            .collect();
+        match tracks.is_empty() {
+            true => writeln!(to, "  \"tracks\": [],")?,
+            false => writeln!(to, "  \"tracks\": [\n{}\n  ],", tracks.join(",\n"))?,
+        }
+        match &self.disc {
+            Some(disc) => writeln!(
+                to,
+                "  \"disc\": {{\"system\": {}, \"label\": {}}}",
+                json_string(&disc.system),
+                json_option(&disc.label)
+            )?,
+            None => writeln!(to, "  \"disc\": null")?,
+        }
+        writeln!(to, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_info() {
+        assert_eq!(
+            HardDiskInfo::parse("CYLS:10,HEADS:2,SECS:16,BPS:512"),
+            Some(HardDiskInfo {
+                cylinders: 10,
+                heads: 2,
+                sectors: 16,
+                bytes_per_sector: 512
+            })
+        );
+        assert_eq!(HardDiskInfo::parse("CYLS:10"), None);
+        let hd = HardDiskInfo::guess(10 * 16 * 63 * 512, 512).unwrap();
+        assert_eq!((hd.cylinders, hd.heads, hd.sectors), (10, 16, 63));
+        assert_eq!(hd.size(), 10 * 16 * 63 * 512);
+        assert_eq!(
+            HardDiskInfo::parse(std::str::from_utf8(&hd.to_metadata()).unwrap()),
+            Some(hd)
+        );
+        assert_eq!(HardDiskInfo::guess(1000, 512), None);
+        assert_eq!(json_string("a\"b\\\n"), "\"a\\\"b\\\\\\u000a\"");
+
+        let raw = include_bytes!("../samples/cdzl.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let info = chd.info().unwrap();
+        assert_eq!(info.version, 5);
+        assert_eq!(info.logical_size, chd.size());
+        assert_eq!(info.tracks.len(), 1);
+        assert_eq!(info.tracks[0].track_type, "AUDIO");
+        assert_eq!(info.disc.as_ref().unwrap().system, "unknown");
+        assert!(info.metadata[0]
+            .text
+            .as_ref()
+            .unwrap()
+            .starts_with("TRACK:1"));
+
+        let mut json = Vec::new();
+        info.write_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("{\n  \"version\": 5,\n"));
+        assert!(json.contains("\"track_type\": \"AUDIO\""));
+        assert!(json.ends_with("}\n"));
+
+        let raw = include_bytes!("../samples/zlib.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let info = chd.info().unwrap();
+        assert!(info.tracks.is_empty());
+        assert_eq!(info.disc, None);
+        assert_eq!(info.compression, chd.compression_names());
+    }
+
+    #[test]
+    fn test_summary() {
+        let raw = include_bytes!("../samples/child.chd");
+        let chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let summary = chd.summary();
+        assert_eq!(summary.logical_size, chd.size());
+        assert_eq!(summary.parent_sha1, Some(chd.parent_sha1_hex()));
+        let text = summary.to_string();
+        assert!(text.starts_with("File size: 672\nCHD version: 5\n"));
+        assert!(text.ends_with(&format!("Parent SHA1: {}\n", chd.parent_sha1_hex())));
+        let mut written = Vec::new();
+        chd.write_summary(&mut written).unwrap();
+        assert_eq!(written, text.as_bytes());
+
+        let raw = include_bytes!("../samples/none.chd");
+        let summary = Chd::open(Cursor::new(&raw[..])).unwrap().summary();
+        assert!(summary.compression.is_empty());
+        assert!(summary.to_string().contains("\nCompression: none\n"));
+    }
+}
@@ -0,0 +1,364 @@
+use super::cd::{read_toc, read_user_data, Track, USER_DATA_SIZE};
+use super::*;
+
+// Minimal ISO9660 reader over a data track of CD chd or over DVD and other chds
+// made of plain 2048 bytes sectors. Only the primary volume descriptor is used,
+// so Joliet and Rock Ridge names are not visible.
+
+const SECTOR_SIZE: u64 = USER_DATA_SIZE as u64;
+// primary volume descriptor follows 16 sectors of system area
+const PVD_SECTOR: u64 = 16;
+const FLAG_DIRECTORY: u8 = 1 << 1;
+
+// Where filesystem sectors are read from
+#[derive(Clone, Debug)]
+pub enum IsoSource {
+    Image,        // logical image as sequence of 2048 bytes sectors
+    Track(Track), // user data of CD data track sectors
+}
+
+impl IsoSource {
+    // 2048 bytes of user data of sector counted from the start of source
+    pub fn read_sector<T: R>(
+        &self,
+        chd: &mut Chd<T>,
+        sector: u64,
+        buf: &mut [u8; USER_DATA_SIZE],
+    ) -> io::Result<()> {
+        match self {
+            IsoSource::Image => chd.read_exact_at(sector * SECTOR_SIZE, buf),
+            IsoSource::Track(track) => read_user_data(chd, track, sector, buf),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IsoEntry {
+    pub path: String, // full path with '/' separators, e.g. "/DIR/FILE.BIN"
+    pub extent: u32,  // first sector
+    pub size: u64,    // bytes of data
+    pub is_dir: bool,
+}
+
+impl IsoEntry {
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or("")
+    }
+}
+
+pub struct IsoFs<'a, T: R> {
+    chd: &'a mut Chd<T>,
+    source: IsoSource,
+    system_id: String,
+    volume_id: String,
+    root: IsoEntry,
+}
+
+impl<'a, T: R> IsoFs<'a, T> {
+    // Filesystem of the first data track of CD chd, or of the whole image otherwise
+    pub fn open(chd: &'a mut Chd<T>) -> io::Result<Self> {
+        let toc = read_toc(chd)?;
+        let source = match toc.is_empty() {
+            true => IsoSource::Image,
+            false => {
+                let track = toc
+                    .into_iter()
+                    .find(|t| t.track_type.user_data_offset().is_some())
+                    .ok_or_else(|| invalid_data_str("iso: cd has no data tracks"))?;
+                IsoSource::Track(track)
+            }
+        };
+        Self::open_source(chd, source)
+    }
+
+    pub fn open_source(chd: &'a mut Chd<T>, source: IsoSource) -> io::Result<Self> {
+        let mut fs = Self {
+            chd,
+            source,
+            system_id: String::new(),
+            volume_id: String::new(),
+            root: IsoEntry {
+                path: String::new(),
+                extent: 0,
+                size: 0,
+                is_dir: true,
+            },
+        };
+        let mut pvd = [0; USER_DATA_SIZE];
+        fs.read_sector(PVD_SECTOR, &mut pvd)?;
+        if pvd[0] != 1 || &pvd[1..6] != b"CD001" {
+            return Err(invalid_data_str("iso: no primary volume descriptor"));
+        }
+        let text = |field: &[u8]| String::from_utf8_lossy(field).trim_end().to_string();
+        fs.system_id = text(&pvd[8..40]);
+        fs.volume_id = text(&pvd[40..72]);
+        let (root, _) = parse_record(&pvd[156..190])?;
+        fs.root.extent = root.extent;
+        fs.root.size = root.size;
+        Ok(fs)
+    }
+
+    // system the volume is meant for, e.g. "PLAYSTATION"
+    pub fn system_id(&self) -> &str {
+        &self.system_id
+    }
+
+    pub fn volume_id(&self) -> &str {
+        &self.volume_id
+    }
+
+    pub fn source(&self) -> &IsoSource {
+        &self.source
+    }
+
+    fn read_sector(&mut self, sector: u64, buf: &mut [u8; USER_DATA_SIZE]) -> io::Result<()> {
+        self.source.read_sector(self.chd, sector, buf)
+    }
+
+    // Entries of directory at path, "/" or "" for root
+    pub fn read_dir(&mut self, path: &str) -> io::Result<Vec<IsoEntry>> {
+        let dir = self.find(path)?;
+        if !dir.is_dir {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("iso: {} is not a directory", path),
+            ));
+        }
+        self.read_dir_entry(&dir)
+    }
+
+    fn read_dir_entry(&mut self, dir: &IsoEntry) -> io::Result<Vec<IsoEntry>> {
+        let mut entries = Vec::new();
+        let mut sector = [0; USER_DATA_SIZE];
+        let sectors = dir.size.div_ceil(SECTOR_SIZE);
+        for i in 0..sectors {
+            self.read_sector(dir.extent as u64 + i, &mut sector)?;
+            let mut pos = 0;
+            // records don't cross sector boundary, zero length pads the rest of sector
+            while pos < USER_DATA_SIZE && sector[pos] != 0 {
+                let (record, len) = parse_record(&sector[pos..])?;
+                pos += len;
+                // "." and ".." are single byte names 0 and 1
+                if record.path == "\0" || record.path == "\x01" {
+                    continue;
+                }
+                entries.push(IsoEntry {
+                    path: format!("{}/{}", dir.path, record.path),
+                    ..record
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    // Entry at path, names are compared ignoring ascii case
+    pub fn find(&mut self, path: &str) -> io::Result<IsoEntry> {
+        let mut entry = self.root.clone();
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            entry = self
+                .read_dir_entry(&entry)?
+                .into_iter()
+                .find(|e| e.name().eq_ignore_ascii_case(name))
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("iso: {} not found", path))
+                })?;
+        }
+        Ok(entry)
+    }
+
+    // All files and directories, parents before their children
+    pub fn list(&mut self) -> io::Result<Vec<IsoEntry>> {
+        let mut result = Vec::new();
+        let mut dirs = vec![self.root.clone()];
+        // guard against directory loops in broken images
+        let mut visited = vec![self.root.extent];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = self.read_dir_entry(&dir)?;
+            for entry in entries.iter().rev() {
+                if entry.is_dir && !visited.contains(&entry.extent) {
+                    visited.push(entry.extent);
+                    dirs.push(entry.clone());
+                }
+            }
+            result.append(&mut entries);
+        }
+        result.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(result)
+    }
+
+    // Write file data to `to`, returns number of bytes written
+    pub fn extract<W: Write>(&mut self, entry: &IsoEntry, to: &mut W) -> io::Result<u64> {
+        if entry.is_dir {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("iso: {} is a directory", entry.path),
+            ));
+        }
+        let mut sector = [0; USER_DATA_SIZE];
+        let mut left = entry.size;
+        let mut lba = entry.extent as u64;
+        while left > 0 {
+            self.read_sector(lba, &mut sector)?;
+            let n = std::cmp::min(left, SECTOR_SIZE) as usize;
+            to.write_all(&sector[..n])?;
+            left -= n as u64;
+            lba += 1;
+        }
+        Ok(entry.size)
+    }
+
+    pub fn read_file(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        let entry = self.find(path)?;
+        let mut data = Vec::with_capacity(entry.size as usize);
+        self.extract(&entry, &mut data)?;
+        Ok(data)
+    }
+}
+
+// Directory record at the start of data and its length. Entry path is just its name.
+fn parse_record(data: &[u8]) -> io::Result<(IsoEntry, usize)> {
+    let len = data[0] as usize;
+    if len < 34 || len > data.len() {
+        return Err(invalid_data(format!(
+            "iso: invalid directory record length {}",
+            len
+        )));
+    }
+    let name_len = data[32] as usize;
+    if 33 + name_len > len {
+        return Err(invalid_data(format!(
+            "iso: directory record name of {} bytes doesn't fit",
+            name_len
+        )));
+    }
+    let name = String::from_utf8_lossy(&data[33..33 + name_len]);
+    // file version and empty extension are not part of the name
+    let name = name.split(';').next().unwrap_or("");
+    let name = match name.len() {
+        1 => name,
+        _ => name.trim_end_matches('.'),
+    };
+    let entry = IsoEntry {
+        path: name.to_string(),
+        extent: u32::from_le_bytes([data[2], data[3], data[4], data[5]]),
+        size: u32::from_le_bytes([data[10], data[11], data[12], data[13]]) as u64,
+        is_dir: data[25] & FLAG_DIRECTORY != 0,
+    };
+    Ok((entry, len))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::cd::TrackType;
+    use crate::tags::metadata;
+    use crate::writer::{ChdWriter, WriterOptions};
+    use std::io::Cursor;
+
+    pub(crate) fn record(to: &mut Vec<u8>, name: &[u8], extent: u32, size: u32, dir: bool) {
+        let len = 33 + name.len() + (name.len() + 1) % 2;
+        let start = to.len();
+        to.resize(start + len, 0);
+        let r = &mut to[start..];
+        r[0] = len as u8;
+        r[2..6].copy_from_slice(&extent.to_le_bytes());
+        r[6..10].copy_from_slice(&extent.to_be_bytes());
+        r[10..14].copy_from_slice(&size.to_le_bytes());
+        r[14..18].copy_from_slice(&size.to_be_bytes());
+        r[25] = if dir { FLAG_DIRECTORY } else { 0 };
+        r[32] = name.len() as u8;
+        r[33..33 + name.len()].copy_from_slice(name);
+    }
+
+    // 24 sectors: pvd at 16, root dir at 18, subdir at 19, file data at 20-22 and 23
+    fn make_iso() -> (Vec<u8>, Vec<u8>) {
+        let file: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let mut iso = vec![0; 24 * USER_DATA_SIZE];
+        let pvd = &mut iso[16 * USER_DATA_SIZE..17 * USER_DATA_SIZE];
+        pvd[0] = 1;
+        pvd[1..6].copy_from_slice(b"CD001");
+        pvd[40..72].copy_from_slice(&[b' '; 32]);
+        pvd[40..44].copy_from_slice(b"TEST");
+        let mut root = Vec::new();
+        record(&mut root, &[0], 18, 2048, true);
+        pvd[156..190].copy_from_slice(&root);
+        record(&mut root, &[1], 18, 2048, true);
+        record(&mut root, b"DIR", 19, 2048, true);
+        record(&mut root, b"README.;1", 23, 3, false);
+        let mut sub = Vec::new();
+        record(&mut sub, &[0], 19, 2048, true);
+        record(&mut sub, &[1], 18, 2048, true);
+        record(&mut sub, b"DATA.BIN;1", 20, file.len() as u32, false);
+        iso[18 * USER_DATA_SIZE..][..root.len()].copy_from_slice(&root);
+        iso[19 * USER_DATA_SIZE..][..sub.len()].copy_from_slice(&sub);
+        iso[20 * USER_DATA_SIZE..][..file.len()].copy_from_slice(&file);
+        iso[23 * USER_DATA_SIZE..][..3].copy_from_slice(b"hi\n");
+        (iso, file)
+    }
+
+    pub(crate) fn make_chd(
+        image: &[u8],
+        hunk_size: u32,
+        unit_size: u32,
+        meta: (u32, &[u8]),
+    ) -> Vec<u8> {
+        let options = WriterOptions::new(image.len() as u64, hunk_size, unit_size);
+        let mut writer = ChdWriter::create(Cursor::new(Vec::new()), &options).unwrap();
+        writer.add_metadata(meta.0, meta.1, true).unwrap();
+        for hunk in image.chunks(hunk_size as usize) {
+            writer.write_hunk(hunk).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn check(fs: &mut IsoFs<Cursor<Vec<u8>>>, file: &[u8]) {
+        assert_eq!(fs.volume_id(), "TEST");
+        let paths: Vec<String> = fs.list().unwrap().into_iter().map(|e| e.path).collect();
+        assert_eq!(paths, ["/DIR", "/DIR/DATA.BIN", "/README"]);
+        assert_eq!(fs.read_dir("/dir").unwrap().len(), 1);
+        assert_eq!(fs.read_file("/DIR/DATA.BIN").unwrap(), file);
+        assert_eq!(fs.read_file("readme").unwrap(), b"hi\n");
+        assert!(fs.find("/nothing").is_err());
+        assert!(fs.read_dir("/README").is_err());
+    }
+
+    #[test]
+    fn test_dvd() {
+        let (iso, file) = make_iso();
+        let chd = make_chd(&iso, 4096, 2048, (metadata::DVD, b""));
+        let mut chd = Chd::open(Cursor::new(chd)).unwrap();
+        let mut fs = IsoFs::open(&mut chd).unwrap();
+        assert!(matches!(fs.source(), IsoSource::Image));
+        check(&mut fs, &file);
+    }
+
+    #[test]
+    fn test_cd() {
+        // MODE1_RAW track: sync and header before user data in every frame
+        let (iso, file) = make_iso();
+        let mut image = Vec::new();
+        for sector in iso.chunks(USER_DATA_SIZE) {
+            let start = image.len();
+            image.resize(start + cd::FRAME_SIZE, 0);
+            image[start..start + 12].copy_from_slice(&cd::SYNC_HEADER);
+            image[start + 16..start + 16 + USER_DATA_SIZE].copy_from_slice(sector);
+        }
+        let meta = b"TRACK:1 TYPE:MODE1_RAW SUBTYPE:NONE FRAMES:24 PREGAP:0 PGTYPE:MODE1 PGSUB:NONE POSTGAP:0\0";
+        let hunk_size = 8 * cd::FRAME_SIZE as u32;
+        let chd = make_chd(
+            &image,
+            hunk_size,
+            cd::FRAME_SIZE as u32,
+            (metadata::CDROM_TRACK2, meta),
+        );
+        let mut chd = Chd::open(Cursor::new(chd)).unwrap();
+        let mut fs = IsoFs::open(&mut chd).unwrap();
+        assert!(matches!(fs.source(), IsoSource::Track(t) if t.track_type == TrackType::Mode1Raw));
+        check(&mut fs, &file);
+
+        // audio only cd has nothing to read
+        let raw = include_bytes!("../samples/cdzl.chd");
+        let mut chd = Chd::open(Cursor::new(raw.to_vec())).unwrap();
+        assert!(IsoFs::open(&mut chd).is_err());
+    }
+}
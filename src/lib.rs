@@ -1,22 +1,54 @@
 extern crate sha1;
 
-mod bitstream;
+pub mod archive;
+pub mod bench;
+pub mod bitstream;
+pub mod cache;
 pub mod cd;
+pub mod cdimage;
+mod compress;
+pub mod dat;
 mod decompress;
-mod ecc;
-mod huffman;
-mod lzma;
+pub mod ecc;
+mod flac;
+#[cfg(feature = "fuse")]
+pub mod fuse;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod huffman;
+pub mod ident;
+pub mod info;
+pub mod iso;
+#[cfg(feature = "lzma")]
+pub mod lzma;
+pub mod nbd;
+pub mod parents;
+pub mod redump;
+pub mod shared;
 pub mod tags;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
 pub mod utils;
-use bitstream::BitReader;
-use decompress::DecompressType;
-use huffman::Huffman;
+pub mod writer;
+pub use bench::bench;
+use bitstream::{BitReader, BitWriter, MAX_BITS};
+use cache::{HunkCache, SingleCache};
+pub use decompress::Decompress;
+use decompress::{Codecs, Note};
+use huffman::{Huffman, HuffmanEncoder};
+pub use info::ChdInfo;
+pub use parents::{verify_many, VerifyOptions, VerifyResult};
+pub use shared::ChdShared;
 use tags::*;
+pub use utils::BufferPool;
 use utils::*;
 
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::io;
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::{mpsc, Arc};
 
 // Define constraints for underlaying Chd file I/O
 pub trait R: Read + Seek {}
@@ -62,14 +94,45 @@ const MDFLAGS_CHECKSUM: u8 = 1;
 type MapHunk = (u8, u64, u32);
 
 // Different drive versions have different map format
-trait Map {
-    fn locate(&self, hunknum: usize) -> MapHunk;
+trait Map: Send + Sync {
+    fn locate(&self, hunknum: usize) -> io::Result<MapHunk>;
+    fn hunk_count(&self) -> usize;
     // Different versions use different digest algorithm
     fn validate(&self, hunknum: usize, buf: &[u8]) -> io::Result<()>;
+    // Checksum stored for hunk data, if any
+    fn crc(&self, hunknum: usize) -> Option<u16>;
+    // bytes held by decoded map
+    fn memory_usage(&self) -> usize;
+    // Only uncompressed map can be changed by in place writes
+    fn as_uncompressed_mut(&mut self) -> Option<&mut UncompressedMap5> {
+        None
+    }
 }
 
 type MapType = Box<dyn Map>;
 
+// Follow references to other hunks to the hunk with data or parent reference.
+// Corrupt map could have them in a loop, so there can't be more than hunk count.
+fn resolve_self(map: &dyn Map, hunknum: usize) -> io::Result<usize> {
+    let mut target = hunknum;
+    for _ in 0..=map.hunk_count() {
+        let (compression, offset, _) = map.locate(target)?;
+        if compression != COMPRESSION_SELF {
+            return Ok(target);
+        }
+        if offset >= map.hunk_count() as u64 {
+            return Err(invalid_data(format!(
+                "hunk#{} refers to hunk#{} of {}",
+                target,
+                offset,
+                map.hunk_count()
+            )));
+        }
+        target = offset as usize;
+    }
+    Err(invalid_data_str("self reference loop"))
+}
+
 #[derive(Default)]
 struct Header {
     // V5 fields
@@ -90,7 +153,17 @@ struct Header {
 }
 
 impl Header {
-    fn read<T: R>(io: &mut T) -> io::Result<(Self, MapType)> {
+    fn read<T: R>(io: &mut T, budget: &mut MemoryBudget) -> io::Result<(Self, MapType)> {
+        let header = Self::read_header(io)?;
+        let map = match header.compressors[0] {
+            0 => UncompressedMap5::read(io, &header, budget),
+            _ => CompressedMap5::read(io, &header, budget),
+        }?;
+        Ok((header, map))
+    }
+
+    // header fields only, without the map
+    fn read_header<T: R>(io: &mut T) -> io::Result<Self> {
         let mut data = [0u8; 124];
         io.read_at(0, &mut data)?;
 
@@ -99,22 +172,40 @@ impl Header {
             return Err(invalid_data(format!("chd: invalid magic {:02x?}", magic)));
         }
 
-        let mut header = Header::default();
-        header.length = read_be32(&data[8..12]);
-        header.version = read_be32(&data[12..16]);
+        let mut header = Header {
+            length: data.get_be32(8)?,
+            version: data.get_be32(12)?,
+            ..Default::default()
+        };
         match header.version {
             V5 => {
                 header.read_header_v5(&data)?;
-                let map = match header.compressors[0] {
-                    0 => UncompressedMap5::read(io, &header),
-                    _ => CompressedMap5::read(io, &header),
-                }?;
-                Ok((header, map))
+                Ok(header)
             }
             x => Err(invalid_data(format!("chd: unsupported version {}", x))),
         }
     }
 
+    // V5 header in file format, reverse of read_header_v5
+    fn to_bytes(&self) -> io::Result<[u8; 124]> {
+        let mut data = [0; 124];
+        data[0..8].copy_from_slice(b"MComprHD");
+        data.put_be32(8, self.length)?;
+        data.put_be32(12, self.version)?;
+        for (i, tag) in self.compressors.iter().enumerate() {
+            data.put_be32(16 + 4 * i, *tag)?;
+        }
+        data.put_be64(32, self.size)?;
+        data.put_be64(40, self.mapoffset)?;
+        data.put_be64(48, self.metaoffset)?;
+        data.put_be32(56, self.hunkbytes)?;
+        data.put_be32(60, self.unitbytes)?;
+        data[64..84].copy_from_slice(&self.rawsha1);
+        data[84..104].copy_from_slice(&self.sha1);
+        data[104..124].copy_from_slice(&self.parentsha1);
+        Ok(data)
+    }
+
     fn read_header_v5(&mut self, data: &[u8]) -> io::Result<()> {
         if self.length != 124 {
             return Err(invalid_data(format!(
@@ -122,15 +213,15 @@ impl Header {
                 self.length
             )));
         }
-        self.compressors[0] = read_be32(&data[16..20]);
-        self.compressors[1] = read_be32(&data[20..24]);
-        self.compressors[2] = read_be32(&data[24..28]);
-        self.compressors[3] = read_be32(&data[28..32]);
-        self.size = read_be64(&data[32..40]);
-        self.mapoffset = read_be64(&data[40..48]);
-        self.metaoffset = read_be64(&data[48..56]);
-        self.hunkbytes = read_be32(&data[56..60]);
-        self.unitbytes = read_be32(&data[60..64]);
+        self.compressors[0] = data.get_be32(16)?;
+        self.compressors[1] = data.get_be32(20)?;
+        self.compressors[2] = data.get_be32(24)?;
+        self.compressors[3] = data.get_be32(28)?;
+        self.size = data.get_be64(32)?;
+        self.mapoffset = data.get_be64(40)?;
+        self.metaoffset = data.get_be64(48)?;
+        self.hunkbytes = data.get_be32(56)?;
+        self.unitbytes = data.get_be32(60)?;
         copy_from(&mut self.rawsha1, &data[64..84]);
         copy_from(&mut self.sha1, &data[84..104]);
         copy_from(&mut self.parentsha1, &data[104..124]);
@@ -144,7 +235,7 @@ impl Header {
         }
         if self.unitbytes < 1
             || self.hunkbytes < self.unitbytes
-            || self.hunkbytes % self.unitbytes > 0
+            || !self.hunkbytes.is_multiple_of(self.unitbytes)
         {
             return Err(invalid_data(format!(
                 "hdrv5: wrong size of unit {} (hunk size {})",
@@ -152,7 +243,7 @@ impl Header {
             )));
         }
         let hunkbytes = self.hunkbytes as u64;
-        let hunkcount = (self.size + hunkbytes - 1) / hunkbytes;
+        let hunkcount = self.size.div_ceil(hunkbytes);
         self.hunkcount = u32::try_from(hunkcount).map_err(|_| {
             invalid_data(format!(
                 "hdrv5: hunk count {} for size {} is too big",
@@ -165,7 +256,9 @@ impl Header {
 
 struct UncompressedMap5 {
     hunkbytes: u64,
-    map: Vec<u8>, // uncompressed hunk map
+    unitbytes: u64,
+    map: Vec<u8>,                    // uncompressed hunk map
+    refs: Option<HashMap<u32, u32>>, // map entries per stored hunk, counted on first write
 }
 
 impl UncompressedMap5 {
@@ -174,30 +267,86 @@ impl UncompressedMap5 {
         V5 uncompressed map format:
 
         [  0] uint32_t offset;        // starting offset / hunk size
+
+        Zero offset means the same hunk in parent or zeroes if there's no parent
         */
         4 * hunknum
     }
 
-    fn read<T: R>(io: &mut T, header: &Header) -> io::Result<MapType> {
+    fn read<T: R>(io: &mut T, header: &Header, budget: &mut MemoryBudget) -> io::Result<MapType> {
         let hunkcount = header.hunkcount as usize;
+        budget.reserve("map", Self::offset(hunkcount))?;
         let mut map = vec![0; Self::offset(hunkcount)];
         io.read_at(header.mapoffset, &mut map)?;
         Ok(Box::new(Self {
             hunkbytes: header.hunkbytes as u64,
+            unitbytes: header.unitbytes as u64,
             map,
+            refs: None,
         }))
     }
+
+    fn entry(&self, hunknum: usize) -> io::Result<u32> {
+        self.map.get_be32(Self::offset(hunknum))
+    }
+
+    // Stored hunk which isn't used by other map entries, so it can be overwritten in place
+    fn owned(&mut self, hunknum: usize) -> io::Result<Option<u32>> {
+        let entry = self.entry(hunknum)?;
+        if entry == 0 {
+            return Ok(None);
+        }
+        let map = &self.map;
+        let refs = self.refs.get_or_insert_with(|| {
+            let mut refs = HashMap::new();
+            let entries = map.chunks_exact(4).filter_map(|e| e.get_be32(0).ok());
+            for entry in entries.filter(|e| *e != 0) {
+                *refs.entry(entry).or_insert(0) += 1;
+            }
+            refs
+        });
+        match refs.get(&entry) {
+            Some(1) => Ok(Some(entry)),
+            _ => Ok(None),
+        }
+    }
+
+    fn set_entry(&mut self, hunknum: usize, entry: u32) -> io::Result<()> {
+        let previous = self.entry(hunknum)?;
+        if let Some(refs) = &mut self.refs {
+            if let Some(count) = refs.get_mut(&previous) {
+                *count -= 1;
+            }
+            *refs.entry(entry).or_insert(0) += 1;
+        }
+        self.map.put_be32(Self::offset(hunknum), entry)
+    }
 }
 
 impl Map for UncompressedMap5 {
-    fn locate(&self, hunknum: usize) -> MapHunk {
-        let offs = Self::offset(hunknum);
-        let offset = read_be32(&self.map[offs..offs + 4]) as u64;
-        (
-            COMPRESSION_NONE,
-            offset * self.hunkbytes,
-            self.hunkbytes as u32,
-        )
+    fn hunk_count(&self) -> usize {
+        self.map.len() / Self::offset(1)
+    }
+
+    fn memory_usage(&self) -> usize {
+        let refs = self.refs.as_ref().map_or(0, |refs| refs.capacity());
+        self.map.capacity() + refs * std::mem::size_of::<(u32, u32)>()
+    }
+
+    fn locate(&self, hunknum: usize) -> io::Result<MapHunk> {
+        let offset = self.entry(hunknum)? as u64;
+        Ok(match offset {
+            0 => (
+                COMPRESSION_PARENT,
+                hunknum as u64 * self.hunkbytes / self.unitbytes,
+                0,
+            ),
+            _ => (
+                COMPRESSION_NONE,
+                offset * self.hunkbytes,
+                self.hunkbytes as u32,
+            ),
+        })
     }
 
     fn validate(&self, _hunknum: usize, _buf: &[u8]) -> io::Result<()> {
@@ -205,6 +354,14 @@ impl Map for UncompressedMap5 {
             "Uncompressed map has no checksum for hunk",
         ))
     }
+
+    fn crc(&self, _hunknum: usize) -> Option<u16> {
+        None
+    }
+
+    fn as_uncompressed_mut(&mut self) -> Option<&mut UncompressedMap5> {
+        Some(self)
+    }
 }
 
 struct CompressedMap5 {
@@ -224,23 +381,37 @@ impl CompressedMap5 {
         12 * hunknum
     }
 
-    fn read<T: R>(io: &mut T, header: &Header) -> io::Result<MapType> {
+    fn read<T: R>(io: &mut T, header: &Header, budget: &mut MemoryBudget) -> io::Result<MapType> {
         let mut maphdr = [0; 16];
         io.read_at(header.mapoffset, &mut maphdr)?;
 
-        let maplength = read_be32(&maphdr[0..4]);
-        let mut comprmap = vec![0; maplength as usize];
-        io.read_exact(comprmap.as_mut_slice())?;
+        let maplength = maphdr.get_be32(0)? as usize;
+        budget.reserve("compressed map", maplength)?;
+        budget.reserve("map", Self::offset(header.hunkcount as usize))?;
+        let mut comprmap = vec![0; maplength];
+        io.read_at(
+            header.mapoffset + maphdr.len() as u64,
+            comprmap.as_mut_slice(),
+        )?;
 
-        Ok(Box::new(Self::decompress(header, &maphdr, &comprmap)?))
+        let map = Self::decompress(header, &maphdr, &comprmap)?;
+        budget.release(maplength);
+        Ok(Box::new(map))
     }
 
     fn decompress(header: &Header, maphdr: &[u8], comprmap: &[u8]) -> io::Result<Self> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "map decompress",
+            hunkcount = header.hunkcount,
+            length = comprmap.len()
+        )
+        .entered();
         let hunkcount = header.hunkcount as usize;
         let hunkbytes = header.hunkbytes;
         let unitbytes = header.unitbytes;
 
-        let mut bits = BitReader::new(&comprmap);
+        let mut bits = BitReader::new(comprmap);
         let mut huffman = Huffman::new(16, 8);
         huffman.import_tree_rle(&mut bits)?;
 
@@ -274,7 +445,7 @@ impl CompressedMap5 {
         let hunkbits = Self::bit_length(maphdr[13])?;
         let parentbits = Self::bit_length(maphdr[14])?;
 
-        let mut curoffset = read_be48(&maphdr[4..10]);
+        let mut curoffset = maphdr.get_be48(4)?;
         let mut lastself = 0;
         let mut lastparent = 0;
         for hunknum in 0..hunkcount {
@@ -301,7 +472,7 @@ impl CompressedMap5 {
                     lastself = offset;
                 }
                 COMPRESSION_PARENT => {
-                    offset = bits.read(parentbits as usize) as u64;
+                    offset = bits.read(parentbits) as u64;
                     lastparent = offset;
                 }
                 // pseudo-types; convert into base types
@@ -329,11 +500,11 @@ impl CompressedMap5 {
                     )))
                 }
             }
-            write_be24(&mut mapentry[1..4], length);
-            write_be48(&mut mapentry[4..10], offset);
-            write_be16(&mut mapentry[10..12], crc);
+            mapentry.put_be24(1, length)?;
+            mapentry.put_be48(4, offset)?;
+            mapentry.put_be16(10, crc)?;
         }
-        let crc = read_be16(&maphdr[10..12]);
+        let crc = maphdr.get_be16(10)?;
         let calc = crc16(&map);
         if calc != crc {
             return Err(invalid_data(format!(
@@ -344,6 +515,153 @@ impl CompressedMap5 {
         Ok(Self { map })
     }
 
+    // Reverse of decompress: map header followed by compressed map. Entries are
+    // expanded ones as decompress makes them, stored hunks must follow each other
+    // in hunk order.
+    fn compress(header: &Header, map: &[u8]) -> io::Result<Vec<u8>> {
+        let hunkcount = header.hunkcount as usize;
+        let hunkunits = (header.hunkbytes / header.unitbytes) as u64;
+
+        // pseudo-types of entries
+        let mut types = Vec::with_capacity(hunkcount);
+        let mut firstoffset = None;
+        let mut curoffset = 0;
+        let mut lastself = 0;
+        let mut lastparent = 0;
+        let (mut maxlength, mut maxself, mut maxparent) = (0, 0, 0);
+        for hunknum in 0..hunkcount {
+            let o = Self::offset(hunknum);
+            let length = map.get_be24(o + 1)?;
+            let offset = map.get_be48(o + 4)?;
+            let compression = match map[o] {
+                COMPRESSION_SELF => {
+                    let compression = match offset {
+                        x if x == lastself => COMPRESSION_SELF_0,
+                        x if x == lastself + 1 => COMPRESSION_SELF_1,
+                        x => {
+                            maxself = maxself.max(x);
+                            COMPRESSION_SELF
+                        }
+                    };
+                    lastself = offset;
+                    compression
+                }
+                COMPRESSION_PARENT => {
+                    let compression = match offset {
+                        x if x == hunknum as u64 * hunkunits => COMPRESSION_PARENT_SELF,
+                        x if x == lastparent => COMPRESSION_PARENT_0,
+                        x if x == lastparent + hunkunits => COMPRESSION_PARENT_1,
+                        x => {
+                            maxparent = maxparent.max(x);
+                            COMPRESSION_PARENT
+                        }
+                    };
+                    lastparent = offset;
+                    compression
+                }
+                compression @ COMPRESSION_TYPE_0..=COMPRESSION_NONE => {
+                    let expected = *firstoffset.get_or_insert(offset) + curoffset;
+                    if offset != expected
+                        || (compression == COMPRESSION_NONE && length != header.hunkbytes)
+                    {
+                        return Err(invalid_data(format!(
+                            "chdv5: hunk#{} of {} bytes at {} doesn't follow previous one at {}",
+                            hunknum, length, offset, expected
+                        )));
+                    }
+                    curoffset += length as u64;
+                    maxlength = maxlength.max(length);
+                    compression
+                }
+                x => {
+                    return Err(invalid_data(format!(
+                        "chdv5: unknown hunk#{} compression type {}",
+                        hunknum, x
+                    )))
+                }
+            };
+            types.push(compression);
+        }
+
+        // runs of the same type are RLE coded
+        let mut codes = Vec::new();
+        let mut hunknum = 0;
+        while hunknum < hunkcount {
+            let value = types[hunknum];
+            let run = types[hunknum..].iter().take_while(|&&t| t == value).count();
+            codes.push(value as u32);
+            let mut left = run - 1;
+            while left > 0 {
+                match left {
+                    1..=2 => {
+                        codes.push(value as u32);
+                        left -= 1;
+                    }
+                    3..=18 => {
+                        codes.extend([COMPRESSION_RLE_SMALL as u32, left as u32 - 3]);
+                        left = 0;
+                    }
+                    _ => {
+                        let count = left.min(19 + 0xff) as u32 - 19;
+                        codes.extend([COMPRESSION_RLE_LARGE as u32, count >> 4, count & 0xf]);
+                        left -= count as usize + 19;
+                    }
+                }
+            }
+            hunknum += run;
+        }
+        let mut huffman = HuffmanEncoder::new(16, 8);
+        for &code in &codes {
+            huffman.histo_one(code);
+        }
+        huffman.compute_tree_from_histo()?;
+        let mut bits = BitWriter::new();
+        huffman.export_tree_rle(&mut bits);
+        for &code in &codes {
+            huffman.encode_one(&mut bits, code)?;
+        }
+
+        // then data of entries which isn't implied by their types
+        let bit_length = |val: u64| -> io::Result<usize> {
+            match (u64::BITS - val.leading_zeros()) as usize {
+                x if x <= MAX_BITS => Ok(x),
+                x => Err(invalid_data(format!(
+                    "chdv5: map value {} needs {} bits",
+                    val, x
+                ))),
+            }
+        };
+        let lengthbits = bit_length(maxlength as u64)?;
+        let hunkbits = bit_length(maxself)?;
+        let parentbits = bit_length(maxparent)?;
+        for (hunknum, compression) in types.iter().enumerate() {
+            let o = Self::offset(hunknum);
+            let offset = map.get_be48(o + 4)? as u32;
+            let crc = map.get_be16(o + 10)? as u32;
+            match *compression {
+                COMPRESSION_TYPE_0..=COMPRESSION_TYPE_3 => {
+                    bits.write(map.get_be24(o + 1)?, lengthbits);
+                    bits.write(crc, 16);
+                }
+                COMPRESSION_NONE => bits.write(crc, 16),
+                COMPRESSION_SELF => bits.write(offset, hunkbits),
+                COMPRESSION_PARENT => bits.write(offset, parentbits),
+                _ => (),
+            }
+        }
+        let comprmap = bits.finish();
+
+        let mut data = vec![0; 16];
+        data.put_be32(0, comprmap.len() as u32)?;
+        data.put_be48(4, firstoffset.unwrap_or(0))?;
+        data.put_be16(10, crc16(map))?;
+        data[12] = lengthbits as u8;
+        data[13] = hunkbits as u8;
+        data[14] = parentbits as u8;
+        data.extend(comprmap);
+        Ok(data)
+    }
+
     fn bit_length(val: u8) -> io::Result<usize> {
         match val {
             32..=u8::MAX => Err(invalid_data(format!(
@@ -356,101 +674,274 @@ impl CompressedMap5 {
 }
 
 impl Map for CompressedMap5 {
-    fn locate(&self, hunknum: usize) -> MapHunk {
+    fn hunk_count(&self) -> usize {
+        self.map.len() / Self::offset(1)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.map.capacity()
+    }
+
+    fn locate(&self, hunknum: usize) -> io::Result<MapHunk> {
         let o = Self::offset(hunknum);
-        (
-            self.map[o],
-            read_be48(&self.map[o + 4..o + 10]),
-            read_be24(&self.map[o + 1..o + 4]),
-        )
+        let offset = self.map.get_be48(o + 4)?;
+        let length = self.map.get_be24(o + 1)?;
+        Ok((self.map[o], offset, length))
+    }
+
+    fn crc(&self, hunknum: usize) -> Option<u16> {
+        let o = Self::offset(hunknum);
+        match self.map.get(o)? {
+            &COMPRESSION_SELF | &COMPRESSION_PARENT => None,
+            _ => self.map.get_be16(o + 10).ok(),
+        }
     }
 
     fn validate(&self, hunknum: usize, buf: &[u8]) -> io::Result<()> {
         let o = Self::offset(hunknum);
-        let crc = read_be16(&self.map[o + 10..o + 12]);
+        let crc = self.map.get_be16(o + 10)?;
         let calc = crc16(buf);
         match calc == crc {
             true => Ok(()),
             false => Err(invalid_data(format!(
-                "hunk#{}: crc16 {:04x} doesn't match map {:04x}",
-                hunknum, calc, crc
+                "crc16 {:04x} doesn't match map {:04x}",
+                calc, crc
             ))),
         }
     }
 }
 
+// decompress hunk data already read from file
+fn decompress_data(
+    maphunk: MapHunk,
+    decompress: &mut Codecs,
+    stat: &mut Stat,
+    compbuf: &[u8],
+    buf: &mut [u8],
+) -> io::Result<()> {
+    let compression = maphunk.0;
+    let dindex = (compression - COMPRESSION_TYPE_0) as usize;
+    let tag = decompress.tags[dindex];
+    let (d, pool) = decompress.get(dindex).ok_or(invalid_data(format!(
+        "no decompressor #{} ({}) for {}",
+        dindex,
+        tag_string(tag),
+        compression
+    )))?;
+    let timer = Timer::start();
+    let result = d.decompress_pooled(compbuf, buf, pool);
+    let elapsed = timer.elapsed();
+    stat.codecs[dindex].add_call(elapsed);
+    result?;
+    let fallback = d.take_fallback();
+    #[cfg(feature = "tracing")]
+    tracing::trace!(
+        codec = %tag_string(tag),
+        offset = maphunk.1,
+        length = compbuf.len(),
+        ?elapsed,
+        "hunk decompressed"
+    );
+    stat.codecs[dindex].add(compbuf.len(), buf.len());
+    if let Some(reason) = fallback {
+        decompress.note(Note::Fallback(tag, reason));
+    }
+    Ok(())
+}
+
 fn decompress_hunk<T: R>(
     io: &mut T,
     maphunk: MapHunk,
-    dindex: usize,
-    decompress: &mut [DecompressType],
+    decompress: &mut Codecs,
+    stat: &mut Stat,
     buf: &mut [u8],
 ) -> io::Result<()> {
-    let (compression, offset, length) = maphunk;
-    let d = decompress[dindex]
-        .as_deref_mut()
-        .ok_or(invalid_data(format!(
-            "hunk@{}: no decompressor #{} for {}",
-            offset, dindex, compression
-        )))?;
-    let mut compbuf = vec![0; length as usize];
-    io.read_at(offset, compbuf.as_mut_slice())?;
-    d.decompress(&compbuf, buf)
+    let (_, offset, length) = maphunk;
+    let mut compbuf = decompress.pool.take(length as usize);
+    let result = io
+        .read_at_stat(offset, compbuf.as_mut_slice(), &mut stat.raw)
+        .and_then(|_| decompress_data(maphunk, decompress, stat, &compbuf, buf));
+    decompress.pool.give(compbuf);
+    result
 }
 
-fn deref_parent<T: R>(parent: &mut ParentType<T>, offset: u64) -> io::Result<&mut Chd<T>> {
-    parent.as_deref_mut().ok_or(invalid_data(format!(
-        "hunk@{}: requires parent chd",
-        offset
-    )))
+// metadata entry contribution to overall sha1: tag followed by sha1 of data
+fn metadata_hash(metatag: u32, data: &[u8]) -> [u8; 24] {
+    let digest = sha1::Sha1::from(data).digest().bytes();
+    let mut buf = [0; 24];
+    buf[..4].copy_from_slice(&metatag.to_be_bytes());
+    copy_from(&mut buf[4..], &digest);
+    buf
+}
+
+// combined sha1 of raw data and checksummed metadata entries
+fn overall_sha1(rawsha1: &[u8; 20], mut metasha: Vec<[u8; 24]>) -> [u8; 20] {
+    metasha.sort();
+    let mut sha1 = sha1::Sha1::new();
+    sha1.update(rawsha1);
+    for s in metasha.into_iter() {
+        sha1.update(&s);
+    }
+    sha1.digest().bytes()
+}
+
+// attach parent through resolver if it wasn't attached yet
+fn resolve_parent(parent: &mut ParentType) -> io::Result<()> {
+    if parent.chd.is_none() {
+        if let Some(resolver) = parent.resolver.clone() {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(sha1 = %hex_string(&parent.sha1), "resolving parent");
+            let mut chd = Chd::open(resolver(&parent.sha1)?)?;
+            chd.parent.resolver = Some(resolver);
+            parent.attach(chd)?;
+        }
+    }
+    Ok(())
+}
+
+// read hunk's worth of units starting at unit `offset` in parent
+fn read_parent(
+    parent: &mut ParentType,
+    decompress: &mut Codecs,
+    stat: &mut Stat,
+    offset: u64,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    if parent.sha1 == [0; 20] {
+        // chd without parent stores zero hunks this way
+        buf.fill(0);
+        return Ok(());
+    }
+    resolve_parent(parent)?;
+    let parent_chd = parent
+        .chd
+        .as_deref_mut()
+        .ok_or(invalid_data_str("requires parent chd"))?;
+    let parent_offs = offset * parent_chd.unit_size_u64();
+    if parent_offs >= parent_chd.size() {
+        return Err(invalid_data(format!(
+            "parent unit {} is past parent end of {} units",
+            offset,
+            parent_chd.size() / parent_chd.unit_size_u64()
+        )));
+    }
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("parent read", offset = parent_offs).entered();
+    if let Some(checked) = &mut parent.checked {
+        // forced parent: check crc of each parent hunk the first time it's used
+        let hunkbytes = parent_chd.header.hunkbytes as u64;
+        let end = std::cmp::min(parent_offs + buf.len() as u64, parent_chd.size());
+        let first = (parent_offs / hunkbytes) as usize;
+        let last = end.div_ceil(hunkbytes) as usize;
+        for (hunknum, checked) in checked.iter_mut().enumerate().take(last).skip(first) {
+            if !*checked {
+                match parent_chd.map.crc(hunknum) {
+                    Some(_) => parent_chd.validate_hunk(hunknum)?,
+                    None => decompress.note(Note::Warning(format!(
+                        "parent hunk#{} has no crc, its data isn't checked",
+                        hunknum
+                    ))),
+                }
+            }
+            *checked = true;
+        }
+    }
+    // partial read is OK, last hunk in parent could be shorter than hunksize,
+    // the rest of child hunk past parent end is zeroes
+    parent_chd.seek(SeekFrom::Start(parent_offs))?;
+    let size = parent_chd.read(buf)?;
+    buf[size..].fill(0);
+    stat.parent.add(size);
+    Ok(())
 }
 
 fn read_hunk_at<T: R>(
     io: &mut T,
     map: &dyn Map,
-    decompress: &mut [DecompressType],
-    parent: &mut ParentType<T>,
+    decompress: &mut Codecs,
+    parent: &mut ParentType,
+    stat: &mut Stat,
     maphunk: MapHunk,
-    hunksize: usize,
     buf: &mut [u8],
 ) -> io::Result<()> {
     let (compression, offset, _) = maphunk;
     match compression {
-        COMPRESSION_NONE => io.read_at(offset, buf),
-        COMPRESSION_SELF => read_hunk(io, map, decompress, parent, offset as usize, hunksize, buf),
-        COMPRESSION_PARENT => {
-            let parent_chd = deref_parent(parent, offset)?;
-            let parent_offs = offset * parent_chd.unit_size_u64();
-            // partial read is OK, last hunk in parent could be shorter than hunksize
-            parent_chd.seek(SeekFrom::Start(parent_offs))?;
-            parent_chd.read(buf)?;
-            Ok(())
-        }
+        COMPRESSION_NONE => io.read_at_stat(offset, buf, &mut stat.raw),
+        COMPRESSION_SELF => read_hunk(io, map, decompress, parent, stat, offset as usize, buf),
+        COMPRESSION_PARENT => read_parent(parent, decompress, stat, offset, buf),
         COMPRESSION_TYPE_0 | COMPRESSION_TYPE_1 | COMPRESSION_TYPE_2 | COMPRESSION_TYPE_3 => {
-            let dindex = (compression - COMPRESSION_TYPE_0) as usize;
-            decompress_hunk(io, maphunk, dindex, decompress, buf)
+            decompress_hunk(io, maphunk, decompress, stat, buf)
         }
-        x => Err(invalid_data(format!(
-            "hunk@{}: unsupported compression {}",
-            offset, x
-        ))),
+        x => Err(invalid_data(format!("unsupported compression {}", x))),
     }
 }
 
-// read_hunk needs both Chd.io and Chd.cache mutable in Chd::read().
+// read_hunk needs both Chd.io and Chd.hunkbuf mutable in Chd::read().
 // to satisfy borrow checker have to move it into free function
 fn read_hunk<T: R>(
     io: &mut T,
     map: &dyn Map,
-    decompress: &mut [DecompressType],
-    parent: &mut ParentType<T>,
+    decompress: &mut Codecs,
+    parent: &mut ParentType,
+    stat: &mut Stat,
     hunknum: usize,
-    hunksize: usize,
     buf: &mut [u8],
 ) -> io::Result<()> {
-    assert_eq!(buf.len(), hunksize);
-    let maphunk = map.locate(hunknum);
-    read_hunk_at(io, map, decompress, parent, maphunk, hunksize, buf)
+    let maphunk = resolve_self(map, hunknum)
+        .and_then(|target| map.locate(target))
+        .map_err(|e| hunk_error(hunknum, None, e))?;
+    let result = read_hunk_at(io, map, decompress, parent, stat, maphunk, buf)
+        .map_err(|e| hunk_error(hunknum, Some(maphunk), e));
+    decompress.report(hunknum);
+    result
+}
+
+// Failure to read or check hunk. Codec and io errors don't know which hunk they
+// are about, so they are wrapped with it and where its data is.
+#[derive(Debug)]
+pub struct HunkError {
+    pub hunk: usize,
+    pub offset: Option<u64>, // in file of data stored in chd
+    pub unit: Option<u64>,   // the first parent unit of Parent hunk
+    pub error: io::Error,    // the original error, e.g. of codec
+}
+
+impl HunkError {
+    // Hunk error wrapped by io error, if it is one
+    pub fn find(e: &io::Error) -> Option<&HunkError> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl std::fmt::Display for HunkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "hunk#{}: {}", self.hunk, self.error)
+    }
+}
+
+impl std::error::Error for HunkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// Wrap error into HunkError of the hunk at map entry, unless it's already one
+fn hunk_error(hunknum: usize, maphunk: Option<MapHunk>, e: io::Error) -> io::Error {
+    if HunkError::find(&e).is_some() {
+        return e;
+    }
+    let (offset, unit) = match maphunk {
+        Some((COMPRESSION_PARENT, unit, _)) => (None, Some(unit)),
+        Some((COMPRESSION_SELF, _, _)) | None => (None, None),
+        Some((_, offset, _)) => (Some(offset), None),
+    };
+    let error = HunkError {
+        hunk: hunknum,
+        offset,
+        unit,
+        error: e,
+    };
+    io::Error::new(error.error.kind(), error)
 }
 
 #[derive(Clone, Copy)]
@@ -466,654 +957,3196 @@ impl MetadataEntry {
     const SIZE: usize = 16;
 }
 
-type ParentType<T> = Option<Box<Chd<T>>>;
-
-pub struct Chd<T: R> {
-    header: Header,
-    filesize: u64,
-    pos: i64,
-    io: T,
-    map: Box<dyn Map>,
-    decompress: [DecompressType; 4],
-    cache: Vec<u8>,   // cached data for reads not aligned to hunk boundaries
-    cachehunk: usize, // cached hunk index
-    cachemeta: Option<(u32, MetadataEntry)>, // cached metadata entry
-    parent: ParentType<T>,
+// Walk over linked list of metadata entries. Links and lengths come from file,
+// so loops, entries past file end and chains longer than file are errors.
+struct MetadataChain {
+    offset: u64,           // of the next entry, 0 at the end
+    filesize: u64,         // entries must be within file
+    visited: HashSet<u64>, // offsets of entries, to detect loops
+    total: u64,            // bytes of entries so far
 }
 
-impl<T: R> Chd<T> {
-    pub fn open(mut io: T) -> io::Result<Chd<T>> {
-        let (header, map) = Header::read(&mut io)?;
-        let decompress = decompress::init(&header);
-        let filesize = io.seek(SeekFrom::End(0))?;
-        let hunksize = header.hunkbytes as usize;
-        let chd = Chd {
-            header,
+impl MetadataChain {
+    const MAX_ENTRIES: usize = 1 << 16;
+
+    fn new(offset: u64, filesize: u64) -> Self {
+        Self {
+            offset,
             filesize,
-            pos: 0,
-            io,
-            map,
-            decompress,
-            cache: vec![0; hunksize],
-            cachehunk: usize::MAX, // definitely out of any hunk index value
-            cachemeta: None,
-            parent: None,
+            visited: HashSet::new(),
+            total: 0,
+        }
+    }
+
+    fn next<T: R>(&mut self, io: &mut T) -> io::Result<Option<MetadataEntry>> {
+        if self.offset == 0 {
+            return Ok(None);
+        }
+        if self.visited.len() >= Self::MAX_ENTRIES {
+            return Err(invalid_data(format!(
+                "metadata: more than {} entries",
+                Self::MAX_ENTRIES
+            )));
+        }
+        if !self.visited.insert(self.offset) {
+            return Err(invalid_data(format!(
+                "metadata: entry at {} links back into the chain",
+                self.offset
+            )));
+        }
+        let mut header = [0; MetadataEntry::SIZE];
+        io.read_at(self.offset, &mut header)?;
+        let entry = MetadataEntry {
+            metatag: header.get_be32(0)?,
+            offset: self.offset + MetadataEntry::SIZE as u64,
+            next: header.get_be64(8)?,
+            length: header.get_be24(5)?,
+            flags: header[4],
         };
-        Ok(chd)
+        if entry.offset + entry.length as u64 > self.filesize {
+            return Err(invalid_data(format!(
+                "metadata: {} bytes of entry at {} are past file end {}",
+                entry.length, self.offset, self.filesize
+            )));
+        }
+        self.total += MetadataEntry::SIZE as u64 + entry.length as u64;
+        if self.total > self.filesize {
+            return Err(invalid_data(format!(
+                "metadata: entries have more than {} bytes of file",
+                self.filesize
+            )));
+        }
+        self.offset = entry.next;
+        Ok(Some(entry))
+    }
+}
+
+// Parent chd can use any reader, not necessarily the same as the child
+pub type ParentReader = Box<dyn R + Send>;
+// Provides reader of parent chd with given sha1 when it's needed for the first time
+pub type ParentResolver = Arc<dyn Fn(&[u8; 20]) -> io::Result<ParentReader> + Send + Sync>;
+
+// How parent chd is checked to be the right one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ParentMatch {
+    #[default]
+    Sha1, // combined data and metadata sha1 must match child's parent sha1
+    RawSha1([u8; 20]), // data sha1 must match the given one, metadata may differ
+    Force,             // accept any parent, check crc of parent hunks when they are read
+}
+
+struct ParentType {
+    sha1: [u8; 20],                      // required parent sha1
+    mode: ParentMatch,                   // how parent sha1 is matched
+    chd: Option<Box<Chd<ParentReader>>>, // attached parent
+    resolver: Option<ParentResolver>,    // opens parent on first access, inherited by ancestors
+    checked: Option<Vec<bool>>,          // parent hunks with checked crc in Force mode
+}
+
+impl ParentType {
+    fn new(sha1: [u8; 20]) -> Self {
+        Self {
+            sha1,
+            mode: ParentMatch::Sha1,
+            chd: None,
+            resolver: None,
+            checked: None,
+        }
     }
 
-    pub fn set_parent(&mut self, parent: Chd<T>) -> io::Result<()> {
-        if parent.header.sha1 != self.header.parentsha1 {
+    fn attach<P: R + Send + 'static>(&mut self, chd: Chd<P>) -> io::Result<()> {
+        let (have, need) = match self.mode {
+            ParentMatch::Sha1 => (chd.header.sha1, self.sha1),
+            ParentMatch::RawSha1(rawsha1) => (chd.header.rawsha1, rawsha1),
+            ParentMatch::Force => (self.sha1, self.sha1),
+        };
+        if have != need {
             return Err(invalid_data(format!(
                 "wrong parent sha1 {}: need {}",
-                hex_string(&parent.header.sha1),
-                hex_string(&self.header.parentsha1)
+                hex_string(&have),
+                hex_string(&need)
             )));
         }
-        self.parent = Some(Box::new(parent));
+        self.checked = match self.mode {
+            ParentMatch::Force => Some(vec![false; chd.hunk_count()]),
+            _ => None,
+        };
+        self.chd = Some(Box::new(chd.into_parent()));
         Ok(())
     }
+}
 
-    pub fn compressed(&self) -> bool {
-        self.header.compressors[0] != 0
-    }
+// Compressor tags this build can decompress
+pub fn supported_codecs() -> &'static [u32] {
+    decompress::SUPPORTED
+}
 
-    pub fn file_size(&self) -> u64 {
-        self.filesize
-    }
+// Result of decompressing built-in known answer vector by a codec
+#[derive(Debug)]
+pub struct CodecSelfTest {
+    pub tag: u32,
+    pub result: io::Result<()>,
+}
 
-    pub fn version(&self) -> u32 {
-        self.header.version
+impl CodecSelfTest {
+    pub fn passed(&self) -> bool {
+        self.result.is_ok()
     }
+}
 
-    pub fn size(&self) -> u64 {
-        self.header.size
-    }
+// Check each codec of this build against known answer vectors, e.g. to
+// validate LZMA FFI backend or feature combination on target system
+pub fn codec_self_test() -> Vec<CodecSelfTest> {
+    supported_codecs()
+        .iter()
+        .map(|&tag| CodecSelfTest {
+            tag,
+            result: decompress::known_answer_test(tag),
+        })
+        .collect()
+}
 
-    pub fn hunk_size(&self) -> usize {
-        self.header.hunkbytes as usize
-    }
+// Identity of chd file as seen by rom managers, from header only
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChdProbe {
+    pub version: u32,              // header version, 3 to 5
+    pub sha1: [u8; 20],            // combined raw+meta SHA1
+    pub rawsha1: Option<[u8; 20]>, // raw data SHA1, v4 and later
+    pub parentsha1: [u8; 20],      // combined SHA1 of parent, zeroes if there's none
+}
 
-    pub fn hunk_size_u32(&self) -> u32 {
-        self.header.hunkbytes
+impl ChdProbe {
+    pub fn has_parent(&self) -> bool {
+        self.parentsha1 != [0; 20]
     }
+}
 
-    pub fn hunk_count(&self) -> usize {
-        self.header.hunkcount as usize
+// Read header fields identifying chd without decoding the map or creating codecs.
+// Unlike Chd::open, older v3 and v4 headers are accepted too.
+pub fn probe<T: R>(io: &mut T) -> io::Result<ChdProbe> {
+    let mut data = [0u8; 124];
+    io.read_at(0, &mut data[..16])?;
+    if &data[0..8] != b"MComprHD" {
+        return Err(invalid_data(format!(
+            "chd: invalid magic {:02x?}",
+            &data[0..8]
+        )));
     }
-
-    pub fn hunk_count_u32(&self) -> u32 {
-        self.header.hunkcount
+    let (length, version) = (data.get_be32(8)?, data.get_be32(12)?);
+    // header length, offsets of sha1, parent sha1 and raw sha1
+    let (expected, sha1, parentsha1, rawsha1) = match version {
+        3 => (120, 80, 100, None),
+        4 => (108, 48, 68, Some(88)),
+        V5 => (124, 84, 104, Some(64)),
+        x => return Err(invalid_data(format!("chd: unsupported version {}", x))),
+    };
+    if length != expected {
+        return Err(invalid_data(format!(
+            "chd: invalid v{} header length {}",
+            version, length
+        )));
     }
+    io.read_at(16, &mut data[16..length as usize])?;
+    let sha = |offset: usize| <[u8; 20]>::try_from(&data[offset..offset + 20]).unwrap();
+    Ok(ChdProbe {
+        version,
+        sha1: sha(sha1),
+        rawsha1: rawsha1.map(sha),
+        parentsha1: sha(parentsha1),
+    })
+}
 
-    pub fn unit_size(&self) -> usize {
-        self.header.unitbytes as usize
+// See probe
+pub fn probe_file<P: AsRef<std::path::Path>>(path: P) -> io::Result<ChdProbe> {
+    probe(&mut std::fs::File::open(path)?)
+}
+
+// Callback of long operations, called with bytes done and total bytes
+#[derive(Clone)]
+pub struct Progress(Arc<dyn Fn(u64, u64) + Send + Sync>);
+
+impl Progress {
+    pub fn new<F: Fn(u64, u64) + Send + Sync + 'static>(callback: F) -> Self {
+        Self(Arc::new(callback))
     }
 
-    pub fn unit_size_u32(&self) -> u32 {
-        self.header.unitbytes
+    pub fn report(&self, done: u64, total: u64) {
+        (self.0)(done, total)
     }
+}
 
-    pub fn unit_size_u64(&self) -> u64 {
-        self.header.unitbytes as u64
+impl std::fmt::Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Progress")
     }
+}
 
-    pub fn write_summary<W: Write>(&self, to: &mut W) -> io::Result<()> {
-        writeln!(to, "File size: {}", self.file_size())?;
-        writeln!(to, "CHD version: {}", self.version())?;
-        writeln!(to, "Logical size: {}", self.size())?;
-        writeln!(to, "Hunk Size: {}", self.hunk_size())?;
-        writeln!(to, "Total Hunks: {}", self.hunk_count())?;
-        writeln!(to, "Unit Size: {}", self.unit_size())?;
-        write!(to, "Compression:")?;
-        if self.compressed() {
-            for i in 0..4 {
-                match self.header.compressors[i] {
-                    0 => break,
-                    tag => write!(to, " {}", tag_string(tag))?,
-                }
-            }
-        } else {
-            write!(to, " none")?;
-        }
-        writeln!(to, "")?;
-        let ratio = 1e2 * (self.file_size() as f32) / (self.size() as f32);
-        writeln!(to, "Ratio: {:.1}%", ratio)?;
-        write!(to, "SHA1: ")?;
-        hex_writeln(to, &self.header.sha1)?;
-        write!(to, "Data SHA1: ")?;
-        hex_writeln(to, &self.header.rawsha1)?;
-        for i in &self.header.parentsha1 {
-            if *i > 0 {
-                write!(to, "Parent SHA1: ")?;
-                hex_writeln(to, &self.header.parentsha1)?;
-                break;
-            }
+// Event of long operations sent to channel, see Chd::set_events
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    // logical bytes done of total, `hunk` is the last one processed
+    HunkDone {
+        hunk: usize,
+        done: u64,
+        total: u64,
+    },
+    // something suspicious, which didn't stop the operation
+    Warning {
+        hunk: usize,
+        reason: String,
+    },
+    // codec `tag` decoded hunk in alternative way, e.g. zlib header of raw deflate data
+    CodecFallback {
+        hunk: usize,
+        tag: u32,
+        reason: String,
+    },
+    // operation went through the whole image, `done` logical bytes
+    Finished {
+        done: u64,
+    },
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ChdOptions {
+    // limit for memory allocated by map, codecs and caches; unlimited if None
+    pub memory_budget: Option<usize>,
+    // reject seek past logical end instead of reading 0 bytes there like File does
+    pub strict_seek: bool,
+    // fail open if header lists codecs this build lacks, instead of failing reads
+    // of hunks compressed with them
+    pub strict_codecs: bool,
+    // throttle reads of hunk data from underlying file
+    pub io_limit: Option<IoLimit>,
+}
+
+impl ChdOptions {
+    // All strict checks enabled
+    pub fn strict() -> Self {
+        Self {
+            strict_seek: true,
+            strict_codecs: true,
+            ..Default::default()
         }
-        Ok(())
     }
+}
 
-    pub fn validate_hunk(&mut self, hunknum: usize) -> io::Result<()> {
-        if hunknum >= self.hunk_count() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!(
-                    "invalid hunk#{}: chd has {} hunks",
-                    hunknum,
-                    self.hunk_count()
-                ),
-            ));
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HunkCompression {
+    None,       // stored uncompressed
+    SelfRef,    // same as another hunk in this chd
+    Parent,     // same as a hunk's worth of units in the parent chd
+    Codec(u32), // compressed by codec with the tag
+}
+
+impl std::fmt::Display for HunkCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HunkCompression::None => f.write_str("none"),
+            HunkCompression::SelfRef => f.write_str("self"),
+            HunkCompression::Parent => f.write_str("parent"),
+            HunkCompression::Codec(tag) => write!(f, "{}", CodecTag(*tag)),
         }
-        let maphunk = self.map.locate(hunknum);
-        match maphunk.0 {
-            COMPRESSION_SELF => self.validate_hunk(maphunk.1 as usize),
-            COMPRESSION_PARENT => Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("hunk#{}: parent chd hunks has no checksum", hunknum),
-            )),
-            _ => {
-                let mut buf = vec![0; self.hunk_size()];
-                self.read_hunk(hunknum, &mut buf)?;
-                self.map.validate(hunknum, &buf)
+    }
+}
+
+// Output format of Chd::export_map
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapFormat {
+    Csv,  // header line and a line per hunk
+    Json, // array of objects per hunk
+}
+
+// Hunk map entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HunkInfo {
+    pub compression: HunkCompression,
+    // file offset for data hunks, hunk index for SelfRef, unit index for Parent
+    pub offset: u64,
+    pub length: u32,      // compressed length in file, 0 for SelfRef and Parent
+    pub crc: Option<u16>, // crc16 of decompressed data stored in map
+}
+
+// Hunks of one compression kind, see Chd::compression_stats
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionStat {
+    pub compression: HunkCompression,
+    pub hunks: u64,
+    // bytes stored in file, hunks stored at the same offset are counted once,
+    // 0 for SelfRef and Parent
+    pub compressed: u64,
+    pub uncompressed: u64, // logical bytes of these hunks
+}
+
+impl CompressionStat {
+    // add hunk to stat of its compression kind in `stats`
+    pub(crate) fn count(
+        stats: &mut Vec<CompressionStat>,
+        compression: HunkCompression,
+        compressed: u64,
+        uncompressed: u64,
+    ) {
+        match stats.iter_mut().find(|s| s.compression == compression) {
+            Some(stat) => {
+                stat.hunks += 1;
+                stat.compressed += compressed;
+                stat.uncompressed += uncompressed;
             }
+            None => stats.push(CompressionStat {
+                compression,
+                hunks: 1,
+                compressed,
+                uncompressed,
+            }),
         }
     }
 
-    // Check each hunk data match map checksum
-    pub fn validate(&mut self) -> io::Result<()> {
-        for i in 0..self.hunk_count() {
-            self.validate_hunk(i)?;
+    // compressed bytes per logical byte, 0 if there are none
+    pub fn ratio(&self) -> f64 {
+        match self.uncompressed {
+            0 => 0.0,
+            total => self.compressed as f64 / total as f64,
         }
-        Ok(())
     }
+}
 
-    // Check chd data match header checksum
-    pub fn verify(&mut self) -> io::Result<()> {
-        if !self.compressed() {
-            return Err(invalid_data_str(
-                "chd: uncompressed file doesn't have checksum",
-            ));
-        }
-        let mut sha1 = sha1::Sha1::new();
-        let mut buffer = vec![0; self.hunk_size()];
-        self.seek(SeekFrom::Start(0))?;
-        for _ in 0..self.hunk_count() {
-            let size = self.read(&mut buffer)?;
-            sha1.update(&buffer[..size]);
+// Bytes held in memory by open chd, see Chd::memory_usage
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub map: usize,     // decoded hunk map
+    pub cache: usize,   // decompressed hunks
+    pub codecs: usize,  // codec state and free scratch buffers of the pool
+    pub buffers: usize, // hunk buffer
+    pub parent: usize,  // total of attached parent chd
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.map + self.cache + self.codecs + self.buffers + self.parent
+    }
+}
+
+// Set of hunks which passed validation, could be saved to resume validation later
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationState {
+    sha1: [u8; 20], // sha1 of chd the state belongs to
+    hunkcount: u32,
+    bitmap: Vec<u8>,
+}
+
+impl ValidationState {
+    const HEADER_SIZE: usize = 24;
+
+    fn new(sha1: [u8; 20], hunkcount: u32) -> Self {
+        Self {
+            sha1,
+            hunkcount,
+            bitmap: vec![0; (hunkcount as usize).div_ceil(8)],
         }
-        let digest = sha1.digest().bytes();
-        if digest != self.header.rawsha1 {
-            return Err(invalid_data(format!(
-                "chd: data sha1 {} doesn't match header rawsha1 {}",
-                hex_string(&digest),
-                hex_string(&self.header.rawsha1)
-            )));
+    }
+
+    pub fn hunk_count(&self) -> usize {
+        self.hunkcount as usize
+    }
+
+    pub fn is_validated(&self, hunknum: usize) -> bool {
+        hunknum < self.hunk_count() && self.bitmap[hunknum / 8] & (1 << (hunknum % 8)) != 0
+    }
+
+    // Hunks past hunk_count() are ignored, as they are never validated
+    pub fn set_validated(&mut self, hunknum: usize) {
+        if hunknum < self.hunk_count() {
+            self.bitmap[hunknum / 8] |= 1 << (hunknum % 8);
         }
-        let mut metasha = Vec::<[u8; 24]>::new();
-        let calcsha = |io: &mut T, entry: &MetadataEntry| -> io::Result<()> {
-            if entry.flags & MDFLAGS_CHECKSUM == 0 {
-                return Ok(());
-            }
-            let mut buf = vec![0; entry.length as usize];
-            io.read_at(entry.offset, &mut buf)?;
+    }
 
-            let digest = sha1::Sha1::from(&buf).digest().bytes();
+    pub fn validated_count(&self) -> usize {
+        self.bitmap.iter().map(|b| b.count_ones() as usize).sum()
+    }
 
-            let mut buf = [0; 24];
-            write_be32(&mut buf[..4], entry.metatag);
-            copy_from(&mut buf[4..], &digest);
-            metasha.push(buf);
-            Ok(())
-        };
-        Self::visit_metadata(&mut self.io, self.header.metaoffset, calcsha)?;
-        metasha.sort();
+    pub fn is_complete(&self) -> bool {
+        self.validated_count() == self.hunk_count()
+    }
 
-        let mut sha1 = sha1::Sha1::new();
-        sha1.update(&self.header.rawsha1);
-        for s in metasha.into_iter() {
-            sha1.update(&s);
+    /*
+    Exported state format:
+
+    [  0] char   sha1[20];        // chd sha1
+    [ 20] uint32_t hunkcount;     // number of hunks in chd
+    [ 24] uint8_t bitmap[];       // bit per hunk, lsb first
+    */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::HEADER_SIZE + self.bitmap.len());
+        data.extend_from_slice(&self.sha1);
+        data.extend_from_slice(&self.hunkcount.to_be_bytes());
+        data.extend_from_slice(&self.bitmap);
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        if data.len() < Self::HEADER_SIZE {
+            return Err(invalid_data(format!(
+                "validation state: too short {} bytes",
+                data.len()
+            )));
         }
-        let digest = sha1.digest().bytes();
-        if digest != self.header.sha1 {
+        let mut sha1 = [0; 20];
+        copy_from(&mut sha1, &data[0..20]);
+        let hunkcount = data.get_be32(20)?;
+        // check size before allocating bitmap for hunkcount read from data
+        let bitmap = &data[Self::HEADER_SIZE..];
+        if bitmap.len() != (hunkcount as usize).div_ceil(8) {
             return Err(invalid_data(format!(
-                "chd: overall sha1 {} doesn't match header sha1 {}",
-                hex_string(&digest),
-                hex_string(&self.header.sha1)
+                "validation state: bitmap size {} doesn't match {} hunks",
+                bitmap.len(),
+                hunkcount
             )));
         }
-        Ok(())
+        let mut state = Self::new(sha1, hunkcount);
+        copy_from(&mut state.bitmap, bitmap);
+        Ok(state)
     }
+}
 
-    fn read_hunk(&mut self, hunknum: usize, buf: &mut [u8]) -> io::Result<()> {
-        let hunksize = self.hunk_size();
-        read_hunk(
-            &mut self.io,
-            &*self.map,
-            &mut self.decompress,
-            &mut self.parent,
-            hunknum,
-            hunksize,
-            buf,
-        )
+// Progress of extract_with(): hunks written, output offset and SHA1 of written
+// data, could be saved to resume extraction after interruption
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtractState {
+    sha1: [u8; 20], // sha1 of chd the state belongs to
+    hunkcount: u32,
+    hunks: u32,            // hunks written
+    offset: u64,           // bytes written
+    hasher: ResumableSha1, // of bytes written
+}
+
+impl ExtractState {
+    const HEADER_SIZE: usize = 36;
+
+    fn new(sha1: [u8; 20], hunkcount: u32) -> Self {
+        Self {
+            sha1,
+            hunkcount,
+            hunks: 0,
+            offset: 0,
+            hasher: ResumableSha1::new(),
+        }
     }
 
-    fn read_metadata_entry(
-        io: &mut T,
-        offset: u64,
-        header: &mut [u8],
-    ) -> io::Result<MetadataEntry> {
-        io.read_at(offset, header)?;
-        Ok(MetadataEntry {
-            metatag: read_be32(&header[0..4]),
-            offset: offset + MetadataEntry::SIZE as u64,
-            next: read_be64(&header[8..16]),
-            length: read_be24(&header[5..8]),
-            flags: header[4],
-        })
+    pub fn hunk_count(&self) -> usize {
+        self.hunkcount as usize
     }
 
-    fn visit_metadata<F>(io: &mut T, mut offset: u64, mut f: F) -> io::Result<()>
-    where
-        F: FnMut(&mut T, &MetadataEntry) -> io::Result<()>,
-    {
-        let mut buffer = [0; MetadataEntry::SIZE];
-        while offset > 0 {
-            let entry = Self::read_metadata_entry(io, offset, &mut buffer)?;
-            f(io, &entry)?;
-            offset = entry.next;
-        }
-        Ok(())
+    pub fn hunks_done(&self) -> usize {
+        self.hunks as usize
     }
 
-    fn find_metadata(&mut self, tag: u32, index: u32) -> io::Result<Option<MetadataEntry>> {
-        if let Some((i, entry)) = self.cachemeta {
-            if tag == entry.metatag && i == index {
-                return Ok(Some(entry));
-            }
+    // Bytes written, output has to be truncated to it before resuming
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.hunks == self.hunkcount
+    }
+
+    // SHA1 of data written so far, of whole logical data when complete
+    pub fn data_sha1(&self) -> [u8; 20] {
+        self.hasher.digest()
+    }
+
+    /*
+    Exported state format:
+
+    [  0] char   sha1[20];        // chd sha1
+    [ 20] uint32_t hunkcount;     // number of hunks in chd
+    [ 24] uint32_t hunks;         // number of hunks written
+    [ 28] uint64_t offset;        // number of bytes written
+    [ 36] uint8_t sha1state[92];  // SHA1 state of written data
+    */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::HEADER_SIZE + ResumableSha1::STATE_SIZE);
+        data.extend_from_slice(&self.sha1);
+        data.extend_from_slice(&self.hunkcount.to_be_bytes());
+        data.extend_from_slice(&self.hunks.to_be_bytes());
+        data.extend_from_slice(&self.offset.to_be_bytes());
+        data.extend_from_slice(&self.hasher.to_bytes());
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        if data.len() != Self::HEADER_SIZE + ResumableSha1::STATE_SIZE {
+            return Err(invalid_data(format!(
+                "extract state: wrong size {} bytes",
+                data.len()
+            )));
+        }
+        let mut sha1 = [0; 20];
+        copy_from(&mut sha1, &data[0..20]);
+        let mut state = Self::new(sha1, data.get_be32(20)?);
+        state.hunks = data.get_be32(24)?;
+        state.offset = data.get_be64(28)?;
+        state.hasher = ResumableSha1::from_bytes(&data[Self::HEADER_SIZE..])?;
+        if state.hunks > state.hunkcount || state.hasher.len() != state.offset {
+            return Err(invalid_data(format!(
+                "extract state: {} hunks of {} and {} bytes of {} hashed are inconsistent",
+                state.hunks,
+                state.hunkcount,
+                state.offset,
+                state.hasher.len()
+            )));
         }
+        Ok(state)
+    }
+}
 
-        let mut buffer = [0; MetadataEntry::SIZE];
-        let mut offset = self.header.metaoffset;
-        let mut i = 0;
-        while offset > 0 {
-            let entry = Self::read_metadata_entry(&mut self.io, offset, &mut buffer)?;
-            if tag == entry.metatag {
-                if i == index {
-                    self.cachemeta = Some((i, entry));
-                    return Ok(Some(entry));
-                }
-                i += 1;
-            }
-            offset = entry.next;
+pub struct Chd<T: R> {
+    header: Header,
+    filesize: u64,
+    pos: i64,
+    io: T,
+    map: Box<dyn Map>,
+    decompress: Codecs,
+    cache: Box<dyn HunkCache>, // decompressed hunks for reads not aligned to hunk boundaries
+    hunkbuf: Vec<u8>,          // decompression buffer for hunks going to the cache
+    cachemeta: Option<(u32, MetadataEntry)>, // cached metadata entry
+    parent: ParentType,
+    stat: Stat,
+    budget: MemoryBudget,       // memory used by everything except cache
+    progress: Option<Progress>, // of extract, validate and verify
+    strict_seek: bool,          // seek past logical end is an error
+    data_changed: bool,         // written since header sha1s were computed
+    throttle: Option<Throttle>, // of reads counted in stat.raw
+}
+
+impl<T: R> Chd<T> {
+    pub fn open(io: T) -> io::Result<Chd<T>> {
+        Self::open_with_options(io, ChdOptions::default())
+    }
+
+    pub fn open_with_options(mut io: T, options: ChdOptions) -> io::Result<Chd<T>> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("chd open", ?options).entered();
+        let mut budget = MemoryBudget::new(options.memory_budget);
+        let (header, map) = Header::read(&mut io, &mut budget)?;
+        let decompress = decompress::init(&header, &mut budget, options.strict_codecs)?;
+        let filesize = io.seek(SeekFrom::End(0))?;
+        let hunksize = header.hunkbytes as usize;
+        budget.reserve("hunk buffer", hunksize)?;
+        // cache must be able to hold at least one hunk
+        if !budget.fits(hunksize) {
+            budget.reserve("hunk cache", hunksize)?;
         }
-        Ok(None)
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            version = header.version,
+            size = header.size,
+            hunkbytes = header.hunkbytes,
+            hunkcount = header.hunkcount,
+            memory = budget.used(),
+            "chd opened"
+        );
+        let parent = ParentType::new(header.parentsha1);
+        let chd = Chd {
+            header,
+            filesize,
+            pos: 0,
+            io,
+            map,
+            decompress,
+            cache: Box::new(SingleCache::new()),
+            hunkbuf: vec![0; hunksize],
+            cachemeta: None,
+            parent,
+            stat: Stat::default(),
+            budget,
+            progress: None,
+            strict_seek: options.strict_seek,
+            data_changed: false,
+            throttle: options.io_limit.map(Throttle::new),
+        };
+        Ok(chd)
     }
 
-    pub fn read_metadata_at(
+    pub fn set_parent<P: R + Send + 'static>(&mut self, parent: Chd<P>) -> io::Result<()> {
+        self.set_parent_with(parent, ParentMatch::Sha1)
+    }
+
+    // Attach parent matched in a relaxed way, e.g. recreated with different metadata.
+    // The mode is also used for replace_parent() and resolver.
+    pub fn set_parent_with<P: R + Send + 'static>(
         &mut self,
-        tag: u32,
-        index: u32,
-        offset: usize,
-        buf: &mut [u8],
-    ) -> io::Result<Option<usize>> {
-        match self.find_metadata(tag, index)? {
-            Some(entry) => {
-                let length = entry.length as usize;
-                if length <= offset {
-                    return Ok(Some(0));
-                }
-                let available = length - offset;
-                let chunk = std::cmp::min(available, buf.len());
-                self.io
-                    .read_at(entry.offset + offset as u64, &mut buf[..chunk])?;
-                Ok(Some(available))
-            }
-            None => Ok(None),
+        parent: Chd<P>,
+        mode: ParentMatch,
+    ) -> io::Result<()> {
+        let previous = self.parent.mode;
+        self.parent.mode = mode;
+        let result = self.parent.attach(parent);
+        if result.is_err() {
+            self.parent.mode = previous;
         }
+        result
     }
 
-    pub fn read_metadata(&mut self, tag: u32, buf: &mut [u8]) -> io::Result<Option<usize>> {
-        self.read_metadata_at(tag, 0, 0, buf)
+    // Report logical bytes processed by extract, validate, verify and other operations
+    // going through the whole image
+    pub fn set_progress(&mut self, progress: Option<Progress>) {
+        self.progress = progress;
     }
 
-    pub fn read_metadata_simple(&mut self, tag: u32) -> io::Result<Option<Vec<u8>>> {
-        match self.find_metadata(tag, 0)? {
-            Some(entry) => {
-                let mut meta = vec![0; entry.length as usize];
-                self.io.read_at(entry.offset, &mut meta)?;
-                Ok(Some(meta))
-            }
-            None => Ok(None),
+    // Limit read rate of hunk data, e.g. for background verification. None removes limit.
+    pub fn set_io_limit(&mut self, limit: Option<IoLimit>) {
+        self.throttle = limit.map(|limit| {
+            let mut throttle = Throttle::new(limit);
+            throttle.pace(&self.stat.raw); // earlier reads aren't limited
+            throttle
+        });
+    }
+
+    pub fn io_limit(&self) -> Option<IoLimit> {
+        self.throttle.as_ref().map(|throttle| throttle.limit())
+    }
+
+    // sleep if reads since the last call are over io limit
+    fn pace(&mut self) {
+        if let Some(throttle) = &mut self.throttle {
+            throttle.pace(&self.stat.raw);
         }
     }
 
-    pub fn dump_metadata<W: Write>(&mut self, to: &mut W) -> io::Result<()> {
-        if self.header.metaoffset == 0 {
-            writeln!(to, "Metadata: none")?;
-            return Ok(());
+    // Send events of the same operations as set_progress into channel, e.g. for GUI
+    // or async consumers. Events of a failed operation end without Finished.
+    pub fn set_events(&mut self, events: Option<mpsc::Sender<Event>>) {
+        self.decompress.events = events;
+    }
+
+    // Same with new channel, returns its receiving end
+    pub fn subscribe(&mut self) -> mpsc::Receiver<Event> {
+        let (events, receiver) = mpsc::channel();
+        self.set_events(Some(events));
+        receiver
+    }
+
+    pub(crate) fn report_progress(&self, done: u64) {
+        let (done, total) = (done.min(self.size()), self.size());
+        if let Some(progress) = &self.progress {
+            progress.report(done, total);
+        }
+        if self.decompress.listening() {
+            let hunk = done.saturating_sub(1) / self.header.hunkbytes as u64;
+            self.decompress.send(Event::HunkDone {
+                hunk: hunk as usize,
+                done,
+                total,
+            });
         }
-        writeln!(to, "Metadata:")?;
-        let mut buf = [0; 32];
-        let buflen = buf.len();
-        Self::visit_metadata(&mut self.io, self.header.metaoffset, |io, entry| {
-            let length = entry.length as usize;
-            let (chunk, tail) = if length > buflen {
-                (&mut buf[..buflen - 2], "...")
-            } else {
-                (&mut buf[..length], "")
-            };
-            io.read_at(entry.offset, chunk)?;
-            writeln!(
-                to,
-                "  {}:{:02x}: ({}){}{}",
-                tag_string(entry.metatag),
-                entry.flags,
-                entry.length,
-                hex_string(chunk),
-                tail
-            )?;
-            Ok(())
-        })
     }
-}
 
-impl<T: R> Seek for Chd<T> {
-    fn seek(&mut self, sf: SeekFrom) -> io::Result<u64> {
-        let size = self.header.size as i64;
-        let newpos = match sf {
-            SeekFrom::Start(x) => x as i64,
-            SeekFrom::Current(x) => {
-                if let Some(xx) = self.pos.checked_add(x) {
-                    xx as i64
-                } else {
-                    return Err(invalid_data(format!(
-                        "chd: overflowing seek {}{:+}, logical size {}",
-                        self.pos,
-                        x,
-                        self.size()
-                    )));
+    pub(crate) fn report_finished(&self, done: u64) {
+        self.decompress.send(Event::Finished { done });
+    }
+
+    // Detach parent, reads of parent hunks fail until another one is attached or resolved
+    pub fn take_parent(&mut self) -> Option<Chd<ParentReader>> {
+        self.parent.chd.take().map(|parent| *parent)
+    }
+
+    // Attach another copy of the same parent, returns previous one.
+    // Cached hunks stay valid only if parent is matched by sha1, other modes
+    // allow parent with different data, so the cache is dropped.
+    pub fn replace_parent<P: R + Send + 'static>(
+        &mut self,
+        parent: Chd<P>,
+    ) -> io::Result<Option<Chd<ParentReader>>> {
+        let previous = self.parent.chd.take();
+        match self.parent.attach(parent) {
+            Ok(()) => {
+                if self.parent.mode != ParentMatch::Sha1 {
+                    self.cache.clear();
                 }
+                Ok(previous.map(|parent| *parent))
             }
-            SeekFrom::End(x) => {
-                if let Some(xx) = size.checked_add(x) {
-                    xx
-                } else {
-                    return Err(invalid_data(format!(
-                        "chd: overflowing seek {} from logical end {}",
-                        x,
-                        self.size()
-                    )));
-                }
+            Err(e) => {
+                self.parent.chd = previous;
+                Err(e)
             }
-        };
-        if newpos < 0 || newpos > size {
-            return Err(invalid_data(format!(
-                "chd: invalid seek to {} out of logical size {}",
-                newpos,
-                self.size()
-            )));
         }
-        self.pos = newpos;
-        Ok(self.pos as u64)
     }
-}
 
-impl<T: R> Read for Chd<T> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let hasbytes = self.header.size - self.pos as u64;
-        if hasbytes == 0 {
-            return Ok(0);
+    // Open parent lazily on the first access to parent hunks, see ParentResolver.
+    // Replaces resolver, but not the parent if it's already attached.
+    pub fn set_parent_resolver<F>(&mut self, resolver: F)
+    where
+        F: Fn(&[u8; 20]) -> io::Result<ParentReader> + Send + Sync + 'static,
+    {
+        self.parent.resolver = Some(Arc::new(resolver));
+    }
+
+    // Open chd and wire its whole parent chain from candidates matched by sha1.
+    // Candidates may come in any order, the ones not in the chain are dropped.
+    pub fn open_with_parents<P, I>(io: T, parents: I) -> io::Result<Chd<T>>
+    where
+        P: R + Send + 'static,
+        I: IntoIterator<Item = Chd<P>>,
+    {
+        let mut chd = Chd::open(io)?;
+        let mut candidates: Vec<Chd<P>> = parents.into_iter().collect();
+        let mut chain = Vec::new();
+        let mut need = match chd.has_parent() {
+            true => Some(chd.parent_sha1()),
+            false => None,
+        };
+        while let Some(sha1) = need {
+            let i = candidates
+                .iter()
+                .position(|c| c.sha1() == sha1)
+                .ok_or_else(|| {
+                    invalid_data(format!(
+                        "missing parent sha1 {} for level {}",
+                        hex_string(&sha1),
+                        chain.len() + 1
+                    ))
+                })?;
+            let parent = candidates.swap_remove(i);
+            need = match parent.has_parent() {
+                true => Some(parent.parent_sha1()),
+                false => None,
+            };
+            chain.push(parent);
         }
+        // attach from the oldest ancestor down to the child
+        if let Some(mut top) = chain.pop() {
+            while let Some(mut next) = chain.pop() {
+                next.set_parent(top)?;
+                top = next;
+            }
+            chd.set_parent(top)?;
+        }
+        Ok(chd)
+    }
 
-        let mut dest = buf;
-        if hasbytes < dest.len() as u64 {
-            dest = dest.split_at_mut(hasbytes as usize).0;
+    // Same chd reading through boxed reader, so it can be a parent of any chd
+    fn into_parent(self) -> Chd<ParentReader>
+    where
+        T: Send + 'static,
+    {
+        Chd {
+            header: self.header,
+            filesize: self.filesize,
+            pos: self.pos,
+            io: Box::new(self.io),
+            map: self.map,
+            decompress: self.decompress,
+            cache: self.cache,
+            hunkbuf: self.hunkbuf,
+            cachemeta: self.cachemeta,
+            parent: self.parent,
+            stat: self.stat,
+            budget: self.budget,
+            progress: self.progress,
+            strict_seek: self.strict_seek,
+            data_changed: self.data_changed,
+            throttle: self.throttle,
         }
-        let lastbyte = self.pos + dest.len() as i64 - 1;
-        let hunkbytes = self.header.hunkbytes as usize;
-        let hunkbytes64 = hunkbytes as i64;
-        let hunklast = hunkbytes - 1;
+    }
 
-        let first_hunk = (self.pos / hunkbytes64) as usize;
-        let last_hunk = (lastbyte / hunkbytes64) as usize;
-        let result = dest.len();
+    // Give back underlying reader, e.g. to reopen it with different options
+    pub fn into_inner(self) -> T {
+        self.io
+    }
 
-        // iterate over hunks
-        for curhunk in first_hunk..=last_hunk {
-            // determine start/end boundaries
-            let startoffs = match curhunk == first_hunk {
-                true => (self.pos % hunkbytes64) as usize,
+    // Give back underlying reader together with attached parent chd
+    pub fn into_parts(self) -> (T, Option<Chd<ParentReader>>) {
+        (self.io, self.parent.chd.map(|parent| *parent))
+    }
+
+    pub fn compressed(&self) -> bool {
+        self.header.compressors[0] != 0
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compressed()
+    }
+
+    // SHA1 of raw data and metadata
+    pub fn sha1(&self) -> [u8; 20] {
+        self.header.sha1
+    }
+
+    // SHA1 of raw data only
+    pub fn raw_sha1(&self) -> [u8; 20] {
+        self.header.rawsha1
+    }
+
+    // SHA1 of parent chd, all zeroes if chd has no parent
+    pub fn parent_sha1(&self) -> [u8; 20] {
+        self.header.parentsha1
+    }
+
+    pub fn has_parent(&self) -> bool {
+        self.header.parentsha1 != [0; 20]
+    }
+
+    pub fn sha1_hex(&self) -> String {
+        hex_string(&self.header.sha1)
+    }
+
+    pub fn raw_sha1_hex(&self) -> String {
+        hex_string(&self.header.rawsha1)
+    }
+
+    pub fn parent_sha1_hex(&self) -> String {
+        hex_string(&self.header.parentsha1)
+    }
+
+    // Compressor tags of the four codec slots, None for unused slots
+    pub fn codecs(&self) -> [Option<u32>; 4] {
+        self.header.compressors.map(|tag| match tag {
+            0 => None,
+            tag => Some(tag),
+        })
+    }
+
+    // Replace decompressor of used slot, e.g. with hardware accelerated one for
+    // this instance only. Codec must decode the format of the slot tag.
+    // Returns previous decompressor, so it could be wrapped or put back later.
+    pub fn set_codec(
+        &mut self,
+        slot: usize,
+        codec: Box<dyn Decompress>,
+    ) -> io::Result<Box<dyn Decompress>> {
+        let old_usage = self.decompress.memory_usage(slot);
+        let new_usage = codec.memory_usage();
+        let previous = self.decompress.replace(slot, codec)?;
+        self.budget.release(old_usage);
+        let what = format!("codec#{} {}", slot, tag_string(self.decompress.tags[slot]));
+        if let Err(e) = self.budget.reserve(&what, new_usage) {
+            self.decompress.replace(slot, previous)?;
+            self.budget.reserve(&what, old_usage)?;
+            return Err(e);
+        }
+        Ok(previous)
+    }
+
+    // Printable names of used compressors, empty for uncompressed chd
+    pub fn compression_names(&self) -> Vec<String> {
+        self.codecs()
+            .iter()
+            .map_while(|tag| tag.map(tag_string))
+            .collect()
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.filesize
+    }
+
+    pub fn version(&self) -> u32 {
+        self.header.version
+    }
+
+    pub fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    pub fn hunk_size(&self) -> usize {
+        self.header.hunkbytes as usize
+    }
+
+    pub fn hunk_size_u32(&self) -> u32 {
+        self.header.hunkbytes
+    }
+
+    pub fn hunk_count(&self) -> usize {
+        self.header.hunkcount as usize
+    }
+
+    pub fn hunk_count_u32(&self) -> u32 {
+        self.header.hunkcount
+    }
+
+    pub fn unit_size(&self) -> usize {
+        self.header.unitbytes as usize
+    }
+
+    pub fn unit_size_u32(&self) -> u32 {
+        self.header.unitbytes
+    }
+
+    pub fn unit_size_u64(&self) -> u64 {
+        self.header.unitbytes as u64
+    }
+
+    // Number of whole units in logical data
+    pub fn unit_count(&self) -> u64 {
+        self.size() / self.unit_size_u64()
+    }
+
+    // put hunkbuf into cache without exceeding memory budget
+    fn cache_hunk(&mut self, hunknum: usize) {
+        if !self
+            .budget
+            .fits(self.cache.memory_usage() + self.hunkbuf.len())
+        {
+            self.cache.clear();
+        }
+        self.cache.put(hunknum, &self.hunkbuf);
+    }
+
+    // Replace cache of decompressed hunks, returns previous one
+    pub fn set_cache(&mut self, cache: Box<dyn HunkCache>) -> Box<dyn HunkCache> {
+        std::mem::replace(&mut self.cache, cache)
+    }
+
+    // Bytes currently held in memory, e.g. for eviction decisions of applications
+    // keeping many chds open. Unlike memory budget, it includes parent and cache.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let codecs: usize = (0..4).map(|i| self.decompress.memory_usage(i)).sum();
+        MemoryUsage {
+            map: self.map.memory_usage(),
+            cache: self.cache.memory_usage(),
+            codecs: codecs + self.decompress.pool.memory_usage(),
+            buffers: self.hunkbuf.capacity(),
+            parent: self
+                .parent
+                .chd
+                .as_ref()
+                .map_or(0, |parent| parent.memory_usage().total()),
+        }
+    }
+
+    // I/O and decompression statistics since open or last reset_stats()
+    pub fn stats(&self) -> &Stat {
+        &self.stat
+    }
+
+    pub fn snapshot_stats(&self) -> StatSnapshot {
+        StatSnapshot::new(&self.stat)
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stat = Stat::default();
+    }
+
+    // Text form of summary(), as printed by rchdtool info
+    pub fn write_summary<W: Write>(&self, to: &mut W) -> io::Result<()> {
+        write!(to, "{}", self.summary())
+    }
+
+    fn check_hunk(&self, hunknum: usize) -> io::Result<()> {
+        match hunknum < self.hunk_count() {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid hunk#{}: chd has {} hunks",
+                    hunknum,
+                    self.hunk_count()
+                ),
+            )),
+        }
+    }
+
+    pub fn hunk_info(&self, hunknum: usize) -> io::Result<HunkInfo> {
+        self.check_hunk(hunknum)?;
+        let (compression, offset, length) = self.map.locate(hunknum)?;
+        let compression = match compression {
+            COMPRESSION_NONE => HunkCompression::None,
+            COMPRESSION_SELF => HunkCompression::SelfRef,
+            COMPRESSION_PARENT => HunkCompression::Parent,
+            x => HunkCompression::Codec(self.header.compressors[x as usize]),
+        };
+        let length = match compression {
+            HunkCompression::SelfRef | HunkCompression::Parent => 0,
+            _ => length,
+        };
+        Ok(HunkInfo {
+            compression,
+            offset,
+            length,
+            crc: self.map.crc(hunknum),
+        })
+    }
+
+    // Hunk counts and byte totals per compression kind in order of first use,
+    // computed from the map without reading hunk data
+    pub fn compression_stats(&self) -> io::Result<Vec<CompressionStat>> {
+        let mut stats: Vec<CompressionStat> = Vec::new();
+        let mut offsets = HashSet::new();
+        let hunkbytes = self.header.hunkbytes as u64;
+        for hunknum in 0..self.hunk_count() {
+            let info = self.hunk_info(hunknum)?;
+            let start = hunknum as u64 * hunkbytes;
+            let uncompressed = std::cmp::min(hunkbytes, self.size().saturating_sub(start));
+            let compressed = match info.length > 0 && offsets.insert(info.offset) {
+                true => info.length as u64,
                 false => 0,
             };
-            let endoffs = match curhunk == last_hunk {
-                true => (lastbyte % hunkbytes64) as usize,
-                false => hunklast as usize,
-            };
-            let length = endoffs + 1 - startoffs;
-            let (mut head, tail) = dest.split_at_mut(length);
-            dest = tail;
+            CompressionStat::count(&mut stats, info.compression, compressed, uncompressed);
+        }
+        Ok(stats)
+    }
 
-            if startoffs == 0 && endoffs == hunklast && curhunk != self.cachehunk {
-                // if it's a full hunk, just read directly from disk unless it's the cached hunk
-                self.read_hunk(curhunk, head)?;
-            } else {
-                // otherwise, read from the cache
-                let hunksize = self.hunk_size();
-                let cache = &mut self.cache;
-                if curhunk != self.cachehunk {
-                    // self.read_hunk(curhunk, cache)?; // error[E0499]: cannot borrow `*self` as mutable more than once at a time
-                    read_hunk(
-                        &mut self.io,
-                        &mut *self.map,
-                        &mut self.decompress,
-                        &mut self.parent,
-                        curhunk,
-                        hunksize,
-                        cache,
+    // Hunk data as stored in file, before decompression. Hunks stored elsewhere
+    // (SelfRef and Parent) have no data of their own, so it's empty for them.
+    pub fn read_hunk_raw(&mut self, hunknum: usize) -> io::Result<Vec<u8>> {
+        let info = self.hunk_info(hunknum)?;
+        let mut data = vec![0; info.length as usize];
+        if !data.is_empty() {
+            self.io
+                .read_at_stat(info.offset, &mut data, &mut self.stat.raw)?;
+        }
+        Ok(data)
+    }
+
+    // Write every hunk map entry: hunk, compression, offset, length, crc
+    pub fn export_map<W: Write>(&self, to: &mut W, format: MapFormat) -> io::Result<()> {
+        match format {
+            MapFormat::Csv => writeln!(to, "hunk,compression,offset,length,crc")?,
+            MapFormat::Json => writeln!(to, "[")?,
+        }
+        for hunknum in 0..self.hunk_count() {
+            let info = self.hunk_info(hunknum)?;
+            match format {
+                MapFormat::Csv => {
+                    write!(
+                        to,
+                        "{},{},{},{},",
+                        hunknum, info.compression, info.offset, info.length
                     )?;
-                    self.cachehunk = curhunk;
+                    match info.crc {
+                        Some(crc) => writeln!(to, "{:04x}", crc)?,
+                        None => writeln!(to)?,
+                    }
+                }
+                MapFormat::Json => {
+                    // tags are printable ascii, but could contain quote or backslash
+                    let compression = info.compression.to_string();
+                    let compression = compression.replace('\\', "\\\\").replace('"', "\\\"");
+                    write!(
+                        to,
+                        "  {{\"hunk\": {}, \"compression\": \"{}\", \"offset\": {}, \"length\": {}, \"crc\": ",
+                        hunknum, compression, info.offset, info.length
+                    )?;
+                    match info.crc {
+                        Some(crc) => write!(to, "{}}}", crc)?,
+                        None => write!(to, "null}}")?,
+                    }
+                    match hunknum + 1 == self.hunk_count() {
+                        true => writeln!(to)?,
+                        false => writeln!(to, ",")?,
+                    }
+                }
+            }
+        }
+        if format == MapFormat::Json {
+            writeln!(to, "]")?;
+        }
+        Ok(())
+    }
+
+    // Decompress hunks into the cache ahead of time. Only makes sense with cache
+    // big enough to hold the whole range, e.g. LruCache
+    pub fn prefetch_hunks(&mut self, hunks: Range<usize>) -> io::Result<()> {
+        if hunks.start < hunks.end {
+            self.check_hunk(hunks.end - 1)?;
+        }
+        for hunknum in hunks {
+            if self.cache.get(hunknum).is_some() {
+                continue;
+            }
+            read_hunk(
+                &mut self.io,
+                &*self.map,
+                &mut self.decompress,
+                &mut self.parent,
+                &mut self.stat,
+                hunknum,
+                &mut self.hunkbuf,
+            )?;
+            self.cache_hunk(hunknum);
+            self.pace();
+        }
+        Ok(())
+    }
+
+    pub fn validate_hunk(&mut self, hunknum: usize) -> io::Result<()> {
+        self.check_hunk(hunknum)?;
+        let target = resolve_self(&*self.map, hunknum).map_err(|e| hunk_error(hunknum, None, e))?;
+        let maphunk = self.map.locate(target)?;
+        match maphunk.0 {
+            COMPRESSION_PARENT => Err(hunk_error(
+                hunknum,
+                Some(maphunk),
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "parent chd hunks has no checksum",
+                ),
+            )),
+            _ => {
+                let mut buf = vec![0; self.hunk_size()];
+                self.read_hunk(target, &mut buf)?;
+                self.map
+                    .validate(target, &buf)
+                    .map_err(|e| hunk_error(hunknum, Some(maphunk), e))
+            }
+        }
+    }
+
+    // Check each hunk data match map checksum
+    pub fn validate(&mut self) -> io::Result<()> {
+        for i in 0..self.hunk_count() {
+            self.validate_hunk(i)?;
+            self.report_progress((i as u64 + 1) * self.hunk_size() as u64);
+        }
+        self.report_finished(self.size());
+        Ok(())
+    }
+
+    // Empty validation state for this chd
+    pub fn validation_state(&self) -> ValidationState {
+        ValidationState::new(self.header.sha1, self.header.hunkcount)
+    }
+
+    // Same as validate(), but skips hunks already marked in state and marks validated ones.
+    // If it fails, state keeps progress and could be used to resume validation
+    pub fn validate_with(&mut self, state: &mut ValidationState) -> io::Result<()> {
+        if state.sha1 != self.header.sha1 || state.hunkcount != self.header.hunkcount {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "validation state for {} with {} hunks doesn't match chd {} with {} hunks",
+                    hex_string(&state.sha1),
+                    state.hunkcount,
+                    hex_string(&self.header.sha1),
+                    self.header.hunkcount
+                ),
+            ));
+        }
+        for i in 0..self.hunk_count() {
+            if !state.is_validated(i) {
+                self.validate_hunk(i)?;
+                state.set_validated(i);
+            }
+            self.report_progress((i as u64 + 1) * self.hunk_size() as u64);
+        }
+        self.report_finished(self.size());
+        Ok(())
+    }
+
+    // Empty extraction state for this chd
+    pub fn extract_state(&self) -> ExtractState {
+        ExtractState::new(self.header.sha1, self.header.hunkcount)
+    }
+
+    // Continue extraction from state: write at most `hunks` more hunks into `to`,
+    // which must be positioned at state.offset(). Returns bytes written by this call.
+    // State is updated after each written hunk, so it could be saved between calls
+    // or after failure, and extraction resumed later by another process.
+    pub fn extract_with<W: Write>(
+        &mut self,
+        to: &mut W,
+        state: &mut ExtractState,
+        hunks: usize,
+    ) -> io::Result<u64> {
+        let hunkbytes = self.header.hunkbytes as u64;
+        let expected = std::cmp::min(state.hunks as u64 * hunkbytes, self.size());
+        if state.sha1 != self.header.sha1
+            || state.hunkcount != self.header.hunkcount
+            || state.offset != expected
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "extract state for {} at hunk {} of {} doesn't match chd {} with {} hunks",
+                    hex_string(&state.sha1),
+                    state.hunks,
+                    state.hunkcount,
+                    hex_string(&self.header.sha1),
+                    self.header.hunkcount
+                ),
+            ));
+        }
+        let mut buf = vec![0; self.hunk_size()];
+        let mut written = 0;
+        let end = std::cmp::min(state.hunks as usize + hunks, self.hunk_count());
+        for hunknum in state.hunks as usize..end {
+            self.read_hunk(hunknum, &mut buf)?;
+            let chunk = std::cmp::min(hunkbytes, self.size() - state.offset) as usize;
+            to.write_all(&buf[..chunk])?;
+            state.hasher.update(&buf[..chunk]);
+            state.hunks += 1;
+            state.offset += chunk as u64;
+            written += chunk as u64;
+            self.report_progress(state.offset);
+        }
+        self.stat.logical.add(written as usize);
+        if written > 0 && state.is_complete() {
+            self.report_finished(state.offset);
+        }
+        Ok(written)
+    }
+
+    // SHA1 of whole logical data
+    fn data_sha1(&mut self) -> io::Result<[u8; 20]> {
+        let mut sha1 = sha1::Sha1::new();
+        let mut buffer = vec![0; self.hunk_size()];
+        self.seek(SeekFrom::Start(0))?;
+        let mut done = 0;
+        for _ in 0..self.hunk_count() {
+            let size = self.read(&mut buffer)?;
+            sha1.update(&buffer[..size]);
+            done += size as u64;
+            self.report_progress(done);
+        }
+        Ok(sha1.digest().bytes())
+    }
+
+    // Check chd data match header checksum
+    pub fn verify(&mut self) -> io::Result<()> {
+        if !self.compressed() {
+            return Err(invalid_data_str(
+                "chd: uncompressed file doesn't have checksum",
+            ));
+        }
+        let digest = self.data_sha1()?;
+        if digest != self.header.rawsha1 {
+            return Err(invalid_data(format!(
+                "chd: data sha1 {} doesn't match header rawsha1 {}",
+                hex_string(&digest),
+                hex_string(&self.header.rawsha1)
+            )));
+        }
+        let mut metasha = Vec::<[u8; 24]>::new();
+        let calcsha = |io: &mut T, entry: &MetadataEntry| -> io::Result<()> {
+            if entry.flags & MDFLAGS_CHECKSUM == 0 {
+                return Ok(());
+            }
+            let mut buf = vec![0; entry.length as usize];
+            io.read_at(entry.offset, &mut buf)?;
+            metasha.push(metadata_hash(entry.metatag, &buf));
+            Ok(())
+        };
+        Self::visit_metadata(&mut self.io, self.filesize, self.header.metaoffset, calcsha)?;
+        let digest = overall_sha1(&self.header.rawsha1, metasha);
+        if digest != self.header.sha1 {
+            return Err(invalid_data(format!(
+                "chd: overall sha1 {} doesn't match header sha1 {}",
+                hex_string(&digest),
+                hex_string(&self.header.sha1)
+            )));
+        }
+        self.report_finished(self.size());
+        Ok(())
+    }
+
+    // Write whole logical image into `to`, returns number of written bytes.
+    // Compressed data of the next hunk is read by separate thread while the current one
+    // is being decompressed, so I/O latency overlaps with decompression time
+    pub fn extract<W: Write>(&mut self, to: &mut W) -> io::Result<u64>
+    where
+        T: Send,
+    {
+        // there are no threads in browser
+        if cfg!(target_arch = "wasm32") {
+            return self.extract_sequential(to);
+        }
+        let Chd {
+            header,
+            io,
+            map,
+            decompress,
+            parent,
+            stat,
+            hunkbuf,
+            progress,
+            throttle,
+            ..
+        } = self;
+        let map = &**map;
+        let hunkcount = header.hunkcount as usize;
+        let hunkbytes = header.hunkbytes as u64;
+        let size = header.size;
+        // follow references to other hunks, so only data and parent hunks are left
+        let locate = |hunknum: usize| -> io::Result<MapHunk> {
+            resolve_self(map, hunknum).and_then(|hunknum| map.locate(hunknum))
+        };
+
+        std::thread::scope(|scope| {
+            let (request, requests) = mpsc::channel::<(u64, Vec<u8>)>();
+            let (reply, replies) = mpsc::channel::<io::Result<Vec<u8>>>();
+            let reader = scope.spawn(move || {
+                let mut iostat = IoStat::default();
+                for (offset, mut buf) in requests {
+                    let result = io.read_at_stat(offset, &mut buf, &mut iostat);
+                    if reply.send(result.map(|_| buf)).is_err() {
+                        break;
+                    }
+                }
+                iostat
+            });
+
+            let mut extract = || -> io::Result<u64> {
+                let mut spare = vec![Vec::new(), Vec::new()]; // one is read while other is decompressed
+                let mut received = stat.raw; // reader thread stat is added at the end
+                let mut pending = VecDeque::with_capacity(2); // located hunks, which data is requested
+                let mut next = 0;
+                let mut written = 0;
+                for hunknum in 0..hunkcount {
+                    while next < hunkcount && pending.len() < 2 && !spare.is_empty() {
+                        let maphunk = locate(next).map_err(|e| hunk_error(next, None, e))?;
+                        if maphunk.0 != COMPRESSION_PARENT {
+                            let mut buf = spare.pop().unwrap();
+                            buf.resize(maphunk.2 as usize, 0);
+                            request
+                                .send((maphunk.1, buf))
+                                .map_err(|_| io::Error::other("chd: extract reader stopped"))?;
+                        }
+                        pending.push_back(maphunk);
+                        next += 1;
+                    }
+                    let maphunk = pending.pop_front().unwrap();
+                    let chunk =
+                        std::cmp::min(hunkbytes, size - hunknum as u64 * hunkbytes) as usize;
+                    if maphunk.0 == COMPRESSION_PARENT {
+                        read_parent(parent, decompress, stat, maphunk.1, hunkbuf)
+                            .map_err(|e| hunk_error(hunknum, Some(maphunk), e))?;
+                        to.write_all(&hunkbuf[..chunk])?;
+                    } else {
+                        let buf = replies
+                            .recv()
+                            .map_err(|_| io::Error::other("chd: extract reader stopped"))?
+                            .map_err(|e| hunk_error(hunknum, Some(maphunk), e))?;
+                        if let Some(throttle) = throttle.as_mut() {
+                            received.add(buf.len());
+                            throttle.pace(&received);
+                        }
+                        match maphunk.0 {
+                            COMPRESSION_NONE => to.write_all(&buf[..chunk])?,
+                            _ => {
+                                decompress_data(maphunk, decompress, stat, &buf, hunkbuf)
+                                    .map_err(|e| hunk_error(hunknum, Some(maphunk), e))?;
+                                to.write_all(&hunkbuf[..chunk])?;
+                            }
+                        }
+                        spare.push(buf);
+                    }
+                    written += chunk as u64;
+                    if let Some(progress) = progress {
+                        progress.report(written, size);
+                    }
+                    decompress.report(hunknum);
+                    decompress.send(Event::HunkDone {
+                        hunk: hunknum,
+                        done: written,
+                        total: size,
+                    });
+                }
+                decompress.send(Event::Finished { done: written });
+                Ok(written)
+            };
+            let result = extract();
+            drop(request);
+            let iostat = reader
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e));
+            stat.raw.reads += iostat.reads;
+            stat.raw.bytes += iostat.bytes;
+            if let Ok(written) = result {
+                stat.logical.add(written as usize);
+            }
+            result
+        })
+    }
+
+    fn extract_sequential<W: Write>(&mut self, to: &mut W) -> io::Result<u64> {
+        let mut buf = vec![0; self.hunk_size()];
+        let mut written = 0;
+        for hunknum in 0..self.hunk_count() {
+            self.read_hunk(hunknum, &mut buf)?;
+            let chunk = std::cmp::min(buf.len() as u64, self.size() - written) as usize;
+            to.write_all(&buf[..chunk])?;
+            written += chunk as u64;
+            self.report_progress(written);
+        }
+        self.stat.logical.add(written as usize);
+        self.report_finished(written);
+        Ok(written)
+    }
+
+    /// Read decompressed hunk `hunknum` into `buf`, which must be exactly hunk_size() bytes.
+    ///
+    /// The last hunk is always returned in full, even if logical size ends in the middle of it.
+    /// Doesn't use the cache and doesn't change current read position.
+    pub fn read_hunk(&mut self, hunknum: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.check_hunk(hunknum)?;
+        if buf.len() != self.hunk_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "hunk#{}: buffer size {} doesn't match hunk size {}",
+                    hunknum,
+                    buf.len(),
+                    self.hunk_size()
+                ),
+            ));
+        }
+        read_hunk(
+            &mut self.io,
+            &*self.map,
+            &mut self.decompress,
+            &mut self.parent,
+            &mut self.stat,
+            hunknum,
+            buf,
+        )?;
+        self.pace();
+        Ok(())
+    }
+
+    // Read `count` units starting from unit `first_unit` into buf, which must be exactly
+    // count * unit_size() bytes. Current position is moved past the last unit read.
+    pub fn read_units(&mut self, first_unit: u64, count: usize, buf: &mut [u8]) -> io::Result<()> {
+        let end = first_unit.checked_add(count as u64);
+        if end.is_none_or(|end| end > self.unit_count()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid units {}+{}: chd has {} units",
+                    first_unit,
+                    count,
+                    self.unit_count()
+                ),
+            ));
+        }
+        if buf.len() as u64 != count as u64 * self.unit_size_u64() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "units {}+{}: buffer size {} doesn't match unit size {}",
+                    first_unit,
+                    count,
+                    buf.len(),
+                    self.unit_size()
+                ),
+            ));
+        }
+        self.seek(SeekFrom::Start(first_unit * self.unit_size_u64()))?;
+        self.read_exact(buf)
+    }
+
+    // Iterate over all hunks in order, see Hunks
+    pub fn hunks(&mut self) -> Hunks<'_, T> {
+        Hunks {
+            chd: self,
+            hunknum: 0,
+        }
+    }
+
+    fn visit_metadata<F>(io: &mut T, filesize: u64, offset: u64, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&mut T, &MetadataEntry) -> io::Result<()>,
+    {
+        let mut chain = MetadataChain::new(offset, filesize);
+        while let Some(entry) = chain.next(io)? {
+            f(io, &entry)?;
+        }
+        Ok(())
+    }
+
+    fn find_metadata(&mut self, tag: u32, index: u32) -> io::Result<Option<MetadataEntry>> {
+        if let Some((i, entry)) = self.cachemeta {
+            if tag == entry.metatag && i == index {
+                return Ok(Some(entry));
+            }
+        }
+
+        let mut chain = MetadataChain::new(self.header.metaoffset, self.filesize);
+        let mut i = 0;
+        while let Some(entry) = chain.next(&mut self.io)? {
+            if tag == entry.metatag {
+                if i == index {
+                    self.cachemeta = Some((i, entry));
+                    return Ok(Some(entry));
+                }
+                i += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn read_metadata_at(
+        &mut self,
+        tag: u32,
+        index: u32,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> io::Result<Option<usize>> {
+        match self.find_metadata(tag, index)? {
+            Some(entry) => {
+                let length = entry.length as usize;
+                if length <= offset {
+                    return Ok(Some(0));
+                }
+                let available = length - offset;
+                let chunk = std::cmp::min(available, buf.len());
+                self.io.read_at_stat(
+                    entry.offset + offset as u64,
+                    &mut buf[..chunk],
+                    &mut self.stat.raw,
+                )?;
+                Ok(Some(available))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn read_metadata(&mut self, tag: u32, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        self.read_metadata_at(tag, 0, 0, buf)
+    }
+
+    // Reader of `index`th entry with tag, which data isn't read into memory at once
+    pub fn metadata_reader(
+        &mut self,
+        tag: u32,
+        index: u32,
+    ) -> io::Result<Option<MetadataReader<'_, T>>> {
+        let entry = match self.find_metadata(tag, index)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        Ok(Some(MetadataReader {
+            chd: self,
+            offset: entry.offset,
+            length: entry.length as u64,
+            pos: 0,
+        }))
+    }
+
+    pub fn read_metadata_simple(&mut self, tag: u32) -> io::Result<Option<Vec<u8>>> {
+        match self.find_metadata(tag, 0)? {
+            Some(entry) => {
+                let mut meta = vec![0; entry.length as usize];
+                self.io
+                    .read_at_stat(entry.offset, &mut meta, &mut self.stat.raw)?;
+                Ok(Some(meta))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn dump_metadata<W: Write>(&mut self, to: &mut W) -> io::Result<()> {
+        if self.header.metaoffset == 0 {
+            writeln!(to, "Metadata: none")?;
+            return Ok(());
+        }
+        writeln!(to, "Metadata:")?;
+        let mut buf = [0; 32];
+        let buflen = buf.len();
+        Self::visit_metadata(
+            &mut self.io,
+            self.filesize,
+            self.header.metaoffset,
+            |io, entry| {
+                let length = entry.length as usize;
+                let (chunk, tail) = if length > buflen {
+                    (&mut buf[..buflen - 2], "...")
+                } else {
+                    (&mut buf[..length], "")
+                };
+                io.read_at(entry.offset, chunk)?;
+                writeln!(
+                    to,
+                    "  {}:{:02x}: ({}){}{}",
+                    tag_string(entry.metatag),
+                    entry.flags,
+                    entry.length,
+                    hex_string(chunk),
+                    tail
+                )?;
+                Ok(())
+            },
+        )
+    }
+}
+
+// Sequential reader of whole hunks returned by Chd::hunks().
+// Either iterate for newly allocated hunk buffers, or call next_into() to reuse one.
+pub struct Hunks<'a, T: R> {
+    chd: &'a mut Chd<T>,
+    hunknum: usize, // next hunk to read
+}
+
+impl<'a, T: R> Hunks<'a, T> {
+    // Read next hunk into buf, which must be exactly hunk_size() bytes.
+    // Returns hunk number or None after the last hunk.
+    pub fn next_into(&mut self, buf: &mut [u8]) -> Option<io::Result<usize>> {
+        if self.hunknum >= self.chd.hunk_count() {
+            return None;
+        }
+        let hunknum = self.hunknum;
+        self.hunknum += 1;
+        Some(self.chd.read_hunk(hunknum, buf).map(|_| hunknum))
+    }
+}
+
+impl<'a, T: R> Iterator for Hunks<'a, T> {
+    type Item = io::Result<(usize, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = vec![0; self.chd.hunk_size()];
+        self.next_into(&mut buf)
+            .map(|result| result.map(|hunknum| (hunknum, buf)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.chd.hunk_count() - self.hunknum;
+        (n, Some(n))
+    }
+}
+
+// Data of one metadata entry returned by Chd::metadata_reader()
+pub struct MetadataReader<'a, T: R> {
+    chd: &'a mut Chd<T>,
+    offset: u64, // of entry data in file
+    length: u64, // of entry data
+    pos: u64,    // within entry data
+}
+
+impl<'a, T: R> MetadataReader<'a, T> {
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+impl<'a, T: R> Read for MetadataReader<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let chunk = std::cmp::min(buf.len() as u64, self.length.saturating_sub(self.pos)) as usize;
+        let chd = &mut *self.chd;
+        chd.io
+            .read_at_stat(self.offset + self.pos, &mut buf[..chunk], &mut chd.stat.raw)?;
+        self.pos += chunk as u64;
+        Ok(chunk)
+    }
+}
+
+// Seek past the end is allowed like for File, reads return 0 bytes there
+impl<'a, T: R> Seek for MetadataReader<'a, T> {
+    fn seek(&mut self, sf: SeekFrom) -> io::Result<u64> {
+        let pos = match sf {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::Current(x) => self.pos.checked_add_signed(x),
+            SeekFrom::End(x) => self.length.checked_add_signed(x),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("metadata: invalid seek {:?} at {}", sf, self.pos),
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl<T: R + Write> Chd<T> {
+    // Make rebuilt parent the parent of this chd: data read through the new parent must
+    // match child's data sha1, then parent sha1 in the header is rewritten in place.
+    // On error the header and previously attached parent stay untouched.
+    pub fn rebase<P: R + Send + 'static>(&mut self, parent: Chd<P>) -> io::Result<()> {
+        if !self.has_parent() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "chd: can't rebase chd without parent",
+            ));
+        }
+        let sha1 = parent.sha1();
+        let mode = self.parent.mode;
+        let previous = self.parent.chd.take();
+        let pos = self.pos;
+        let result = self
+            .set_parent_with(parent, ParentMatch::Force)
+            .and_then(|_| self.data_sha1());
+        self.pos = pos;
+        match result {
+            Ok(digest) if digest == self.header.rawsha1 => {}
+            Ok(digest) => {
+                // hunks read through rejected parent are wrong
+                self.cache.clear();
+                self.parent.mode = mode;
+                self.parent.chd = previous;
+                return Err(invalid_data(format!(
+                    "chd: data sha1 {} with parent {} doesn't match rawsha1 {}",
+                    hex_string(&digest),
+                    hex_string(&sha1),
+                    hex_string(&self.header.rawsha1)
+                )));
+            }
+            Err(e) => {
+                self.cache.clear();
+                self.parent.mode = mode;
+                self.parent.chd = previous;
+                return Err(e);
+            }
+        }
+        self.io.seek(SeekFrom::Start(104))?;
+        self.io.write_all(&sha1)?;
+        self.io.flush()?;
+        self.header.parentsha1 = sha1;
+        self.parent.sha1 = sha1;
+        self.parent.mode = ParentMatch::Sha1;
+        self.parent.checked = None;
+        Ok(())
+    }
+}
+
+impl<T: R> Seek for Chd<T> {
+    fn seek(&mut self, sf: SeekFrom) -> io::Result<u64> {
+        let size = self.header.size as i64;
+        let newpos = match sf {
+            SeekFrom::Start(x) => x as i64,
+            SeekFrom::Current(x) => {
+                if let Some(xx) = self.pos.checked_add(x) {
+                    xx
+                } else {
+                    return Err(invalid_data(format!(
+                        "chd: overflowing seek {}{:+}, logical size {}",
+                        self.pos,
+                        x,
+                        self.size()
+                    )));
+                }
+            }
+            SeekFrom::End(x) => {
+                if let Some(xx) = size.checked_add(x) {
+                    xx
+                } else {
+                    return Err(invalid_data(format!(
+                        "chd: overflowing seek {} from logical end {}",
+                        x,
+                        self.size()
+                    )));
+                }
+            }
+        };
+        if newpos < 0 || (self.strict_seek && newpos > size) {
+            return Err(invalid_data(format!(
+                "chd: invalid seek to {} out of logical size {}",
+                newpos,
+                self.size()
+            )));
+        }
+        self.pos = newpos;
+        Ok(self.pos as u64)
+    }
+}
+
+impl<T: R> Chd<T> {
+    // Read logical data at pos through the cache, without touching current position
+    fn read_logical(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        // nothing to read at or past the end, like File after seek past it
+        let hasbytes = self.header.size.saturating_sub(pos);
+        if hasbytes == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut dest = buf;
+        if hasbytes < dest.len() as u64 {
+            dest = dest.split_at_mut(hasbytes as usize).0;
+        }
+        let lastbyte = pos + dest.len() as u64 - 1;
+        let hunkbytes = self.header.hunkbytes as usize;
+        let hunkbytes64 = hunkbytes as u64;
+        let hunklast = hunkbytes - 1;
+
+        let first_hunk = (pos / hunkbytes64) as usize;
+        let last_hunk = (lastbyte / hunkbytes64) as usize;
+        let result = dest.len();
+
+        // iterate over hunks
+        for curhunk in first_hunk..=last_hunk {
+            // determine start/end boundaries
+            let startoffs = match curhunk == first_hunk {
+                true => (pos % hunkbytes64) as usize,
+                false => 0,
+            };
+            let endoffs = match curhunk == last_hunk {
+                true => (lastbyte % hunkbytes64) as usize,
+                false => hunklast,
+            };
+            let length = endoffs + 1 - startoffs;
+            let (mut head, tail) = dest.split_at_mut(length);
+            dest = tail;
+
+            if let Some(cached) = self.cache.get(curhunk) {
+                self.stat.cache.hits += 1;
+                #[cfg(feature = "tracing")]
+                tracing::trace!(hunk = curhunk, "cache hit");
+                head.write_all(&cached[startoffs..startoffs + length])?;
+                continue;
+            }
+            self.stat.cache.misses += 1;
+            #[cfg(feature = "tracing")]
+            tracing::trace!(hunk = curhunk, "cache miss");
+            if startoffs == 0 && endoffs == hunklast {
+                // if it's a full hunk, just read directly from disk
+                self.read_hunk(curhunk, head)?;
+            } else {
+                // otherwise, read through the cache
+                let hunkbuf = &mut self.hunkbuf;
+                // self.read_hunk(curhunk, hunkbuf)?; // error[E0499]: cannot borrow `*self` as mutable more than once at a time
+                read_hunk(
+                    &mut self.io,
+                    &*self.map,
+                    &mut self.decompress,
+                    &mut self.parent,
+                    &mut self.stat,
+                    curhunk,
+                    hunkbuf,
+                )?;
+                head.write_all(&hunkbuf[startoffs..startoffs + length])?;
+                self.cache_hunk(curhunk);
+                self.pace();
+            }
+        }
+        self.stat.logical.add(result);
+        Ok(result)
+    }
+
+    // Fill buf with logical data at offset (pread), current position is not changed
+    pub fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if offset
+            .checked_add(buf.len() as u64)
+            .is_none_or(|end| end > self.size())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "chd: read of {} bytes at {} past logical size {}",
+                    buf.len(),
+                    offset,
+                    self.size()
+                ),
+            ));
+        }
+        self.read_logical(offset, buf)?;
+        Ok(())
+    }
+}
+
+impl<T: R> Read for Chd<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.read_logical(self.pos as u64, buf)?;
+        self.pos += result as i64;
+        Ok(result)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        // slices are filled one after another until the end of logical data
+        let mut result = 0;
+        for buf in bufs {
+            let n = self.read_logical(self.pos as u64, buf)?;
+            self.pos += n as i64;
+            result += n;
+            if n < buf.len() {
+                break;
+            }
+        }
+        Ok(result)
+    }
+}
+
+// Buffer is the rest of current hunk, kept in the hunk cache
+impl<T: R> io::BufRead for Chd<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let pos = self.pos as u64;
+        if pos >= self.size() {
+            return Ok(&[]);
+        }
+        let hunkbytes = self.header.hunkbytes as u64;
+        let hunknum = (pos / hunkbytes) as usize;
+        let start = (pos % hunkbytes) as usize;
+        let end = start + std::cmp::min(hunkbytes - start as u64, self.size() - pos) as usize;
+
+        if self.cache.get(hunknum).is_none() {
+            self.stat.cache.misses += 1;
+            #[cfg(feature = "tracing")]
+            tracing::trace!(hunk = hunknum, "cache miss");
+            read_hunk(
+                &mut self.io,
+                &*self.map,
+                &mut self.decompress,
+                &mut self.parent,
+                &mut self.stat,
+                hunknum,
+                &mut self.hunkbuf,
+            )?;
+            self.cache_hunk(hunknum);
+            self.pace();
+            return Ok(&self.hunkbuf[start..end]);
+        }
+        self.stat.cache.hits += 1;
+        #[cfg(feature = "tracing")]
+        tracing::trace!(hunk = hunknum, "cache hit");
+        let cached = self.cache.get(hunknum).expect("cached hunk disappeared");
+        Ok(&cached[start..end])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = std::cmp::min(amt as u64, self.size().saturating_sub(self.pos as u64));
+        self.pos += amt as i64;
+        self.stat.logical.add(amt as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    /*
+    dd if=/dev/urandom of=data.bin bs=4096 count=8
+    base64 < data.bin > data.b64
+    du -b samples/data.b64
+    */
+    const DATA_SIZE: usize = 44267;
+    const IMAGE: &[u8] = include_bytes!("../samples/data.b64");
+    type MemChd<'a> = Chd<Cursor<&'a [u8]>>;
+
+    fn open_chd(raw: &[u8]) -> MemChd<'_> {
+        let file = Cursor::new(raw);
+        let chd = Chd::open(file).unwrap();
+        assert_eq!(chd.version(), V5);
+        assert_eq!(chd.file_size(), raw.len() as u64);
+        chd
+    }
+
+    fn validate_all(chd: &mut MemChd) {
+        chd.validate().unwrap();
+        chd.verify().unwrap();
+    }
+
+    #[test]
+    fn test_basic() {
+        /*
+        chdman createraw -hs 4096 -us 512 -i data.b64 -o none.chd -c none
+        */
+        let mut chd = open_chd(include_bytes!("../samples/none.chd"));
+        assert_eq!(chd.size(), DATA_SIZE as u64);
+
+        // read hunk
+        let mut buf = vec![0; chd.hunk_size()];
+        chd.read_hunk(0, &mut buf).unwrap();
+        let image = IMAGE;
+        assert_eq!(buf, image[0..chd.hunk_size()]);
+        let err = chd.read_hunk(chd.hunk_count(), &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = chd.read_hunk(0, &mut buf[1..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // seek
+        let last_byte = chd.size() - 1;
+        assert_eq!(chd.seek(SeekFrom::Start(0)).unwrap(), 0);
+        assert!(chd.seek(SeekFrom::Current(-1)).is_err());
+        assert_eq!(chd.stream_position().unwrap(), 0);
+        assert_eq!(chd.seek(SeekFrom::Start(1)).unwrap(), 1);
+        assert_eq!(chd.seek(SeekFrom::End(0)).unwrap(), chd.size());
+        assert_eq!(chd.seek(SeekFrom::Current(1)).unwrap(), chd.size() + 1);
+        assert_eq!(chd.read(&mut buf).unwrap(), 0);
+        assert_eq!(chd.seek(SeekFrom::Current(-2)).unwrap(), last_byte);
+        assert!(chd.seek(SeekFrom::Current(0 - chd.size() as i64)).is_err());
+
+        // read
+        let hunksize = chd.hunk_size();
+        let fixtures = [
+            (1, 1),
+            (0, hunksize),
+            (0, hunksize - 1),
+            (0, 1),
+            (1, hunksize - 2),
+            (hunksize - 1, 2),
+            (hunksize - 1, hunksize + 2),
+            (0, chd.size() as usize),
+        ];
+        for (offset, length) in &fixtures {
+            let end = *offset + *length;
+            let original = &image[*offset..end];
+            let mut sample = vec![0; *length];
+            chd.read_at(*offset as u64, &mut sample).unwrap();
+            assert_eq!(sample, original);
+            // check read updates pos
+            assert_eq!(chd.stream_position().unwrap(), end as u64);
+        }
+
+        // try read missing metadata
+        assert!(chd.read_metadata(metadata::AV, &mut buf).unwrap().is_none());
+    }
+
+    // Reader returning a few bytes per read, interrupted or blocked in between
+    struct Flaky<'a> {
+        inner: Cursor<&'a [u8]>,
+        calls: usize,
+        block: bool,
+    }
+
+    impl Read for Flaky<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.calls += 1;
+            match self.calls % 3 {
+                0 if self.block => Err(io::ErrorKind::WouldBlock.into()),
+                0 => Err(io::ErrorKind::Interrupted.into()),
+                _ => {
+                    let n = std::cmp::min(buf.len(), 7);
+                    self.inner.read(&mut buf[..n])
+                }
+            }
+        }
+    }
+
+    impl Seek for Flaky<'_> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_interrupted_reads() {
+        let raw = include_bytes!("../samples/zlib.chd");
+        let flaky = Flaky {
+            inner: Cursor::new(&raw[..]),
+            calls: 0,
+            block: false,
+        };
+        let mut chd = Chd::open(flaky).unwrap();
+        chd.validate().unwrap();
+        chd.verify().unwrap();
+        let mut image = Vec::new();
+        chd.seek(SeekFrom::Start(0)).unwrap();
+        chd.read_to_end(&mut image).unwrap();
+        let mut expected = Vec::new();
+        open_chd(raw).read_to_end(&mut expected).unwrap();
+        assert!(image == expected);
+
+        // blocked read is reported and can be retried
+        let mut flaky = Flaky {
+            inner: Cursor::new(&raw[..]),
+            calls: 2,
+            block: true,
+        };
+        let mut buf = [0; 14];
+        let err = flaky.read_at(0, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        flaky.read_at(0, &mut buf).unwrap();
+        assert_eq!(&buf[..8], b"MComprHD");
+    }
+
+    #[test]
+    fn test_seek_past_end() {
+        use std::io::BufRead;
+        let raw = include_bytes!("../samples/zlib.chd");
+        let mut chd = open_chd(raw);
+        let past = chd.size() + 100;
+        assert_eq!(chd.seek(SeekFrom::End(100)).unwrap(), past);
+        let mut buf = [1; 16];
+        assert_eq!(chd.read(&mut buf).unwrap(), 0);
+        assert!(chd.fill_buf().unwrap().is_empty());
+        chd.consume(1);
+        assert_eq!(chd.stream_position().unwrap(), past);
+        let err = chd.read_exact(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let options = ChdOptions {
+            strict_seek: true,
+            ..Default::default()
+        };
+        let mut chd = Chd::open_with_options(Cursor::new(&raw[..]), options).unwrap();
+        assert!(chd.seek(SeekFrom::End(1)).is_err());
+        assert_eq!(chd.seek(SeekFrom::End(0)).unwrap(), chd.size());
+    }
+
+    #[test]
+    fn test_strict_codecs() {
+        let mut raw = include_bytes!("../samples/zlib.chd").to_vec();
+        raw[20..24].copy_from_slice(b"xxxx"); // second compressor slot
+        let mut chd = open_chd(&raw);
+        let mut buf = vec![0; chd.hunk_size()];
+        chd.read_hunk(0, &mut buf).unwrap();
+
+        let err = Chd::open_with_options(Cursor::new(&raw[..]), ChdOptions::strict())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("xxxx"));
+    }
+
+    struct Counting {
+        inner: Box<dyn Decompress>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Decompress for Counting {
+        fn decompress(&mut self, src: &[u8], dest: &mut [u8]) -> io::Result<()> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            self.inner.decompress(src, dest)
+        }
+    }
+
+    struct Fail;
+
+    impl Decompress for Fail {
+        fn decompress(&mut self, _src: &[u8], _dest: &mut [u8]) -> io::Result<()> {
+            Err(io::Error::other("fail"))
+        }
+
+        fn memory_usage(&self) -> usize {
+            1 << 40
+        }
+    }
+
+    #[test]
+    fn test_set_codec() {
+        let raw = include_bytes!("../samples/zlib.chd");
+        let mut chd = open_chd(raw);
+        let mut buf = vec![0; chd.hunk_size()];
+        assert!(chd.set_codec(1, Box::new(Fail)).is_err());
+        assert!(chd.set_codec(4, Box::new(Fail)).is_err());
+
+        let inner = chd.set_codec(0, Box::new(Fail)).unwrap();
+        assert!(chd.read_hunk(0, &mut buf).is_err());
+        assert_eq!(chd.stats().codecs[0].calls, 1);
+        assert_eq!(chd.stats().codecs[0].hunks, 0);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counting = Counting {
+            inner,
+            calls: calls.clone(),
+        };
+        chd.set_codec(0, Box::new(counting)).unwrap();
+        chd.read_hunk(0, &mut buf).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        validate_all(&mut chd);
+
+        // replacement has to fit memory budget
+        let options = ChdOptions {
+            memory_budget: Some(1 << 20),
+            ..Default::default()
+        };
+        let mut chd = Chd::open_with_options(Cursor::new(&raw[..]), options).unwrap();
+        let used = chd.budget.used();
+        assert!(chd.set_codec(0, Box::new(Fail)).is_err());
+        assert_eq!(chd.budget.used(), used);
+        chd.read_hunk(0, &mut buf).unwrap();
+    }
+
+    #[test]
+    fn test_io_limit() {
+        let raw = include_bytes!("../samples/zlib.chd");
+        let limit = IoLimit {
+            reads_per_sec: Some(100),
+            ..Default::default()
+        };
+        let options = ChdOptions {
+            io_limit: Some(limit),
+            ..Default::default()
+        };
+        let mut chd = Chd::open_with_options(Cursor::new(&raw[..]), options).unwrap();
+        assert_eq!(chd.io_limit(), Some(limit));
+        let clock = Arc::new(utils::VirtualClock::new());
+        chd.throttle = Some(Throttle::with_clock(limit, clock.clone()));
+        let mut buf = vec![0; chd.hunk_size()];
+        let start = clock.now();
+        for hunknum in 0..6 {
+            chd.read_hunk(hunknum % chd.hunk_count(), &mut buf).unwrap();
+        }
+        assert_eq!(clock.now() - start, std::time::Duration::from_millis(60));
+        let start = clock.now();
+        chd.extract(&mut io::sink()).unwrap();
+        let limited = std::time::Duration::from_millis(10 * chd.hunk_count() as u64);
+        assert_eq!(clock.now() - start, limited);
+
+        chd.set_io_limit(None);
+        assert_eq!(chd.io_limit(), None);
+    }
+
+    fn test_compressed_chd(raw: &[u8]) {
+        let mut chd = open_chd(raw);
+        // read hunk
+        let mut buf = vec![0; chd.hunk_size()];
+        chd.read_hunk(0, &mut buf).unwrap();
+        validate_all(&mut chd);
+    }
+
+    #[test]
+    fn test_huffman() {
+        /*
+        chdman createraw -hs 4096 -us 512 -i data.b64 -o huff.chd -c huff
+        */
+        test_compressed_chd(include_bytes!("../samples/huff.chd"))
+    }
+
+    #[test]
+    fn test_flac() {
+        /*
+        chdman createraw -hs 4096 -us 512 -i data.b64 -o flac.chd -c flac
+        */
+        test_compressed_chd(include_bytes!("../samples/flac.chd"))
+    }
+
+    #[test]
+    #[cfg(feature = "lzma")]
+    fn test_lzma() {
+        /*
+        chdman createraw -hs 4096 -us 512 -i data.b64 -o lzma.chd -c lzma
+        */
+        test_compressed_chd(include_bytes!("../samples/lzma.chd"))
+    }
+
+    #[test]
+    #[cfg(feature = "lzma")]
+    fn test_hunk_error() {
+        // range coder of lzma stream starts with zero byte
+        let mut raw = include_bytes!("../samples/lzma.chd").to_vec();
+        let info = open_chd(include_bytes!("../samples/lzma.chd"))
+            .hunk_info(2)
+            .unwrap();
+        raw[info.offset as usize] = 0xff;
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let mut buf = vec![0; chd.hunk_size()];
+        for err in [
+            chd.read_hunk(2, &mut buf).unwrap_err(),
+            chd.validate_hunk(2).unwrap_err(),
+            chd.extract(&mut Vec::new()).unwrap_err(),
+        ] {
+            assert_eq!(err.to_string(), "hunk#2: lzma: corrupt data");
+            let error = HunkError::find(&err).unwrap();
+            assert_eq!((error.hunk, error.offset), (2, Some(info.offset)));
+            let lzma = error.error.get_ref().unwrap().downcast_ref();
+            assert!(matches!(lzma, Some(lzma::LzmaError::Data)));
+        }
+    }
+
+    #[test]
+    fn test_zlib() {
+        /*
+        chdman createraw -hs 4096 -us 512 -i data.b64 -o zlib.chd -c zlib
+        */
+        test_compressed_chd(include_bytes!("../samples/zlib.chd"))
+    }
+
+    #[test]
+    fn test_cdfl() {
+        /*
+        ffmpeg -i /usr/share/sounds/freedesktop/stereo/bell.oga -o bell.wav
+        chdman createcd -i bell.cue -o cdfl.chd -c cdfl
+         */
+        test_compressed_chd(include_bytes!("../samples/cdfl.chd"))
+    }
+
+    #[test]
+    #[cfg(feature = "lzma")]
+    fn test_cdlz() {
+        /*
+        chdman createcd -i bell.cue -o cdlz.chd -c cdlz
+         */
+        test_compressed_chd(include_bytes!("../samples/cdlz.chd"))
+    }
+
+    #[test]
+    fn test_cdzl() {
+        /*
+        chdman createcd -i bell.cue -o cdzl.chd -c cdzl
+         */
+        test_compressed_chd(include_bytes!("../samples/cdzl.chd"))
+    }
+
+    #[test]
+    fn test_cd_length_past_end() {
+        let mut raw = include_bytes!("../samples/cdzl.chd").to_vec();
+        let chd = open_chd(&raw);
+        let (_, offset, _) = chd.map.locate(0).unwrap();
+        let ecc_bytes = (chd.hunk_size() / cd::FRAME_SIZE).div_ceil(8);
+        let length = offset as usize + ecc_bytes;
+        raw[length..length + 2].copy_from_slice(&[0xff, 0xff]);
+        let mut chd = open_chd(&raw);
+        let mut buf = vec![0; chd.hunk_size()];
+        let e = chd.read_hunk(0, &mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_compression_self() {
+        /* generate file with lots of repetitions
+        tr \\000 A < /dev/zero | dd of=a.bin bs=44267 count=1
+        chdman createraw -hs 4096 -us 512 -i a.bin -o self.chd -c huff
+        */
+        let mut chd = open_chd(include_bytes!("../samples/self.chd"));
+        let mut buf = vec![1; chd.hunk_size()];
+        chd.read_hunk(0, &mut buf).unwrap();
+        validate_all(&mut chd);
+    }
+
+    // Every hunk refers to the next one, the last one to the first, or past the end
+    struct LoopMap(usize, usize);
+
+    impl Map for LoopMap {
+        fn hunk_count(&self) -> usize {
+            self.0
+        }
+        fn locate(&self, hunknum: usize) -> io::Result<MapHunk> {
+            Ok((COMPRESSION_SELF, ((hunknum + 1) % self.1) as u64, 0))
+        }
+        fn validate(&self, _hunknum: usize, _buf: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+        fn crc(&self, _hunknum: usize) -> Option<u16> {
+            None
+        }
+
+        fn memory_usage(&self) -> usize {
+            0
+        }
+    }
+
+    #[test]
+    fn test_self_loop() {
+        let mut chd = open_chd(include_bytes!("../samples/self.chd"));
+        let hunkcount = chd.hunk_count();
+        chd.map = Box::new(LoopMap(hunkcount, hunkcount));
+        let mut buf = vec![0; chd.hunk_size()];
+        let err = chd.read_hunk(1, &mut buf).unwrap_err();
+        assert_eq!(err.to_string(), "hunk#1: self reference loop");
+        assert!(chd.validate_hunk(0).is_err());
+        assert!(chd.read(&mut buf[..10]).is_err());
+        let err = chd.extract(&mut Vec::new()).unwrap_err();
+        assert_eq!(HunkError::find(&err).unwrap().hunk, 0);
+
+        chd.map = Box::new(LoopMap(hunkcount, hunkcount + 1));
+        let err = chd.read_hunk(1, &mut buf).unwrap_err();
+        let message = format!(
+            "hunk#1: hunk#{} refers to hunk#{1} of {1}",
+            hunkcount - 1,
+            hunkcount
+        );
+        assert_eq!(err.to_string(), message);
+        let error = HunkError::find(&err).unwrap();
+        assert_eq!((error.hunk, error.offset, error.unit), (1, None, None));
+        assert!(chd.validate_hunk(0).is_err());
+    }
+
+    #[test]
+    fn test_child() {
+        /* changes some hunks in source data otherwise we will have the same sha1 hash in parent and child
+        cp data.b64 child.b64
+        dd if=a.bin of=child.b64 bs=4096 count=4 seek=3 conv=notrunc
+        chdman createraw -hs 4096 -us 512 -i child.b64 -o child.chd -op huff.chd -c huff
+        */
+        let mut chd = open_chd(include_bytes!("../samples/child.chd"));
+        let mut buf = vec![1; chd.hunk_size()];
+        let err = chd.read_hunk(0, &mut buf).unwrap_err();
+        assert_eq!(err.to_string(), "hunk#0: requires parent chd");
+        let error = HunkError::find(&err).unwrap();
+        assert_eq!((error.hunk, error.offset, error.unit), (0, None, Some(0)));
+        let err = chd.read_hunk(1, &mut buf).unwrap_err();
+        // unit of parent, not offset in file
+        assert_eq!(HunkError::find(&err).unwrap().unit, Some(8));
+
+        let wrong = open_chd(include_bytes!("../samples/self.chd"));
+        assert!(chd.set_parent(wrong).is_err());
+
+        let parent = open_chd(include_bytes!("../samples/huff.chd"));
+        chd.set_parent(parent).unwrap();
+        chd.read_hunk(0, &mut buf).unwrap();
+        // can't validate() parent hunks, read chd instead
+        assert!(chd.validate().is_err());
+        let image = include_bytes!("../samples/child.b64");
+        let mut sample = vec![0; image.len()];
+        chd.read_at(0, &mut sample).unwrap();
+        assert_eq!(sample, image);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut chd = open_chd(include_bytes!("../samples/zlib.chd"));
+        let mut sample = vec![0; chd.size() as usize];
+        chd.read_at(0, &mut sample).unwrap();
+        let stat = chd.stats();
+        assert_eq!(stat.logical.bytes, chd.size());
+        assert_eq!(stat.logical.reads, 1);
+        let hunks = chd.hunk_count() as u64;
+        let codec: u64 = stat.codecs.iter().map(|c| c.hunks).sum();
+        let output: u64 = stat.codecs.iter().map(|c| c.output).sum();
+        let input: u64 = stat.codecs.iter().map(|c| c.input).sum();
+        assert!(codec <= hunks);
+        assert!(output <= hunks * chd.hunk_size() as u64);
+        assert!(stat.raw.bytes >= input);
+        assert_eq!(stat.parent, IoStat::default());
+        let calls: u64 = stat.codecs.iter().map(|c| c.calls).sum();
+        assert_eq!(calls, codec);
+        assert!(stat.decode_time() > std::time::Duration::ZERO);
+        let zlib = stat.codecs.iter().find(|c| c.hunks > 0).unwrap();
+        assert!(zlib.output_per_sec() > 0.0);
+        assert!(zlib.time_per_call() <= zlib.time);
+
+        let before = chd.snapshot_stats();
+        let mut buf = [0; 2];
+        chd.read_at(1, &mut buf).unwrap();
+        chd.read_at(3, &mut buf).unwrap();
+        let (delta, _) = chd.snapshot_stats().since(&before);
+        assert_eq!(delta.logical.bytes, 4);
+        assert_eq!(delta.cache, CacheStat { hits: 1, misses: 1 });
+        assert_eq!(delta.cache.hit_ratio(), 0.5);
+
+        chd.reset_stats();
+        assert_eq!(*chd.stats(), Stat::default());
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut chd = open_chd(include_bytes!("../samples/huff.chd"));
+        chd.set_cache(Box::new(cache::LruCache::new(4)));
+        let hunksize = chd.hunk_size() as u64;
+        let mut buf = [0; 16];
+        for _ in 0..2 {
+            for hunk in 0..4 {
+                chd.read_at(hunk * hunksize + 1, &mut buf).unwrap();
+                assert_eq!(&buf, &IMAGE[hunk as usize * 4096 + 1..][..16]);
+            }
+        }
+        assert_eq!(chd.stats().cache, CacheStat { hits: 4, misses: 4 });
+
+        chd.reset_stats();
+        assert!(chd.prefetch_hunks(8..chd.hunk_count() + 1).is_err());
+        chd.prefetch_hunks(6..10).unwrap();
+        let mut buf = vec![0; 4 * hunksize as usize];
+        chd.read_at(6 * hunksize, &mut buf).unwrap();
+        assert_eq!(buf, IMAGE[6 * 4096..10 * 4096]);
+        assert_eq!(chd.stats().cache, CacheStat { hits: 4, misses: 0 });
+    }
+
+    #[test]
+    #[cfg(feature = "lzma")]
+    fn test_memory_budget() {
+        let raw = include_bytes!("../samples/lzma.chd");
+        let options = ChdOptions {
+            memory_budget: Some(4096),
+            ..Default::default()
+        };
+        let err = Chd::open_with_options(Cursor::new(&raw[..]), options).err();
+        assert_eq!(err.unwrap().kind(), io::ErrorKind::OutOfMemory);
+
+        let chd = open_chd(raw);
+        let used = chd.budget.used();
+        let hunksize = chd.hunk_size();
+        let options = ChdOptions {
+            memory_budget: Some(used + 2 * hunksize),
+            ..Default::default()
+        };
+        let mut chd = Chd::open_with_options(Cursor::new(&raw[..]), options).unwrap();
+        chd.set_cache(Box::new(cache::LruCache::new(8)));
+        let mut buf = [0; 16];
+        for hunk in 0..chd.hunk_count() {
+            chd.read_at((hunk * hunksize + 1) as u64, &mut buf).unwrap();
+            assert_eq!(&buf, &IMAGE[hunk * hunksize + 1..][..16]);
+        }
+        let cache = chd.set_cache(Box::new(SingleCache::new()));
+        assert!(cache.memory_usage() <= 2 * hunksize);
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let mut chd = open_chd(include_bytes!("../samples/huff.chd"));
+        let hunksize = chd.hunk_size();
+        let usage = chd.memory_usage();
+        assert!(usage.map >= chd.hunk_count() * 12);
+        assert_eq!(usage.cache, 0);
+        assert_eq!(usage.buffers, hunksize);
+        assert_eq!(usage.parent, 0);
+
+        let mut buf = [0; 16];
+        chd.read_at(1, &mut buf).unwrap();
+        let usage = chd.memory_usage();
+        assert_eq!(usage.cache, hunksize);
+        assert!(usage.codecs > 0);
+        assert_eq!(
+            usage.total(),
+            usage.map + usage.cache + usage.codecs + usage.buffers
+        );
+
+        let mut child = open_chd(include_bytes!("../samples/child.chd"));
+        child.set_parent(chd).unwrap();
+        assert_eq!(child.memory_usage().parent, usage.total());
+    }
+
+    #[test]
+    fn test_extract_state() {
+        let raw = include_bytes!("../samples/zlib.chd");
+        let mut chd = open_chd(raw);
+        let mut expected = Vec::new();
+        chd.extract(&mut expected).unwrap();
+
+        let mut state = chd.extract_state();
+        let mut data = Vec::new();
+        chd.extract_with(&mut data, &mut state, 2).unwrap();
+        assert_eq!(state.hunks_done(), 2);
+        assert_eq!(state.offset(), data.len() as u64);
+
+        // resume by another instance
+        let mut chd = open_chd(raw);
+        let mut state = ExtractState::from_bytes(&state.to_bytes()).unwrap();
+        assert!(chd.extract_with(&mut data, &mut state.clone(), 0).is_ok());
+        while !state.is_complete() {
+            chd.extract_with(&mut data, &mut state, 3).unwrap();
+        }
+        assert_eq!(data, expected);
+        assert_eq!(state.data_sha1(), chd.raw_sha1());
+
+        let other = open_chd(include_bytes!("../samples/cdlz.chd"));
+        assert!(chd
+            .extract_with(&mut data, &mut other.extract_state(), 1)
+            .is_err());
+        let mut bytes = state.to_bytes();
+        bytes[31] ^= 1; // offset
+        assert!(ExtractState::from_bytes(&bytes).is_err());
+        assert!(ExtractState::from_bytes(&bytes[..36]).is_err());
+    }
+
+    #[test]
+    fn test_validation_state() {
+        let mut chd = open_chd(include_bytes!("../samples/zlib.chd"));
+        let mut state = chd.validation_state();
+        assert_eq!(state.validated_count(), 0);
+        chd.validate_with(&mut state).unwrap();
+        assert!(state.is_complete());
+
+        let mut state = ValidationState::from_bytes(&state.to_bytes()).unwrap();
+        chd.reset_stats();
+        chd.validate_with(&mut state).unwrap();
+        assert_eq!(chd.stats().raw, IoStat::default());
+
+        let other = open_chd(include_bytes!("../samples/cdlz.chd"));
+        let mut state = other.validation_state();
+        assert!(chd.validate_with(&mut state).is_err());
+        let mut bytes = state.to_bytes();
+        bytes.push(0);
+        assert!(ValidationState::from_bytes(&bytes).is_err());
+        assert!(ValidationState::from_bytes(&bytes[..20]).is_err());
+        bytes[20..24].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(ValidationState::from_bytes(&bytes).is_err());
+
+        let hunks = state.hunk_count();
+        state.set_validated(hunks);
+        state.set_validated(hunks + 100);
+        assert_eq!(state.validated_count(), 0);
+        assert!(!state.is_validated(hunks));
+    }
+
+    #[test]
+    #[cfg(feature = "lzma")]
+    fn test_bench() {
+        let mut chd = open_chd(include_bytes!("../samples/cdlz.chd"));
+        chd.seek(SeekFrom::Start(5)).unwrap();
+        let profile = bench::BenchProfile {
+            random_reads: 10,
+            ..Default::default()
+        };
+        let report = bench(&mut chd, &profile).unwrap();
+        assert_eq!(chd.stream_position().unwrap(), 5);
+        let hunks = chd.hunk_count() as u64;
+        assert_eq!(report.sequential.reads, hunks);
+        assert_eq!(report.random.reads, 10);
+        let decoded: u64 = report.hunks.iter().map(|h| h.decode.reads).sum();
+        assert_eq!(decoded, hunks);
+        assert!(report.hunks.iter().any(|h| h.tag == CHD_CODEC_CD_LZMA));
+    }
+
+    #[test]
+    fn test_extract() {
+        for raw in [
+            &include_bytes!("../samples/none.chd")[..],
+            &include_bytes!("../samples/cdfl.chd")[..],
+            &include_bytes!("../samples/self.chd")[..],
+        ] {
+            let mut chd = open_chd(raw);
+            let mut expected = vec![0; chd.size() as usize];
+            chd.read_at(0, &mut expected).unwrap();
+            let mut extracted = Vec::new();
+            assert_eq!(chd.extract(&mut extracted).unwrap(), chd.size());
+            assert_eq!(extracted, expected);
+            let mut sequential = Vec::new();
+            chd.extract_sequential(&mut sequential).unwrap();
+            assert_eq!(sequential, expected);
+        }
+
+        let mut chd = open_chd(include_bytes!("../samples/child.chd"));
+        assert!(chd.extract(&mut Vec::new()).is_err());
+        chd.set_parent(open_chd(include_bytes!("../samples/huff.chd")))
+            .unwrap();
+        let mut extracted = Vec::new();
+        chd.extract(&mut extracted).unwrap();
+        assert_eq!(extracted, &include_bytes!("../samples/child.b64")[..]);
+    }
+
+    #[test]
+    fn test_hunk_info() {
+        let chd = open_chd(include_bytes!("../samples/none.chd"));
+        let info = chd.hunk_info(1).unwrap();
+        assert_eq!(info.compression, HunkCompression::None);
+        assert_eq!(info.length, chd.hunk_size_u32());
+        assert_eq!(info.crc, None);
+        assert!(chd.hunk_info(chd.hunk_count()).is_err());
+
+        let mut chd = open_chd(include_bytes!("../samples/self.chd"));
+        let mut buf = vec![0; chd.hunk_size()];
+        let mut selfref = 0;
+        for hunknum in 0..chd.hunk_count() {
+            let info = chd.hunk_info(hunknum).unwrap();
+            match info.compression {
+                HunkCompression::SelfRef => {
+                    assert!(info.offset < chd.hunk_count() as u64);
+                    assert_eq!(info.crc, None);
+                    selfref += 1;
+                }
+                HunkCompression::Codec(tag) => {
+                    assert_eq!(tag, CHD_CODEC_HUFF);
+                    chd.read_hunk(hunknum, &mut buf).unwrap();
+                    assert_eq!(info.crc, Some(crc16(&buf)));
                 }
-                head.write(&cache[startoffs..startoffs + length])?;
+                x => panic!("unexpected hunk#{} compression {:?}", hunknum, x),
             }
         }
-        self.pos += result as i64;
-        Ok(result)
-    }
-}
+        assert!(selfref > 0);
 
-#[cfg(feature = "write_nop")]
-impl<T: R> Write for Chd<T> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        // only advance file position
-        let hasbytes = self.header.size - self.pos as u64;
-        Ok(if hasbytes < buf.len() as u64 {
-            hasbytes as usize
-        } else {
-            buf.len()
-        })
+        let chd = open_chd(include_bytes!("../samples/child.chd"));
+        let parent = (0..chd.hunk_count())
+            .filter(|h| chd.hunk_info(*h).unwrap().compression == HunkCompression::Parent)
+            .count();
+        assert!(parent > 0);
     }
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+
+    #[test]
+    fn test_read_hunk_raw() {
+        let mut chd = open_chd(include_bytes!("../samples/none.chd"));
+        let mut buf = vec![0; chd.hunk_size()];
+        chd.read_hunk(1, &mut buf).unwrap();
+        assert_eq!(chd.read_hunk_raw(1).unwrap(), buf);
+
+        let mut chd = open_chd(include_bytes!("../samples/self.chd"));
+        for hunknum in 0..chd.hunk_count() {
+            let info = chd.hunk_info(hunknum).unwrap();
+            let raw = chd.read_hunk_raw(hunknum).unwrap();
+            assert_eq!(raw.len(), info.length as usize);
+        }
+        assert!(chd.read_hunk_raw(chd.hunk_count()).is_err());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Cursor;
-    /*
-    dd if=/dev/urandom of=data.bin bs=4096 count=8
-    base64 < data.bin > data.b64
-    du -b samples/data.b64
-    */
-    const DATA_SIZE: usize = 44267;
-    const IMAGE: &[u8] = include_bytes!("../samples/data.b64");
-    type MemChd<'a> = Chd<Cursor<&'a [u8]>>;
+    #[test]
+    fn test_hunks() {
+        let mut chd = open_chd(include_bytes!("../samples/self.chd"));
+        let mut expected = Vec::new();
+        chd.read_to_end(&mut expected).unwrap();
 
-    fn open_chd(raw: &[u8]) -> MemChd {
-        let file = Cursor::new(raw);
-        let chd = Chd::open(file).unwrap();
-        assert_eq!(chd.version(), V5);
-        assert_eq!(chd.file_size(), raw.len() as u64);
-        chd
+        let mut data = Vec::new();
+        for (i, hunk) in chd.hunks().enumerate() {
+            let (hunknum, buf) = hunk.unwrap();
+            assert_eq!(hunknum, i);
+            data.extend_from_slice(&buf);
+        }
+        data.truncate(expected.len());
+        assert!(data == expected);
+
+        let mut hunks = chd.hunks();
+        assert_eq!(hunks.size_hint(), (11, Some(11)));
+        let mut buf = vec![0; 1];
+        assert!(hunks.next_into(&mut buf).unwrap().is_err());
+        let mut buf = vec![0; hunks.chd.hunk_size()];
+        let mut count = 0;
+        while let Some(hunknum) = hunks.next_into(&mut buf) {
+            assert_eq!(hunknum.unwrap(), count + 1);
+            count += 1;
+        }
+        assert_eq!(count, 10);
     }
 
-    fn validate_all(chd: &mut MemChd) {
-        chd.validate().unwrap();
-        chd.verify().unwrap();
+    #[test]
+    fn test_read_units() {
+        let mut chd = open_chd(include_bytes!("../samples/cdzl.chd"));
+        let unit = chd.unit_size();
+        assert_eq!(unit, 2448);
+        assert_eq!(chd.unit_count(), chd.size() / 2448);
+        let mut expected = vec![0; 3 * unit];
+        chd.seek(SeekFrom::Start(5 * unit as u64)).unwrap();
+        chd.read_exact(&mut expected).unwrap();
+
+        let mut buf = vec![0; 3 * unit];
+        chd.read_units(5, 3, &mut buf).unwrap();
+        assert!(buf == expected);
+        assert_eq!(chd.stream_position().unwrap(), 8 * unit as u64);
+
+        let last = chd.unit_count() - 1;
+        chd.read_units(last, 1, &mut buf[..unit]).unwrap();
+        assert!(chd.read_units(last, 2, &mut buf[..2 * unit]).is_err());
+        assert!(chd.read_units(u64::MAX, 1, &mut buf[..unit]).is_err());
+        assert!(chd.read_units(0, 1, &mut buf).is_err());
     }
 
     #[test]
-    fn test_basic() {
-        /*
-        chdman createraw -hs 4096 -us 512 -i data.b64 -o none.chd -c none
-        */
-        let mut chd = open_chd(include_bytes!("../samples/none.chd"));
-        assert_eq!(chd.size(), DATA_SIZE as u64);
+    fn test_read_exact_at() {
+        let mut chd = open_chd(include_bytes!("../samples/zlib.chd"));
+        let mut expected = Vec::new();
+        chd.read_to_end(&mut expected).unwrap();
+        chd.seek(SeekFrom::Start(100)).unwrap();
 
-        // read hunk
-        let mut buf = vec![0; chd.hunk_size()];
-        chd.read_hunk(0, &mut buf).unwrap();
-        let image = IMAGE;
-        assert_eq!(buf, image[0..chd.hunk_size()]);
+        let mut buf = vec![0; 5000];
+        chd.read_exact_at(4000, &mut buf).unwrap();
+        assert!(buf[..] == expected[4000..9000]);
+        let size = chd.size();
+        chd.read_exact_at(size - 5000, &mut buf).unwrap();
+        assert!(buf[..] == expected[expected.len() - 5000..]);
+        let e = chd.read_exact_at(size - 4999, &mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(chd.read_exact_at(u64::MAX, &mut buf).is_err());
+        chd.read_exact_at(size, &mut []).unwrap();
+        chd.read_exact_at(0, &mut []).unwrap();
+        assert_eq!(chd.stream_position().unwrap(), 100);
+    }
 
-        // seek
-        let last_byte = chd.size() - 1;
-        assert_eq!(chd.seek(SeekFrom::Start(0)).unwrap(), 0);
-        assert!(chd.seek(SeekFrom::Current(-1)).is_err());
-        assert_eq!(chd.seek(SeekFrom::Current(0)).unwrap(), 0);
-        assert_eq!(chd.seek(SeekFrom::Start(1)).unwrap(), 1);
-        assert_eq!(chd.seek(SeekFrom::End(0)).unwrap(), chd.size());
-        assert!(chd.seek(SeekFrom::Current(1)).is_err());
-        assert_eq!(chd.seek(SeekFrom::Current(-1)).unwrap(), last_byte);
-        assert!(chd.seek(SeekFrom::Current(0 - chd.size() as i64)).is_err());
+    #[test]
+    fn test_read_vectored() {
+        let mut chd = open_chd(include_bytes!("../samples/zlib.chd"));
+        let mut expected = Vec::new();
+        chd.read_to_end(&mut expected).unwrap();
 
-        // read
-        let hunksize = chd.hunk_size();
-        let fixtures = [
-            (1, 1),
-            (0, hunksize),
-            (0, hunksize - 1),
-            (0, 1),
-            (1, hunksize - 2),
-            (hunksize - 1, 2),
-            (hunksize - 1, hunksize + 2),
-            (0, chd.size() as usize),
+        let size = chd.size();
+        chd.seek(SeekFrom::End(-7000)).unwrap();
+        let (mut a, mut b, mut c) = (vec![0; 10], vec![0; 6000], vec![0; 2000]);
+        let mut bufs = [
+            io::IoSliceMut::new(&mut a),
+            io::IoSliceMut::new(&mut []),
+            io::IoSliceMut::new(&mut b),
+            io::IoSliceMut::new(&mut c),
         ];
-        for (offset, length) in &fixtures {
-            let end = *offset + *length;
-            let original = &image[*offset..end];
-            let mut sample = vec![0; *length];
-            chd.read_at(*offset as u64, &mut sample).unwrap();
-            assert_eq!(sample, original);
-            // check read updates pos
-            assert_eq!(chd.seek(SeekFrom::Current(0)).unwrap(), end as u64);
-        }
+        assert_eq!(chd.read_vectored(&mut bufs).unwrap(), 7000);
+        assert_eq!(chd.stream_position().unwrap(), size);
+        assert_eq!(chd.read_vectored(&mut bufs).unwrap(), 0);
+        let start = expected.len() - 7000;
+        assert!(a[..] == expected[start..start + 10]);
+        assert!(b[..] == expected[start + 10..start + 6010]);
+        assert!(c[..990] == expected[start + 6010..]);
+    }
 
-        // try read missing metadata
-        assert!(chd.read_metadata(metadata::AV, &mut buf).unwrap().is_none());
+    #[test]
+    fn test_buf_read() {
+        use std::io::BufRead;
+
+        let mut chd = open_chd(include_bytes!("../samples/flac.chd"));
+        let mut expected = Vec::new();
+        chd.read_to_end(&mut expected).unwrap();
+
+        chd.seek(SeekFrom::Start(100)).unwrap();
+        let hunk_size = chd.hunk_size();
+        assert_eq!(chd.fill_buf().unwrap().len(), hunk_size - 100);
+        assert!(chd.fill_buf().unwrap() == &expected[100..hunk_size]);
+        chd.consume(hunk_size - 110);
+        assert!(chd.fill_buf().unwrap() == &expected[hunk_size - 10..hunk_size]);
+        chd.consume(10);
+        assert_eq!(chd.stream_position().unwrap(), hunk_size as u64);
+
+        // mix buffered and regular reads
+        let mut data = expected[..hunk_size].to_vec();
+        let mut buf = [0; 3];
+        loop {
+            let n = chd.fill_buf().unwrap().len();
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&chd.fill_buf().unwrap()[..n / 2]);
+            chd.consume(n / 2);
+            let n = chd.read(&mut buf).unwrap();
+            data.extend_from_slice(&buf[..n]);
+        }
+        assert!(data == expected);
+        chd.consume(1);
+        assert_eq!(chd.stream_position().unwrap(), chd.size());
     }
 
-    fn test_compressed_chd(raw: &[u8]) {
-        let mut chd = open_chd(raw);
-        // read hunk
+    #[test]
+    fn test_into_parts() {
+        let chd = open_chd(include_bytes!("../samples/zlib.chd"));
+        let size = chd.size();
+        let io = chd.into_inner();
+        let options = ChdOptions {
+            memory_budget: Some(1 << 20),
+            ..Default::default()
+        };
+        let chd = Chd::open_with_options(io, options).unwrap();
+        assert_eq!(chd.size(), size);
+
+        let (_, parent) = chd.into_parts();
+        assert!(parent.is_none());
+        let mut chd = open_chd(include_bytes!("../samples/child.chd"));
+        chd.set_parent(open_chd(include_bytes!("../samples/huff.chd")))
+            .unwrap();
+        let (io, parent) = chd.into_parts();
+        let mut chd = Chd::open(io).unwrap();
+        chd.set_parent(parent.unwrap()).unwrap();
         let mut buf = vec![0; chd.hunk_size()];
         chd.read_hunk(0, &mut buf).unwrap();
-        validate_all(&mut chd);
     }
 
     #[test]
-    fn test_huffman() {
-        /*
-        chdman createraw -hs 4096 -us 512 -i data.b64 -o huff.chd -c huff
-        */
-        test_compressed_chd(include_bytes!("../samples/huff.chd"))
+    fn test_codecs() {
+        let chd = open_chd(include_bytes!("../samples/none.chd"));
+        assert!(!chd.is_compressed());
+        assert_eq!(chd.codecs(), [None; 4]);
+        assert!(chd.compression_names().is_empty());
+
+        let chd = open_chd(include_bytes!("../samples/cdlz.chd"));
+        assert!(chd.is_compressed());
+        let codecs = chd.codecs();
+        assert_eq!(codecs[0], Some(CHD_CODEC_CD_LZMA));
+        let names = chd.compression_names();
+        assert_eq!(names.len(), codecs.iter().flatten().count());
+        assert!(names[0].starts_with("cdlz"));
     }
 
     #[test]
-    fn test_flac() {
-        /*
-        chdman createraw -hs 4096 -us 512 -i data.b64 -o flac.chd -c flac
-        */
-        test_compressed_chd(include_bytes!("../samples/flac.chd"))
+    fn test_sha1() {
+        let chd = open_chd(include_bytes!("../samples/huff.chd"));
+        assert!(!chd.has_parent());
+        assert_eq!(chd.parent_sha1(), [0; 20]);
+        assert_eq!(chd.sha1_hex().len(), 40);
+        assert_eq!(chd.sha1_hex(), hex_string(&chd.sha1()));
+        assert_eq!(chd.raw_sha1_hex(), hex_string(&chd.raw_sha1()));
+
+        let child = open_chd(include_bytes!("../samples/child.chd"));
+        assert!(child.has_parent());
+        assert_eq!(child.parent_sha1(), chd.sha1());
+        assert_eq!(child.parent_sha1_hex(), chd.sha1_hex());
+        assert_ne!(child.raw_sha1(), chd.raw_sha1());
     }
 
     #[test]
-    fn test_lzma() {
-        /*
-        chdman createraw -hs 4096 -us 512 -i data.b64 -o lzma.chd -c lzma
-        */
-        test_compressed_chd(include_bytes!("../samples/lzma.chd"))
+    fn test_supported_codecs() {
+        let codecs = supported_codecs();
+        assert!(codecs.contains(&CHD_CODEC_CD_FLAC));
+        for sample in [
+            &include_bytes!("../samples/huff.chd")[..],
+            include_bytes!("../samples/cdzl.chd"),
+            include_bytes!("../samples/flac.chd"),
+        ] {
+            let chd = open_chd(sample);
+            assert!(chd.codecs().iter().flatten().all(|c| codecs.contains(c)));
+        }
+        assert!(!codecs.contains(&make_tag(['a', 'v', 'h', 'u'])));
     }
 
     #[test]
-    fn test_zlib() {
-        /*
-        chdman createraw -hs 4096 -us 512 -i data.b64 -o zlib.chd -c zlib
-        */
-        test_compressed_chd(include_bytes!("../samples/zlib.chd"))
+    fn test_parent_reader() {
+        // child reads from memory, parent from a file
+        let mut chd = open_chd(include_bytes!("../samples/child.chd"));
+        let file =
+            std::fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/samples/huff.chd")).unwrap();
+        chd.set_parent(Chd::open(file).unwrap()).unwrap();
+        let image = include_bytes!("../samples/child.b64");
+        let mut sample = vec![0; image.len()];
+        chd.read_exact(&mut sample).unwrap();
+        assert!(sample[..] == image[..]);
     }
 
     #[test]
-    fn test_cdfl() {
-        /*
-        ffmpeg -i /usr/share/sounds/freedesktop/stereo/bell.oga -o bell.wav
-        chdman createcd -i bell.cue -o cdfl.chd -c cdfl
-         */
-        test_compressed_chd(include_bytes!("../samples/cdfl.chd"))
+    fn test_open_with_parents() {
+        let child = Cursor::new(&include_bytes!("../samples/child.chd")[..]);
+        let candidates = vec![
+            open_chd(include_bytes!("../samples/cdlz.chd")),
+            open_chd(include_bytes!("../samples/huff.chd")),
+        ];
+        let mut chd = Chd::open_with_parents(child, candidates).unwrap();
+        let image = include_bytes!("../samples/child.b64");
+        let mut sample = vec![0; image.len()];
+        chd.read_exact(&mut sample).unwrap();
+        assert!(sample[..] == image[..]);
+
+        let child = Cursor::new(&include_bytes!("../samples/child.chd")[..]);
+        let candidates = vec![open_chd(include_bytes!("../samples/cdlz.chd"))];
+        assert!(Chd::open_with_parents(child, candidates).is_err());
+
+        // no parent needed
+        let raw = Cursor::new(&include_bytes!("../samples/huff.chd")[..]);
+        Chd::open_with_parents(raw, Vec::<MemChd>::new()).unwrap();
     }
 
     #[test]
-    fn test_cdlz() {
-        /*
-        chdman createcd -i bell.cue -o cdlz.chd -c cdlz
-         */
-        test_compressed_chd(include_bytes!("../samples/cdlz.chd"))
+    fn test_replace_parent() {
+        let mut chd = open_chd(include_bytes!("../samples/child.chd"));
+        assert!(chd.take_parent().is_none());
+        chd.set_parent(open_chd(include_bytes!("../samples/huff.chd")))
+            .unwrap();
+        let mut buf = vec![0; chd.hunk_size()];
+        chd.read_hunk(0, &mut buf).unwrap();
+
+        let wrong = open_chd(include_bytes!("../samples/cdlz.chd"));
+        assert!(chd.replace_parent(wrong).is_err());
+        chd.read_hunk(0, &mut buf).unwrap();
+
+        let file = std::fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/samples/huff.chd"));
+        let previous = chd.replace_parent(Chd::open(file.unwrap()).unwrap());
+        assert!(previous.unwrap().is_some());
+        chd.read_hunk(0, &mut buf).unwrap();
+
+        assert!(chd.take_parent().is_some());
+        assert!(chd.read_hunk(0, &mut buf).is_err());
     }
 
     #[test]
-    fn test_cdzl() {
-        /*
-        chdman createcd -i bell.cue -o cdzl.chd -c cdzl
-         */
-        test_compressed_chd(include_bytes!("../samples/cdzl.chd"))
+    fn test_parent_match() {
+        let parent = open_chd(include_bytes!("../samples/huff.chd"));
+        let rawsha1 = parent.raw_sha1();
+        let mut chd = open_chd(include_bytes!("../samples/child.chd"));
+        let mode = ParentMatch::RawSha1([0; 20]);
+        assert!(chd.set_parent_with(parent, mode).is_err());
+        let parent = open_chd(include_bytes!("../samples/huff.chd"));
+        chd.set_parent_with(parent, ParentMatch::RawSha1(rawsha1))
+            .unwrap();
+        let mut buf = vec![0; chd.hunk_size()];
+        chd.read_hunk(0, &mut buf).unwrap();
+
+        // wrong data is detected by hunk crc
+        let mut chd = open_chd(include_bytes!("../samples/child.chd"));
+        let parent = open_chd(include_bytes!("../samples/huff.chd"));
+        chd.set_parent_with(parent, ParentMatch::Force).unwrap();
+        chd.read_hunk(0, &mut buf).unwrap();
+        let mut raw = include_bytes!("../samples/huff.chd").to_vec();
+        let parent = open_chd(include_bytes!("../samples/huff.chd"));
+        let (_, offset, length) = parent.map.locate(0).unwrap();
+        raw[(offset + length as u64 / 2) as usize] ^= 0x55;
+        let parent = Chd::open(Cursor::new(raw.clone())).unwrap();
+        let mut chd = open_chd(include_bytes!("../samples/child.chd"));
+        chd.set_parent_with(parent, ParentMatch::Force).unwrap();
+        assert!(chd.read_hunk(0, &mut buf).is_err());
+
+        // hunk cached from previous parent isn't used after replacing it
+        let mut chd = open_chd(include_bytes!("../samples/child.chd"));
+        let parent = open_chd(include_bytes!("../samples/huff.chd"));
+        chd.set_parent_with(parent, ParentMatch::Force).unwrap();
+        let mut head = [0; 16];
+        chd.read_exact(&mut head).unwrap();
+        let wrong = Chd::open(Cursor::new(raw)).unwrap();
+        assert!(chd.replace_parent(wrong).unwrap().is_some());
+        chd.seek(SeekFrom::Start(0)).unwrap();
+        assert!(chd.read_exact(&mut head).is_err());
     }
 
     #[test]
-    fn test_compression_self() {
-        /* generate file with lots of repetitions
-        tr \\000 A < /dev/zero | dd of=a.bin bs=44267 count=1
-        chdman createraw -hs 4096 -us 512 -i a.bin -o self.chd -c huff
-        */
-        let mut chd = open_chd(include_bytes!("../samples/self.chd"));
-        let mut buf = vec![1; chd.hunk_size()];
+    fn test_rebase() {
+        // parent with the same data, but different sha1 as if metadata was changed
+        let mut rebuilt = include_bytes!("../samples/huff.chd").to_vec();
+        rebuilt[84] ^= 0xff;
+        let child = include_bytes!("../samples/child.chd").to_vec();
+        let mut chd = Chd::open(Cursor::new(child)).unwrap();
+        assert!(chd
+            .set_parent(Chd::open(Cursor::new(rebuilt.clone())).unwrap())
+            .is_err());
+
+        let wrong = open_chd(include_bytes!("../samples/cdlz.chd"));
+        assert!(chd.rebase(wrong).is_err());
+        assert_eq!(
+            chd.parent_sha1(),
+            open_chd(include_bytes!("../samples/huff.chd")).sha1()
+        );
+
+        chd.rebase(Chd::open(Cursor::new(rebuilt.clone())).unwrap())
+            .unwrap();
+        let mut buf = vec![0; chd.hunk_size()];
         chd.read_hunk(0, &mut buf).unwrap();
-        validate_all(&mut chd);
+
+        let child = chd.into_inner().into_inner();
+        let mut chd = Chd::open(Cursor::new(child)).unwrap();
+        chd.set_parent(Chd::open(Cursor::new(rebuilt)).unwrap())
+            .unwrap();
+        let image = include_bytes!("../samples/child.b64");
+        let mut sample = vec![0; image.len()];
+        chd.read_exact(&mut sample).unwrap();
+        assert!(sample[..] == image[..]);
     }
 
     #[test]
-    fn test_child() {
-        /* changes some hunks in source data otherwise we will have the same sha1 hash in parent and child
-        cp data.b64 child.b64
-        dd if=a.bin of=child.b64 bs=4096 count=4 seek=3 conv=notrunc
-        chdman createraw -hs 4096 -us 512 -i child.b64 -o child.chd -op huff.chd -c huff
-        */
+    fn test_parent_resolver() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
         let mut chd = open_chd(include_bytes!("../samples/child.chd"));
-        let mut buf = vec![1; chd.hunk_size()];
-        assert!(chd.read_hunk(0, &mut buf).is_err());
+        chd.set_parent_resolver(move |sha1| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            let raw = &include_bytes!("../samples/huff.chd")[..];
+            let parent = Chd::open(Cursor::new(raw))?;
+            assert!(parent.sha1() == *sha1);
+            Ok(Box::new(Cursor::new(raw)))
+        });
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+        let image = include_bytes!("../samples/child.b64");
+        let mut sample = vec![0; image.len()];
+        chd.read_exact(&mut sample).unwrap();
+        assert!(sample[..] == image[..]);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
 
-        let wrong = open_chd(include_bytes!("../samples/self.chd"));
-        assert!(chd.set_parent(wrong).is_err());
+        let mut chd = open_chd(include_bytes!("../samples/child.chd"));
+        chd.set_parent_resolver(|_| {
+            let raw = &include_bytes!("../samples/cdlz.chd")[..];
+            Ok(Box::new(Cursor::new(raw)))
+        });
+        assert!(chd.read_exact(&mut sample).is_err());
+    }
 
-        let parent = open_chd(include_bytes!("../samples/huff.chd"));
-        chd.set_parent(parent).unwrap();
+    #[test]
+    fn test_progress() {
+        use std::sync::Mutex;
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let log = reports.clone();
+        let mut chd = open_chd(include_bytes!("../samples/huff.chd"));
+        chd.set_progress(Some(Progress::new(move |done, total| {
+            log.lock().unwrap().push((done, total))
+        })));
+        chd.extract(&mut io::sink()).unwrap();
+        chd.verify().unwrap();
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 2 * chd.hunk_count());
+        assert_eq!(reports[0], (4096, chd.size()));
+        assert_eq!(reports[chd.hunk_count() - 1], (chd.size(), chd.size()));
+        assert_eq!(reports.last(), Some(&(chd.size(), chd.size())));
+    }
+
+    #[test]
+    fn test_events() {
+        let mut chd = open_chd(include_bytes!("../samples/huff.chd"));
+        let events = chd.subscribe();
+        chd.extract(&mut io::sink()).unwrap();
+        chd.validate().unwrap();
+        let events: Vec<Event> = events.try_iter().collect();
+        let (hunkcount, size) = (chd.hunk_count(), chd.size());
+        assert_eq!(events.len(), 2 * (hunkcount + 1));
+        let first = Event::HunkDone {
+            hunk: 0,
+            done: 4096,
+            total: size,
+        };
+        assert_eq!(events[0], first);
+        assert_eq!(events[hunkcount + 1], first);
+        assert_eq!(events[hunkcount], Event::Finished { done: size });
+        assert_eq!(events.last(), Some(&Event::Finished { done: size }));
+
+        // forced uncompressed parent has no hunk crcs to check its data
+        let mut huff = open_chd(include_bytes!("../samples/huff.chd"));
+        let hunk_size = huff.hunk_size_u32();
+        let to = Cursor::new(Vec::new());
+        let parent = writer::transcode(&mut huff, None::<&mut MemChd>, hunk_size, &[], to).unwrap();
+        let mut chd = open_chd(include_bytes!("../samples/child.chd"));
+        chd.set_parent_with(Chd::open(parent).unwrap(), ParentMatch::Force)
+            .unwrap();
+        let events = chd.subscribe();
+        let mut buf = vec![0; chd.hunk_size()];
         chd.read_hunk(0, &mut buf).unwrap();
-        // can't validate() parent hunks, read chd instead
-        assert!(chd.validate().is_err());
-        let image = include_bytes!("../samples/child.b64");
-        let mut sample = vec![0; image.len()];
-        chd.read_at(0, &mut sample).unwrap();
-        assert_eq!(sample, image);
+        match events.try_recv() {
+            Ok(Event::Warning { hunk, reason }) => {
+                assert_eq!(hunk, 0);
+                assert!(reason.contains("no crc"), "{}", reason);
+            }
+            x => panic!("unexpected {:?}", x),
+        }
+        // nobody listens anymore
+        drop(events);
+        chd.read_hunk(1, &mut buf).unwrap();
+        chd.set_events(None);
+        chd.read_hunk(0, &mut buf).unwrap();
+    }
+
+    #[test]
+    fn test_compression_stats() {
+        let chd = open_chd(include_bytes!("../samples/self.chd"));
+        let stats = chd.compression_stats().unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].compression, HunkCompression::Codec(CHD_CODEC_HUFF));
+        assert_eq!(stats[1].compression, HunkCompression::SelfRef);
+        assert_eq!(stats[1].compressed, 0);
+        let hunks: u64 = stats.iter().map(|s| s.hunks).sum();
+        let logical: u64 = stats.iter().map(|s| s.uncompressed).sum();
+        assert_eq!(hunks, chd.hunk_count() as u64);
+        assert_eq!(logical, chd.size());
+        let huff = &stats[0];
+        assert!(huff.compressed < chd.file_size());
+        assert!(huff.ratio() > 0.0 && huff.ratio() < 1.0);
+
+        let chd = open_chd(include_bytes!("../samples/none.chd"));
+        let stats = chd.compression_stats().unwrap();
+        let stored = stats
+            .iter()
+            .find(|s| s.compression == HunkCompression::None)
+            .unwrap();
+        // stored hunks are whole, even the last one
+        assert!(stored.compressed <= stored.hunks * chd.hunk_size() as u64);
+        assert!(stored.compressed < chd.file_size());
+    }
+
+    #[test]
+    fn test_export_map() {
+        let chd = open_chd(include_bytes!("../samples/self.chd"));
+        let mut csv = Vec::new();
+        chd.export_map(&mut csv, MapFormat::Csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), chd.hunk_count() + 1);
+        assert_eq!(lines[0], "hunk,compression,offset,length,crc");
+        let info = chd.hunk_info(0).unwrap();
+        assert_eq!(
+            lines[1],
+            format!(
+                "0,huff,{},{},{:04x}",
+                info.offset,
+                info.length,
+                info.crc.unwrap()
+            )
+        );
+        assert!(csv.contains(",self,"));
+
+        let mut json = Vec::new();
+        chd.export_map(&mut json, MapFormat::Json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("[\n  {\"hunk\": 0, \"compression\": \"huff\""));
+        assert!(json.ends_with("}\n]\n"));
+        assert_eq!(json.matches("\"hunk\"").count(), chd.hunk_count());
+        assert!(json.contains("\"crc\": null"));
+    }
+
+    #[test]
+    fn test_probe() {
+        let raw = include_bytes!("../samples/child.chd");
+        let probe = probe(&mut Cursor::new(&raw[..])).unwrap();
+        let chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        assert_eq!(probe.version, 5);
+        assert_eq!(probe.sha1, chd.sha1());
+        assert_eq!(probe.rawsha1, Some(chd.raw_sha1()));
+        assert_eq!(probe.parentsha1, chd.parent_sha1());
+        assert!(probe.has_parent());
+        assert!(!probe_file("samples/zlib.chd").unwrap().has_parent());
+
+        // v4 header: sha1 at 48, parent sha1 at 68, raw sha1 at 88
+        let mut v4 = vec![0; 108];
+        v4[0..8].copy_from_slice(b"MComprHD");
+        v4.put_be32(8, 108).unwrap();
+        v4.put_be32(12, 4).unwrap();
+        v4[48..68].copy_from_slice(&[1; 20]);
+        v4[88..108].copy_from_slice(&[2; 20]);
+        let probe = super::probe(&mut Cursor::new(&v4)).unwrap();
+        assert_eq!(probe.version, 4);
+        assert_eq!(probe.sha1, [1; 20]);
+        assert_eq!(probe.rawsha1, Some([2; 20]));
+        assert!(!probe.has_parent());
+
+        // header length has to match version, truncated header is an error
+        v4.put_be32(8, 124).unwrap();
+        assert!(super::probe(&mut Cursor::new(&v4)).is_err());
+        assert!(super::probe(&mut Cursor::new(&raw[..100])).is_err());
     }
 
     #[test]
@@ -1169,14 +4202,47 @@ mod tests {
         chd.verify().unwrap();
     }
 
-    #[cfg(feature = "write_nop")]
     #[test]
-    fn test_write() {
-        let mut chd = open_chd(include_bytes!("../samples/none.chd"));
-        let buf = [42; 4096];
-        chd.seek(SeekFrom::Start(0)).unwrap();
-        assert_eq!(chd.write(&buf).unwrap(), buf.len());
-        chd.seek(SeekFrom::End(0)).unwrap();
-        assert_eq!(chd.write(&buf).unwrap(), 0);
+    fn test_metadata_reader() {
+        let mut chd = open_chd(include_bytes!("../samples/meta.chd"));
+        let tag = make_tag(['A', 'B', 'C', 'D']);
+        assert!(chd.metadata_reader(tag, 1).unwrap().is_none());
+        let mut reader = chd.metadata_reader(tag, 0).unwrap().unwrap();
+        assert_eq!(reader.len(), IMAGE.len() as u64);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert!(data == IMAGE);
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, IMAGE[IMAGE.len() - 10..]);
+        assert_eq!(reader.seek(SeekFrom::Current(5)).unwrap(), reader.len() + 5);
+        assert_eq!(reader.read(&mut tail).unwrap(), 0);
+        assert!(reader.seek(SeekFrom::Current(-1000000)).is_err());
+    }
+
+    #[test]
+    fn test_metadata_chain() {
+        let raw = include_bytes!("../samples/meta.chd");
+        let chd = open_chd(raw);
+        let first = chd.header.metaoffset as usize;
+        let second = raw.get_be64(first + 8).unwrap() as usize;
+
+        // the second entry links back to the first one
+        let mut looped = raw.to_vec();
+        looped.put_be64(second + 8, first as u64).unwrap();
+        let mut chd = Chd::open(Cursor::new(&looped[..])).unwrap();
+        let mut buf = [0; 16];
+        let err = chd.read_metadata(0, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(chd.verify().is_err());
+        assert!(chd.info().is_err());
+
+        // the first entry claims more bytes than file has
+        let mut long = raw.to_vec();
+        long.put_be24(first + 5, 0xffffff).unwrap();
+        let mut chd = Chd::open(Cursor::new(&long[..])).unwrap();
+        assert!(chd.read_metadata(0, &mut buf).is_err());
+        assert!(chd.dump_metadata(&mut Vec::new()).is_err());
     }
 }
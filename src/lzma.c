@@ -6,11 +6,13 @@
 
 static ISzAllocPtr allocator = &g_Alloc;
 
-size_t lzma_create(UInt32 hunkbytes)
+// Returns SZ_OK and decoder in *result, or SZ_ERROR_MEM, SZ_ERROR_PARAM or SZ_ERROR_UNSUPPORTED
+int lzma_create(UInt32 hunkbytes, CLzmaDec **result)
 {
+    *result = NULL;
     CLzmaDec *dec = MyAlloc(sizeof(*dec));
     if (!dec)
-        return 0;
+        return SZ_ERROR_MEM;
 
     LzmaDec_Construct(dec);
 
@@ -27,6 +29,7 @@ size_t lzma_create(UInt32 hunkbytes)
     LzmaEncProps_Normalize(&encoder_props);
 
     // convert to decoder properties
+    SRes res = SZ_ERROR_MEM;
     CLzmaEncHandle enc = LzmaEnc_Create(allocator);
     if (!enc)
         goto fail;
@@ -34,7 +37,7 @@ size_t lzma_create(UInt32 hunkbytes)
     Byte decoder_props[LZMA_PROPS_SIZE];
     SizeT props_size = sizeof(decoder_props);
 
-    SRes res = LzmaEnc_SetProps(enc, &encoder_props);
+    res = LzmaEnc_SetProps(enc, &encoder_props);
     if (res == SZ_OK)
         res = LzmaEnc_WriteProperties(enc, decoder_props, &props_size);
 
@@ -43,19 +46,20 @@ size_t lzma_create(UInt32 hunkbytes)
         goto fail;
 
     // do memory allocations
-    if (LzmaDec_Allocate(dec, decoder_props, LZMA_PROPS_SIZE, allocator) != SZ_OK)
+    res = LzmaDec_Allocate(dec, decoder_props, LZMA_PROPS_SIZE, allocator);
+    if (res != SZ_OK)
         goto fail;
 
-    return (size_t)dec;
+    *result = dec;
+    return SZ_OK;
 
 fail:
     MyFree(dec);
-    return 0;
+    return res;
 }
 
-void lzma_destroy(size_t _dec)
+void lzma_destroy(CLzmaDec *dec)
 {
-    CLzmaDec *dec = (void*)_dec;
     if (!dec)
         return;
 
@@ -63,17 +67,43 @@ void lzma_destroy(size_t _dec)
     MyFree((void*)dec);
 }
 
-int lzma_decompress(size_t _dec, const Byte *src, UInt32 complen, Byte *dest, UInt32 destlen)
+size_t lzma_memory(const CLzmaDec *dec)
+{
+    return sizeof(*dec) + dec->dicBufSize + dec->numProbs * sizeof(CLzmaProb);
+}
+
+// Returns SZ_ result of decoding, bytes consumed and decoded are reported in *consumed and *decoded
+int lzma_decompress(CLzmaDec *dec, const Byte *src, SizeT complen, Byte *dest, SizeT destlen,
+                    SizeT *consumed, SizeT *decoded)
 {
-    CLzmaDec *dec = (void*)_dec;
     // initialize
     LzmaDec_Init(dec);
 
     // decode
-    SizeT consumedlen = complen;
-    SizeT decodedlen = destlen;
+    *consumed = complen;
+    *decoded = destlen;
     ELzmaStatus status;
-    SRes res = LzmaDec_DecodeToBuf(dec, dest, &decodedlen, src, &consumedlen, LZMA_FINISH_END, &status);
+    return LzmaDec_DecodeToBuf(dec, dest, decoded, src, consumed, LZMA_FINISH_END, &status);
+}
+
+// Returns SZ_ result of encoding, compressed size is reported in *destlen. Encoder is
+// configured like lzma_create configures decoder, there is no end mark.
+int lzma_compress(UInt32 hunkbytes, const Byte *src, SizeT srclen, Byte *dest, SizeT *destlen)
+{
+    CLzmaEncHandle enc = LzmaEnc_Create(allocator);
+    if (!enc)
+        return SZ_ERROR_MEM;
 
-    return ((res != SZ_OK && res != LZMA_STATUS_MAYBE_FINISHED_WITHOUT_MARK) || consumedlen != complen || decodedlen != destlen);
+    CLzmaEncProps encoder_props;
+    LzmaEncProps_Init(&encoder_props);
+    encoder_props.level = 9;
+    encoder_props.reduceSize = hunkbytes;
+    LzmaEncProps_Normalize(&encoder_props);
+
+    SRes res = LzmaEnc_SetProps(enc, &encoder_props);
+    if (res == SZ_OK)
+        res = LzmaEnc_MemEncode(enc, dest, destlen, src, srclen, 0, NULL, allocator, allocator);
+
+    LzmaEnc_Destroy(enc, allocator, allocator);
+    return res;
 }
@@ -1,25 +1,230 @@
+// Decoder and encoder of LZMA hunks of chd. They are raw streams: there is no header
+// with properties, both are configured like MAME's encoder with level 9 and size of
+// the hunk, and there is no end mark, so the stream has to fill the hunk exactly
+// and consume all compressed data.
+use std::ffi::c_void;
+use std::fmt;
+use std::io;
+use std::ptr::NonNull;
+
 extern "C" {
-    pub fn lzma_create(hunkbytes: u32) -> usize;
-    pub fn lzma_destroy(dec: usize);
-    pub fn lzma_decompress(
-        dec: usize,
+    fn lzma_create(hunkbytes: u32, dec: *mut *mut c_void) -> i32;
+    fn lzma_destroy(dec: *mut c_void);
+    fn lzma_memory(dec: *const c_void) -> usize;
+    fn lzma_decompress(
+        dec: *mut c_void,
+        src: *const u8,
+        complen: usize,
+        dest: *mut u8,
+        destlen: usize,
+        consumed: *mut usize,
+        decoded: *mut usize,
+    ) -> i32;
+    fn lzma_compress(
+        hunkbytes: u32,
         src: *const u8,
-        complen: u32,
+        srclen: usize,
         dest: *mut u8,
-        destlen: u32,
+        destlen: *mut usize,
     ) -> i32;
 }
 
+// LZMA SDK result codes
+const SZ_OK: i32 = 0;
+const SZ_ERROR_DATA: i32 = 1;
+const SZ_ERROR_MEM: i32 = 2;
+const SZ_ERROR_UNSUPPORTED: i32 = 4;
+const SZ_ERROR_PARAM: i32 = 5;
+const SZ_ERROR_INPUT_EOF: i32 = 6;
+const SZ_ERROR_OUTPUT_EOF: i32 = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LzmaError {
+    Memory,                                        // decoder allocation failed
+    Props,                                         // decoder can't use properties for hunk size
+    Data,                                          // corrupt compressed data
+    InputLeft { consumed: usize, length: usize },  // hunk is filled before end of compressed data
+    OutputShort { decoded: usize, length: usize }, // compressed data ends before hunk is filled
+    OutputFull { length: usize },                  // compressed data doesn't fit into length bytes
+    Other(i32),                                    // other LZMA SDK result code
+}
+
+impl fmt::Display for LzmaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Memory => write!(f, "lzma: out of memory"),
+            Self::Props => write!(f, "lzma: unsupported decoder properties"),
+            Self::Data => write!(f, "lzma: corrupt data"),
+            Self::InputLeft { consumed, length } => write!(
+                f,
+                "lzma: stream ended after {} of {} compressed bytes",
+                consumed, length
+            ),
+            Self::OutputShort { decoded, length } => {
+                write!(f, "lzma: decoded {} bytes, expected {}", decoded, length)
+            }
+            Self::OutputFull { length } => {
+                write!(f, "lzma: compressed data is longer than {} bytes", length)
+            }
+            Self::Other(code) => write!(f, "lzma: error {}", code),
+        }
+    }
+}
+
+impl std::error::Error for LzmaError {}
+
+impl From<LzmaError> for io::Error {
+    fn from(e: LzmaError) -> Self {
+        let kind = match e {
+            LzmaError::Memory => io::ErrorKind::OutOfMemory,
+            LzmaError::Props => io::ErrorKind::Unsupported,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+pub struct LzmaDecoder {
+    dec: NonNull<c_void>,
+}
+
+// Decoder state is used only through &mut self and isn't tied to a thread,
+// so it can be moved to another thread. It's not Sync.
+unsafe impl Send for LzmaDecoder {}
+
+impl LzmaDecoder {
+    pub fn new(hunkbytes: u32) -> Result<Self, LzmaError> {
+        let mut dec = std::ptr::null_mut();
+        let res = unsafe { lzma_create(hunkbytes, &mut dec) };
+        match (res, NonNull::new(dec)) {
+            (SZ_OK, Some(dec)) => Ok(Self { dec }),
+            (SZ_ERROR_MEM, _) | (SZ_OK, None) => Err(LzmaError::Memory),
+            (SZ_ERROR_PARAM, _) | (SZ_ERROR_UNSUPPORTED, _) => Err(LzmaError::Props),
+            (res, _) => Err(LzmaError::Other(res)),
+        }
+    }
+
+    // Bytes allocated by decoder
+    pub fn memory_usage(&self) -> usize {
+        unsafe { lzma_memory(self.dec.as_ptr()) }
+    }
+
+    // Decode whole src, which must produce exactly dest.len() bytes
+    pub fn decompress(&mut self, src: &[u8], dest: &mut [u8]) -> Result<(), LzmaError> {
+        let mut consumed = 0;
+        let mut decoded = 0;
+        let res = unsafe {
+            lzma_decompress(
+                self.dec.as_ptr(),
+                src.as_ptr(),
+                src.len(),
+                dest.as_mut_ptr(),
+                dest.len(),
+                &mut consumed,
+                &mut decoded,
+            )
+        };
+        match res {
+            SZ_OK | SZ_ERROR_INPUT_EOF => (),
+            SZ_ERROR_DATA => return Err(LzmaError::Data),
+            SZ_ERROR_MEM => return Err(LzmaError::Memory),
+            res => return Err(LzmaError::Other(res)),
+        }
+        if decoded != dest.len() {
+            return Err(LzmaError::OutputShort {
+                decoded,
+                length: dest.len(),
+            });
+        }
+        if consumed != src.len() {
+            return Err(LzmaError::InputLeft {
+                consumed,
+                length: src.len(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LzmaDecoder {
+    fn drop(&mut self) {
+        unsafe { lzma_destroy(self.dec.as_ptr()) };
+    }
+}
+
+// Encoder of hunks LzmaDecoder of the same hunk size reads
+pub struct LzmaEncoder {
+    hunkbytes: u32,
+}
+
+impl LzmaEncoder {
+    pub fn new(hunkbytes: u32) -> Self {
+        Self { hunkbytes }
+    }
+
+    // Append compressed src to dest, fails if it's longer than limit bytes
+    pub fn compress(&self, src: &[u8], dest: &mut Vec<u8>, limit: usize) -> Result<(), LzmaError> {
+        let start = dest.len();
+        dest.resize(start + limit, 0);
+        let mut length = limit;
+        let res = unsafe {
+            lzma_compress(
+                self.hunkbytes,
+                src.as_ptr(),
+                src.len(),
+                dest[start..].as_mut_ptr(),
+                &mut length,
+            )
+        };
+        dest.truncate(start + length.min(limit));
+        match res {
+            SZ_OK => Ok(()),
+            SZ_ERROR_OUTPUT_EOF => Err(LzmaError::OutputFull { length: limit }),
+            SZ_ERROR_MEM => Err(LzmaError::Memory),
+            SZ_ERROR_PARAM => Err(LzmaError::Props),
+            res => Err(LzmaError::Other(res)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_cc_linkage() {
-        unsafe {
-            let smth = lzma_create(4096);
-            assert_ne!(smth, 0);
-            lzma_destroy(smth);
-        }
+    fn test_decoder() {
+        fn is_send<T: Send>(_: &T) {}
+
+        let mut dec = LzmaDecoder::new(4096).unwrap();
+        is_send(&dec);
+        assert!(dec.memory_usage() > 4096);
+
+        let mut dest = vec![0; 4096];
+        // first byte of range coder must be zero
+        let e = dec.decompress(&[0xff; 16], &mut dest).unwrap_err();
+        assert_eq!(e, LzmaError::Data);
+        // stream of zero bytes ends before the hunk is filled
+        let e = dec.decompress(&[0; 5], &mut dest).unwrap_err();
+        assert!(matches!(e, LzmaError::OutputShort { .. }));
+        let e: io::Error = e.into();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_encoder() {
+        let data: Vec<u8> = (0..4096u32)
+            .map(|i| (i % 251) as u8 ^ (i / 512) as u8)
+            .collect();
+        let enc = LzmaEncoder::new(4096);
+        let mut compressed = vec![0xaa];
+        enc.compress(&data, &mut compressed, 4096).unwrap();
+        assert!(compressed.len() < 1000);
+        let mut dest = vec![0; 4096];
+        let mut dec = LzmaDecoder::new(4096).unwrap();
+        dec.decompress(&compressed[1..], &mut dest).unwrap();
+        assert_eq!(dest, data);
+
+        let e = enc.compress(&data, &mut compressed, 10).unwrap_err();
+        assert_eq!(e, LzmaError::OutputFull { length: 10 });
     }
 }
@@ -0,0 +1,369 @@
+use super::shared::ChdShared;
+use super::utils::ByteOrderExt;
+use super::*;
+use std::net::{TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::Path;
+
+// Read-only NBD server of chd logical image, fixed newstyle handshake only.
+// See https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943; // "NBDMAGIC"
+const NBD_OPTS_MAGIC: u64 = 0x4948_4156_454f_5054; // "IHAVEOPT"
+const NBD_REP_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+// handshake flags
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+
+// transmission flags
+const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_CAN_MULTI_CONN: u16 = 1 << 8;
+
+// options
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_ABORT: u32 = 2;
+const NBD_OPT_LIST: u32 = 3;
+const NBD_OPT_INFO: u32 = 6;
+const NBD_OPT_GO: u32 = 7;
+
+// option replies
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_SERVER: u32 = 2;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const NBD_REP_ERR_UNKNOWN: u32 = (1 << 31) + 6;
+const NBD_INFO_EXPORT: u16 = 0;
+
+// commands
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+
+// errors sent back in replies
+const NBD_EPERM: u32 = 1;
+const NBD_EIO: u32 = 5;
+const NBD_EINVAL: u32 = 22;
+
+// largest read request served, the same limit as reference server
+const MAX_REQUEST: u32 = 32 << 20;
+// options are short, longer ones are refused without reading into memory
+const MAX_OPTION: u32 = 4096;
+
+pub struct NbdServer<T: R> {
+    chd: ChdShared<T>,
+    name: String, // export name, empty name selects it too
+}
+
+impl<T: R + Send> NbdServer<T> {
+    pub fn new(chd: Chd<T>) -> Self {
+        Self {
+            chd: ChdShared::new(chd),
+            name: String::new(),
+        }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn into_inner(self) -> io::Result<Chd<T>> {
+        self.chd.into_inner()
+    }
+
+    // Accept connections on TCP address forever, each one served in its own thread
+    pub fn serve_tcp<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        std::thread::scope(|s| loop {
+            let (stream, _) = listener.accept()?;
+            stream.set_nodelay(true)?;
+            s.spawn(move || self.serve(stream));
+        })
+    }
+
+    // Accept connections on unix socket forever, each one served in its own thread
+    #[cfg(unix)]
+    pub fn serve_unix<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let listener = UnixListener::bind(path)?;
+        std::thread::scope(|s| loop {
+            let (stream, _) = listener.accept()?;
+            s.spawn(move || self.serve(stream));
+        })
+    }
+
+    // Handshake and transmission over one connection, returns when client disconnects
+    pub fn serve<S: Read + Write>(&self, mut stream: S) -> io::Result<()> {
+        match self.handshake(&mut stream)? {
+            true => self.transmission(&mut stream),
+            false => Ok(()),
+        }
+    }
+
+    // true if client selected the export, false if it aborted
+    fn handshake<S: Read + Write>(&self, stream: &mut S) -> io::Result<bool> {
+        let mut greeting = Vec::with_capacity(18);
+        greeting.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+        greeting.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+        greeting.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+        stream.write_all(&greeting)?;
+        stream.flush()?;
+
+        let mut flags = [0; 4];
+        stream.read_exact(&mut flags)?;
+        let no_zeroes = flags.get_be32(0)? & NBD_FLAG_NO_ZEROES as u32 != 0;
+
+        loop {
+            let mut header = [0; 16];
+            stream.read_exact(&mut header)?;
+            if header.get_be64(0)? != NBD_OPTS_MAGIC {
+                return Err(invalid_data_str("nbd: bad option magic"));
+            }
+            let option = header.get_be32(8)?;
+            let length = header.get_be32(12)?;
+            if length > MAX_OPTION {
+                return Err(invalid_data(format!(
+                    "nbd: option {} is too long ({} bytes)",
+                    option, length
+                )));
+            }
+            let mut data = vec![0; length as usize];
+            stream.read_exact(&mut data)?;
+
+            match option {
+                NBD_OPT_EXPORT_NAME => {
+                    if !self.is_export(&data) {
+                        // the only way to refuse this option is to drop connection
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            "nbd: client asked for unknown export",
+                        ));
+                    }
+                    let mut reply = Vec::with_capacity(10 + 124);
+                    reply.extend_from_slice(&self.chd.size().to_be_bytes());
+                    reply.extend_from_slice(&Self::transmission_flags().to_be_bytes());
+                    if !no_zeroes {
+                        reply.resize(reply.len() + 124, 0);
+                    }
+                    stream.write_all(&reply)?;
+                    stream.flush()?;
+                    return Ok(true);
+                }
+                NBD_OPT_ABORT => {
+                    Self::option_reply(stream, option, NBD_REP_ACK, &[])?;
+                    return Ok(false);
+                }
+                NBD_OPT_LIST => {
+                    let mut server = Vec::with_capacity(4 + self.name.len());
+                    server.extend_from_slice(&(self.name.len() as u32).to_be_bytes());
+                    server.extend_from_slice(self.name.as_bytes());
+                    Self::option_reply(stream, option, NBD_REP_SERVER, &server)?;
+                    Self::option_reply(stream, option, NBD_REP_ACK, &[])?;
+                }
+                NBD_OPT_INFO | NBD_OPT_GO => {
+                    let name = data
+                        .get_be32(0)
+                        .ok()
+                        .and_then(|len| data.get(4..4 + len as usize));
+                    // requested info types are ignored, export info is mandatory anyway
+                    if !name.is_some_and(|name| self.is_export(name)) {
+                        Self::option_reply(stream, option, NBD_REP_ERR_UNKNOWN, &[])?;
+                        continue;
+                    }
+                    let mut info = Vec::with_capacity(12);
+                    info.extend_from_slice(&NBD_INFO_EXPORT.to_be_bytes());
+                    info.extend_from_slice(&self.chd.size().to_be_bytes());
+                    info.extend_from_slice(&Self::transmission_flags().to_be_bytes());
+                    Self::option_reply(stream, option, NBD_REP_INFO, &info)?;
+                    Self::option_reply(stream, option, NBD_REP_ACK, &[])?;
+                    if option == NBD_OPT_GO {
+                        return Ok(true);
+                    }
+                }
+                _ => Self::option_reply(stream, option, NBD_REP_ERR_UNSUP, &[])?,
+            }
+        }
+    }
+
+    fn transmission<S: Read + Write>(&self, stream: &mut S) -> io::Result<()> {
+        let mut data = Vec::new();
+        loop {
+            let mut request = [0; 28];
+            stream.read_exact(&mut request)?;
+            if request.get_be32(0)? != NBD_REQUEST_MAGIC {
+                return Err(invalid_data_str("nbd: bad request magic"));
+            }
+            let command = request.get_be16(6)?;
+            let handle = request.get_be64(8)?;
+            let offset = request.get_be64(16)?;
+            let length = request.get_be32(24)?;
+
+            let error = match command {
+                NBD_CMD_READ => match length <= MAX_REQUEST {
+                    true => {
+                        data.resize(length as usize, 0);
+                        match self.chd.read_exact_at(offset, &mut data) {
+                            Ok(()) => 0,
+                            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => NBD_EINVAL,
+                            Err(_) => NBD_EIO,
+                        }
+                    }
+                    false => NBD_EINVAL,
+                },
+                NBD_CMD_WRITE => {
+                    // payload has to be consumed to stay in sync with client
+                    io::copy(&mut (&mut *stream).take(length as u64), &mut io::sink())?;
+                    NBD_EPERM
+                }
+                NBD_CMD_DISC => return Ok(()),
+                NBD_CMD_FLUSH => 0,
+                _ => NBD_EINVAL,
+            };
+
+            let mut reply = Vec::with_capacity(16);
+            reply.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+            reply.extend_from_slice(&error.to_be_bytes());
+            reply.extend_from_slice(&handle.to_be_bytes());
+            stream.write_all(&reply)?;
+            if command == NBD_CMD_READ && error == 0 {
+                stream.write_all(&data)?;
+            }
+            stream.flush()?;
+        }
+    }
+
+    fn is_export(&self, name: &[u8]) -> bool {
+        name.is_empty() || name == self.name.as_bytes()
+    }
+
+    fn transmission_flags() -> u16 {
+        NBD_FLAG_HAS_FLAGS | NBD_FLAG_READ_ONLY | NBD_FLAG_CAN_MULTI_CONN
+    }
+
+    fn option_reply<S: Write>(
+        stream: &mut S,
+        option: u32,
+        kind: u32,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut reply = Vec::with_capacity(20 + data.len());
+        reply.extend_from_slice(&NBD_REP_MAGIC.to_be_bytes());
+        reply.extend_from_slice(&option.to_be_bytes());
+        reply.extend_from_slice(&kind.to_be_bytes());
+        reply.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        reply.extend_from_slice(data);
+        stream.write_all(&reply)?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // scripted client input, server output collected for checking
+    struct Script {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn option(to: &mut Vec<u8>, option: u32, data: &[u8]) {
+        to.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+        to.extend_from_slice(&option.to_be_bytes());
+        to.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        to.extend_from_slice(data);
+    }
+
+    fn request(to: &mut Vec<u8>, command: u16, handle: u64, offset: u64, length: u32) {
+        to.extend_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+        to.extend_from_slice(&0u16.to_be_bytes());
+        to.extend_from_slice(&command.to_be_bytes());
+        to.extend_from_slice(&handle.to_be_bytes());
+        to.extend_from_slice(&offset.to_be_bytes());
+        to.extend_from_slice(&length.to_be_bytes());
+    }
+
+    #[test]
+    fn test_serve() {
+        let raw = include_bytes!("../samples/zlib.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let mut image = Vec::new();
+        chd.read_to_end(&mut image).unwrap();
+        let size = image.len() as u64;
+        let server = NbdServer::new(chd).with_name("zlib");
+
+        let mut input = Vec::new();
+        input.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE as u32).to_be_bytes());
+        option(&mut input, 42, &[]);
+        let mut go = Vec::new();
+        go.extend_from_slice(&4u32.to_be_bytes());
+        go.extend_from_slice(b"zlib");
+        go.extend_from_slice(&0u16.to_be_bytes());
+        option(&mut input, NBD_OPT_GO, &go);
+        request(&mut input, NBD_CMD_READ, 1, 5000, 3000);
+        request(&mut input, NBD_CMD_WRITE, 2, 0, 4);
+        input.extend_from_slice(b"data");
+        request(&mut input, NBD_CMD_READ, 3, size - 1, 2);
+        request(&mut input, NBD_CMD_DISC, 4, 0, 0);
+
+        let mut script = Script {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        server.serve(&mut script).unwrap();
+        let out = script.output;
+
+        assert_eq!(out.get_be64(0).unwrap(), NBD_MAGIC);
+        assert_eq!(out.get_be64(8).unwrap(), NBD_OPTS_MAGIC);
+        // unknown option
+        let mut pos = 18;
+        assert_eq!(out.get_be64(pos).unwrap(), NBD_REP_MAGIC);
+        assert_eq!(out.get_be32(pos + 12).unwrap(), NBD_REP_ERR_UNSUP);
+        pos += 20;
+        // export info, then ack
+        assert_eq!(out.get_be32(pos + 12).unwrap(), NBD_REP_INFO);
+        assert_eq!(out.get_be32(pos + 16).unwrap(), 12);
+        assert_eq!(out.get_be64(pos + 22).unwrap(), size);
+        pos += 32;
+        assert_eq!(out.get_be32(pos + 12).unwrap(), NBD_REP_ACK);
+        pos += 20;
+
+        let reply = |pos: usize| {
+            (
+                out.get_be32(pos + 4).unwrap(),
+                out.get_be64(pos + 8).unwrap(),
+            )
+        };
+        assert_eq!(reply(pos), (0, 1));
+        assert_eq!(&out[pos + 16..pos + 16 + 3000], &image[5000..8000]);
+        pos += 16 + 3000;
+        assert_eq!(reply(pos), (NBD_EPERM, 2));
+        pos += 16;
+        assert_eq!(reply(pos), (NBD_EINVAL, 3));
+        pos += 16;
+        assert_eq!(out.len(), pos);
+    }
+}
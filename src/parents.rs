@@ -0,0 +1,283 @@
+use super::*;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Index of chd files by their sha1 for finding parents of child chds
+#[derive(Clone, Debug, Default)]
+pub struct ParentIndex {
+    by_sha1: HashMap<[u8; 20], PathBuf>,
+}
+
+impl ParentIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_sha1.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_sha1.is_empty()
+    }
+
+    // Add a known chd, e.g. from caller's own database
+    pub fn insert<P: Into<PathBuf>>(&mut self, sha1: [u8; 20], path: P) {
+        self.by_sha1.insert(sha1, path.into());
+    }
+
+    pub fn get(&self, sha1: &[u8; 20]) -> Option<&Path> {
+        self.by_sha1.get(sha1).map(|p| p.as_path())
+    }
+
+    // Index *.chd files in dir and its subdirectories, reading only their headers.
+    // Files that are not valid chds are skipped. Returns number of indexed files.
+    pub fn scan_dir<P: AsRef<Path>>(&mut self, dir: P) -> io::Result<usize> {
+        self.scan_dir_with(dir, |_, _| {})
+    }
+
+    // Like scan_dir, and passes each *.chd file with its probe, or the error
+    // that it isn't a valid chd, to visit
+    pub fn scan_dir_with<P, F>(&mut self, dir: P, mut visit: F) -> io::Result<usize>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&Path, io::Result<ChdProbe>),
+    {
+        let mut count = 0;
+        let mut dirs = vec![dir.as_ref().to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let is_chd = path
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("chd"));
+                if !is_chd {
+                    continue;
+                }
+                let probe = probe_file(&path);
+                if let Ok(probe) = &probe {
+                    self.insert(probe.sha1, &path);
+                    count += 1;
+                }
+                visit(&path, probe);
+            }
+        }
+        Ok(count)
+    }
+
+    // Open indexed chd with sha1 and all its ancestors
+    pub fn open(&self, sha1: &[u8; 20]) -> io::Result<Chd<File>> {
+        self.open_level(sha1, 0)
+    }
+
+    fn open_level(&self, sha1: &[u8; 20], level: usize) -> io::Result<Chd<File>> {
+        // a longer chain must have a loop in it
+        if level > self.len() {
+            return Err(invalid_data(format!(
+                "parent chain loop at sha1 {}",
+                hex_string(sha1)
+            )));
+        }
+        let path = self.get(sha1).ok_or_else(|| {
+            invalid_data(format!("no chd with sha1 {} in index", hex_string(sha1)))
+        })?;
+        let mut chd = Chd::open(File::open(path)?)?;
+        if chd.sha1() != *sha1 {
+            return Err(invalid_data(format!(
+                "{}: sha1 changed since indexing",
+                path.display()
+            )));
+        }
+        if chd.has_parent() {
+            let parent = self.open_level(&chd.parent_sha1(), level + 1)?;
+            chd.set_parent(parent)?;
+        }
+        Ok(chd)
+    }
+
+    // Attach parent chain of chd, does nothing for chd without parent
+    pub fn resolve<T: R>(&self, chd: &mut Chd<T>) -> io::Result<()> {
+        if chd.has_parent() {
+            let parent = self.open_level(&chd.parent_sha1(), 1)?;
+            chd.set_parent(parent)?;
+        }
+        Ok(())
+    }
+}
+
+// Options of verify_many
+#[derive(Clone, Debug)]
+pub struct VerifyOptions {
+    pub jobs: usize,          // parallel workers, files are verified one by one if 1
+    pub chd: ChdOptions,      // options of opened files, parents are opened with defaults
+    pub parents: ParentIndex, // known parents in addition to the verified files
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            jobs: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            chd: ChdOptions::default(),
+            parents: ParentIndex::new(),
+        }
+    }
+}
+
+// Outcome of verification of one file by verify_many
+#[derive(Debug)]
+pub struct VerifyResult {
+    pub path: PathBuf,
+    pub sha1: Option<[u8; 20]>, // header sha1, if file could be opened
+    pub result: io::Result<()>,
+}
+
+impl VerifyResult {
+    pub fn passed(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+// Verify set of chds by parallel workers. Parents of child chds are looked up among
+// the set and in options.parents. Results are in order of paths.
+pub fn verify_many<P: AsRef<Path> + Sync>(
+    paths: &[P],
+    options: &VerifyOptions,
+) -> Vec<VerifyResult> {
+    let mut index = options.parents.clone();
+    for path in paths {
+        let header = File::open(path).and_then(|mut f| Header::read_header(&mut f));
+        if let Ok(header) = header {
+            index.insert(header.sha1, path.as_ref());
+        }
+    }
+    let verify = |path: &Path| -> VerifyResult {
+        let mut sha1 = None;
+        let result = File::open(path)
+            .and_then(|file| Chd::open_with_options(file, options.chd.clone()))
+            .and_then(|mut chd| {
+                sha1 = Some(chd.sha1());
+                index.resolve(&mut chd)?;
+                chd.verify()
+            });
+        VerifyResult {
+            path: path.to_path_buf(),
+            sha1,
+            result,
+        }
+    };
+    // there are no threads in browser
+    let jobs = match cfg!(target_arch = "wasm32") {
+        true => 1,
+        false => options.jobs.clamp(1, paths.len().max(1)),
+    };
+    if jobs == 1 {
+        return paths.iter().map(|path| verify(path.as_ref())).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, VerifyResult)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        match paths.get(i) {
+                            Some(path) => done.push((i, verify(path.as_ref()))),
+                            None => break done,
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_scan_dir() {
+        let mut index = ParentIndex::new();
+        let samples = concat!(env!("CARGO_MANIFEST_DIR"), "/samples");
+        let count = index.scan_dir(samples).unwrap();
+        assert!(count > 0);
+        assert!(index.len() <= count);
+        let mut visited = Vec::new();
+        let again = ParentIndex::new()
+            .scan_dir_with(samples, |path, probe| {
+                visited.push((path.to_path_buf(), probe))
+            })
+            .unwrap();
+        assert_eq!(again, count);
+        assert_eq!(visited.iter().filter(|(_, p)| p.is_ok()).count(), count);
+        assert!(visited
+            .iter()
+            .all(|(path, _)| path.extension().unwrap() == "chd"));
+
+        let raw = include_bytes!("../samples/child.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        assert!(index.get(&chd.parent_sha1()).is_some());
+        index.resolve(&mut chd).unwrap();
+        let image = include_bytes!("../samples/child.b64");
+        let mut sample = vec![0; image.len()];
+        chd.read_exact(&mut sample).unwrap();
+        assert!(sample[..] == image[..]);
+
+        let empty = ParentIndex::new();
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        assert!(empty.resolve(&mut chd).is_err());
+    }
+
+    #[test]
+    fn test_verify_many() {
+        let samples = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/samples"));
+        let paths: Vec<PathBuf> = [
+            "child.chd",
+            "huff.chd",
+            "none.chd",
+            "missing.chd",
+            "zlib.chd",
+        ]
+        .iter()
+        .map(|name| samples.join(name))
+        .collect();
+        for jobs in [1, 3] {
+            let options = VerifyOptions {
+                jobs,
+                ..Default::default()
+            };
+            let results = verify_many(&paths, &options);
+            let passed: Vec<bool> = results.iter().map(|r| r.passed()).collect();
+            // uncompressed chd has no data checksum
+            assert_eq!(passed, [true, true, false, false, true]);
+            for (result, path) in results.iter().zip(&paths) {
+                assert_eq!(&result.path, path);
+            }
+            assert!(results[0].sha1.is_some());
+            assert!(results[3].sha1.is_none());
+        }
+
+        // child without its parent in the set
+        let results = verify_many(&paths[..1], &VerifyOptions::default());
+        assert!(!results[0].passed());
+        assert!(verify_many::<PathBuf>(&[], &VerifyOptions::default()).is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+use super::cd::{self, read_toc, read_track_at, Track, TrackType, MAX_SECTOR_DATA};
+use super::*;
+
+// Checksums of one track as listed by redump.org dats: raw 2352 bytes sectors
+// without subcode, audio samples in little endian
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrackHashes {
+    pub number: u32,
+    pub size: u64,
+    pub crc32: u32,
+    pub md5: [u8; 16],
+    pub sha1: [u8; 20],
+}
+
+// Checksums of whole logical data, as listed by dats of hard disk and other images
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageHashes {
+    pub size: u64,
+    pub crc32: u32,
+    pub md5: [u8; 16],
+    pub sha1: [u8; 20],
+}
+
+// Checksums of logical data of any chd, sha1 is the header raw sha1 of valid chd
+pub fn image_hashes<T: R>(chd: &mut Chd<T>) -> io::Result<ImageHashes> {
+    let mut crc32 = Crc32::new();
+    let mut md5 = md5::Context::new();
+    let mut sha1 = sha1::Sha1::new();
+    let mut buf = vec![0; chd.hunk_size()];
+    let mut done = 0;
+    for hunknum in 0..chd.hunk_count() {
+        chd.read_hunk(hunknum, &mut buf)?;
+        let data = &buf[..std::cmp::min(buf.len() as u64, chd.size() - done) as usize];
+        crc32.update(data);
+        md5.consume(data);
+        sha1.update(data);
+        done += data.len() as u64;
+        chd.report_progress(done);
+    }
+    chd.report_finished(done);
+    Ok(ImageHashes {
+        size: done,
+        crc32: crc32.finish(),
+        md5: md5.finalize().0,
+        sha1: sha1.digest().bytes(),
+    })
+}
+
+// Raw sector of track frame, rebuilding sync, header, EDC and ECC for cooked
+// mode 1 sectors. lba is absolute sector address used for the header.
+pub fn read_raw_sector<T: R>(
+    chd: &mut Chd<T>,
+    track: &Track,
+    frame: u64,
+    lba: u32,
+    buf: &mut [u8; MAX_SECTOR_DATA],
+) -> io::Result<()> {
+    let sector_size = track.sector_size();
+    let (mode, data_offset) = match track.track_type {
+        TrackType::Mode1Raw | TrackType::Mode2Raw | TrackType::Audio => (0, 0),
+        TrackType::Mode1 => (1, 16),
+        // the whole mode 2 payload after the header is stored
+        TrackType::Mode2 | TrackType::Mode2FormMix => (2, 16),
+        // subheaders are lost, so raw sectors can't be rebuilt
+        TrackType::Mode2Form1 | TrackType::Mode2Form2 => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "redump: track {} of type {} has no raw sector data",
+                    track.number,
+                    track.track_type.name()
+                ),
+            ))
+        }
+    };
+    let offset = frame * sector_size as u64;
+    let data = &mut buf[data_offset..data_offset + sector_size];
+    if read_track_at(chd, track, offset, data)? != sector_size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "redump: frame {} is past end of track {}",
+                frame, track.number
+            ),
+        ));
+    }
+    if mode != 0 {
+        ecc::set_header(buf, lba, mode);
+    }
+    if mode == 1 {
+        let edc = cd::edc(&buf[..2064]);
+        buf[2064..2068].copy_from_slice(&edc.to_le_bytes());
+        buf[2068..2076].fill(0);
+        ecc::generate(buf);
+    }
+    Ok(())
+}
+
+// Checksums of every track of CD chd in redump form
+pub fn track_hashes<T: R>(chd: &mut Chd<T>) -> io::Result<Vec<TrackHashes>> {
+    let toc = read_toc(chd)?;
+    let starts = cd::track_starts(&toc, cd::is_gdrom(chd)?);
+    let mut result = Vec::with_capacity(toc.len());
+    let mut sector = [0; MAX_SECTOR_DATA];
+    for (track, start) in toc.into_iter().zip(starts) {
+        // physical address of the first stored frame of the track
+        let lba = start - track.stored_pregap();
+        let mut crc32 = Crc32::new();
+        let mut md5 = md5::Context::new();
+        let mut sha1 = sha1::Sha1::new();
+        for frame in 0..track.frames as u64 {
+            read_raw_sector(chd, &track, frame, lba + frame as u32, &mut sector)?;
+            crc32.update(&sector);
+            md5.consume(sector);
+            sha1.update(&sector);
+        }
+        chd.report_progress(track.chd_offset() + track.frames as u64 * cd::FRAME_SIZE as u64);
+        result.push(TrackHashes {
+            number: track.number,
+            size: track.frames as u64 * MAX_SECTOR_DATA as u64,
+            crc32: crc32.finish(),
+            md5: md5.finalize().0,
+            sha1: sha1.digest().bytes(),
+        });
+    }
+    chd.report_finished(chd.size());
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iso::tests::make_chd;
+    use crate::tags::metadata;
+    use crate::testing::ChdBuilder;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        // bitwise EDC to check the table
+        let data: Vec<u8> = (0..2064u32).map(|i| (i * 13) as u8).collect();
+        let mut expected = 0u32;
+        for byte in &data {
+            expected ^= *byte as u32;
+            for _ in 0..8 {
+                expected = (expected >> 1) ^ (0xd801_8001 * (expected & 1));
+            }
+        }
+        assert_eq!(cd::edc(&data), expected);
+        assert_eq!(cd::msf_bcd(0), [0x00, 0x02, 0x00]);
+        assert_eq!(cd::msf_bcd(4350 - 150 + 74), [0x00, 0x58, 0x74]);
+    }
+
+    #[test]
+    fn test_audio() {
+        let raw = include_bytes!("../samples/cdzl.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let track = read_toc(&mut chd).unwrap().remove(0);
+        let mut data = vec![0; track.data_size() as usize];
+        read_track_at(&mut chd, &track, 0, &mut data).unwrap();
+
+        let hashes = track_hashes(&mut chd).unwrap();
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(hashes[0].number, 1);
+        assert_eq!(hashes[0].size, data.len() as u64);
+        assert_eq!(hashes[0].crc32, crc32(&data));
+        assert_eq!(hashes[0].md5, md5::compute(&data).0);
+        assert_eq!(hashes[0].sha1, sha1::Sha1::from(&data).digest().bytes());
+
+        let mut image = vec![0; chd.size() as usize];
+        chd.read_exact_at(0, &mut image).unwrap();
+        let hashes = image_hashes(&mut chd).unwrap();
+        assert_eq!(hashes.size, chd.size());
+        assert_eq!(hashes.crc32, crc32(&image));
+        assert_eq!(hashes.md5, md5::compute(&image).0);
+        assert_eq!(hashes.sha1, chd.raw_sha1());
+    }
+
+    #[test]
+    fn test_mode1() {
+        // cooked and raw chds of the same sectors have equal hashes
+        let sectors = 6;
+        let mut cooked = Vec::new();
+        for i in 0..sectors {
+            let mut frame = vec![0; cd::FRAME_SIZE];
+            frame[..2048].fill(i as u8 + 1);
+            cooked.extend_from_slice(&frame);
+        }
+        let meta = b"TRACK:1 TYPE:MODE1 SUBTYPE:NONE FRAMES:6 PREGAP:0 PGTYPE:MODE1 PGSUB:NONE POSTGAP:0\0";
+        let unit = cd::FRAME_SIZE as u32;
+        let chd = make_chd(&cooked, 2 * unit, unit, (metadata::CDROM_TRACK2, meta));
+        let mut chd = Chd::open(Cursor::new(chd)).unwrap();
+        let track = read_toc(&mut chd).unwrap().remove(0);
+
+        let mut raw = Vec::new();
+        let mut sector = [0; MAX_SECTOR_DATA];
+        for i in 0..sectors {
+            read_raw_sector(&mut chd, &track, i, i as u32, &mut sector).unwrap();
+            assert_eq!(&sector[..12], &cd::SYNC_HEADER);
+            assert_eq!(sector[15], 1);
+            assert!(sector[16..2064].iter().all(|b| *b == i as u8 + 1));
+            assert_eq!(&sector[2064..2068], &cd::edc(&sector[..2064]).to_le_bytes());
+            raw.extend_from_slice(&sector);
+            raw.resize(raw.len() + cd::MAX_SUBCODE_DATA, 0);
+        }
+        let hashes = track_hashes(&mut chd).unwrap();
+
+        let meta = b"TRACK:1 TYPE:MODE1_RAW SUBTYPE:NONE FRAMES:6 PREGAP:0 PGTYPE:MODE1 PGSUB:NONE POSTGAP:0\0";
+        let chd = make_chd(&raw, 2 * unit, unit, (metadata::CDROM_TRACK2, meta));
+        let mut chd = Chd::open(Cursor::new(chd)).unwrap();
+        assert_eq!(track_hashes(&mut chd).unwrap(), hashes);
+        assert_eq!(hashes[0].size, sectors * MAX_SECTOR_DATA as u64);
+    }
+
+    #[test]
+    fn test_unstored_pregap() {
+        // the second track is 150 frames further on disc than in chd
+        let mut cooked = Vec::new();
+        for i in 0..8 {
+            let mut frame = vec![0; cd::FRAME_SIZE];
+            frame[..2048].fill(i as u8 + 1);
+            cooked.extend_from_slice(&frame);
+        }
+        let unit = cd::FRAME_SIZE as u32;
+        let chd = ChdBuilder::new(2 * unit, unit)
+            .with_data(&cooked)
+            .with_metadata(
+                metadata::CDROM_TRACK2,
+                b"TRACK:1 TYPE:MODE1 SUBTYPE:NONE FRAMES:4 PREGAP:0 PGTYPE:MODE1 PGSUB:NONE POSTGAP:0\0",
+                true,
+            )
+            .with_metadata(
+                metadata::CDROM_TRACK2,
+                b"TRACK:2 TYPE:MODE1 SUBTYPE:NONE FRAMES:4 PREGAP:150 PGTYPE:MODE1 PGSUB:NONE POSTGAP:0\0",
+                true,
+            );
+        let mut chd = chd.open().unwrap();
+        let toc = read_toc(&mut chd).unwrap();
+        let mut sector = [0; MAX_SECTOR_DATA];
+        let mut sha1 = sha1::Sha1::new();
+        for frame in 0..4 {
+            read_raw_sector(&mut chd, &toc[1], frame, 154 + frame as u32, &mut sector).unwrap();
+            sha1.update(&sector);
+        }
+        assert_eq!(&sector[12..15], &cd::msf_bcd(157));
+
+        let hashes = track_hashes(&mut chd).unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hashes[1].sha1, sha1.digest().bytes());
+    }
+}
@@ -0,0 +1,134 @@
+use super::*;
+use std::sync::{Mutex, MutexGuard};
+
+// Chd that can be read from several threads at once through &self.
+// Reads are positioned and serialized by a lock, so decode state and cache stay shared.
+pub struct ChdShared<T: R> {
+    chd: Mutex<Chd<T>>,
+    size: u64,
+    hunk_size: usize,
+}
+
+impl<T: R> ChdShared<T> {
+    pub fn new(chd: Chd<T>) -> Self {
+        Self {
+            size: chd.size(),
+            hunk_size: chd.hunk_size(),
+            chd: Mutex::new(chd),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn hunk_size(&self) -> usize {
+        self.hunk_size
+    }
+
+    // Exclusive access to the chd for everything else, e.g. metadata or stats
+    pub fn lock(&self) -> io::Result<MutexGuard<'_, Chd<T>>> {
+        self.chd
+            .lock()
+            .map_err(|_| io::Error::other("chd: lock poisoned by panicked reader"))
+    }
+
+    // See Chd::read_exact_at
+    pub fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.lock()?.read_exact_at(offset, buf)
+    }
+
+    // See Chd::read_hunk
+    pub fn read_hunk(&self, hunknum: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.lock()?.read_hunk(hunknum, buf)
+    }
+
+    pub fn into_inner(self) -> io::Result<Chd<T>> {
+        self.chd
+            .into_inner()
+            .map_err(|_| io::Error::other("chd: lock poisoned by panicked reader"))
+    }
+}
+
+impl<T: R> From<Chd<T>> for ChdShared<T> {
+    fn from(chd: Chd<T>) -> Self {
+        Self::new(chd)
+    }
+}
+
+// Positioned reads for consumers of positioned-io sources. Unlike read_exact_at,
+// reads at the end of logical data are short instead of failing.
+#[cfg(feature = "positioned-io")]
+impl<T: R> positioned_io::ReadAt for ChdShared<T> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock()?.read_logical(pos, buf)
+    }
+}
+
+#[cfg(feature = "positioned-io")]
+impl<T: R> positioned_io::Size for ChdShared<T> {
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn assert_send_sync<S: Send + Sync>() {}
+
+    #[test]
+    fn test_threads() {
+        assert_send_sync::<ChdShared<std::fs::File>>();
+
+        let raw = include_bytes!("../samples/zlib.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let mut expected = Vec::new();
+        chd.read_to_end(&mut expected).unwrap();
+
+        let shared = ChdShared::new(chd);
+        let size = shared.size() as usize;
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let (shared, expected) = (&shared, &expected);
+                s.spawn(move || {
+                    let mut buf = vec![0; 1000];
+                    let mut offset = t * 777;
+                    while offset + buf.len() <= size {
+                        shared.read_exact_at(offset as u64, &mut buf).unwrap();
+                        assert!(buf[..] == expected[offset..offset + buf.len()]);
+                        offset += 4 * 777;
+                    }
+                });
+            }
+        });
+        let mut buf = vec![0; shared.hunk_size()];
+        shared.read_hunk(0, &mut buf).unwrap();
+        assert!(buf[..] == expected[..buf.len()]);
+        assert!(shared.lock().unwrap().stats().logical.bytes > 0);
+        shared.into_inner().unwrap();
+    }
+
+    #[cfg(feature = "positioned-io")]
+    #[test]
+    fn test_positioned_io() {
+        use positioned_io::{ReadAt, Size};
+
+        let raw = include_bytes!("../samples/zlib.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let mut expected = Vec::new();
+        chd.read_to_end(&mut expected).unwrap();
+
+        let shared = ChdShared::new(chd);
+        assert_eq!(Size::size(&shared).unwrap(), Some(expected.len() as u64));
+        let mut buf = vec![0; 10000];
+        shared.read_exact_at(1234, &mut buf).unwrap();
+        assert_eq!(buf, &expected[1234..11234]);
+        let end = expected.len() as u64;
+        assert_eq!(ReadAt::read_at(&shared, end - 10, &mut buf).unwrap(), 10);
+        assert_eq!(&buf[..10], &expected[expected.len() - 10..]);
+        assert_eq!(ReadAt::read_at(&shared, end, &mut buf).unwrap(), 0);
+    }
+}
@@ -1,3 +1,7 @@
+use std::fmt::{self, Write};
+use std::io;
+use std::str::FromStr;
+
 pub const CHD_CODEC_HUFF: u32 = make_tag(['h', 'u', 'f', 'f']);
 pub const CHD_CODEC_FLAC: u32 = make_tag(['f', 'l', 'a', 'c']);
 pub const CHD_CODEC_LZMA: u32 = make_tag(['l', 'z', 'm', 'a']);
@@ -23,6 +27,9 @@ pub mod metadata {
     pub const GDROM_OLD: u32 = make_tag(['C', 'H', 'G', 'T']);
     pub const GDROM_TRACK: u32 = make_tag(['C', 'H', 'G', 'D']);
 
+    // standard DVD-ROM metadata
+    pub const DVD: u32 = make_tag(['D', 'V', 'D', ' ']);
+
     // standard A/V metadata
     pub const AV: u32 = make_tag(['A', 'V', 'A', 'V']);
     // A/V laserdisc frame metadata
@@ -33,17 +40,93 @@ pub const fn make_tag(data: [char; 4]) -> u32 {
     (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
 }
 
+// Tag from its text form, shorter strings are padded with spaces (e.g. "KEY").
+// None if the string is empty, too long or not printable ascii.
+pub fn parse_tag(s: &str) -> Option<u32> {
+    if s.is_empty() || s.len() > 4 {
+        return None;
+    }
+    let mut data = [' '; 4];
+    for (i, c) in s.chars().enumerate() {
+        match c.is_ascii_graphic() || c == ' ' {
+            true => data[i] = c,
+            false => return None,
+        }
+    }
+    Some(make_tag(data))
+}
+
+// Tag text followed by its hex value, e.g. "huff (68756666)"
 pub fn tag_string(tag: u32) -> String {
-    let mut s = String::with_capacity(5);
-    let mut v = tag;
-    for _ in 0..4 {
-        let c = std::char::from_u32(v >> 24);
-        if c.is_some() && c.unwrap().is_ascii() {
-            s.push(c.unwrap());
-        } else {
-            s.push('?');
+    let mut s = String::with_capacity(15);
+    write!(s, "{} ({:08x})", CodecTag(tag), tag).unwrap();
+    s
+}
+
+// Four character code with text conversions: "cdlz".parse::<CodecTag>()
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CodecTag(pub u32);
+
+impl fmt::Display for CodecTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0.to_be_bytes() {
+            match byte {
+                0x20..=0x7e => f.write_char(byte as char)?,
+                _ => f.write_char('?')?,
+            }
         }
-        v <<= 8;
+        Ok(())
+    }
+}
+
+impl FromStr for CodecTag {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_tag(s) {
+            Some(tag) => Ok(CodecTag(tag)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid tag '{}': need up to 4 printable ascii chars", s),
+            )),
+        }
+    }
+}
+
+impl From<u32> for CodecTag {
+    fn from(tag: u32) -> Self {
+        CodecTag(tag)
+    }
+}
+
+impl From<CodecTag> for u32 {
+    fn from(tag: CodecTag) -> Self {
+        tag.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_tag("cdlz"), Some(CHD_CODEC_CD_LZMA));
+        assert_eq!(parse_tag("KEY"), Some(metadata::HARD_DISK_KEY));
+        assert_eq!(parse_tag(""), None);
+        assert_eq!(parse_tag("lzma2"), None);
+        assert_eq!(parse_tag("a\tb"), None);
+        assert_eq!(parse_tag("\u{e9}"), None);
+        let tag: CodecTag = "flac".parse().unwrap();
+        assert_eq!(u32::from(tag), CHD_CODEC_FLAC);
+        assert!("".parse::<CodecTag>().is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(CodecTag(CHD_CODEC_ZLIB).to_string(), "zlib");
+        assert_eq!(CodecTag(0x41000a42).to_string(), "A??B");
+        assert_eq!(tag_string(CHD_CODEC_HUFF), "huff (68756666)");
+        assert_eq!(tag_string(0x00000061), "???a (00000061)");
     }
-    format!("{} ({:8x})", s, tag)
 }
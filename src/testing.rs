@@ -0,0 +1,139 @@
+use super::info::HardDiskInfo;
+use super::tags::metadata;
+use super::writer::{ChdWriter, WriterOptions};
+use super::*;
+use std::io::Cursor;
+
+// Builds small valid V5 chds in memory from hunks and metadata, so code using this
+// crate could be tested without binary fixtures. Map is uncompressed like ChdWriter
+// makes it: hunks are stored as is, identical ones once.
+#[derive(Clone, Debug)]
+pub struct ChdBuilder {
+    hunk_size: u32,
+    unit_size: u32,
+    hunks: Vec<(bool, Vec<u8>)>, // data of each hunk, true if it's referenced from parent
+    parent_sha1: Option<[u8; 20]>,
+    metadata: Vec<(u32, Vec<u8>, bool)>, // tag, data, checksum
+}
+
+impl ChdBuilder {
+    pub fn new(hunk_size: u32, unit_size: u32) -> Self {
+        Self {
+            hunk_size,
+            unit_size,
+            hunks: Vec::new(),
+            parent_sha1: None,
+            metadata: Vec::new(),
+        }
+    }
+
+    // Append hunk. Only the last one can be shorter than hunk size, logical size
+    // ends there and the rest of the hunk is zeroes.
+    pub fn with_hunk(mut self, data: &[u8]) -> Self {
+        self.hunks.push((false, data.to_vec()));
+        self
+    }
+
+    // Append data split into hunks
+    pub fn with_data(mut self, data: &[u8]) -> Self {
+        for hunk in data.chunks(self.hunk_size.max(1) as usize) {
+            self = self.with_hunk(hunk);
+        }
+        self
+    }
+
+    // Append hunk referenced from parent, `data` must be the same as parent has there
+    pub fn with_parent_hunk(mut self, data: &[u8]) -> Self {
+        self.hunks.push((true, data.to_vec()));
+        self
+    }
+
+    // Make child of chd with `sha1`
+    pub fn with_parent(mut self, sha1: [u8; 20]) -> Self {
+        self.parent_sha1 = Some(sha1);
+        self
+    }
+
+    pub fn with_metadata(mut self, metatag: u32, data: &[u8], checksum: bool) -> Self {
+        self.metadata.push((metatag, data.to_vec(), checksum));
+        self
+    }
+
+    // Hard disk geometry metadata, as chdman writes it
+    pub fn with_hard_disk(self, info: &HardDiskInfo) -> Self {
+        let data = info.to_metadata();
+        self.with_metadata(metadata::HARD_DISK, &data, true)
+    }
+
+    // Bytes of chd file
+    pub fn build(&self) -> io::Result<Vec<u8>> {
+        let size = self.hunks.iter().map(|(_, data)| data.len() as u64).sum();
+        let mut options = WriterOptions::new(size, self.hunk_size, self.unit_size);
+        options.parent_sha1 = self.parent_sha1;
+        let mut writer = ChdWriter::create(Cursor::new(Vec::new()), &options)?;
+        for (metatag, data, checksum) in &self.metadata {
+            writer.add_metadata(*metatag, data, *checksum)?;
+        }
+        for (parent, data) in &self.hunks {
+            match parent {
+                true => writer.write_parent_hunk(data)?,
+                false => writer.write_hunk(data)?,
+            }
+        }
+        Ok(writer.finish()?.into_inner())
+    }
+
+    pub fn open(&self) -> io::Result<Chd<Cursor<Vec<u8>>>> {
+        Chd::open(Cursor::new(self.build()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * 13) as u8).collect();
+        let geometry = HardDiskInfo::parse("CYLS:1,HEADS:1,SECS:10,BPS:512").unwrap();
+        let builder = ChdBuilder::new(1024, 512)
+            .with_data(&data)
+            .with_hard_disk(&geometry)
+            .with_metadata(0x74657374, b"test", false);
+        let mut chd = builder.open().unwrap();
+        assert_eq!(chd.size(), data.len() as u64);
+        assert_eq!(chd.hunk_count(), 5);
+        let mut image = Vec::new();
+        chd.extract(&mut image).unwrap();
+        assert_eq!(image, data);
+        assert_eq!(
+            chd.read_metadata_simple(metadata::HARD_DISK).unwrap(),
+            Some(geometry.to_metadata())
+        );
+        assert_eq!(builder.build().unwrap(), chd.into_inner().into_inner());
+
+        // child referencing the first hunk of parent
+        let parent = builder.open().unwrap();
+        let child = ChdBuilder::new(1024, 512)
+            .with_parent(parent.sha1())
+            .with_parent_hunk(&data[..1024])
+            .with_hunk(&[0x55; 100]);
+        let mut chd = child.open().unwrap();
+        assert!(chd.has_parent());
+        chd.set_parent(parent).unwrap();
+        let mut image = Vec::new();
+        chd.extract(&mut image).unwrap();
+        assert_eq!(&image[..1024], &data[..1024]);
+        assert_eq!(&image[1024..], &[0x55; 100]);
+
+        // only the last hunk can be short
+        let short = ChdBuilder::new(1024, 512)
+            .with_hunk(&[1; 100])
+            .with_hunk(&[2; 1024]);
+        assert!(short.build().is_err());
+        assert!(ChdBuilder::new(1024, 512)
+            .with_parent_hunk(&data[..1024])
+            .build()
+            .is_err());
+    }
+}
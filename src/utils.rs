@@ -4,84 +4,555 @@ use super::R;
 use std::fmt::Write as FmtWrite;
 use std::io;
 use std::io::{SeekFrom, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub fn read_be16(data: &[u8]) -> u16 {
-    assert_eq!(data.len(), 2);
-    (data[0] as u16) << 8 | data[1] as u16
+// Big and little endian integers at offset within byte slice. Access past the
+// end of slice is an error, so parsers can use ? on malformed data.
+pub trait ByteOrderExt {
+    fn get_be16(&self, offset: usize) -> io::Result<u16>;
+    fn get_be24(&self, offset: usize) -> io::Result<u32>;
+    fn get_be32(&self, offset: usize) -> io::Result<u32>;
+    fn get_be48(&self, offset: usize) -> io::Result<u64>;
+    fn get_be64(&self, offset: usize) -> io::Result<u64>;
+    fn get_le16(&self, offset: usize) -> io::Result<u16>;
+    // values are truncated to the number of bits
+    fn put_be16(&mut self, offset: usize, val: u16) -> io::Result<()>;
+    fn put_be24(&mut self, offset: usize, val: u32) -> io::Result<()>;
+    fn put_be32(&mut self, offset: usize, val: u32) -> io::Result<()>;
+    fn put_be48(&mut self, offset: usize, val: u64) -> io::Result<()>;
+    fn put_be64(&mut self, offset: usize, val: u64) -> io::Result<()>;
+    fn put_le16(&mut self, offset: usize, val: u16) -> io::Result<()>;
 }
 
-pub fn read_be24(data: &[u8]) -> u32 {
-    assert_eq!(data.len(), 3);
-    (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32
+fn byte_range(size: usize, offset: usize, len: usize) -> io::Result<std::ops::Range<usize>> {
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(offset..end),
+        _ => Err(invalid_data(format!(
+            "bytes: {} bytes at {} are past the end of {} bytes",
+            len, offset, size
+        ))),
+    }
 }
 
-pub fn read_be32(data: &[u8]) -> u32 {
-    assert_eq!(data.len(), 4);
-    (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
+fn get_be(data: &[u8], offset: usize, len: usize) -> io::Result<u64> {
+    let range = byte_range(data.len(), offset, len)?;
+    Ok(data[range]
+        .iter()
+        .fold(0, |val, byte| (val << 8) | *byte as u64))
 }
 
-pub fn read_be48(data: &[u8]) -> u64 {
-    assert_eq!(data.len(), 6);
-    (data[0] as u64) << 40
-        | (data[1] as u64) << 32
-        | (data[2] as u64) << 24
-        | (data[3] as u64) << 16
-        | (data[4] as u64) << 8
-        | data[5] as u64
+fn put_be(data: &mut [u8], offset: usize, len: usize, val: u64) -> io::Result<()> {
+    let range = byte_range(data.len(), offset, len)?;
+    for (i, byte) in data[range].iter_mut().rev().enumerate() {
+        *byte = (val >> (8 * i)) as u8;
+    }
+    Ok(())
 }
 
-pub fn read_be64(data: &[u8]) -> u64 {
-    assert_eq!(data.len(), 8);
-    (data[0] as u64) << 56
-        | (data[1] as u64) << 48
-        | (data[2] as u64) << 40
-        | (data[3] as u64) << 32
-        | (data[4] as u64) << 24
-        | (data[5] as u64) << 16
-        | (data[6] as u64) << 8
-        | data[7] as u64
+impl ByteOrderExt for [u8] {
+    fn get_be16(&self, offset: usize) -> io::Result<u16> {
+        get_be(self, offset, 2).map(|v| v as u16)
+    }
+    fn get_be24(&self, offset: usize) -> io::Result<u32> {
+        get_be(self, offset, 3).map(|v| v as u32)
+    }
+    fn get_be32(&self, offset: usize) -> io::Result<u32> {
+        get_be(self, offset, 4).map(|v| v as u32)
+    }
+    fn get_be48(&self, offset: usize) -> io::Result<u64> {
+        get_be(self, offset, 6)
+    }
+    fn get_be64(&self, offset: usize) -> io::Result<u64> {
+        get_be(self, offset, 8)
+    }
+    fn get_le16(&self, offset: usize) -> io::Result<u16> {
+        self.get_be16(offset).map(u16::swap_bytes)
+    }
+    fn put_be16(&mut self, offset: usize, val: u16) -> io::Result<()> {
+        put_be(self, offset, 2, val as u64)
+    }
+    fn put_be24(&mut self, offset: usize, val: u32) -> io::Result<()> {
+        put_be(self, offset, 3, val as u64)
+    }
+    fn put_be32(&mut self, offset: usize, val: u32) -> io::Result<()> {
+        put_be(self, offset, 4, val as u64)
+    }
+    fn put_be48(&mut self, offset: usize, val: u64) -> io::Result<()> {
+        put_be(self, offset, 6, val)
+    }
+    fn put_be64(&mut self, offset: usize, val: u64) -> io::Result<()> {
+        put_be(self, offset, 8, val)
+    }
+    fn put_le16(&mut self, offset: usize, val: u16) -> io::Result<()> {
+        self.put_be16(offset, val.swap_bytes())
+    }
+}
+
+// Amount of data passed through some I/O layer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoStat {
+    pub reads: u64, // number of read requests
+    pub bytes: u64, // total bytes read
+}
+
+impl IoStat {
+    pub fn add(&mut self, bytes: usize) {
+        self.reads += 1;
+        self.bytes += bytes as u64;
+    }
+
+    pub fn since(&self, earlier: &IoStat) -> IoStat {
+        IoStat {
+            reads: self.reads.saturating_sub(earlier.reads),
+            bytes: self.bytes.saturating_sub(earlier.bytes),
+        }
+    }
+}
+
+// Amount of data processed by decompressor and time it took
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CodecStat {
+    pub hunks: u64,     // number of decompressed hunks
+    pub input: u64,     // compressed bytes consumed
+    pub output: u64,    // decompressed bytes produced
+    pub calls: u64,     // decompress invocations, including failed ones
+    pub time: Duration, // spent in decompressor by all calls
+}
+
+impl CodecStat {
+    pub fn add(&mut self, input: usize, output: usize) {
+        self.hunks += 1;
+        self.input += input as u64;
+        self.output += output as u64;
+    }
+
+    pub fn add_call(&mut self, time: Duration) {
+        self.calls += 1;
+        self.time += time;
+    }
+
+    pub fn since(&self, earlier: &CodecStat) -> CodecStat {
+        CodecStat {
+            hunks: self.hunks.saturating_sub(earlier.hunks),
+            input: self.input.saturating_sub(earlier.input),
+            output: self.output.saturating_sub(earlier.output),
+            calls: self.calls.saturating_sub(earlier.calls),
+            time: self.time.saturating_sub(earlier.time),
+        }
+    }
+
+    // decompressed bytes per second of decode time, 0 if nothing was timed
+    pub fn output_per_sec(&self) -> f64 {
+        match self.time.as_secs_f64() {
+            secs if secs > 0.0 => self.output as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    // average time of a call, zero if there were none
+    pub fn time_per_call(&self) -> Duration {
+        match self.calls {
+            0 => Duration::ZERO,
+            calls => self.time / calls as u32,
+        }
+    }
+}
+
+// Measures time of frequent operations. There is no clock in browser,
+// Instant::now() panics there, so nothing is measured and elapsed time is zero.
+#[derive(Clone, Copy, Debug)]
+pub struct Timer(Option<Instant>);
+
+impl Timer {
+    pub fn start() -> Self {
+        match cfg!(target_arch = "wasm32") {
+            true => Self(None),
+            false => Self(Some(Instant::now())),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.0.map_or(Duration::ZERO, |start| start.elapsed())
+    }
+
+    // time between start of `earlier` and this timer
+    pub fn since(&self, earlier: &Timer) -> Duration {
+        match (self.0, earlier.0) {
+            (Some(start), Some(earlier)) => start.saturating_duration_since(earlier),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+// Lookups of decompressed hunk cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStat {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStat {
+    pub fn since(&self, earlier: &CacheStat) -> CacheStat {
+        CacheStat {
+            hits: self.hits.saturating_sub(earlier.hits),
+            misses: self.misses.saturating_sub(earlier.misses),
+        }
+    }
+
+    // share of lookups served from cache, 0 if there were no lookups
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stat {
+    pub raw: IoStat,            // reads from underlaying file
+    pub logical: IoStat,        // reads from logical image
+    pub parent: IoStat,         // reads forwarded to parent chd
+    pub codecs: [CodecStat; 4], // per compressor slot
+    pub cache: CacheStat,       // hunk cache lookups
+}
+
+impl Stat {
+    // counters accumulated after `earlier` was taken
+    pub fn since(&self, earlier: &Stat) -> Stat {
+        let mut codecs = [CodecStat::default(); 4];
+        for (i, codec) in codecs.iter_mut().enumerate() {
+            *codec = self.codecs[i].since(&earlier.codecs[i]);
+        }
+        Stat {
+            raw: self.raw.since(&earlier.raw),
+            logical: self.logical.since(&earlier.logical),
+            parent: self.parent.since(&earlier.parent),
+            codecs,
+            cache: self.cache.since(&earlier.cache),
+        }
+    }
+
+    // total bytes produced by all decompressors
+    pub fn decompressed(&self) -> u64 {
+        self.codecs.iter().map(|c| c.output).sum()
+    }
+
+    // total time spent in all decompressors
+    pub fn decode_time(&self) -> Duration {
+        self.codecs.iter().map(|c| c.time).sum()
+    }
+}
+
+// Statistics at some moment of time, used to calculate per-interval rates
+#[derive(Clone, Copy, Debug)]
+pub struct StatSnapshot {
+    pub stat: Stat,
+    pub time: Timer,
+}
+
+impl StatSnapshot {
+    pub fn new(stat: &Stat) -> Self {
+        Self {
+            stat: *stat,
+            time: Timer::start(),
+        }
+    }
+
+    // counters and time passed between `earlier` and this snapshot
+    pub fn since(&self, earlier: &StatSnapshot) -> (Stat, Duration) {
+        (
+            self.stat.since(&earlier.stat),
+            self.time.since(&earlier.time),
+        )
+    }
+
+    // decompressed bytes per second between `earlier` and this snapshot
+    pub fn decompress_rate(&self, earlier: &StatSnapshot) -> f64 {
+        let (stat, elapsed) = self.since(earlier);
+        match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => stat.decompressed() as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+// Read rate limit of underlying file, e.g. for background verification of a
+// collection sharing disk with an emulator or server. None is unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoLimit {
+    pub bytes_per_sec: Option<u64>,
+    pub reads_per_sec: Option<u64>,
+}
+
+// Time source of Throttle, time is counted from creation of the clock
+pub(crate) trait Clock: std::fmt::Debug + Send + Sync {
+    fn now(&self) -> Duration;
+    fn sleep(&self, duration: Duration);
+}
+
+// Stands still in browser like Timer, and doesn't sleep there either,
+// so reads aren't limited
+#[derive(Debug)]
+struct SystemClock(Timer);
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.0.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        if !cfg!(target_arch = "wasm32") {
+            std::thread::sleep(duration)
+        }
+    }
+}
+
+// Clock which sleeps by moving its time forward, so tests don't wait
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct VirtualClock(std::sync::Mutex<Duration>);
+
+#[cfg(test)]
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self(std::sync::Mutex::new(Duration::ZERO))
+    }
+}
+
+#[cfg(test)]
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        *self.0.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+// Keeps reads counted in IoStat under IoLimit by sleeping after them. Rate is
+// averaged since the period start, which moves when reads are more than a second
+// behind the limit, so idle time doesn't turn into a burst later.
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    limit: IoLimit,
+    clock: Arc<dyn Clock>,
+    start: Duration, // of current period by clock
+    done: IoStat,    // reads in current period
+    seen: IoStat,    // counters accounted so far
+}
+
+impl Throttle {
+    pub fn new(limit: IoLimit) -> Self {
+        Self::with_clock(limit, Arc::new(SystemClock(Timer::start())))
+    }
+
+    pub(crate) fn with_clock(limit: IoLimit, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            limit,
+            start: clock.now(),
+            clock,
+            done: IoStat::default(),
+            seen: IoStat::default(),
+        }
+    }
+
+    pub fn limit(&self) -> IoLimit {
+        self.limit
+    }
+
+    // time `done` reads take at limited rate
+    fn due(&self, done: &IoStat) -> Duration {
+        let secs = |count: u64, limit: Option<u64>| match limit {
+            Some(limit) if limit > 0 => count as f64 / limit as f64,
+            _ => 0.0,
+        };
+        let bytes = secs(done.bytes, self.limit.bytes_per_sec);
+        let reads = secs(done.reads, self.limit.reads_per_sec);
+        Duration::from_secs_f64(bytes.max(reads))
+    }
+
+    // Account reads added to `stat` since the last call, sleep while they are over limit
+    pub fn pace(&mut self, stat: &IoStat) {
+        if stat.reads < self.seen.reads || stat.bytes < self.seen.bytes {
+            self.seen = *stat; // counters were reset
+        }
+        let new = stat.since(&self.seen);
+        self.seen = *stat;
+        if new.reads == 0 {
+            return;
+        }
+        let now = self.clock.now();
+        if now - self.start > self.due(&self.done) + Duration::from_secs(1) {
+            self.start = now;
+            self.done = IoStat::default();
+        }
+        self.done.reads += new.reads;
+        self.done.bytes += new.bytes;
+        let due = self.due(&self.done);
+        let elapsed = now - self.start;
+        if due > elapsed {
+            self.clock.sleep(due - elapsed);
+        }
+    }
 }
 
-pub fn write_le16(data: &mut [u8], val: u16) {
-    data[0] = val as u8;
-    data[1] = (val >> 8) as u8;
+// Accounts memory allocated by Chd against optional limit
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    used: usize,
 }
 
-pub fn write_be16(data: &mut [u8], val: u16) {
-    data[1] = val as u8;
-    data[0] = (val >> 8) as u8;
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self { limit, used: 0 }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    // whether `bytes` more could be allocated without exceeding the limit
+    pub fn fits(&self, bytes: usize) -> bool {
+        match self.limit {
+            Some(limit) => self.used.saturating_add(bytes) <= limit,
+            None => true,
+        }
+    }
+
+    pub fn reserve(&mut self, what: &str, bytes: usize) -> io::Result<()> {
+        if !self.fits(bytes) {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!(
+                    "chd: {} needs {} bytes, but only {} of {} bytes memory budget left",
+                    what,
+                    bytes,
+                    self.limit.unwrap_or(0).saturating_sub(self.used),
+                    self.limit.unwrap_or(0)
+                ),
+            ));
+        }
+        self.used += bytes;
+        Ok(())
+    }
+
+    pub fn release(&mut self, bytes: usize) {
+        self.used = self.used.saturating_sub(bytes);
+    }
 }
 
-pub fn write_be24(data: &mut [u8], val: u32) {
-    data[2] = val as u8;
-    data[1] = (val >> 8) as u8;
-    data[0] = (val >> 16) as u8;
+// Free buffers shared by codecs of Chd. Hunks are decompressed one at a time,
+// so buffers given back after each hunk serve all configured codecs.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    bytes: Vec<Vec<u8>>,
+    samples: Vec<Vec<i32>>,
 }
 
-pub fn write_be32(data: &mut [u8], val: u32) {
-    data[3] = val as u8;
-    data[2] = (val >> 8) as u8;
-    data[1] = (val >> 16) as u8;
-    data[0] = (val >> 24) as u8;
+// largest free buffer, so it rarely has to grow
+fn take_largest<T>(free: &mut Vec<Vec<T>>) -> Vec<T> {
+    match (0..free.len()).max_by_key(|&i| free[i].capacity()) {
+        Some(i) => free.swap_remove(i),
+        None => Vec::new(),
+    }
 }
 
-pub fn write_be48(data: &mut [u8], val: u64) {
-    data[5] = val as u8;
-    data[4] = (val >> 8) as u8;
-    data[3] = (val >> 16) as u8;
-    data[2] = (val >> 24) as u8;
-    data[1] = (val >> 32) as u8;
-    data[0] = (val >> 40) as u8;
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Zero filled buffer of len bytes
+    pub fn take(&mut self, len: usize) -> Vec<u8> {
+        let mut buf = take_largest(&mut self.bytes);
+        buf.clear();
+        buf.resize(len, 0);
+        buf
+    }
+
+    pub fn give(&mut self, buf: Vec<u8>) {
+        self.bytes.push(buf);
+    }
+
+    // Empty buffer for decoded audio samples
+    pub fn take_samples(&mut self) -> Vec<i32> {
+        let mut buf = take_largest(&mut self.samples);
+        buf.clear();
+        buf
+    }
+
+    pub fn give_samples(&mut self, buf: Vec<i32>) {
+        self.samples.push(buf);
+    }
+
+    // bytes held by free buffers
+    pub fn memory_usage(&self) -> usize {
+        let bytes: usize = self.bytes.iter().map(|b| b.capacity()).sum();
+        let samples: usize = self.samples.iter().map(|b| b.capacity()).sum();
+        bytes + samples * std::mem::size_of::<i32>()
+    }
 }
 
 pub trait ReadAt {
     fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()>;
+
+    fn read_at_stat(&mut self, offset: u64, data: &mut [u8], stat: &mut IoStat) -> io::Result<()> {
+        self.read_at(offset, data)?;
+        stat.add(data.len());
+        Ok(())
+    }
 }
 
+// Interrupted reads and seeks are retried. Every read seeks first, so after
+// WouldBlock the whole read_at can be retried by caller.
 impl<T: R> ReadAt for T {
     fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
-        self.seek(SeekFrom::Start(offset))?;
-        self.read_exact(data)
+        loop {
+            match self.seek(SeekFrom::Start(offset)) {
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let mut done = 0;
+        while done < data.len() {
+            match self.read(&mut data[done..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!(
+                            "read of {} bytes at {}: end of file after {} bytes",
+                            data.len(),
+                            offset,
+                            done
+                        ),
+                    ))
+                }
+                Ok(n) => done += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        format!(
+                            "read of {} bytes at {}: would block after {} bytes",
+                            data.len(),
+                            offset,
+                            done
+                        ),
+                    ))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
     }
 }
 
@@ -106,7 +577,7 @@ pub fn hex_write<W: Write>(to: &mut W, hash: &[u8]) -> io::Result<()> {
 
 pub fn hex_writeln<W: Write>(to: &mut W, hash: &[u8]) -> io::Result<()> {
     hex_write(to, hash)?;
-    writeln!(to, "")?;
+    writeln!(to)?;
     Ok(())
 }
 
@@ -121,3 +592,369 @@ pub fn hex_string(hash: &[u8]) -> String {
 pub fn crc16(data: &[u8]) -> u16 {
     crc16::State::<crc16::CCITT_FALSE>::calculate(data)
 }
+
+// Slice-by-8 tables of reflected CRC-32 polynomial: table[0] is the classic
+// byte table, table[k] advances CRC of a byte followed by k zero bytes
+pub const fn crc32_tables(poly: u32) -> [[u32; 256]; 8] {
+    let mut tables = [[0; 256]; 8];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ poly,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        tables[0][i] = crc;
+        i += 1;
+    }
+    let mut k = 1;
+    while k < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let prev = tables[k - 1][i];
+            tables[k][i] = (prev >> 8) ^ tables[0][(prev & 0xff) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+    tables
+}
+
+// Update reflected CRC-32 state without pre and post inversion, 8 bytes per step
+pub fn crc32_update(tables: &[[u32; 256]; 8], mut crc: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let lo = crc ^ u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        let hi = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        crc = tables[7][(lo & 0xff) as usize]
+            ^ tables[6][((lo >> 8) & 0xff) as usize]
+            ^ tables[5][((lo >> 16) & 0xff) as usize]
+            ^ tables[4][(lo >> 24) as usize]
+            ^ tables[3][(hi & 0xff) as usize]
+            ^ tables[2][((hi >> 8) & 0xff) as usize]
+            ^ tables[1][((hi >> 16) & 0xff) as usize]
+            ^ tables[0][(hi >> 24) as usize];
+    }
+    for byte in chunks.remainder() {
+        crc = tables[0][((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+// CRC-32 (IEEE 802.3, reflected 0xedb88320) used by zip, png, dat files and v3/v4 maps
+const CRC32_TABLES: [[u32; 256]; 8] = crc32_tables(0xedb8_8320);
+
+// SHA-1 which intermediate state could be saved and restored, e.g. to resume
+// hashing of extracted data later. sha1 crate keeps its state private.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResumableSha1 {
+    h: [u32; 5],
+    block: [u8; 64], // pending bytes of incomplete block
+    len: u64,        // bytes hashed
+}
+
+impl Default for ResumableSha1 {
+    fn default() -> Self {
+        Self {
+            h: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0],
+            block: [0; 64],
+            len: 0,
+        }
+    }
+}
+
+fn sha1_block(h: &mut [u32; 5], block: &[u8]) {
+    let mut w = [0u32; 80];
+    for (i, word) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e] = *h;
+    for (i, wi) in w.iter().enumerate() {
+        let (f, k) = match i / 20 {
+            0 => ((b & c) | (!b & d), 0x5a827999),
+            1 => (b ^ c ^ d, 0x6ed9eba1),
+            2 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+            _ => (b ^ c ^ d, 0xca62c1d6),
+        };
+        let t = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(*wi);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = t;
+    }
+    for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+        *h = h.wrapping_add(v);
+    }
+}
+
+impl ResumableSha1 {
+    pub const STATE_SIZE: usize = 92;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // number of bytes hashed so far
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        let used = (self.len % 64) as usize;
+        self.len += data.len() as u64;
+        if used > 0 {
+            let n = std::cmp::min(64 - used, data.len());
+            self.block[used..used + n].copy_from_slice(&data[..n]);
+            data = &data[n..];
+            if used + n < 64 {
+                return;
+            }
+            let block = self.block;
+            sha1_block(&mut self.h, &block);
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            sha1_block(&mut self.h, block);
+        }
+        let rest = blocks.remainder();
+        self.block[..rest.len()].copy_from_slice(rest);
+    }
+
+    // Digest of data hashed so far, hashing could continue after it
+    pub fn digest(&self) -> [u8; 20] {
+        let mut last = self.clone();
+        let bits = self.len.wrapping_mul(8);
+        last.update(&[0x80]);
+        while last.len % 64 != 56 {
+            last.update(&[0]);
+        }
+        last.update(&bits.to_be_bytes());
+        let mut digest = [0; 20];
+        for (bytes, h) in digest.chunks_exact_mut(4).zip(last.h) {
+            bytes.copy_from_slice(&h.to_be_bytes());
+        }
+        digest
+    }
+
+    /*
+    Exported state format:
+
+    [  0] uint32_t h[5];          // intermediate hash
+    [ 20] uint64_t length;        // bytes hashed
+    [ 28] uint8_t block[64];      // pending bytes of incomplete block, zero padded
+    */
+    pub fn to_bytes(&self) -> [u8; Self::STATE_SIZE] {
+        let mut data = [0; Self::STATE_SIZE];
+        for (bytes, h) in data[..20].chunks_exact_mut(4).zip(self.h) {
+            bytes.copy_from_slice(&h.to_be_bytes());
+        }
+        data[20..28].copy_from_slice(&self.len.to_be_bytes());
+        let used = (self.len % 64) as usize;
+        data[28..28 + used].copy_from_slice(&self.block[..used]);
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        if data.len() != Self::STATE_SIZE {
+            return Err(invalid_data(format!(
+                "sha1 state: size {} bytes instead of {}",
+                data.len(),
+                Self::STATE_SIZE
+            )));
+        }
+        let mut state = Self::new();
+        for (i, h) in state.h.iter_mut().enumerate() {
+            *h = data.get_be32(i * 4)?;
+        }
+        state.len = data.get_be64(20)?;
+        copy_from(&mut state.block, &data[28..]);
+        Ok(state)
+    }
+}
+
+// Incremental CRC-32 of data passed to update() calls
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { state: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.state = crc32_update(&CRC32_TABLES, self.state, data);
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_order() {
+        let mut data = [0; 10];
+        data.put_be48(1, 0x1122_3344_5566).unwrap();
+        data.put_be24(7, 0xff_aabbcc).unwrap();
+        data.put_le16(8, 0xeedd).unwrap();
+        assert_eq!(
+            data,
+            [0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0xaa, 0xdd, 0xee]
+        );
+        assert_eq!(data.get_be16(1).unwrap(), 0x1122);
+        assert_eq!(data.get_be24(7).unwrap(), 0xaaddee);
+        assert_eq!(data.get_be32(0).unwrap(), 0x0011_2233);
+        assert_eq!(data.get_be48(1).unwrap(), 0x1122_3344_5566);
+        assert_eq!(data.get_be64(2).unwrap(), 0x2233_4455_66aa_ddee);
+        assert_eq!(data.get_le16(8).unwrap(), 0xeedd);
+
+        assert!(data.get_be64(3).is_err());
+        assert!(data.get_be16(usize::MAX).is_err());
+        assert!(data.put_be32(7, 0).is_err());
+        assert_eq!(data[7..], [0xaa, 0xdd, 0xee]);
+        assert!(data[..0].get_be16(0).is_err());
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        let mut pool = BufferPool::new();
+        let mut buf = pool.take(100);
+        buf[0] = 1;
+        pool.give(buf);
+        let small = pool.take(10);
+        pool.give(small);
+        assert_eq!(pool.memory_usage(), 100);
+        assert_eq!(pool.take(50), vec![0; 50]);
+        let mut samples = pool.take_samples();
+        samples.resize(8, 1);
+        pool.give_samples(samples);
+        assert!(pool.take_samples().is_empty());
+    }
+
+    #[test]
+    fn test_throttle() {
+        let limit = IoLimit {
+            bytes_per_sec: Some(10_000),
+            reads_per_sec: Some(100),
+        };
+        let clock = Arc::new(VirtualClock::new());
+        let mut throttle = Throttle::with_clock(limit, clock.clone());
+        let mut stat = IoStat::default();
+        let start = clock.now();
+        for _ in 0..5 {
+            stat.add(100); // 5 reads at 100 per second
+            throttle.pace(&stat);
+        }
+        assert_eq!(clock.now() - start, Duration::from_millis(50));
+        stat.add(500); // 1000 bytes at 10000 per second
+        throttle.pace(&stat);
+        assert_eq!(clock.now() - start, Duration::from_millis(100));
+
+        // reset counters aren't accounted as reads
+        let start = clock.now();
+        stat = IoStat::default();
+        throttle.pace(&stat);
+        assert_eq!(clock.now(), start);
+
+        // idle time isn't used for a burst
+        clock.sleep(Duration::from_secs(5));
+        let start = clock.now();
+        for _ in 0..3 {
+            stat.add(1);
+            throttle.pace(&stat);
+        }
+        assert_eq!(clock.now() - start, Duration::from_millis(30));
+
+        let mut unlimited = Throttle::with_clock(IoLimit::default(), clock.clone());
+        let start = clock.now();
+        stat.add(1 << 30);
+        unlimited.pace(&stat);
+        assert_eq!(clock.now(), start);
+    }
+
+    #[test]
+    fn test_resumable_sha1() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 13) as u8).collect();
+        for split in [0, 1, 55, 56, 64, 65, 999, 1000] {
+            let mut hasher = ResumableSha1::new();
+            hasher.update(&data[..split]);
+            let state = hasher.to_bytes();
+            let mut hasher = ResumableSha1::from_bytes(&state).unwrap();
+            for chunk in data[split..].chunks(7) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.len(), data.len() as u64);
+            assert_eq!(hasher.digest(), sha1::Sha1::from(&data).digest().bytes());
+        }
+        assert_eq!(
+            ResumableSha1::new().digest(),
+            sha1::Sha1::new().digest().bytes()
+        );
+        assert!(ResumableSha1::from_bytes(&[0; 20]).is_err());
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"a"), 0xe8b7_be43);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+        assert_eq!(crc32(&[0; 32]), 0x190a_55ad);
+        assert_eq!(crc32(&[0xff; 32]), 0xff6c_ab0b);
+
+        // bitwise CRC, and updates split at any position
+        let data: Vec<u8> = (0..300u32).map(|i| (i * 31 + i / 7) as u8).collect();
+        for len in 0..data.len() {
+            let mut expected = !0u32;
+            for byte in &data[..len] {
+                expected ^= *byte as u32;
+                for _ in 0..8 {
+                    expected = (expected >> 1) ^ (0xedb8_8320 * (expected & 1));
+                }
+            }
+            assert_eq!(crc32(&data[..len]), !expected);
+
+            let mut crc = Crc32::new();
+            crc.update(&data[..len / 3]);
+            crc.update(&data[len / 3..len]);
+            assert_eq!(crc.finish(), !expected);
+        }
+    }
+}
@@ -0,0 +1,1264 @@
+use super::compress::{self, Compress};
+use super::*;
+use std::collections::HashMap;
+
+// Geometry of chd being created
+#[derive(Clone, Debug)]
+pub struct WriterOptions {
+    pub logical_size: u64,             // size of logical data in bytes
+    pub hunk_size: u32,                // bytes per hunk
+    pub unit_size: u32,                // bytes per unit, hunk_size must be multiple of it
+    pub parent_sha1: Option<[u8; 20]>, // sha1 of parent for child chd
+    pub progress: Option<Progress>,    // reported after each hunk written
+    pub compression: [u32; 4],         // codecs, unused slots are 0; none for uncompressed map
+}
+
+impl WriterOptions {
+    pub fn new(logical_size: u64, hunk_size: u32, unit_size: u32) -> Self {
+        Self {
+            logical_size,
+            hunk_size,
+            unit_size,
+            parent_sha1: None,
+            progress: None,
+            compression: [0; 4],
+        }
+    }
+
+    // Use codecs, the first 4 of them
+    pub fn with_compression(mut self, codecs: &[u32]) -> Self {
+        self.compression = [0; 4];
+        for (slot, codec) in self.compression.iter_mut().zip(codecs) {
+            *slot = *codec;
+        }
+        self
+    }
+}
+
+// Codecs the writer can compress hunks with
+pub const COMPRESSORS: &[u32] = compress::SUPPORTED;
+
+// Creates V5 chd. Hunks are written in order, identical hunks are stored once.
+// Without codecs map is uncompressed and zero hunks of chd without parent aren't
+// stored at all. With codecs each hunk is stored compressed by the codec making
+// it the shortest, or as is if none of them makes it shorter, and map is compressed.
+pub struct ChdWriter<W: Write + Seek> {
+    io: W,
+    header: Header,
+    map: Vec<u8>,                      // uncompressed map, or entries of compressed one
+    hunknum: usize,                    // next hunk to write
+    offset: u64,                       // file offset of next stored hunk
+    stored: HashMap<[u8; 20], u32>,    // sha1 of stored hunk -> map entry or hunknum
+    rawsha1: sha1::Sha1,               // of logical data written so far
+    metadata: Vec<(u32, u8, Vec<u8>)>, // tag, flags, data
+    hunkbuf: Vec<u8>,                  // last hunk padding
+    codecs: Vec<Box<dyn Compress>>,    // of header compressor slots in use
+    compressed: Vec<u8>,               // hunk compressed by current codec
+    best: Vec<u8>,                     // the shortest compressed hunk
+    progress: Option<Progress>,
+}
+
+impl<W: Write + Seek> ChdWriter<W> {
+    pub fn create(io: W, options: &WriterOptions) -> io::Result<Self> {
+        let mut header = Header {
+            length: 124,
+            version: V5,
+            size: options.logical_size,
+            hunkbytes: options.hunk_size,
+            unitbytes: options.unit_size,
+            compressors: options.compression,
+            parentsha1: options.parent_sha1.unwrap_or([0; 20]),
+            ..Default::default()
+        };
+        // same sanity checks as for reading
+        header.read_header_v5(&header.to_bytes()?)?;
+        let hunkbytes = header.hunkbytes as u64;
+        let codecs = match header.compressors.iter().position(|tag| *tag == 0) {
+            Some(0) if header.compressors.iter().any(|tag| *tag != 0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "writer: the first codec slot is empty",
+                ))
+            }
+            Some(used) => &header.compressors[..used],
+            None => &header.compressors[..],
+        };
+        let codecs = codecs
+            .iter()
+            .map(|tag| compress::create(header.hunkbytes, *tag))
+            .collect::<io::Result<Vec<_>>>()?;
+        let (map, offset) = match codecs.is_empty() {
+            // the first hunks are occupied by header, zero offset has special meaning
+            true => (
+                vec![0; UncompressedMap5::offset(header.hunkcount as usize)],
+                124u64.div_ceil(hunkbytes) * hunkbytes,
+            ),
+            // compressed hunks follow header
+            false => (
+                vec![0; CompressedMap5::offset(header.hunkcount as usize)],
+                124,
+            ),
+        };
+        Ok(Self {
+            io,
+            map,
+            header,
+            hunknum: 0,
+            offset,
+            stored: HashMap::new(),
+            rawsha1: sha1::Sha1::new(),
+            metadata: Vec::new(),
+            hunkbuf: Vec::new(),
+            codecs,
+            compressed: Vec::new(),
+            best: Vec::new(),
+            progress: options.progress.clone(),
+        })
+    }
+
+    pub fn hunk_size(&self) -> usize {
+        self.header.hunkbytes as usize
+    }
+
+    pub fn hunk_count(&self) -> usize {
+        self.header.hunkcount as usize
+    }
+
+    // Number of hunks written so far
+    pub fn hunks_written(&self) -> usize {
+        self.hunknum
+    }
+
+    // Hunks are compressed and map is compressed
+    pub fn compressed(&self) -> bool {
+        !self.codecs.is_empty()
+    }
+
+    // Add metadata entry, `checksum` includes it into chd sha1
+    pub fn add_metadata(&mut self, metatag: u32, data: &[u8], checksum: bool) -> io::Result<()> {
+        if data.len() >= 1 << 24 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "metadata {}: {} bytes is too long",
+                    tag_string(metatag),
+                    data.len()
+                ),
+            ));
+        }
+        let flags = match checksum {
+            true => MDFLAGS_CHECKSUM,
+            false => 0,
+        };
+        self.metadata.push((metatag, flags, data.to_vec()));
+        Ok(())
+    }
+
+    // logical bytes of the next hunk, checks that there is one
+    fn next_hunk(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.hunknum >= self.hunk_count() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "writer: all {} hunks are already written",
+                    self.hunk_count()
+                ),
+            ));
+        }
+        let hunkbytes = self.header.hunkbytes as u64;
+        let left = self.header.size - self.hunknum as u64 * hunkbytes;
+        let need = std::cmp::min(left, hunkbytes) as usize;
+        if data.len() < need || data.len() > self.hunk_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "writer: hunk#{} has {} bytes, need {}",
+                    self.hunknum,
+                    data.len(),
+                    need
+                ),
+            ));
+        }
+        self.rawsha1.update(&data[..need]);
+        Ok(need)
+    }
+
+    // hunk is written, move to the next one
+    fn advance(&mut self) {
+        self.hunknum += 1;
+        if let Some(progress) = &self.progress {
+            let done = self.hunknum as u64 * self.header.hunkbytes as u64;
+            progress.report(done.min(self.header.size), self.header.size);
+        }
+    }
+
+    fn set_entry(&mut self, entry: u32) -> io::Result<()> {
+        let o = UncompressedMap5::offset(self.hunknum);
+        self.map.put_be32(o, entry)?;
+        self.advance();
+        Ok(())
+    }
+
+    // Set expanded entry of compressed map, length and crc are 0 for references
+    fn set_compressed_entry(
+        &mut self,
+        compression: u8,
+        length: u32,
+        offset: u64,
+        crc: u16,
+    ) -> io::Result<()> {
+        let o = CompressedMap5::offset(self.hunknum);
+        self.map[o] = compression;
+        self.map.put_be24(o + 1, length)?;
+        self.map.put_be48(o + 4, offset)?;
+        self.map.put_be16(o + 10, crc)?;
+        self.advance();
+        Ok(())
+    }
+
+    // Compress hunk by every codec, the shortest data is left in best.
+    // Returns its compression type, COMPRESSION_NONE if no codec makes hunk shorter.
+    fn compress_hunk(&mut self, hunk: &[u8]) -> u8 {
+        let mut compression = COMPRESSION_NONE;
+        self.best.clear();
+        for (i, codec) in self.codecs.iter_mut().enumerate() {
+            self.compressed.clear();
+            // codec which can't handle data, like flac of odd bytes, isn't used for hunk
+            if codec.compress(hunk, &mut self.compressed).is_err() {
+                continue;
+            }
+            let shorter = match compression {
+                COMPRESSION_NONE => self.compressed.len() < hunk.len(),
+                _ => self.compressed.len() < self.best.len(),
+            };
+            if shorter {
+                std::mem::swap(&mut self.compressed, &mut self.best);
+                compression = COMPRESSION_TYPE_0 + i as u8;
+            }
+        }
+        compression
+    }
+
+    // Write next hunk. Only the last hunk can be shorter than hunk size, it's padded by zeroes
+    pub fn write_hunk(&mut self, data: &[u8]) -> io::Result<()> {
+        self.next_hunk(data)?;
+        if data.len() == self.hunk_size() {
+            return self.write_full_hunk(data);
+        }
+        let mut hunk = std::mem::take(&mut self.hunkbuf);
+        hunk.clear();
+        hunk.extend_from_slice(data);
+        hunk.resize(self.hunk_size(), 0);
+        let result = self.write_full_hunk(&hunk);
+        self.hunkbuf = hunk;
+        result
+    }
+
+    fn write_full_hunk(&mut self, hunk: &[u8]) -> io::Result<()> {
+        match self.compressed() {
+            true => self.write_compressed(hunk),
+            false => self.write_uncompressed(hunk),
+        }
+    }
+
+    fn write_uncompressed(&mut self, hunk: &[u8]) -> io::Result<()> {
+        if self.header.parentsha1 == [0; 20] && hunk.iter().all(|b| *b == 0) {
+            return self.set_entry(0);
+        }
+        let digest = sha1::Sha1::from(hunk).digest().bytes();
+        if let Some(entry) = self.stored.get(&digest) {
+            let entry = *entry;
+            return self.set_entry(entry);
+        }
+        let hunkbytes = self.header.hunkbytes as u64;
+        let entry = u32::try_from(self.offset / hunkbytes)
+            .map_err(|_| invalid_data(format!("writer: hunk offset {} is too big", self.offset)))?;
+        self.io.seek(SeekFrom::Start(self.offset))?;
+        self.io.write_all(hunk)?;
+        self.offset += hunkbytes;
+        self.stored.insert(digest, entry);
+        self.set_entry(entry)
+    }
+
+    fn write_compressed(&mut self, hunk: &[u8]) -> io::Result<()> {
+        let digest = sha1::Sha1::from(hunk).digest().bytes();
+        if let Some(first) = self.stored.get(&digest) {
+            let first = *first as u64;
+            return self.set_compressed_entry(COMPRESSION_SELF, 0, first, 0);
+        }
+        let compression = self.compress_hunk(hunk);
+        let data = match compression {
+            COMPRESSION_NONE => hunk,
+            _ => &self.best[..],
+        };
+        self.io.seek(SeekFrom::Start(self.offset))?;
+        self.io.write_all(data)?;
+        let (offset, length) = (self.offset, data.len() as u32);
+        self.offset += length as u64;
+        self.stored.insert(digest, self.hunknum as u32);
+        self.set_compressed_entry(compression, length, offset, crc16(hunk))
+    }
+
+    // Write next hunk as data already compressed by codec, e.g. copied from chd of
+    // the same hunk size. `data` is the whole hunk it decompresses to, needed for
+    // checksums; it isn't checked against the compressed data.
+    pub fn write_compressed_hunk(
+        &mut self,
+        data: &[u8],
+        codec: u32,
+        compressed: &[u8],
+    ) -> io::Result<()> {
+        let slot = match self.compressed() {
+            true => self.header.compressors.iter().position(|tag| *tag == codec),
+            false => None,
+        };
+        let compression = match slot {
+            Some(slot) if codec != 0 => COMPRESSION_TYPE_0 + slot as u8,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("writer: chd isn't compressed by {}", tag_string(codec)),
+                ))
+            }
+        };
+        if data.len() != self.hunk_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "writer: hunk#{} has {} bytes, need whole hunk",
+                    self.hunknum,
+                    data.len()
+                ),
+            ));
+        }
+        self.next_hunk(data)?;
+        let digest = sha1::Sha1::from(data).digest().bytes();
+        if let Some(first) = self.stored.get(&digest) {
+            let first = *first as u64;
+            return self.set_compressed_entry(COMPRESSION_SELF, 0, first, 0);
+        }
+        self.io.seek(SeekFrom::Start(self.offset))?;
+        self.io.write_all(compressed)?;
+        let (offset, length) = (self.offset, compressed.len() as u32);
+        self.offset += length as u64;
+        self.stored.insert(digest, self.hunknum as u32);
+        self.set_compressed_entry(compression, length, offset, crc16(data))
+    }
+
+    // Write next hunk of child chd, which is the same as in parent.
+    // `data` must be the parent data, it's not stored but needed for checksum.
+    pub fn write_parent_hunk(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.header.parentsha1 == [0; 20] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "writer: chd has no parent",
+            ));
+        }
+        self.next_hunk(data)?;
+        match self.compressed() {
+            true => {
+                let units = (self.header.hunkbytes / self.header.unitbytes) as u64;
+                let offset = self.hunknum as u64 * units;
+                self.set_compressed_entry(COMPRESSION_PARENT, 0, offset, 0)
+            }
+            false => self.set_entry(0),
+        }
+    }
+
+    // Write map, metadata and header. Returns writer positioned at the end of chd.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.hunknum != self.hunk_count() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "writer: only {} of {} hunks are written",
+                    self.hunknum,
+                    self.hunk_count()
+                ),
+            ));
+        }
+        self.header.mapoffset = self.offset;
+        self.io.seek(SeekFrom::Start(self.header.mapoffset))?;
+        if self.compressed() {
+            self.map = CompressedMap5::compress(&self.header, &self.map)?;
+        }
+        self.io.write_all(&self.map)?;
+        let offset = self.header.mapoffset + self.map.len() as u64;
+
+        self.header.metaoffset = match self.metadata.is_empty() {
+            true => 0,
+            false => offset,
+        };
+        let metasha = write_metadata(&mut self.io, offset, &self.metadata)?;
+        let end = self.io.stream_position()?;
+
+        self.header.rawsha1 = self.rawsha1.digest().bytes();
+        self.header.sha1 = overall_sha1(&self.header.rawsha1, metasha);
+        self.io.seek(SeekFrom::Start(0))?;
+        self.io.write_all(&self.header.to_bytes()?)?;
+        self.io.seek(SeekFrom::Start(end))?;
+        self.io.flush()?;
+        Ok(self.io)
+    }
+}
+
+// Write metadata chain at current position, which is `offset`.
+// Returns hashes of checksummed entries for overall sha1.
+fn write_metadata<W: Write>(
+    io: &mut W,
+    mut offset: u64,
+    metadata: &[(u32, u8, Vec<u8>)],
+) -> io::Result<Vec<[u8; 24]>> {
+    let mut metasha = Vec::new();
+    for (i, (metatag, flags, data)) in metadata.iter().enumerate() {
+        let next = match i + 1 == metadata.len() {
+            true => 0,
+            false => offset + (MetadataEntry::SIZE + data.len()) as u64,
+        };
+        let mut entry = [0; MetadataEntry::SIZE];
+        entry.put_be32(0, *metatag)?;
+        entry[4] = *flags;
+        entry.put_be24(5, data.len() as u32)?;
+        entry.put_be64(8, next)?;
+        io.write_all(&entry)?;
+        io.write_all(data)?;
+        if flags & MDFLAGS_CHECKSUM != 0 {
+            metasha.push(metadata_hash(*metatag, data));
+        }
+        offset = next;
+    }
+    Ok(metasha)
+}
+
+fn read_all_metadata<T: R>(chd: &mut Chd<T>) -> io::Result<Vec<(u32, u8, Vec<u8>)>> {
+    let mut metadata = Vec::new();
+    Chd::visit_metadata(
+        &mut chd.io,
+        chd.filesize,
+        chd.header.metaoffset,
+        |io, entry| {
+            let mut data = vec![0; entry.length as usize];
+            io.read_at(entry.offset, &mut data)?;
+            metadata.push((entry.metatag, entry.flags, data));
+            Ok(())
+        },
+    )?;
+    Ok(metadata)
+}
+
+// In place metadata editing of chd opened for writing. The whole chain is
+// appended to the end of file, header is updated with new sha1.
+impl<T: R + Write> Chd<T> {
+    // Set data of `index`th entry with tag, or add a new entry if there's no such one
+    pub fn set_metadata(
+        &mut self,
+        metatag: u32,
+        index: u32,
+        data: &[u8],
+        checksum: bool,
+    ) -> io::Result<()> {
+        if data.len() >= 1 << 24 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "metadata {}: {} bytes is too long",
+                    tag_string(metatag),
+                    data.len()
+                ),
+            ));
+        }
+        let flags = match checksum {
+            true => MDFLAGS_CHECKSUM,
+            false => 0,
+        };
+        let mut metadata = read_all_metadata(self)?;
+        let entry = metadata
+            .iter_mut()
+            .filter(|(tag, _, _)| *tag == metatag)
+            .nth(index as usize);
+        match entry {
+            Some(entry) => *entry = (metatag, flags, data.to_vec()),
+            None => metadata.push((metatag, flags, data.to_vec())),
+        }
+        self.rewrite_metadata(&metadata)
+    }
+
+    // Remove `index`th entry with tag, returns false if there's no such one
+    pub fn delete_metadata(&mut self, metatag: u32, index: u32) -> io::Result<bool> {
+        let mut metadata = read_all_metadata(self)?;
+        let position = metadata
+            .iter()
+            .enumerate()
+            .filter(|(_, (tag, _, _))| *tag == metatag)
+            .nth(index as usize)
+            .map(|(i, _)| i);
+        match position {
+            Some(i) => {
+                metadata.remove(i);
+                self.rewrite_metadata(&metadata)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn rewrite_metadata(&mut self, metadata: &[(u32, u8, Vec<u8>)]) -> io::Result<()> {
+        let offset = self.io.seek(SeekFrom::End(0))?;
+        let metasha = write_metadata(&mut self.io, offset, metadata)?;
+        self.filesize = self.io.stream_position()?;
+        self.header.metaoffset = match metadata.is_empty() {
+            true => 0,
+            false => offset,
+        };
+        self.header.sha1 = overall_sha1(&self.header.rawsha1, metasha);
+        self.io.seek(SeekFrom::Start(0))?;
+        self.io.write_all(&self.header.to_bytes()?)?;
+        self.io.flush()?;
+        self.cachemeta = None;
+        Ok(())
+    }
+}
+
+// In place writes of logical data of chd with uncompressed map, e.g. hard disk of
+// emulated machine. Stored hunks used only by the written hunk are overwritten,
+// zero, parent and shared hunks get a new copy at the end of file. Header sha1s
+// aren't updated by writes: update_sha1() must be called after them, or the file
+// fails verification. ChdWriteHandle calls it on flush and drop.
+impl<T: R + Write> Chd<T> {
+    // Read, Seek and Write of logical data, which keeps header sha1s up to date
+    pub fn write_handle(&mut self) -> io::Result<ChdWriteHandle<'_, T>> {
+        if self.map.as_uncompressed_mut().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "chd: only chd with uncompressed map can be written",
+            ));
+        }
+        Ok(ChdWriteHandle { chd: self })
+    }
+
+    // Write logical data at offset, current position is not changed.
+    // Returns number of bytes written, which is less than buf has at logical end.
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        if self.map.as_uncompressed_mut().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "chd: only chd with uncompressed map can be written",
+            ));
+        }
+        let end = std::cmp::min(offset.saturating_add(buf.len() as u64), self.size());
+        let hunkbytes = self.header.hunkbytes as u64;
+        let mut pos = offset;
+        while pos < end {
+            let hunknum = (pos / hunkbytes) as usize;
+            let start = (pos % hunkbytes) as usize;
+            let length = std::cmp::min(hunkbytes - start as u64, end - pos) as usize;
+            let data = &buf[(pos - offset) as usize..][..length];
+            // partially written hunk is read first, including zero and parent hunks
+            let mut hunk = std::mem::take(&mut self.hunkbuf);
+            let result = match length == hunk.len() {
+                true => self.write_hunk_in_place(hunknum, data),
+                false => self.read_hunk(hunknum, &mut hunk).and_then(|_| {
+                    hunk[start..start + length].copy_from_slice(data);
+                    self.write_hunk_in_place(hunknum, &hunk)
+                }),
+            };
+            self.hunkbuf = hunk;
+            result?;
+            pos += length as u64;
+        }
+        Ok((end.max(offset) - offset) as usize)
+    }
+
+    // Logical data was written and header sha1s don't match it until update_sha1()
+    pub fn data_changed(&self) -> bool {
+        self.data_changed
+    }
+
+    // Recalculate data sha1 and overall sha1 with metadata, and rewrite the header
+    pub fn update_sha1(&mut self) -> io::Result<()> {
+        let pos = self.pos;
+        let rawsha1 = self.data_sha1();
+        self.pos = pos;
+        let rawsha1 = rawsha1?;
+        let metasha = read_all_metadata(self)?
+            .iter()
+            .filter(|(_, flags, _)| flags & MDFLAGS_CHECKSUM != 0)
+            .map(|(metatag, _, data)| metadata_hash(*metatag, data))
+            .collect();
+        self.header.rawsha1 = rawsha1;
+        self.header.sha1 = overall_sha1(&rawsha1, metasha);
+        self.io.seek(SeekFrom::Start(0))?;
+        self.io.write_all(&self.header.to_bytes()?)?;
+        self.io.flush()?;
+        self.data_changed = false;
+        Ok(())
+    }
+
+    fn write_hunk_in_place(&mut self, hunknum: usize, data: &[u8]) -> io::Result<()> {
+        let hunkbytes = self.header.hunkbytes as u64;
+        let owned = self.map.as_uncompressed_mut().unwrap().owned(hunknum)?;
+        let entry = match owned {
+            Some(entry) => entry,
+            None => u32::try_from(self.filesize.div_ceil(hunkbytes))
+                .map_err(|_| invalid_data(format!("chd: no room for hunk#{}", hunknum)))?,
+        };
+        self.io.seek(SeekFrom::Start(entry as u64 * hunkbytes))?;
+        self.io.write_all(data)?;
+        self.cache.evict(hunknum);
+        self.data_changed = true;
+        if owned.is_none() {
+            // map entry points to the new copy only when its data is written
+            self.filesize = (entry as u64 + 1) * hunkbytes;
+            let value = entry.to_be_bytes();
+            let mapentry = UncompressedMap5::offset(hunknum) as u64;
+            self.io
+                .seek(SeekFrom::Start(self.header.mapoffset + mapentry))?;
+            self.io.write_all(&value)?;
+            self.map
+                .as_uncompressed_mut()
+                .unwrap()
+                .set_entry(hunknum, entry)?;
+        }
+        Ok(())
+    }
+}
+
+// Logical data of chd opened for writing as a file. Writes go to Chd::write_at
+// at current position. Header sha1s are updated on flush and, if data changed
+// since then, on drop, where errors are ignored; flush to see them.
+pub struct ChdWriteHandle<'a, T: R + Write> {
+    chd: &'a mut Chd<T>,
+}
+
+impl<T: R + Write> ChdWriteHandle<'_, T> {
+    pub fn chd(&mut self) -> &mut Chd<T> {
+        self.chd
+    }
+}
+
+impl<T: R + Write> Read for ChdWriteHandle<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.chd.read(buf)
+    }
+}
+
+impl<T: R + Write> Seek for ChdWriteHandle<'_, T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.chd.seek(pos)
+    }
+}
+
+impl<T: R + Write> Write for ChdWriteHandle<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.chd.write_at(self.chd.pos as u64, buf)?;
+        self.chd.pos += written as i64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.chd.data_changed() {
+            self.chd.update_sha1()?;
+        }
+        self.chd.io.flush()
+    }
+}
+
+impl<T: R + Write> Drop for ChdWriteHandle<'_, T> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+fn copy_metadata<T: R, W: Write + Seek>(
+    chd: &mut Chd<T>,
+    writer: &mut ChdWriter<W>,
+) -> io::Result<()> {
+    for (metatag, flags, data) in read_all_metadata(chd)? {
+        writer.add_metadata(metatag, &data, flags & MDFLAGS_CHECKSUM != 0)?;
+    }
+    Ok(())
+}
+
+// Codecs of chd which the writer can compress hunks with, in slot order
+pub fn codecs_of<T: R>(chd: &Chd<T>) -> Vec<u32> {
+    chd.codecs()
+        .iter()
+        .flatten()
+        .copied()
+        .filter(|tag| COMPRESSORS.contains(tag))
+        .collect()
+}
+
+// Write copy of chd with the same logical data and metadata in hunks of given size,
+// compressed by codecs, or uncompressed if there are none. Hunks of the same size
+// compressed by one of codecs are copied without recompression.
+// With `parent`, hunks equal to the same hunks of parent are referenced instead of
+// stored, otherwise the copy is standalone with parent hunks of chd read in.
+pub fn transcode<T: R, P: R, W: Write + Seek>(
+    chd: &mut Chd<T>,
+    mut parent: Option<&mut Chd<P>>,
+    hunk_size: u32,
+    codecs: &[u32],
+    to: W,
+) -> io::Result<W> {
+    let mut options =
+        WriterOptions::new(chd.size(), hunk_size, chd.unit_size_u32()).with_compression(codecs);
+    // the copy goes through the whole image of chd
+    options.progress = chd.progress.clone();
+    if let Some(parent) = &parent {
+        if chd.unit_size() != parent.unit_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "transcode: unit size {} differs from parent unit size {}",
+                    chd.unit_size(),
+                    parent.unit_size()
+                ),
+            ));
+        }
+        options.parent_sha1 = Some(parent.sha1());
+    }
+    let mut writer = ChdWriter::create(to, &options)?;
+    copy_metadata(chd, &mut writer)?;
+    let same_hunks = writer.compressed() && hunk_size == chd.hunk_size_u32();
+    let hunkbytes = writer.hunk_size() as u64;
+    let mut buf = vec![0; writer.hunk_size()];
+    let mut parent_buf = vec![0; writer.hunk_size()];
+    for hunknum in 0..writer.hunk_count() {
+        let offset = hunknum as u64 * hunkbytes;
+        let length = std::cmp::min(hunkbytes, chd.size() - offset) as usize;
+        buf[length..].fill(0);
+        chd.read_exact_at(offset, &mut buf[..length])?;
+        // only whole hunks of parent can be referenced
+        let same = match &mut parent {
+            Some(parent) if offset + hunkbytes <= parent.size() => {
+                parent.read_exact_at(offset, &mut parent_buf)?;
+                parent_buf == buf
+            }
+            _ => false,
+        };
+        if same {
+            writer.write_parent_hunk(&buf[..length])?;
+            continue;
+        }
+        // whole hunk only, padding of the last one may differ
+        let codec = match same_hunks && length == buf.len() {
+            true => match chd.hunk_info(hunknum)?.compression {
+                HunkCompression::Codec(tag) if codecs.contains(&tag) => Some(tag),
+                _ => None,
+            },
+            false => None,
+        };
+        match codec {
+            Some(tag) => writer.write_compressed_hunk(&buf, tag, &chd.read_hunk_raw(hunknum)?)?,
+            None => writer.write_hunk(&buf[..length])?,
+        }
+    }
+    writer.finish()
+}
+
+// Write standalone copy of chd with all parent hunks read from its parent.
+// Logical data, metadata and so sha1 stay the same. Compressed hunks of chd are
+// copied as they are, hunks of parent are compressed by codecs of chd.
+pub fn merge<T: R, W: Write + Seek>(chd: &mut Chd<T>, to: W) -> io::Result<W> {
+    let hunk_size = chd.hunk_size_u32();
+    let codecs = codecs_of(chd);
+    transcode(chd, None::<&mut Chd<T>>, hunk_size, &codecs, to)
+}
+
+// Write child of `parent` with the same data as chd, storing only hunks that differ
+// from the same hunks of parent, compressed like in chd. Inverse of merge().
+pub fn split<T: R, P: R, W: Write + Seek>(
+    chd: &mut Chd<T>,
+    parent: &mut Chd<P>,
+    to: W,
+) -> io::Result<W> {
+    let hunk_size = chd.hunk_size_u32();
+    let codecs = codecs_of(chd);
+    transcode(chd, Some(parent), hunk_size, &codecs, to)
+}
+
+// Hunk of written chd that doesn't read back as its source data
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HunkMismatch {
+    pub hunk: usize,
+    pub reason: String, // read error or first differing byte
+}
+
+// Result of reading written chd back and comparing it with source data
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoundTripReport {
+    pub hunks: usize, // hunks compared
+    pub bytes: u64,   // logical bytes compared
+    pub mismatched: Vec<HunkMismatch>,
+    pub rawsha1: [u8; 20],        // of source data
+    pub header_rawsha1: [u8; 20], // stored in header
+    pub sha1: [u8; 20],           // of source data and checksummed metadata
+    pub header_sha1: [u8; 20],    // stored in header
+}
+
+impl RoundTripReport {
+    pub fn passed(&self) -> bool {
+        self.mismatched.is_empty()
+            && self.rawsha1 == self.header_rawsha1
+            && self.sha1 == self.header_sha1
+    }
+}
+
+// Check just written chd: read every hunk back and compare with the same bytes of
+// source, which is read from its start to chd size, and compare sha1s of source
+// with header ones. Errors of chd reads are reported per hunk, errors of source
+// reads end the check. Parent of child chd must be attached.
+pub fn verify_written<T: R, S: Read>(
+    chd: &mut Chd<T>,
+    source: &mut S,
+) -> io::Result<RoundTripReport> {
+    let mut report = RoundTripReport {
+        header_rawsha1: chd.raw_sha1(),
+        header_sha1: chd.sha1(),
+        ..Default::default()
+    };
+    let hunkbytes = chd.hunk_size() as u64;
+    let mut expected = vec![0; chd.hunk_size()];
+    let mut actual = vec![0; chd.hunk_size()];
+    let mut rawsha1 = sha1::Sha1::new();
+    for hunknum in 0..chd.hunk_count() {
+        let offset = hunknum as u64 * hunkbytes;
+        let length = std::cmp::min(hunkbytes, chd.size() - offset) as usize;
+        source.read_exact(&mut expected[..length])?;
+        rawsha1.update(&expected[..length]);
+        let reason = match chd.read_exact_at(offset, &mut actual[..length]) {
+            Err(e) => Some(e.to_string()),
+            Ok(()) => (0..length)
+                .find(|&i| actual[i] != expected[i])
+                .map(|i| format!("differs from source at byte {}", offset + i as u64)),
+        };
+        if let Some(reason) = reason {
+            report.mismatched.push(HunkMismatch {
+                hunk: hunknum,
+                reason,
+            });
+        }
+        report.hunks += 1;
+        report.bytes += length as u64;
+        chd.report_progress(report.bytes);
+    }
+    report.rawsha1 = rawsha1.digest().bytes();
+    let metasha = read_all_metadata(chd)?
+        .into_iter()
+        .filter(|(_, flags, _)| flags & MDFLAGS_CHECKSUM != 0)
+        .map(|(metatag, _, data)| metadata_hash(metatag, &data))
+        .collect();
+    report.sha1 = overall_sha1(&report.rawsha1, metasha);
+    chd.report_finished(report.bytes);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_writer() {
+        let data = include_bytes!("../samples/data.b64");
+        let mut options = WriterOptions::new(data.len() as u64 + 4096, 4096, 512);
+        let mut writer = ChdWriter::create(Cursor::new(Vec::new()), &options).unwrap();
+        assert!(writer.write_hunk(&[0; 100]).is_err());
+        writer
+            .add_metadata(tags::metadata::HARD_DISK, b"CYLS:1", true)
+            .unwrap();
+        for (i, hunk) in data.chunks(4096).enumerate() {
+            writer.write_hunk(hunk).unwrap();
+            if i == 0 {
+                // duplicate hunk isn't stored
+                writer.write_hunk(hunk).unwrap();
+            }
+        }
+        let file = writer.finish().unwrap().into_inner();
+        assert!(file.len() < options.logical_size as usize + 4096);
+
+        let mut chd = Chd::open(Cursor::new(&file[..])).unwrap();
+        assert!(!chd.compressed());
+        assert_eq!(chd.size(), options.logical_size);
+        let mut image = Vec::new();
+        chd.read_to_end(&mut image).unwrap();
+        let mut expected = data[..4096].to_vec();
+        expected.extend_from_slice(data);
+        assert!(image == expected);
+        let meta = chd.read_metadata_simple(tags::metadata::HARD_DISK).unwrap();
+        assert_eq!(meta.unwrap(), b"CYLS:1");
+        assert_eq!(chd.raw_sha1(), sha1::Sha1::from(&expected).digest().bytes());
+
+        options.logical_size = 0;
+        options.unit_size = 3;
+        assert!(ChdWriter::create(Cursor::new(Vec::new()), &options).is_err());
+    }
+
+    #[test]
+    fn test_compressed_writer() {
+        let data = include_bytes!("../samples/data.b64");
+        let mut expected = data[..8192].to_vec();
+        expected.extend_from_slice(&[0; 8192]);
+        expected.extend_from_slice(&data[..4096]);
+        let mut x = 0x2545f491u32;
+        expected.extend((0..4096).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        }));
+        expected.extend_from_slice(&data[8192..8192 + 100]);
+        let codecs: Vec<u32> = [
+            CHD_CODEC_LZMA,
+            CHD_CODEC_ZLIB,
+            CHD_CODEC_HUFF,
+            CHD_CODEC_FLAC,
+        ]
+        .iter()
+        .copied()
+        .filter(|tag| COMPRESSORS.contains(tag))
+        .collect();
+        let options =
+            WriterOptions::new(expected.len() as u64, 4096, 512).with_compression(&codecs);
+        let mut writer = ChdWriter::create(Cursor::new(Vec::new()), &options).unwrap();
+        assert!(writer.compressed());
+        for hunk in expected.chunks(4096) {
+            writer.write_hunk(hunk).unwrap();
+        }
+        let file = writer.finish().unwrap().into_inner();
+        assert!(file.len() < expected.len() / 2 + 4096);
+
+        let mut chd = Chd::open(Cursor::new(&file[..])).unwrap();
+        assert!(chd.compressed());
+        assert_eq!(chd.size(), expected.len() as u64);
+        let mut image = Vec::new();
+        chd.read_to_end(&mut image).unwrap();
+        assert!(image == expected);
+        assert!(chd.verify().is_ok());
+        let types: Vec<HunkCompression> = (0..chd.hunk_count())
+            .map(|h| chd.hunk_info(h).unwrap().compression)
+            .collect();
+        assert_eq!(types[3], HunkCompression::SelfRef); // second zero hunk
+        assert_eq!(types[4], HunkCompression::SelfRef); // the first hunk again
+        assert_eq!(types[5], HunkCompression::None); // noise
+
+        // empty slot before codec
+        let options = WriterOptions::new(4096, 4096, 512).with_compression(&[0, CHD_CODEC_ZLIB]);
+        assert!(ChdWriter::create(Cursor::new(Vec::new()), &options).is_err());
+    }
+
+    #[test]
+    fn test_compressed_child() {
+        let data = include_bytes!("../samples/data.b64");
+        let parent = write_image(&data[..3 * 4096]);
+        let parent = Chd::open(Cursor::new(parent)).unwrap();
+        let mut options =
+            WriterOptions::new(3 * 4096, 4096, 512).with_compression(&[CHD_CODEC_ZLIB]);
+        options.parent_sha1 = Some(parent.sha1());
+        let mut writer = ChdWriter::create(Cursor::new(Vec::new()), &options).unwrap();
+        writer.write_parent_hunk(&data[..4096]).unwrap();
+        writer.write_hunk(&data[8192..3 * 4096]).unwrap();
+        writer.write_parent_hunk(&data[8192..3 * 4096]).unwrap();
+        let child = writer.finish().unwrap().into_inner();
+        let mut chd = Chd::open(Cursor::new(&child[..])).unwrap();
+        chd.set_parent(parent).unwrap();
+        let mut image = Vec::new();
+        chd.read_to_end(&mut image).unwrap();
+        let mut expected = data[..4096].to_vec();
+        expected.extend_from_slice(&data[8192..3 * 4096]);
+        expected.extend_from_slice(&data[8192..3 * 4096]);
+        assert!(image == expected);
+        assert!(chd.verify().is_ok());
+    }
+
+    fn write_image(data: &[u8]) -> Vec<u8> {
+        let options = WriterOptions::new(data.len() as u64, 4096, 512);
+        let mut writer = ChdWriter::create(Cursor::new(Vec::new()), &options).unwrap();
+        writer
+            .add_metadata(tags::metadata::HARD_DISK, b"CYLS:1", true)
+            .unwrap();
+        for hunk in data.chunks(4096) {
+            writer.write_hunk(hunk).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_short_parent() {
+        // parent ends in the middle of child's second hunk, before the third one
+        let data = include_bytes!("../samples/data.b64");
+        let parent = write_image(&data[..4096 + 100]);
+        let parent = Chd::open(Cursor::new(parent)).unwrap();
+        let mut options = WriterOptions::new(3 * 4096, 4096, 512);
+        options.parent_sha1 = Some(parent.sha1());
+        let mut writer = ChdWriter::create(Cursor::new(Vec::new()), &options).unwrap();
+        for hunk in data[..3 * 4096].chunks(4096) {
+            writer.write_parent_hunk(hunk).unwrap();
+        }
+        let child = writer.finish().unwrap().into_inner();
+        let mut chd = Chd::open(Cursor::new(&child[..])).unwrap();
+        chd.set_parent(parent).unwrap();
+
+        let mut expected = data[..4096 + 100].to_vec();
+        expected.resize(2 * 4096, 0);
+        let mut buf = vec![0xff; 4096];
+        for (hunknum, expected) in expected.chunks(4096).enumerate() {
+            chd.read_hunk(hunknum, &mut buf).unwrap();
+            assert_eq!(buf, expected);
+            buf.fill(0xff);
+        }
+        // the third hunk is entirely past parent end
+        let err = chd.read_hunk(2, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(chd.extract(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_write_in_place() {
+        // the first two hunks share stored data and the last one is zeroes
+        let data = include_bytes!("../samples/data.b64");
+        let mut expected = data[..4096].to_vec();
+        expected.extend_from_slice(&data[..4096]);
+        expected.extend_from_slice(&data[8192..]);
+        expected.extend_from_slice(&[0; 5000]);
+        let mut chd = Chd::open(Cursor::new(write_image(&expected))).unwrap();
+        let size = chd.size();
+
+        assert!(!chd.data_changed());
+        assert_eq!(chd.write_at(4000, &[0x55; 200]).unwrap(), 200);
+        expected[4000..4200].fill(0x55);
+        let last = size - 100;
+        assert_eq!(chd.write_at(last, &[0xaa; 200]).unwrap(), 100);
+        expected[last as usize..].fill(0xaa);
+        let hunk = vec![0x33; 4096];
+        assert_eq!(chd.write_at(8192, &hunk).unwrap(), 4096);
+        expected[8192..12288].copy_from_slice(&hunk);
+        assert_eq!(chd.write_at(size, &hunk).unwrap(), 0);
+        assert!(chd.data_changed());
+        let mut image = vec![0; size as usize];
+        chd.read_exact_at(0, &mut image).unwrap();
+        assert!(image == expected);
+
+        chd.update_sha1().unwrap();
+        assert!(!chd.data_changed());
+        let sha1 = Chd::open(Cursor::new(write_image(&expected)))
+            .unwrap()
+            .sha1();
+        let chd = Chd::open(Cursor::new(chd.into_inner().into_inner())).unwrap();
+        assert_eq!(chd.raw_sha1(), sha1::Sha1::from(&expected).digest().bytes());
+        assert_eq!(chd.sha1(), sha1);
+
+        let raw = include_bytes!("../samples/zlib.chd").to_vec();
+        let mut chd = Chd::open(Cursor::new(raw)).unwrap();
+        let err = chd.write_at(0, &[0; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_write_handle() {
+        let data = include_bytes!("../samples/data.b64");
+        let mut expected = data[..3 * 4096].to_vec();
+        let mut chd = Chd::open(Cursor::new(write_image(&expected))).unwrap();
+        let sha1 = chd.sha1();
+        {
+            let mut handle = chd.write_handle().unwrap();
+            handle.seek(SeekFrom::Start(100)).unwrap();
+            handle.write_all(&[42; 5000]).unwrap();
+            assert_eq!(handle.stream_position().unwrap(), 5100);
+            handle.seek(SeekFrom::End(-1)).unwrap();
+            assert!(handle.write_all(&[42; 2]).is_err());
+            assert!(handle.chd().data_changed());
+            handle.flush().unwrap();
+            assert!(!handle.chd().data_changed());
+            handle.seek(SeekFrom::Start(0)).unwrap();
+            handle.write_all(&[7; 10]).unwrap();
+            // header is updated on drop too
+        }
+        expected[100..5100].fill(42);
+        expected[..10].fill(7);
+        expected[3 * 4096 - 1] = 42;
+        assert!(!chd.data_changed());
+        assert_ne!(chd.sha1(), sha1);
+        let chd = Chd::open(Cursor::new(chd.into_inner().into_inner())).unwrap();
+        assert_eq!(chd.raw_sha1(), sha1::Sha1::from(&expected).digest().bytes());
+
+        let raw = include_bytes!("../samples/zlib.chd").to_vec();
+        let mut chd = Chd::open(Cursor::new(raw)).unwrap();
+        assert!(chd.write_handle().is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let raw = include_bytes!("../samples/child.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let parent = Chd::open(Cursor::new(&include_bytes!("../samples/huff.chd")[..]));
+        chd.set_parent(parent.unwrap()).unwrap();
+        let file = merge(&mut chd, Cursor::new(Vec::new()))
+            .unwrap()
+            .into_inner();
+
+        let mut merged = Chd::open(Cursor::new(&file[..])).unwrap();
+        assert!(!merged.has_parent());
+        assert_eq!(merged.sha1(), chd.sha1());
+        assert_eq!(merged.raw_sha1(), chd.raw_sha1());
+        let image = include_bytes!("../samples/child.b64");
+        let mut sample = vec![0; image.len()];
+        merged.read_exact(&mut sample).unwrap();
+        assert!(sample[..] == image[..]);
+
+        // compressed by codecs of child, its compressed hunks are copied as is
+        assert_eq!(merged.codecs(), chd.codecs());
+        merged.verify().unwrap();
+        let mut copied = 0;
+        for hunknum in 0..chd.hunk_count() {
+            let whole = (hunknum + 1) * chd.hunk_size() <= chd.size() as usize;
+            if let HunkCompression::Codec(_) = chd.hunk_info(hunknum).unwrap().compression {
+                if whole {
+                    let raw = chd.read_hunk_raw(hunknum).unwrap();
+                    assert_eq!(merged.read_hunk_raw(hunknum).unwrap(), raw);
+                    copied += 1;
+                }
+            }
+        }
+        assert!(copied > 0);
+    }
+
+    #[test]
+    fn test_split() {
+        let raw = include_bytes!("../samples/child.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        let parent_raw = &include_bytes!("../samples/huff.chd")[..];
+        chd.set_parent(Chd::open(Cursor::new(parent_raw)).unwrap())
+            .unwrap();
+        let merged = merge(&mut chd, Cursor::new(Vec::new()))
+            .unwrap()
+            .into_inner();
+
+        let mut merged = Chd::open(Cursor::new(&merged[..])).unwrap();
+        let mut parent = Chd::open(Cursor::new(parent_raw)).unwrap();
+        let file = split(&mut merged, &mut parent, Cursor::new(Vec::new()))
+            .unwrap()
+            .into_inner();
+        // only 4 changed hunks are stored
+        assert!(file.len() < 6 * merged.hunk_size());
+
+        let mut child = Chd::open(Cursor::new(&file[..])).unwrap();
+        assert_eq!(child.parent_sha1(), parent.sha1());
+        assert_eq!(child.sha1(), chd.sha1());
+        assert_eq!(
+            child.hunk_info(0).unwrap().compression,
+            HunkCompression::Parent
+        );
+        child.set_parent(parent).unwrap();
+        let image = include_bytes!("../samples/child.b64");
+        let mut sample = vec![0; image.len()];
+        child.read_exact(&mut sample).unwrap();
+        assert!(sample[..] == image[..]);
+    }
+
+    #[test]
+    fn test_transcode() {
+        let raw = include_bytes!("../samples/huff.chd");
+        let mut chd = Chd::open(Cursor::new(&raw[..])).unwrap();
+        assert_eq!(codecs_of(&chd), [CHD_CODEC_HUFF]);
+        let huff = [CHD_CODEC_HUFF];
+        for (hunk_size, codecs) in [(2048, &[][..]), (8192, &[][..]), (8192, &huff[..])] {
+            let file = transcode(
+                &mut chd,
+                None::<&mut Chd<Cursor<&[u8]>>>,
+                hunk_size,
+                codecs,
+                Cursor::new(Vec::new()),
+            )
+            .unwrap()
+            .into_inner();
+            let mut copy = Chd::open(Cursor::new(&file[..])).unwrap();
+            assert_eq!(copy.hunk_size(), hunk_size as usize);
+            assert_eq!(copy.size(), chd.size());
+            assert_eq!(copy.sha1(), chd.sha1());
+            if !codecs.is_empty() {
+                assert!(copy.compressed());
+                copy.verify().unwrap();
+            }
+        }
+        // hunk size must be multiple of unit size
+        let to = Cursor::new(Vec::new());
+        assert!(transcode(&mut chd, None::<&mut Chd<Cursor<&[u8]>>>, 1000, &[], to).is_err());
+    }
+
+    #[test]
+    fn test_edit_metadata() {
+        let raw = include_bytes!("../samples/huff.chd");
+        let mut chd = Chd::open(Cursor::new(raw.to_vec())).unwrap();
+        let sha1 = chd.sha1();
+        let tag = tags::metadata::HARD_DISK_IDENT;
+        chd.set_metadata(tag, 0, b"first", true).unwrap();
+        chd.set_metadata(tag, 1, b"second", false).unwrap();
+        chd.set_metadata(tag, 0, b"changed", true).unwrap();
+        let checksummed = chd.sha1();
+        assert_ne!(checksummed, sha1);
+
+        // reopen to see what's in the file
+        let mut chd = Chd::open(Cursor::new(chd.into_inner().into_inner())).unwrap();
+        assert_eq!(chd.sha1(), checksummed);
+        assert_eq!(chd.read_metadata_simple(tag).unwrap().unwrap(), b"changed");
+        let mut buf = [0; 16];
+        assert_eq!(chd.read_metadata_at(tag, 1, 0, &mut buf).unwrap(), Some(6));
+        chd.verify().unwrap();
+
+        // entry without checksum doesn't change sha1
+        assert!(chd.delete_metadata(tag, 1).unwrap());
+        assert_eq!(chd.sha1(), checksummed);
+        assert!(chd.delete_metadata(tag, 0).unwrap());
+        assert!(!chd.delete_metadata(tag, 0).unwrap());
+        assert_eq!(chd.sha1(), sha1);
+        let mut data = Vec::new();
+        chd.seek(SeekFrom::Start(0)).unwrap();
+        chd.read_to_end(&mut data).unwrap();
+        let mut expected = Vec::new();
+        Chd::open(Cursor::new(&raw[..]))
+            .unwrap()
+            .read_to_end(&mut expected)
+            .unwrap();
+        assert!(data == expected);
+    }
+
+    #[test]
+    fn test_verify_written() {
+        let data = include_bytes!("../samples/data.b64");
+        let file = write_image(data);
+        let mut chd = Chd::open(Cursor::new(&file[..])).unwrap();
+        let report = verify_written(&mut chd, &mut &data[..]).unwrap();
+        assert!(report.passed());
+        assert_eq!(report.hunks, chd.hunk_count());
+        assert_eq!(report.bytes, data.len() as u64);
+        assert_eq!(report.sha1, chd.sha1());
+
+        // source differs in the second hunk
+        let mut source = data.to_vec();
+        source[5000] ^= 1;
+        let report = verify_written(&mut chd, &mut &source[..]).unwrap();
+        assert!(!report.passed());
+        assert_eq!(report.mismatched.len(), 1);
+        assert_eq!(report.mismatched[0].hunk, 1);
+        assert!(report.mismatched[0].reason.contains("byte 5000"));
+        assert_ne!(report.rawsha1, report.header_rawsha1);
+
+        // chd file damaged after writing
+        let mut damaged = file.clone();
+        let stored = file
+            .windows(64)
+            .position(|w| w == &data[4096..4096 + 64])
+            .unwrap();
+        damaged[stored] ^= 1;
+        let mut chd = Chd::open(Cursor::new(&damaged[..])).unwrap();
+        let report = verify_written(&mut chd, &mut &data[..]).unwrap();
+        assert_eq!(report.mismatched.len(), 1);
+        assert_eq!(report.mismatched[0].hunk, 1);
+        assert_eq!(report.rawsha1, report.header_rawsha1);
+
+        // source is shorter than chd
+        assert!(verify_written(&mut chd, &mut &data[..100]).is_err());
+    }
+}